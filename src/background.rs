@@ -0,0 +1,211 @@
+// Book scans get darker toward the spine and photos of pages are lit unevenly, so no single threshold separates the
+// paper from the ink everywhere. Dividing out the paper's own brightness first makes it evenly white.
+
+use image::*;
+
+use crate::pixel::from_u64;
+use crate::{Connectivity, DocumentImage, DocumentPixel};
+
+// Which fraction of a block's pixels are darker than its paper. Even dense text covers less of a block than the rest.
+const PAPER_PERCENTILE: f32 = 0.9;
+// Blocks whose paper comes out darker than this fraction of the typical block's are covered in ink (like a photo or a
+// solid heading), so their paper is taken from the blocks around them instead.
+const MIN_PAPER_RATIO: f32 = 0.5;
+
+// Estimates the paper's color in each block_size by block_size block of the image from its lightest pixels, and divides
+// every pixel by the paper color interpolated between the blocks' centers, so the paper comes out white. Each color
+// channel is divided separately, which also whitens tinted paper. Alpha is left as it is.
+pub fn normalize_background<P: DocumentPixel>(
+    image: &DocumentImage<P>,
+    block_size: u32,
+) -> DocumentImage<P> {
+    let (width, height) = image.dimensions();
+    let block_size = block_size.max(1);
+    let (blocks_x, blocks_y) = (width.div_ceil(block_size), height.div_ceil(block_size));
+    if blocks_x == 0 || blocks_y == 0 {
+        return image.clone();
+    }
+
+    let color_channels = P::CHANNEL_COUNT as usize - P::COLOR_TYPE.has_alpha() as usize;
+    let max: u64 = P::Subpixel::DEFAULT_MAX_VALUE.into();
+    let max = max as f32;
+
+    // The paper color of each block, None where it's all ink or transparent.
+    let mut paper: Vec<Option<[f32; 3]>> = Vec::with_capacity((blocks_x * blocks_y) as usize);
+    let mut values = Vec::new();
+    for block_y in 0..blocks_y {
+        for block_x in 0..blocks_x {
+            let (left, top) = (block_x * block_size, block_y * block_size);
+            let block = image.view(
+                left,
+                top,
+                block_size.min(width - left),
+                block_size.min(height - top),
+            );
+
+            let mut color = [0.0; 3];
+            for (channel, paper) in color.iter_mut().enumerate().take(color_channels) {
+                values.clear();
+                values.extend(
+                    block
+                        .pixels()
+                        .filter(|(_, _, p)| !p.is_transparent())
+                        .map(|(_, _, p)| p.channels()[channel]),
+                );
+                if values.is_empty() {
+                    break;
+                }
+                let nth = ((values.len() - 1) as f32 * PAPER_PERCENTILE) as usize;
+                let (_, &mut value, _) = values.select_nth_unstable(nth);
+                let value: u64 = value.into();
+                *paper = value as f32;
+            }
+            paper.push((!values.is_empty()).then_some(color));
+        }
+    }
+
+    let lightness =
+        |color: &[f32; 3]| color[..color_channels].iter().sum::<f32>() / color_channels as f32;
+    let mut lightnesses: Vec<f32> = paper.iter().flatten().map(lightness).collect();
+    if lightnesses.is_empty() {
+        return image.clone();
+    }
+    let middle = lightnesses.len() / 2;
+    let (_, &mut typical, _) = lightnesses.select_nth_unstable_by(middle, f32::total_cmp);
+    for color in &mut paper {
+        if color.is_some_and(|color| lightness(&color) < typical * MIN_PAPER_RATIO) {
+            *color = None;
+        }
+    }
+
+    // Fill in the blocks without paper from their neighbors, spreading out a ring of blocks at a time.
+    let blocks_x = blocks_x as usize;
+    while paper.iter().any(Option::is_none) {
+        let previous = paper.clone();
+        for (i, color) in paper.iter_mut().enumerate() {
+            if color.is_some() {
+                continue;
+            }
+
+            let (x, y) = ((i % blocks_x) as i32, (i / blocks_x) as i32);
+            let mut sum = [0.0; 3];
+            let mut count = 0.0;
+            for (dx, dy) in Connectivity::Eight.neighbors() {
+                let (x, y) = (x + dx, y + dy);
+                if x < 0 || y < 0 || x >= blocks_x as i32 {
+                    continue;
+                }
+                let Some(Some(neighbor)) = previous.get(y as usize * blocks_x + x as usize) else {
+                    continue;
+                };
+                for (sum, channel) in sum.iter_mut().zip(neighbor) {
+                    *sum += channel;
+                }
+                count += 1.0;
+            }
+            if count > 0.0 {
+                *color = Some(sum.map(|sum| sum / count));
+            }
+        }
+    }
+    let paper: Vec<[f32; 3]> = paper.into_iter().flatten().collect();
+    let blocks_y = blocks_y as usize;
+
+    // Bilinear interpolation between the blocks' centers, clamped to the outermost ones along the edges.
+    let block_position = |position: u32, blocks: usize| {
+        let position =
+            ((position as f32 + 0.5) / block_size as f32 - 0.5).clamp(0.0, (blocks - 1) as f32);
+        let before = (position as usize).min(blocks.saturating_sub(2));
+        (
+            before,
+            (before + 1).min(blocks - 1),
+            position - before as f32,
+        )
+    };
+    let mut normalized = image.clone();
+    for (x, y, pixel) in normalized.enumerate_pixels_mut() {
+        let (left, right, fraction_x) = block_position(x, blocks_x);
+        let (top, bottom, fraction_y) = block_position(y, blocks_y);
+        for (channel, value) in pixel
+            .channels_mut()
+            .iter_mut()
+            .enumerate()
+            .take(color_channels)
+        {
+            let at = |block_x: usize, block_y: usize| paper[block_y * blocks_x + block_x][channel];
+            let upper = at(left, top) * (1.0 - fraction_x) + at(right, top) * fraction_x;
+            let lower = at(left, bottom) * (1.0 - fraction_x) + at(right, bottom) * fraction_x;
+            let paper = (upper * (1.0 - fraction_y) + lower * fraction_y).max(1.0);
+
+            let original: u64 = (*value).into();
+            let scaled = (original as f32 * max / paper).round().min(max);
+            *value = from_u64(scaled as u64);
+        }
+    }
+
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uneven_paper_comes_out_white() {
+        // Paper darkening from 250 on the left to 150 on the right, with a line of ink at 40% of it.
+        let image = GrayImage::from_fn(200, 100, |x, y| {
+            let paper = 250.0 - x as f32 / 2.0;
+            let value = if y == 50 { paper * 0.4 } else { paper };
+            Luma([value.round() as u8])
+        });
+        let normalized = normalize_background(&image, 20);
+
+        for (x, y, pixel) in normalized.enumerate_pixels() {
+            let value = pixel.0[0] as f32;
+            if y == 50 {
+                assert!((value - 102.0).abs() <= 10.0, "({x}, {y}): {value}");
+            } else {
+                assert!(value >= 240.0, "({x}, {y}): {value}");
+            }
+        }
+    }
+
+    #[test]
+    fn all_ink_blocks_take_their_neighbors_paper() {
+        // A solid dark heading filling a whole block on paper at 200.
+        let mut image = GrayImage::from_pixel(64, 64, Luma([200]));
+        for (x, y, pixel) in image.enumerate_pixels_mut() {
+            if (16..32).contains(&x) && (16..32).contains(&y) {
+                *pixel = Luma([60]);
+            }
+        }
+        let normalized = normalize_background(&image, 16);
+
+        for (x, y, pixel) in normalized.enumerate_pixels() {
+            if (16..32).contains(&x) && (16..32).contains(&y) {
+                assert_eq!(pixel.0[0], 77, "({x}, {y})");
+            } else {
+                assert_eq!(pixel.0[0], 255, "({x}, {y})");
+            }
+        }
+
+        // A page that's nothing but ink is left as it is.
+        let image = GrayImage::from_pixel(64, 64, Luma([0]));
+        assert_eq!(normalize_background(&image, 16), image);
+    }
+
+    #[test]
+    fn images_smaller_than_a_block() {
+        let mut image = RgbImage::from_pixel(10, 6, Rgb([200, 180, 160]));
+        image.put_pixel(4, 3, Rgb([100, 90, 80]));
+        let normalized = normalize_background(&image, 64);
+
+        for (x, y, pixel) in normalized.enumerate_pixels() {
+            let expected = if (x, y) == (4, 3) { [128; 3] } else { [255; 3] };
+            assert_eq!(pixel.0, expected, "({x}, {y})");
+        }
+
+        let empty = GrayImage::new(0, 0);
+        assert_eq!(normalize_background(&empty, 64), empty);
+    }
+}
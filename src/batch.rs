@@ -0,0 +1,407 @@
+// Cleaning and saving whole batches of pages, shared by the GUI's export and the headless command line mode.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use image::*;
+
+use crate::{auto_deskew, CancelToken, ImageAnalyzer, ImageCleaner, Margins, OutputFormat};
+
+// The settings of the pages that don't use the batch's own, by page number (counting from 1), for things like title
+// pages and plates in a book of text.
+pub type PageSettings = HashMap<usize, (ImageAnalyzer, ImageCleaner)>;
+
+// Everything about how a batch of pages is cleaned and saved.
+#[derive(Clone)]
+pub struct ExportOptions {
+    pub analyzer: ImageAnalyzer,
+    pub cleaner: ImageCleaner,
+    // Used instead of analyzer and cleaner for the pages in it.
+    pub page_settings: PageSettings,
+    pub output_format: OutputFormat,
+    // Straightens each page before analyzing it.
+    pub auto_deskew: bool,
+    // Mirrors the margins on even pages, see page_cleaner.
+    pub mirror_margins: bool,
+    // Pages with less of their area in kept graphemes than this are flagged in the summary, to be checked by hand.
+    pub min_foreground_fraction: Option<f32>,
+    // How many pages are cleaned at the same time.
+    #[cfg(feature = "parallel")]
+    pub threads: usize,
+}
+
+// What came of exporting a batch.
+#[derive(Clone, Debug, Default)]
+pub struct ExportSummary {
+    // The pages that couldn't be exported with their errors, sorted by path.
+    pub failed: Vec<(PathBuf, String)>,
+    // The foreground_fraction of every page that was exported, in page order.
+    pub foreground_fractions: Vec<(PathBuf, f32)>,
+    // The exported pages under min_foreground_fraction, in page order.
+    pub flagged: Vec<(PathBuf, f32)>,
+}
+
+impl ExportOptions {
+    // The analyzer and cleaner for the page-th page, before page_cleaner.
+    pub fn settings(&self, page: usize) -> (ImageAnalyzer, &ImageCleaner) {
+        match self.page_settings.get(&page) {
+            Some((analyzer, cleaner)) => (*analyzer, cleaner),
+            None => (self.analyzer, &self.cleaner),
+        }
+    }
+}
+
+// Where each image gets saved in the export directory. Images keep their file names, unless images from different
+// folders share a name, in which case they're prefixed with the name of the folder they're in.
+pub fn export_paths(image_paths: &[PathBuf], directory: &Path) -> Vec<PathBuf> {
+    let mut name_counts = HashMap::new();
+    for path in image_paths {
+        *name_counts.entry(path.file_name()).or_insert(0) += 1;
+    }
+
+    image_paths
+        .iter()
+        .map(|path| {
+            let file_name = path.file_name().unwrap_or_default();
+            if name_counts[&path.file_name()] == 1 {
+                return directory.join(file_name);
+            }
+
+            let folder_name = path.parent().and_then(Path::file_name).unwrap_or_default();
+            let mut prefixed_name = folder_name.to_os_string();
+            prefixed_name.push("_");
+            prefixed_name.push(file_name);
+            directory.join(prefixed_name)
+        })
+        .collect()
+}
+
+// The extensions of the image formats that can be opened.
+pub fn image_extensions() -> Vec<&'static str> {
+    [
+        ImageFormat::Png,
+        ImageFormat::Jpeg,
+        ImageFormat::Tiff,
+        ImageFormat::WebP,
+    ]
+    .into_iter()
+    .flat_map(|format| format.extensions_str().iter().copied())
+    .collect()
+}
+
+// The images among paths, with folders replaced by the images anywhere inside them. Anything else is left out.
+pub fn collect_image_paths(paths: impl IntoIterator<Item = PathBuf>) -> Vec<PathBuf> {
+    let extensions = image_extensions();
+    let mut images = Vec::new();
+    let mut pending: Vec<PathBuf> = paths.into_iter().collect();
+    while let Some(path) = pending.pop() {
+        if path.is_dir() {
+            if let Ok(entries) = std::fs::read_dir(&path) {
+                pending.extend(entries.flatten().map(|entry| entry.path()));
+            }
+        } else if path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .is_some_and(|extension| {
+                extensions
+                    .iter()
+                    .any(|image_extension| extension.eq_ignore_ascii_case(image_extension))
+            })
+        {
+            images.push(path);
+        }
+    }
+
+    images
+}
+
+// Compares strings with runs of digits compared by their value, so page2 comes before page10.
+pub fn natural_cmp(a: &str, b: &str) -> std::cmp::Ordering {
+    let (mut a, mut b) = (a.chars().peekable(), b.chars().peekable());
+    loop {
+        match (a.peek().copied(), b.peek().copied()) {
+            (None, None) => return std::cmp::Ordering::Equal,
+            (None, Some(_)) => return std::cmp::Ordering::Less,
+            (Some(_), None) => return std::cmp::Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let digits = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+                    let mut number = String::new();
+                    while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+                        number.push(digit);
+                    }
+                    number
+                };
+                let (x, y) = (digits(&mut a), digits(&mut b));
+                let (x_value, y_value) = (x.trim_start_matches('0'), y.trim_start_matches('0'));
+                let ordering = x_value
+                    .len()
+                    .cmp(&y_value.len())
+                    .then_with(|| x_value.cmp(y_value))
+                    .then_with(|| x.len().cmp(&y.len()));
+                if ordering.is_ne() {
+                    return ordering;
+                }
+            }
+            (Some(x), Some(y)) => {
+                let ordering = x.cmp(&y);
+                if ordering.is_ne() {
+                    return ordering;
+                }
+                a.next();
+                b.next();
+            }
+        }
+    }
+}
+
+// The cleaner for the page-th page (counting from 1).
+pub fn page_cleaner(cleaner: &ImageCleaner, mirror_margins: bool, page: usize) -> ImageCleaner {
+    match mirror_margins && page.is_multiple_of(2) {
+        true => ImageCleaner {
+            page_margins: cleaner.page_margins.mirrored(),
+            page_margin_fractions: cleaner.page_margin_fractions.map(Margins::mirrored),
+            ..cleaner.clone()
+        },
+        false => cleaner.clone(),
+    }
+}
+
+// Opens, analyzes, and cleans the page at path, giving back the cleaned page and its foreground_fraction. None if it
+// was cancelled before it was done.
+pub fn clean_page(
+    path: &Path,
+    analyzer: ImageAnalyzer,
+    cleaner: ImageCleaner,
+    deskew: bool,
+    cancel_token: &CancelToken,
+) -> ImageResult<Option<(DynamicImage, f32)>> {
+    let mut image = image::io::Reader::open(path)?.decode()?;
+    if deskew {
+        image = auto_deskew(image);
+    }
+    let Some(analyzed_image) = analyzer.try_analyze_dynamic_cancellable(&image, cancel_token)?
+    else {
+        return Ok(None);
+    };
+    let foreground_fraction = analyzed_image.foreground_fraction(&cleaner);
+    let cleaned_image = if cleaner.preserve_original {
+        cleaner.clean_dynamic_onto(&analyzed_image, &image)?
+    } else {
+        let Ok(cleaned_image) = cleaner.clean_dynamic_cancellable(&analyzed_image, cancel_token)
+        else {
+            return Ok(None);
+        };
+        cleaned_image
+    };
+
+    Ok(Some((cleaned_image, foreground_fraction)))
+}
+
+// Cleans each of image_paths and saves it to the same place in export_paths (with the output format's extension), the
+// page number being its place in image_paths. progress is called with how many pages are done after each one. Pages
+// that fail are skipped so one bad file doesn't stop the whole batch, and they're in the summary with their errors.
+pub fn export_all(
+    image_paths: &[PathBuf],
+    export_paths: &[PathBuf],
+    options: &ExportOptions,
+    progress: impl Fn(usize) + Sync,
+    cancel_token: &CancelToken,
+) -> ExportSummary {
+    let errors = Mutex::new(Vec::new());
+    let fractions = Mutex::new(Vec::new());
+    // Pages finish out of order when they're exported on multiple threads, so progress counts finished pages.
+    let exported = AtomicUsize::new(0);
+    let export = |(i, (path, export_path)): (usize, (&PathBuf, &PathBuf))| {
+        if cancel_token.is_cancelled() {
+            return;
+        }
+
+        match export_page(path, export_path, i + 1, options, cancel_token) {
+            Ok(Some(fraction)) => fractions.lock().unwrap().push((i, path.clone(), fraction)),
+            Ok(None) => {}
+            Err(error) => errors
+                .lock()
+                .unwrap()
+                .push((path.clone(), error.to_string())),
+        }
+        progress(exported.fetch_add(1, Ordering::Relaxed) + 1);
+    };
+
+    // Each thread decodes, cleans, and saves one page at a time, so only that many pages are in memory at once.
+    #[cfg(feature = "parallel")]
+    {
+        use rayon::prelude::*;
+
+        let export_pages = || {
+            image_paths
+                .par_iter()
+                .zip(export_paths)
+                .enumerate()
+                .for_each(export)
+        };
+        match rayon::ThreadPoolBuilder::new()
+            .num_threads(options.threads)
+            .build()
+        {
+            Ok(pool) => pool.install(export_pages),
+            Err(_) => export_pages(),
+        }
+    }
+    #[cfg(not(feature = "parallel"))]
+    image_paths
+        .iter()
+        .zip(export_paths)
+        .enumerate()
+        .for_each(export);
+
+    let mut failed = errors.into_inner().unwrap();
+    failed.sort();
+    let mut fractions = fractions.into_inner().unwrap();
+    fractions.sort_by_key(|&(i, _, _)| i);
+    let foreground_fractions: Vec<(PathBuf, f32)> = fractions
+        .into_iter()
+        .map(|(_, path, fraction)| (path, fraction))
+        .collect();
+    let flagged = foreground_fractions
+        .iter()
+        .filter(|&&(_, fraction)| {
+            options
+                .min_foreground_fraction
+                .is_some_and(|min| fraction < min)
+        })
+        .cloned()
+        .collect();
+
+    ExportSummary {
+        failed,
+        foreground_fractions,
+        flagged,
+    }
+}
+
+fn export_page(
+    path: &Path,
+    export_path: &Path,
+    page: usize,
+    options: &ExportOptions,
+    cancel_token: &CancelToken,
+) -> ImageResult<Option<f32>> {
+    let (analyzer, cleaner) = options.settings(page);
+    let cleaner = page_cleaner(cleaner, options.mirror_margins, page);
+    // Cancelled part way through, so there's nothing to save.
+    let Some((cleaned_image, foreground_fraction)) =
+        clean_page(path, analyzer, cleaner, options.auto_deskew, cancel_token)?
+    else {
+        return Ok(None);
+    };
+    let output_format = options.output_format;
+    output_format.save(&cleaned_image, output_format.output_path(export_path))?;
+
+    Ok(Some(foreground_fraction))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cmp::Ordering;
+
+    use crate::tests::blank_page;
+
+    #[test]
+    fn natural_order() {
+        let mut names = vec![
+            "page10.png",
+            "page2.png",
+            "p01.png",
+            "page1.png",
+            "p1.png",
+            "page.png",
+            "page02.png",
+            "appendix.png",
+            "page1b.png",
+        ];
+        names.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(
+            names,
+            [
+                "appendix.png",
+                "p1.png",
+                "p01.png",
+                "page.png",
+                "page1.png",
+                "page1b.png",
+                "page2.png",
+                "page02.png",
+                "page10.png",
+            ]
+        );
+
+        assert_eq!(natural_cmp("page2", "page10"), Ordering::Less);
+        assert_eq!(natural_cmp("page10", "page2"), Ordering::Greater);
+        assert_eq!(natural_cmp("p1", "p01"), Ordering::Less);
+        assert_eq!(natural_cmp("p007", "p7"), Ordering::Greater);
+        assert_eq!(natural_cmp("page10.png", "page10.png"), Ordering::Equal);
+        assert_eq!(natural_cmp("", ""), Ordering::Equal);
+        assert_eq!(natural_cmp("", "1"), Ordering::Less);
+        // Too long for any integer type.
+        assert_eq!(
+            natural_cmp(
+                "scan99999999999999999999999",
+                "scan100000000000000000000000"
+            ),
+            Ordering::Less
+        );
+    }
+
+    #[test]
+    fn too_big_pages_fail_without_stopping_the_batch() {
+        let directory = std::env::temp_dir().join(format!(
+            "document_cleaner_{}_too_big_pages",
+            std::process::id()
+        ));
+        let export_directory = directory.join("export");
+        std::fs::create_dir_all(&export_directory).unwrap();
+        let image_paths: Vec<PathBuf> = [(20, 10), (40, 40), (10, 10)]
+            .into_iter()
+            .enumerate()
+            .map(|(i, (width, height))| {
+                let path = directory.join(format!("page{i}.png"));
+                blank_page(width, height).save(&path).unwrap();
+                path
+            })
+            .collect();
+        let options = ExportOptions {
+            analyzer: ImageAnalyzer {
+                max_pixels: 1000,
+                ..ImageAnalyzer::default()
+            },
+            cleaner: ImageCleaner::default(),
+            page_settings: PageSettings::default(),
+            output_format: OutputFormat::Png,
+            auto_deskew: false,
+            mirror_margins: false,
+            min_foreground_fraction: None,
+            #[cfg(feature = "parallel")]
+            threads: 2,
+        };
+        let summary = export_all(
+            &image_paths,
+            &export_paths(&image_paths, &export_directory),
+            &options,
+            |_| {},
+            &CancelToken::default(),
+        );
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(summary.failed.len(), 1);
+        assert_eq!(summary.failed[0].0, image_paths[1]);
+        let exported: Vec<&PathBuf> = summary
+            .foreground_fractions
+            .iter()
+            .map(|(path, _)| path)
+            .collect();
+        assert_eq!(exported, [&image_paths[0], &image_paths[2]]);
+    }
+}
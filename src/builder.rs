@@ -0,0 +1,580 @@
+// Builders for setting up an analyzer and cleaner from code a few parameters at a time, which check the parameters make
+// sense together before handing them over. The fields can still be set directly.
+
+use std::error::Error;
+use std::fmt;
+
+use image::math;
+
+use crate::{
+    Connectivity, FillStyle, ImageAnalyzer, ImageCleaner, IsolationMetric, LuminanceMode, Margins,
+    OutputMode, RegionMatch, SizeMetric, ThresholdMode,
+};
+
+// Half the width of a page scanned at 600 dpi, margins any wider would cover the whole page.
+const MAX_PAGE_MARGIN: u32 = 2500;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BuildError {
+    // Every pixel lighter than off_white_threshold is background anyway, so a higher lightness threshold does nothing.
+    LightnessAboveOffWhite {
+        lightness_threshold: u8,
+        off_white_threshold: u8,
+    },
+    SauvolaWindowTooSmall(u32),
+    SauvolaKOutOfRange(f32),
+    MarginTooLarge(u32),
+    MarginFractionOutOfRange(f32),
+    FillRatioOutOfRange(f32),
+    AspectRatioOutOfRange(f32),
+    AreaFractionOutOfRange(f32),
+    InkGammaNotPositive(f32),
+    // Everything would be darker than it, leaving nothing for the curve to darken.
+    InkBlackPointWhite,
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BuildError::LightnessAboveOffWhite {
+                lightness_threshold,
+                off_white_threshold,
+            } => write!(
+                f,
+                "the lightness threshold ({lightness_threshold}) is above the off-white threshold ({off_white_threshold})"
+            ),
+            BuildError::SauvolaWindowTooSmall(window) => {
+                write!(f, "the Sauvola window ({window}px) is smaller than 3px")
+            }
+            BuildError::SauvolaKOutOfRange(k) => {
+                write!(f, "the Sauvola k ({k}) isn't between 0 and 1")
+            }
+            BuildError::MarginTooLarge(margin) => write!(
+                f,
+                "the page margin ({margin}px) is over {MAX_PAGE_MARGIN}px"
+            ),
+            BuildError::MarginFractionOutOfRange(fraction) => write!(
+                f,
+                "the page margin ({fraction}) isn't between 0 and 0.5 of the page"
+            ),
+            BuildError::FillRatioOutOfRange(ratio) => {
+                write!(f, "the fill ratio ({ratio}) isn't between 0 and 1")
+            }
+            BuildError::AspectRatioOutOfRange(ratio) => {
+                write!(f, "the max aspect ratio ({ratio}) is less than 1")
+            }
+            BuildError::AreaFractionOutOfRange(fraction) => write!(
+                f,
+                "the max grapheme area fraction ({fraction}) isn't between 0 and 1"
+            ),
+            BuildError::InkGammaNotPositive(gamma) => {
+                write!(f, "the ink gamma ({gamma}) isn't above 0")
+            }
+            BuildError::InkBlackPointWhite => write!(f, "the ink black point is white"),
+        }
+    }
+}
+
+impl Error for BuildError {}
+
+#[derive(Clone, Copy, Default)]
+pub struct ImageAnalyzerBuilder {
+    analyzer: ImageAnalyzer,
+}
+
+impl ImageAnalyzer {
+    // Starts from the defaults.
+    pub fn builder() -> ImageAnalyzerBuilder {
+        ImageAnalyzerBuilder::default()
+    }
+}
+
+impl ImageAnalyzerBuilder {
+    pub fn off_white_threshold(mut self, threshold: u8) -> Self {
+        self.analyzer.off_white_threshold = threshold;
+        self
+    }
+
+    pub fn off_white_threshold_rgb(mut self, thresholds: [u8; 3]) -> Self {
+        self.analyzer.off_white_threshold_rgb = Some(thresholds);
+        self
+    }
+
+    pub fn lightness(mut self, threshold: u8, distance: u32) -> Self {
+        self.analyzer.lightness_threshold = threshold;
+        self.analyzer.lightness_distance = distance;
+        self
+    }
+
+    pub fn luminance_mode(mut self, luminance_mode: LuminanceMode) -> Self {
+        self.analyzer.luminance_mode = luminance_mode;
+        self
+    }
+
+    pub fn connectivity(mut self, connectivity: Connectivity) -> Self {
+        self.analyzer.connectivity = connectivity;
+        self
+    }
+
+    pub fn threshold_mode(mut self, threshold_mode: ThresholdMode) -> Self {
+        self.analyzer.threshold_mode = threshold_mode;
+        self
+    }
+
+    pub fn transparent_background_color(mut self, color: [u8; 3]) -> Self {
+        self.analyzer.transparent_background_color = color;
+        self
+    }
+
+    pub fn merge_distance(mut self, distance: u32) -> Self {
+        self.analyzer.merge_distance = distance;
+        self
+    }
+
+    pub fn morph_close(mut self, radius: u32) -> Self {
+        self.analyzer.morph_close = radius;
+        self
+    }
+
+    pub fn auto_flatten(mut self, block_size: u32) -> Self {
+        self.analyzer.auto_flatten = Some(block_size);
+        self
+    }
+
+    pub fn invert(mut self, invert: bool) -> Self {
+        self.analyzer.invert = invert;
+        self
+    }
+
+    pub fn max_pixels(mut self, max_pixels: u64) -> Self {
+        self.analyzer.max_pixels = max_pixels;
+        self
+    }
+
+    pub fn build(self) -> Result<ImageAnalyzer, BuildError> {
+        let analyzer = self.analyzer;
+        if analyzer.off_white_threshold_rgb.is_none()
+            && analyzer.lightness_threshold > analyzer.off_white_threshold
+        {
+            return Err(BuildError::LightnessAboveOffWhite {
+                lightness_threshold: analyzer.lightness_threshold,
+                off_white_threshold: analyzer.off_white_threshold,
+            });
+        }
+        if let ThresholdMode::Sauvola { window, k } = analyzer.threshold_mode {
+            if window < 3 {
+                return Err(BuildError::SauvolaWindowTooSmall(window));
+            }
+            if !(0.0..=1.0).contains(&k) {
+                return Err(BuildError::SauvolaKOutOfRange(k));
+            }
+        }
+
+        Ok(analyzer)
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct ImageCleanerBuilder {
+    cleaner: ImageCleaner,
+}
+
+impl ImageCleaner {
+    // Starts from the defaults.
+    pub fn builder() -> ImageCleanerBuilder {
+        ImageCleanerBuilder::default()
+    }
+}
+
+impl ImageCleanerBuilder {
+    pub fn speck_size_threshold(mut self, threshold: usize) -> Self {
+        self.cleaner.speck_size_threshold = threshold;
+        self
+    }
+
+    pub fn size_metric(mut self, metric: SizeMetric) -> Self {
+        self.cleaner.size_metric = metric;
+        self
+    }
+
+    pub fn margins(self, x: u32, y: u32) -> Self {
+        self.page_margins((x, y))
+    }
+
+    pub fn page_margins(mut self, margins: impl Into<Margins>) -> Self {
+        self.cleaner.page_margins = margins.into();
+        self.cleaner.page_margin_fractions = None;
+        self
+    }
+
+    // Margins as fractions of the page's width and height, from 0 to 0.5.
+    pub fn page_margin_fractions(mut self, fractions: impl Into<Margins<f32>>) -> Self {
+        self.cleaner.page_margin_fractions = Some(fractions.into());
+        self
+    }
+
+    // Keeps graphemes in the margins that are bigger than size, on the given sides only. (false, true) keeps them in
+    // the top and bottom margins, where page numbers and running headers go.
+    pub fn margin_keep_size(mut self, size: usize, sides: impl Into<Margins<bool>>) -> Self {
+        self.cleaner.margin_keep_size = Some(size);
+        self.cleaner.margin_keep_sides = sides.into();
+        self
+    }
+
+    pub fn remove_border_touching(mut self, remove: bool) -> Self {
+        self.cleaner.remove_border_touching = remove;
+        self
+    }
+
+    // Adds to the protected regions.
+    pub fn protected_region(mut self, region: math::Rect) -> Self {
+        self.cleaner.protected_regions.push(region);
+        self
+    }
+
+    // Adds to the removal regions.
+    pub fn removal_region(mut self, region: math::Rect) -> Self {
+        self.cleaner.removal_regions.push(region);
+        self
+    }
+
+    pub fn removal_region_match(mut self, region_match: RegionMatch) -> Self {
+        self.cleaner.removal_region_match = region_match;
+        self
+    }
+
+    pub fn isolation(mut self, size: u32, distance: u32) -> Self {
+        self.cleaner.isolation_size_threshold = size;
+        self.cleaner.isolation_distance_threshold = distance;
+        self
+    }
+
+    pub fn isolation_metric(mut self, metric: IsolationMetric) -> Self {
+        self.cleaner.isolation_metric = metric;
+        self
+    }
+
+    // Keeps specks that are within distance of a letter and line up with it, like periods.
+    pub fn protect_punctuation(mut self, distance: u32) -> Self {
+        self.cleaner.protect_punctuation = true;
+        self.cleaner.punctuation_distance = distance;
+        self
+    }
+
+    pub fn max_average_value(mut self, max: u8) -> Self {
+        self.cleaner.max_average_value = Some(max);
+        self
+    }
+
+    pub fn max_fill_ratio(mut self, ratio: f32) -> Self {
+        self.cleaner.max_fill_ratio = Some(ratio);
+        self
+    }
+
+    pub fn min_fill_ratio(mut self, ratio: f32) -> Self {
+        self.cleaner.min_fill_ratio = Some(ratio);
+        self
+    }
+
+    pub fn fill_ratio_size_limit(mut self, limit: usize) -> Self {
+        self.cleaner.fill_ratio_size_limit = Some(limit);
+        self
+    }
+
+    pub fn max_aspect_ratio(mut self, ratio: f32) -> Self {
+        self.cleaner.max_aspect_ratio = Some(ratio);
+        self
+    }
+
+    // Limits which long graphemes max_aspect_ratio fills, see the fields.
+    pub fn streak_limits(mut self, max_thickness: Option<u32>, min_length: u32) -> Self {
+        self.cleaner.max_streak_thickness = max_thickness;
+        self.cleaner.min_streak_length = min_length;
+        self
+    }
+
+    pub fn streak_directions(mut self, vertical: bool, horizontal: bool) -> Self {
+        self.cleaner.remove_vertical_streaks = vertical;
+        self.cleaner.remove_horizontal_streaks = horizontal;
+        self
+    }
+
+    pub fn max_grapheme_area_fraction(mut self, fraction: f32) -> Self {
+        self.cleaner.max_grapheme_area_fraction = Some(fraction);
+        self
+    }
+
+    pub fn hole_fill_threshold(mut self, threshold: usize) -> Self {
+        self.cleaner.hole_fill_threshold = threshold;
+        self
+    }
+
+    pub fn fill_grapheme_holes(mut self, fill: bool) -> Self {
+        self.cleaner.fill_grapheme_holes = fill;
+        self
+    }
+
+    pub fn colors(mut self, speck: [u8; 3], background: [u8; 3]) -> Self {
+        self.cleaner.speck_fill_color = speck;
+        self.cleaner.background_fill_color = background;
+        self
+    }
+
+    pub fn output_mode(mut self, output_mode: OutputMode) -> Self {
+        self.cleaner.output_mode = output_mode;
+        self
+    }
+
+    pub fn speck_fill_style(mut self, style: FillStyle) -> Self {
+        self.cleaner.speck_fill_style = style;
+        self
+    }
+
+    pub fn ink_curve(mut self, gamma: f32, black_point: u8) -> Self {
+        self.cleaner.ink_gamma = gamma;
+        self.cleaner.ink_black_point = black_point;
+        self
+    }
+
+    pub fn binarize_threshold(mut self, threshold: u8) -> Self {
+        self.cleaner.binarize_threshold = Some(threshold);
+        self
+    }
+
+    pub fn rgba_fill_color(mut self, color: [u8; 4]) -> Self {
+        self.cleaner.rgba_fill_color = color;
+        self
+    }
+
+    pub fn preserve_original(mut self, preserve: bool) -> Self {
+        self.cleaner.preserve_original = preserve;
+        self
+    }
+
+    pub fn build(self) -> Result<ImageCleaner, BuildError> {
+        let cleaner = self.cleaner;
+        if cleaner.page_margins.max() > MAX_PAGE_MARGIN {
+            return Err(BuildError::MarginTooLarge(cleaner.page_margins.max()));
+        }
+        if let Some(fractions) = cleaner.page_margin_fractions {
+            let Margins {
+                left,
+                right,
+                top,
+                bottom,
+            } = fractions;
+            if let Some(fraction) = [left, right, top, bottom]
+                .into_iter()
+                .find(|fraction| !(0.0..=0.5).contains(fraction))
+            {
+                return Err(BuildError::MarginFractionOutOfRange(fraction));
+            }
+        }
+        for ratio in [cleaner.max_fill_ratio, cleaner.min_fill_ratio]
+            .into_iter()
+            .flatten()
+        {
+            if !(0.0..=1.0).contains(&ratio) {
+                return Err(BuildError::FillRatioOutOfRange(ratio));
+            }
+        }
+        if let Some(ratio) = cleaner.max_aspect_ratio {
+            if !(1.0..=f32::INFINITY).contains(&ratio) {
+                return Err(BuildError::AspectRatioOutOfRange(ratio));
+            }
+        }
+        if let Some(fraction) = cleaner.max_grapheme_area_fraction {
+            if !(0.0..=1.0).contains(&fraction) {
+                return Err(BuildError::AreaFractionOutOfRange(fraction));
+            }
+        }
+        if !(f32::MIN_POSITIVE..=f32::INFINITY).contains(&cleaner.ink_gamma) {
+            return Err(BuildError::InkGammaNotPositive(cleaner.ink_gamma));
+        }
+        if cleaner.ink_black_point == 255 {
+            return Err(BuildError::InkBlackPointWhite);
+        }
+
+        Ok(cleaner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults_build() {
+        assert!(ImageAnalyzer::builder().build().is_ok());
+        assert!(ImageCleaner::builder().build().is_ok());
+
+        let analyzer = ImageAnalyzer::builder()
+            .off_white_threshold(200)
+            .lightness(200, 4)
+            .threshold_mode(ThresholdMode::Sauvola { window: 3, k: 1.0 })
+            .build()
+            .unwrap();
+        assert_eq!(
+            (analyzer.off_white_threshold, analyzer.lightness_threshold),
+            (200, 200)
+        );
+        let cleaner = ImageCleaner::builder()
+            .margins(MAX_PAGE_MARGIN, 0)
+            .max_fill_ratio(1.0)
+            .min_fill_ratio(0.0)
+            .max_aspect_ratio(1.0)
+            .max_grapheme_area_fraction(1.0)
+            .ink_curve(f32::MIN_POSITIVE, 254)
+            .build()
+            .unwrap();
+        assert_eq!(cleaner.page_margins.max(), MAX_PAGE_MARGIN);
+    }
+
+    #[test]
+    fn lightness_above_off_white() {
+        let builder = ImageAnalyzer::builder()
+            .off_white_threshold(200)
+            .lightness(201, 4);
+        assert_eq!(
+            builder.build().err().unwrap(),
+            BuildError::LightnessAboveOffWhite {
+                lightness_threshold: 201,
+                off_white_threshold: 200,
+            }
+        );
+        // Per channel thresholds don't have a single off-white threshold to compare with.
+        assert!(builder.off_white_threshold_rgb([200; 3]).build().is_ok());
+    }
+
+    #[test]
+    fn sauvola_window_too_small() {
+        for window in [0, 2] {
+            let error = ImageAnalyzer::builder()
+                .threshold_mode(ThresholdMode::Sauvola { window, k: 0.2 })
+                .build()
+                .err()
+                .unwrap();
+            assert_eq!(error, BuildError::SauvolaWindowTooSmall(window));
+        }
+    }
+
+    #[test]
+    fn sauvola_k_out_of_range() {
+        for k in [-0.1, 1.1, f32::NAN] {
+            let error = ImageAnalyzer::builder()
+                .threshold_mode(ThresholdMode::Sauvola { window: 15, k })
+                .build()
+                .err()
+                .unwrap();
+            assert!(matches!(error, BuildError::SauvolaKOutOfRange(_)), "{k}");
+        }
+    }
+
+    #[test]
+    fn margin_too_large() {
+        let error = ImageCleaner::builder()
+            .page_margins(Margins {
+                left: 0,
+                right: 0,
+                top: MAX_PAGE_MARGIN + 1,
+                bottom: 0,
+            })
+            .build()
+            .err()
+            .unwrap();
+        assert_eq!(error, BuildError::MarginTooLarge(MAX_PAGE_MARGIN + 1));
+    }
+
+    #[test]
+    fn margin_fraction_out_of_range() {
+        for fraction in [-0.1, 0.6, f32::NAN] {
+            let error = ImageCleaner::builder()
+                .page_margin_fractions(Margins {
+                    left: 0.1,
+                    right: 0.1,
+                    top: 0.1,
+                    bottom: fraction,
+                })
+                .build()
+                .err()
+                .unwrap();
+            assert!(
+                matches!(error, BuildError::MarginFractionOutOfRange(_)),
+                "{fraction}"
+            );
+        }
+        // Setting pixel margins afterwards replaces the fractions.
+        let builder = ImageCleaner::builder().page_margin_fractions(Margins::from((0.6, 0.6)));
+        assert!(builder.margins(10, 10).build().is_ok());
+    }
+
+    #[test]
+    fn fill_ratio_out_of_range() {
+        let error = ImageCleaner::builder()
+            .max_fill_ratio(1.5)
+            .build()
+            .err()
+            .unwrap();
+        assert_eq!(error, BuildError::FillRatioOutOfRange(1.5));
+        let error = ImageCleaner::builder()
+            .min_fill_ratio(-0.5)
+            .build()
+            .err()
+            .unwrap();
+        assert_eq!(error, BuildError::FillRatioOutOfRange(-0.5));
+    }
+
+    #[test]
+    fn aspect_ratio_out_of_range() {
+        for ratio in [0.5, 0.0, -2.0] {
+            let error = ImageCleaner::builder()
+                .max_aspect_ratio(ratio)
+                .build()
+                .err()
+                .unwrap();
+            assert_eq!(error, BuildError::AspectRatioOutOfRange(ratio));
+        }
+        assert!(ImageCleaner::builder()
+            .max_aspect_ratio(f32::INFINITY)
+            .build()
+            .is_ok());
+    }
+
+    #[test]
+    fn area_fraction_out_of_range() {
+        for fraction in [-0.1, 1.1] {
+            let error = ImageCleaner::builder()
+                .max_grapheme_area_fraction(fraction)
+                .build()
+                .err()
+                .unwrap();
+            assert_eq!(error, BuildError::AreaFractionOutOfRange(fraction));
+        }
+    }
+
+    #[test]
+    fn ink_gamma_not_positive() {
+        for gamma in [0.0, -1.0, f32::NAN] {
+            let error = ImageCleaner::builder()
+                .ink_curve(gamma, 0)
+                .build()
+                .err()
+                .unwrap();
+            assert!(
+                matches!(error, BuildError::InkGammaNotPositive(_)),
+                "{gamma}"
+            );
+        }
+    }
+
+    #[test]
+    fn ink_black_point_white() {
+        let error = ImageCleaner::builder()
+            .ink_curve(1.0, 255)
+            .build()
+            .err()
+            .unwrap();
+        assert_eq!(error, BuildError::InkBlackPointWhite);
+        assert_eq!(error.to_string(), "the ink black point is white");
+    }
+}
@@ -0,0 +1,466 @@
+// Saving analyzed images so pages don't have to be analyzed again every time they're opened. Analysis is deterministic,
+// so a saved one stays good as long as the analyzer's parameters and the source image are the same, which the header
+// records.
+//
+// The format is the header, then the width, height, and graphemes. Each grapheme is its bounding box, override,
+// perimeter, value sum, and runs relative to its bounding box, followed by its pixels' colors. Numbers are LEB128
+// varints and colors are little endian samples. The map is rebuilt from the runs instead of being stored.
+
+use std::error::Error;
+use std::fmt;
+use std::hash::Hasher;
+
+use crate::pixel::from_u64;
+use crate::{AnalyzedImage, DocumentPixel, Grapheme, ImageAnalyzer, ThresholdMode, MAX_PIXELS};
+
+const MAGIC: &[u8; 4] = b"DCAI";
+const VERSION: u8 = 1;
+
+// FNV-1a, which unlike the standard library's hasher gives the same hashes on every platform and version.
+pub(crate) struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+}
+
+impl Hasher for Fnv1a {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn write_u32(&mut self, value: u32) {
+        self.write(&value.to_le_bytes());
+    }
+
+    fn write_u64(&mut self, value: u64) {
+        self.write(&value.to_le_bytes());
+    }
+}
+
+// What a saved analysis was made from. Loading it with a different key fails, so the page gets analyzed again.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct CacheKey {
+    pub analyzer_hash: u64,
+    pub source_hash: u64,
+}
+
+impl CacheKey {
+    // source is whatever identifies the page, like the image file's bytes or the decoded image's.
+    pub fn new(analyzer: &ImageAnalyzer, source: &[u8]) -> Self {
+        let mut source_hasher = Fnv1a::default();
+        source_hasher.write(source);
+        Self {
+            analyzer_hash: analyzer.parameter_hash(),
+            source_hash: source_hasher.finish(),
+        }
+    }
+}
+
+impl ImageAnalyzer {
+    // A hash of every parameter that affects the analysis.
+    fn parameter_hash(&self) -> u64 {
+        let mut hasher = Fnv1a::default();
+        hasher.write(&[self.off_white_threshold, self.lightness_threshold]);
+        match self.off_white_threshold_rgb {
+            Some(thresholds) => hasher.write(&[1, thresholds[0], thresholds[1], thresholds[2]]),
+            None => hasher.write(&[0]),
+        }
+        hasher.write_u32(self.lightness_distance);
+        hasher.write(&[self.luminance_mode as u8, self.connectivity as u8]);
+        match self.threshold_mode {
+            ThresholdMode::Global => hasher.write(&[0]),
+            ThresholdMode::Sauvola { window, k } => {
+                hasher.write(&[1]);
+                hasher.write_u32(window);
+                hasher.write_u32(k.to_bits());
+            }
+        }
+        hasher.write(&self.transparent_background_color);
+        hasher.write_u32(self.merge_distance);
+        hasher.write_u32(self.morph_close);
+        match self.auto_flatten {
+            Some(block_size) => {
+                hasher.write(&[1]);
+                hasher.write_u32(block_size);
+            }
+            None => hasher.write(&[0]),
+        }
+        hasher.write(&[self.invert as u8]);
+
+        hasher.finish()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CacheError {
+    UnsupportedVersion(u8),
+    // Saved from an image of a different pixel type.
+    WrongPixelType,
+    // Saved with different parameters or from a different image.
+    Stale,
+    // Not a saved analysis at all, or cut short or damaged.
+    Corrupt,
+}
+
+impl fmt::Display for CacheError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CacheError::UnsupportedVersion(version) => {
+                write!(f, "saved analysis version {version} isn't supported")
+            }
+            CacheError::WrongPixelType => {
+                write!(f, "the saved analysis is of a different pixel type")
+            }
+            CacheError::Stale => write!(
+                f,
+                "the saved analysis was made with different parameters or from a different image"
+            ),
+            CacheError::Corrupt => write!(f, "the saved analysis is damaged"),
+        }
+    }
+}
+
+impl Error for CacheError {}
+
+impl<P: DocumentPixel> AnalyzedImage<P> {
+    pub fn to_bytes(&self, key: CacheKey) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        bytes.extend_from_slice(&pixel_type::<P>());
+        bytes.extend_from_slice(&key.analyzer_hash.to_le_bytes());
+        bytes.extend_from_slice(&key.source_hash.to_le_bytes());
+
+        let sample_size = std::mem::size_of::<P::Subpixel>();
+        write_varint(&mut bytes, self.width as u64);
+        write_varint(&mut bytes, self.height as u64);
+        write_varint(&mut bytes, self.graphemes.len() as u64);
+        for grapheme in &self.graphemes {
+            for value in [
+                grapheme.top,
+                grapheme.left,
+                grapheme.height(),
+                grapheme.width(),
+            ] {
+                write_varint(&mut bytes, value as u64);
+            }
+            bytes.push(match grapheme.manual_override {
+                None => 0,
+                Some(true) => 1,
+                Some(false) => 2,
+            });
+            write_varint(&mut bytes, grapheme.perimeter as u64);
+            write_varint(&mut bytes, grapheme.value_sum);
+            write_varint(&mut bytes, grapheme.runs.len() as u64);
+            for &(y, start, end) in &grapheme.runs {
+                write_varint(&mut bytes, (y - grapheme.top) as u64);
+                write_varint(&mut bytes, (start - grapheme.left) as u64);
+                write_varint(&mut bytes, (end - start) as u64);
+            }
+            for color in &grapheme.colors {
+                for &channel in color.channels() {
+                    let channel: u64 = channel.into();
+                    bytes.extend_from_slice(&channel.to_le_bytes()[..sample_size]);
+                }
+            }
+        }
+
+        bytes
+    }
+
+    // Fails instead of loading anything if bytes weren't saved with key or don't hold a whole analysis.
+    pub fn from_bytes(bytes: &[u8], key: CacheKey) -> Result<Self, CacheError> {
+        let mut reader = Reader { bytes };
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(CacheError::Corrupt);
+        }
+        let version = reader.take(1)?[0];
+        if version != VERSION {
+            return Err(CacheError::UnsupportedVersion(version));
+        }
+        if reader.take(2)? != pixel_type::<P>() {
+            return Err(CacheError::WrongPixelType);
+        }
+        let analyzer_hash = u64::from_le_bytes(reader.take(8)?.try_into().unwrap());
+        let source_hash = u64::from_le_bytes(reader.take(8)?.try_into().unwrap());
+        if (CacheKey {
+            analyzer_hash,
+            source_hash,
+        }) != key
+        {
+            return Err(CacheError::Stale);
+        }
+
+        let sample_size = std::mem::size_of::<P::Subpixel>();
+        let width = reader.u32()?;
+        let height = reader.u32()?;
+        let mut analyzed_image = AnalyzedImage::default();
+        // A real size was allocated when the page was analyzed, so only a damaged one can fail.
+        analyzed_image
+            .reset(width, height, MAX_PIXELS)
+            .map_err(|_| CacheError::Corrupt)?;
+        let grapheme_count = reader.varint()?;
+        for id in 0..grapheme_count {
+            let top = reader.u32()?;
+            let left = reader.u32()?;
+            let (grapheme_height, grapheme_width) = (reader.u32()?, reader.u32()?);
+            if grapheme_height == 0
+                || grapheme_width == 0
+                || top as u64 + grapheme_height as u64 > height as u64
+                || left as u64 + grapheme_width as u64 > width as u64
+            {
+                return Err(CacheError::Corrupt);
+            }
+            let (bottom, right) = (top + grapheme_height - 1, left + grapheme_width - 1);
+            let manual_override = match reader.take(1)?[0] {
+                0 => None,
+                1 => Some(true),
+                2 => Some(false),
+                _ => return Err(CacheError::Corrupt),
+            };
+            let perimeter = reader.varint()? as usize;
+            let value_sum = reader.varint()?;
+
+            let run_count = reader.varint()?;
+            let mut runs = Vec::new();
+            let mut pixel_count = 0;
+            for _ in 0..run_count {
+                let y = top.checked_add(reader.u32()?).ok_or(CacheError::Corrupt)?;
+                let start = left.checked_add(reader.u32()?).ok_or(CacheError::Corrupt)?;
+                let end = start
+                    .checked_add(reader.u32()?)
+                    .ok_or(CacheError::Corrupt)?;
+                if y > bottom || end <= start || end > right + 1 {
+                    return Err(CacheError::Corrupt);
+                }
+                let row = y as usize * width as usize;
+                let pixels = &mut analyzed_image.map[row + start as usize..row + end as usize];
+                // Every pixel belongs to one grapheme only.
+                if pixels.iter().any(|&i| i != u32::MAX) {
+                    return Err(CacheError::Corrupt);
+                }
+                pixels.fill(id as u32);
+                runs.push((y, start, end));
+                pixel_count += (end - start) as usize;
+            }
+            if pixel_count == 0 {
+                return Err(CacheError::Corrupt);
+            }
+
+            let colors = reader
+                .take(pixel_count * P::CHANNEL_COUNT as usize * sample_size)?
+                .chunks(sample_size)
+                .map(|sample| {
+                    let mut value = [0; 8];
+                    value[..sample_size].copy_from_slice(sample);
+                    from_u64(u64::from_le_bytes(value))
+                })
+                .collect::<Vec<P::Subpixel>>()
+                .chunks(P::CHANNEL_COUNT as usize)
+                .map(|channels| *P::from_slice(channels))
+                .collect();
+
+            analyzed_image.graphemes.push(Grapheme {
+                runs,
+                colors,
+                perimeter,
+                value_sum,
+                top,
+                bottom,
+                left,
+                right,
+                manual_override,
+            });
+        }
+        if !reader.bytes.is_empty() {
+            return Err(CacheError::Corrupt);
+        }
+        analyzed_image.rebuild_spatial_index();
+
+        Ok(analyzed_image)
+    }
+}
+
+// The channel count and sample size, which tell every pixel type the analyzer works in apart.
+fn pixel_type<P: DocumentPixel>() -> [u8; 2] {
+    [P::CHANNEL_COUNT, std::mem::size_of::<P::Subpixel>() as u8]
+}
+
+fn write_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], CacheError> {
+        if count > self.bytes.len() {
+            return Err(CacheError::Corrupt);
+        }
+        let (taken, rest) = self.bytes.split_at(count);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn varint(&mut self) -> Result<u64, CacheError> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.take(1)?[0];
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte < 0x80 {
+                return Ok(value);
+            }
+        }
+
+        Err(CacheError::Corrupt)
+    }
+
+    fn u32(&mut self) -> Result<u32, CacheError> {
+        self.varint()?.try_into().map_err(|_| CacheError::Corrupt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, Rgba};
+
+    use super::*;
+    use crate::tests::demo_page;
+
+    fn assert_same(a: &AnalyzedImage<Rgb<u8>>, b: &AnalyzedImage<Rgb<u8>>) {
+        assert_eq!((a.width, a.height), (b.width, b.height));
+        assert_eq!(a.map, b.map);
+        assert_eq!(a.graphemes.len(), b.graphemes.len());
+        for (a, b) in a.graphemes.iter().zip(&b.graphemes) {
+            assert_eq!(a.runs, b.runs);
+            assert_eq!(a.colors, b.colors);
+            assert_eq!(a.bounds(), b.bounds());
+            assert_eq!(
+                (a.perimeter, a.value_sum, a.manual_override),
+                (b.perimeter, b.value_sum, b.manual_override)
+            );
+        }
+    }
+
+    fn saved_demo_page() -> (AnalyzedImage<Rgb<u8>>, Vec<u8>, CacheKey) {
+        let image = demo_page();
+        let analyzer = ImageAnalyzer::default();
+        let mut analyzed_image = analyzer.analyze(&image);
+        analyzed_image.graphemes[0].manual_override = Some(true);
+        analyzed_image.graphemes[1].manual_override = Some(false);
+        let key = CacheKey::new(&analyzer, image.as_raw());
+        let bytes = analyzed_image.to_bytes(key);
+        (analyzed_image, bytes, key)
+    }
+
+    #[test]
+    fn round_trip() {
+        let (analyzed_image, bytes, key) = saved_demo_page();
+        let loaded = AnalyzedImage::<Rgb<u8>>::from_bytes(&bytes, key).unwrap();
+        assert_same(&analyzed_image, &loaded);
+        assert_eq!(loaded.to_bytes(key), bytes);
+        // The spatial index is rebuilt too.
+        for grapheme in analyzed_image.graphemes.iter().step_by(50) {
+            let (y, x, _) = grapheme.runs[0];
+            assert_eq!(
+                loaded.nearest_grapheme(x, y, 10),
+                analyzed_image.nearest_grapheme(x, y, 10)
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_damaged_headers() {
+        let (_, bytes, key) = saved_demo_page();
+        let load = |bytes: &[u8], key| AnalyzedImage::<Rgb<u8>>::from_bytes(bytes, key).err();
+
+        let mut flipped = bytes.clone();
+        flipped[0] ^= 1;
+        assert_eq!(load(&flipped, key), Some(CacheError::Corrupt));
+
+        let mut version = bytes.clone();
+        version[MAGIC.len()] = VERSION + 1;
+        assert_eq!(
+            load(&version, key),
+            Some(CacheError::UnsupportedVersion(VERSION + 1))
+        );
+
+        assert_eq!(
+            AnalyzedImage::<Rgba<u8>>::from_bytes(&bytes, key).err(),
+            Some(CacheError::WrongPixelType)
+        );
+
+        let other_analyzer = ImageAnalyzer {
+            lightness_threshold: 101,
+            ..ImageAnalyzer::default()
+        };
+        let stale_key = CacheKey {
+            analyzer_hash: other_analyzer.parameter_hash(),
+            ..key
+        };
+        assert_eq!(load(&bytes, stale_key), Some(CacheError::Stale));
+        let stale_key = CacheKey {
+            source_hash: key.source_hash ^ 1,
+            ..key
+        };
+        assert_eq!(load(&bytes, stale_key), Some(CacheError::Stale));
+    }
+
+    #[test]
+    fn rejects_truncated_bytes() {
+        let (_, bytes, key) = saved_demo_page();
+        for length in [
+            0,
+            3,
+            MAGIC.len() + 1,
+            20,
+            30,
+            bytes.len() / 2,
+            bytes.len() - 1,
+        ] {
+            assert_eq!(
+                AnalyzedImage::<Rgb<u8>>::from_bytes(&bytes[..length], key).err(),
+                Some(CacheError::Corrupt),
+                "{length} bytes"
+            );
+        }
+        let mut extra = bytes.clone();
+        extra.push(0);
+        assert_eq!(
+            AnalyzedImage::<Rgb<u8>>::from_bytes(&extra, key).err(),
+            Some(CacheError::Corrupt)
+        );
+    }
+
+    #[test]
+    fn rejects_sizes_too_big_to_allocate() {
+        let key = CacheKey::new(&ImageAnalyzer::default(), &[]);
+        for (width, height) in [(u32::MAX, u32::MAX), (1 << 20, 1 << 20)] {
+            let mut bytes = AnalyzedImage::<Rgb<u8>>::default().to_bytes(key);
+            // The width, height, and grapheme count are the last three bytes of an empty image.
+            bytes.truncate(bytes.len() - 3);
+            write_varint(&mut bytes, width as u64);
+            write_varint(&mut bytes, height as u64);
+            write_varint(&mut bytes, 0);
+            assert_eq!(
+                AnalyzedImage::<Rgb<u8>>::from_bytes(&bytes, key).err(),
+                Some(CacheError::Corrupt)
+            );
+        }
+    }
+}
@@ -0,0 +1,257 @@
+// Scanned pages are often rotated a degree or two, which this finds and undoes.
+
+use image::imageops::interpolate_bilinear;
+use image::*;
+
+use crate::{otsu_threshold, AnalyzedImage, DocumentImage, DocumentPixel, LuminanceMode};
+
+// The steepest skew looked for, in degrees either way.
+const MAX_ANGLE: f32 = 5.0;
+// Fewer ink pixels than this isn't enough to tell which way lines of text run.
+const MIN_INK_PIXELS: usize = 500;
+// Only every so many ink pixels are projected on pages with more than this, which doesn't change the angle.
+const MAX_SAMPLES: usize = 200_000;
+// Text graphemes are small and plentiful, fewer than this and there isn't enough text to go on.
+const MIN_GRAPHEMES: usize = 20;
+// How much sharper the best angle has to be than the average one to be trusted. Scattered ink (like an illustration or
+// noise) is about as sharp at any angle.
+const MIN_CONFIDENCE: f32 = 0.1;
+
+// How many degrees clockwise the lines of text on the page are rotated, found by projecting the ink onto rows at each
+// angle and picking the one whose rows are the most sharply split into lines and gaps. 0 for blank pages and pages
+// without any clear lines.
+pub fn estimate_skew_angle<P: DocumentPixel>(image: &DocumentImage<P>) -> f32 {
+    let max: u64 = P::Subpixel::DEFAULT_MAX_VALUE.into();
+    let value = |pixel: &P| {
+        let value: u64 = pixel
+            .composite([255, 255, 255])
+            .value(LuminanceMode::Mean)
+            .into();
+        (value * 255 / max) as usize
+    };
+
+    let mut histogram = [0; 256];
+    for pixel in image.pixels().filter(|p| !p.is_transparent()) {
+        histogram[value(pixel)] += 1;
+    }
+    // A blank page.
+    let Some(split) = otsu_threshold(&histogram) else {
+        return 0.0;
+    };
+
+    let mut ink: Vec<(f32, f32)> = image
+        .enumerate_pixels()
+        .filter(|(_, _, p)| !p.is_transparent() && value(p) <= split as usize)
+        .map(|(x, y, _)| (x as f32, y as f32))
+        .collect();
+    if ink.len() < MIN_INK_PIXELS {
+        return 0.0;
+    }
+    if ink.len() > MAX_SAMPLES {
+        let step = ink.len().div_ceil(MAX_SAMPLES);
+        ink = ink.into_iter().step_by(step).collect();
+    }
+
+    let (angle, confidence) = find_skew(&ink, 1.0, image.dimensions());
+    if confidence < MIN_CONFIDENCE {
+        return 0.0;
+    }
+
+    angle
+}
+
+impl<P> AnalyzedImage<P> {
+    // Like estimate_skew_angle, but from the bottoms of the graphemes' bounding boxes instead of every ink pixel, which
+    // is a lot quicker once the image has been analyzed.
+    pub fn estimate_skew(&self) -> f32 {
+        self.estimate_skew_with_confidence().0
+    }
+
+    // The skew angle along with how sure it is, from 0 (any angle is as good as any other) to 1. The angle is 0 when the
+    // confidence is too low to go on, or there are too few graphemes.
+    pub fn estimate_skew_with_confidence(&self) -> (f32, f32) {
+        let mut heights: Vec<u32> = self.graphemes.iter().map(|g| g.height()).collect();
+        if heights.len() < MIN_GRAPHEMES {
+            return (0.0, 0.0);
+        }
+        let middle = heights.len() / 2;
+        let (_, &mut median_height, _) = heights.select_nth_unstable(middle);
+
+        // Illustrations, rules, and specks don't sit on the lines of text.
+        let baselines: Vec<(f32, f32)> = self
+            .graphemes
+            .iter()
+            .filter(|g| (median_height / 2..=median_height * 3).contains(&g.height()))
+            .map(|g| ((g.left + g.right) as f32 / 2.0, g.bottom as f32))
+            .collect();
+        if baselines.len() < MIN_GRAPHEMES {
+            return (0.0, 0.0);
+        }
+
+        // Descenders hang a bit below the baseline, so rows a fraction of a letter high catch most of a line.
+        let row_height = (median_height as f32 / 3.0).max(1.0);
+        let (angle, confidence) = find_skew(&baselines, row_height, (self.width, self.height));
+        if confidence < MIN_CONFIDENCE {
+            return (0.0, confidence);
+        }
+
+        (angle, confidence)
+    }
+}
+
+// Projects the points onto rows at each angle and picks the one whose rows are the most sharply split into lines and
+// gaps, along with how much sharper it is than the average angle.
+fn find_skew(points: &[(f32, f32)], row_height: f32, (width, height): (u32, u32)) -> (f32, f32) {
+    let (width, height) = (width as f32, height as f32);
+    let diagonal = (width * width + height * height).sqrt();
+    let mut rows = vec![0u64; (2.0 * diagonal / row_height).ceil() as usize + 2];
+    // The sum of the squares of the row counts, which is highest when the points are bunched up into as few rows as
+    // possible.
+    let mut sharpness = |angle: f32| {
+        let (sin, cos) = angle.to_radians().sin_cos();
+        rows.iter_mut().for_each(|n| *n = 0);
+        for &(x, y) in points {
+            rows[((y * cos - x * sin + diagonal) / row_height) as usize] += 1;
+        }
+        rows.iter().map(|&n| n * n).sum::<u64>()
+    };
+
+    // Coarse steps over the whole range, then finer ones around the best of them.
+    let mut best = (0.0, sharpness(0.0));
+    let mut coarse_total = 0;
+    let mut coarse_count = 0;
+    for (step, range) in [(0.25, MAX_ANGLE), (0.025, 0.25)] {
+        let center: f32 = best.0;
+        let steps = (range / step).round() as i32;
+        for i in -steps..=steps {
+            let angle = center + i as f32 * step;
+            let score = sharpness(angle);
+            if step == 0.25 {
+                coarse_total += score;
+                coarse_count += 1;
+            }
+            if score > best.1 {
+                best = (angle, score);
+            }
+        }
+    }
+
+    if best.1 == 0 {
+        return (0.0, 0.0);
+    }
+    let average = coarse_total as f64 / coarse_count as f64;
+    (best.0, (1.0 - average / best.1 as f64) as f32)
+}
+
+// Rotates the image counterclockwise by angle degrees about its center, so deskew(image, estimate_skew_angle(image))
+// straightens it. The image stays the same size, and the corners rotated in from outside it are white.
+pub fn deskew<P: DocumentPixel>(image: &DocumentImage<P>, angle: f32) -> DocumentImage<P> {
+    deskew_with_fill_color(image, angle, [255, 255, 255])
+}
+
+// Like deskew, with the corners rotated in from outside the image filled with fill_color, like the cleaner's
+// background_fill_color for deskewing an already cleaned image.
+pub fn deskew_with_fill_color<P: DocumentPixel>(
+    image: &DocumentImage<P>,
+    angle: f32,
+    fill_color: [u8; 3],
+) -> DocumentImage<P> {
+    let (width, height) = image.dimensions();
+    if angle == 0.0 {
+        return image.clone();
+    }
+
+    let (sin, cos) = angle.to_radians().sin_cos();
+    let (center_x, center_y) = ((width as f32 - 1.0) / 2.0, (height as f32 - 1.0) / 2.0);
+    let fill = P::from_rgb(fill_color);
+    ImageBuffer::from_fn(width, height, |x, y| {
+        let (dx, dy) = (x as f32 - center_x, y as f32 - center_y);
+        let source_x = center_x + dx * cos - dy * sin;
+        let source_y = center_y + dx * sin + dy * cos;
+        interpolate_bilinear(image, source_x, source_y).unwrap_or(fill)
+    })
+}
+
+// Straightens the page, keeping it in its own pixel type where the cleaner supports it.
+pub fn auto_deskew(image: DynamicImage) -> DynamicImage {
+    fn straighten<P: DocumentPixel>(image: &DocumentImage<P>) -> DocumentImage<P> {
+        deskew(image, estimate_skew_angle(image))
+    }
+
+    match image {
+        DynamicImage::ImageLuma8(image) => DynamicImage::ImageLuma8(straighten(&image)),
+        DynamicImage::ImageRgb8(image) => DynamicImage::ImageRgb8(straighten(&image)),
+        DynamicImage::ImageRgba8(image) => DynamicImage::ImageRgba8(straighten(&image)),
+        DynamicImage::ImageLuma16(image) => DynamicImage::ImageLuma16(straighten(&image)),
+        DynamicImage::ImageRgb16(image) => DynamicImage::ImageRgb16(straighten(&image)),
+        DynamicImage::ImageRgba16(image) => DynamicImage::ImageRgba16(straighten(&image)),
+        image if image.color().has_alpha() => {
+            DynamicImage::ImageRgba16(straighten(&image.to_rgba16()))
+        }
+        image => DynamicImage::ImageRgb16(straighten(&image.to_rgb16())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{blank_page, draw, fill_rect, Rng, FOURTEEN};
+    use crate::ImageAnalyzer;
+
+    // Rows of 14s filling a 600x600 page.
+    fn text_page() -> GrayImage {
+        let mut image = blank_page(600, 600);
+        for y in (60..540).step_by(24) {
+            for x in (60..520).step_by(20) {
+                draw(&mut image, x, y, FOURTEEN);
+            }
+        }
+        image
+    }
+
+    #[test]
+    fn blank_page_is_straight() {
+        let image = blank_page(300, 400);
+        assert_eq!(estimate_skew_angle(&image), 0.0);
+        let analyzed_image = ImageAnalyzer::default().analyze(&image);
+        assert_eq!(analyzed_image.estimate_skew_with_confidence(), (0.0, 0.0));
+    }
+
+    #[test]
+    fn rotated_text_is_straightened() {
+        let image = text_page();
+        assert!(estimate_skew_angle(&image).abs() < 0.1);
+
+        for angle in [-3.0, -1.5, 2.0, 4.0] {
+            // Rotated clockwise by angle.
+            let rotated = deskew(&image, -angle);
+            let estimate = estimate_skew_angle(&rotated);
+            assert!((estimate - angle).abs() < 0.2, "{angle}: {estimate}");
+
+            let analyzed_image = ImageAnalyzer::default().analyze(&rotated);
+            let (estimate, confidence) = analyzed_image.estimate_skew_with_confidence();
+            assert!((estimate - angle).abs() < 0.2, "{angle}: {estimate}");
+            assert!(confidence >= MIN_CONFIDENCE, "{angle}: {confidence}");
+
+            let straightened = deskew(&rotated, estimate);
+            assert!(estimate_skew_angle(&straightened).abs() < 0.2);
+        }
+    }
+
+    #[test]
+    fn scattered_marks_have_low_confidence() {
+        // Stippling, the way an illustration shades, with no lines to follow.
+        let mut image = blank_page(600, 600);
+        let mut rng = Rng(7);
+        for _ in 0..400 {
+            fill_rect(&mut image, rng.below(590), rng.below(590), 4, 4);
+        }
+        assert_eq!(estimate_skew_angle(&image), 0.0);
+
+        let analyzed_image = ImageAnalyzer::default().analyze(&image);
+        assert!(analyzed_image.graphemes.len() >= MIN_GRAPHEMES);
+        let (angle, confidence) = analyzed_image.estimate_skew_with_confidence();
+        assert_eq!(angle, 0.0);
+        assert!(confidence < MIN_CONFIDENCE, "{confidence}");
+    }
+}
@@ -0,0 +1,335 @@
+// Finding the graphemes around a point or in a rectangle without going through all of them.
+
+use std::collections::HashMap;
+
+use image::math;
+
+use crate::{AnalyzedImage, Grapheme, GraphemeId};
+
+// Big enough that most letters only cover one or two cells, small enough that a cell holds only a few of them.
+pub(crate) const CELL_SIZE: u32 = 64;
+
+// Every grapheme goes in each grid cell its bounding box covers, so only the graphemes in the cells around a spot need
+// to be looked at.
+#[derive(Default)]
+pub(crate) struct GraphemeGrid {
+    cell_size: u32,
+    cells: HashMap<(u32, u32), Vec<usize>>,
+    // The last cells with any graphemes in them, so areas reaching far past them don't go through empty cells.
+    last_cell: (u32, u32),
+}
+
+impl GraphemeGrid {
+    pub(crate) fn new<P>(graphemes: &[Grapheme<P>], cell_size: u32) -> Self {
+        Self::with_filter(graphemes, cell_size, |_| true)
+    }
+
+    // Only the graphemes that include returns true for go in the grid, the rest are never given by near.
+    pub(crate) fn with_filter<P>(
+        graphemes: &[Grapheme<P>],
+        cell_size: u32,
+        include: impl Fn(&Grapheme<P>) -> bool,
+    ) -> Self {
+        let cell_size = cell_size.max(1);
+        let mut cells: HashMap<(u32, u32), Vec<usize>> = HashMap::new();
+        let mut last_cell = (0, 0);
+        for (i, grapheme) in graphemes.iter().enumerate().filter(|(_, g)| include(g)) {
+            last_cell.0 = last_cell.0.max(grapheme.right / cell_size);
+            last_cell.1 = last_cell.1.max(grapheme.bottom / cell_size);
+            for cell_y in grapheme.top / cell_size..=grapheme.bottom / cell_size {
+                for cell_x in grapheme.left / cell_size..=grapheme.right / cell_size {
+                    cells.entry((cell_x, cell_y)).or_default().push(i);
+                }
+            }
+        }
+
+        Self {
+            cell_size,
+            cells,
+            last_cell,
+        }
+    }
+
+    // The graphemes in the cells that the box from (left, top) to (right, bottom) covers, each once, in the order of
+    // the cells. They aren't necessarily inside the box, only near it.
+    pub(crate) fn near<'a, P>(
+        &'a self,
+        graphemes: &'a [Grapheme<P>],
+        (left, top, right, bottom): (u32, u32, u32, u32),
+    ) -> impl Iterator<Item = usize> + 'a {
+        let cell_size = self.cell_size.max(1);
+        let (left, top) = (left / cell_size, top / cell_size);
+        let right = (right / cell_size).min(self.last_cell.0);
+        let bottom = (bottom / cell_size).min(self.last_cell.1);
+        (top..=bottom)
+            .flat_map(move |cell_y| (left..=right).map(move |cell_x| (cell_x, cell_y)))
+            .filter_map(|cell| Some((cell, self.cells.get(&cell)?)))
+            .flat_map(move |((cell_x, cell_y), indices)| {
+                // A grapheme covering several of the cells is only given from the first of them.
+                indices.iter().copied().filter(move |&i| {
+                    // Graphemes removed since the grid was built are skipped.
+                    let Some(grapheme) = graphemes.get(i) else {
+                        return false;
+                    };
+                    cell_x == (grapheme.left / cell_size).max(left)
+                        && cell_y == (grapheme.top / cell_size).max(top)
+                })
+            })
+    }
+}
+
+// How far (x, y) is from the grapheme's bounding box, squared. Saturates, which is still further than any max_distance
+// squared.
+fn squared_distance<P>(grapheme: &Grapheme<P>, x: u32, y: u32) -> u64 {
+    let dx = grapheme
+        .left
+        .saturating_sub(x)
+        .max(x.saturating_sub(grapheme.right));
+    let dy = grapheme
+        .top
+        .saturating_sub(y)
+        .max(y.saturating_sub(grapheme.bottom));
+    (dx as u64 * dx as u64).saturating_add(dy as u64 * dy as u64)
+}
+
+impl<P> AnalyzedImage<P> {
+    // The graphemes whose bounding boxes overlap rect (in image coordinates), with their ids.
+    pub fn graphemes_in_rect(
+        &self,
+        rect: math::Rect,
+    ) -> impl Iterator<Item = (GraphemeId, &Grapheme<P>)> + '_ {
+        let right = rect.x.saturating_add(rect.width);
+        let bottom = rect.y.saturating_add(rect.height);
+        let empty = rect.width == 0 || rect.height == 0;
+        (!empty)
+            .then(|| {
+                self.grid
+                    .near(&self.graphemes, (rect.x, rect.y, right - 1, bottom - 1))
+                    .filter(move |&i| {
+                        let grapheme = &self.graphemes[i];
+                        grapheme.left < right
+                            && grapheme.right >= rect.x
+                            && grapheme.top < bottom
+                            && grapheme.bottom >= rect.y
+                    })
+                    .map(|i| (GraphemeId(i as u32), &self.graphemes[i]))
+            })
+            .into_iter()
+            .flatten()
+    }
+
+    // The grapheme whose bounding box is closest to (x, y), if any are at most max_distance pixels away. 0 for the
+    // graphemes whose bounding boxes (x, y) is inside of.
+    pub fn nearest_grapheme(&self, x: u32, y: u32, max_distance: u32) -> Option<GraphemeId> {
+        let area = (
+            x.saturating_sub(max_distance),
+            y.saturating_sub(max_distance),
+            x.saturating_add(max_distance),
+            y.saturating_add(max_distance),
+        );
+        self.grid
+            .near(&self.graphemes, area)
+            .map(|i| (squared_distance(&self.graphemes[i], x, y), i))
+            .filter(|&(distance, _)| distance <= max_distance as u64 * max_distance as u64)
+            .min()
+            .map(|(_, i)| GraphemeId(i as u32))
+    }
+
+    // graphemes_in_rect and nearest_grapheme go by where the graphemes were when the image was analyzed, so this needs
+    // to be called after moving, adding, or removing any.
+    pub fn rebuild_spatial_index(&mut self) {
+        self.grid = GraphemeGrid::new(&self.graphemes, CELL_SIZE);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::math;
+
+    use super::*;
+    use crate::tests::{blank_page, fill_rect};
+    use crate::{Decision, ImageAnalyzer, ImageCleaner, Margins};
+
+    // Up to 3 graphemes on a 200x200 page, one small, one covering several grid cells, and one in the far corner.
+    fn page(count: usize) -> AnalyzedImage<image::Luma<u8>> {
+        let mut image = blank_page(200, 200);
+        for &(x, y, width, height) in
+            &[(10, 10, 5, 5), (60, 30, 80, 100), (185, 185, 10, 10)][..count]
+        {
+            fill_rect(&mut image, x, y, width, height);
+        }
+        let analyzed_image = ImageAnalyzer::default().analyze(&image);
+        assert_eq!(analyzed_image.graphemes.len(), count);
+        analyzed_image
+    }
+
+    #[test]
+    fn nearest_grapheme_with_few_graphemes() {
+        for count in 0..=3 {
+            let analyzed_image = page(count);
+            for max_distance in [0, 5, 40, 300] {
+                for (x, y) in (0..200)
+                    .step_by(7)
+                    .flat_map(|x| (0..200).step_by(7).map(move |y| (x, y)))
+                {
+                    // Going through every grapheme instead of the grid.
+                    let expected = analyzed_image
+                        .graphemes
+                        .iter()
+                        .enumerate()
+                        .map(|(i, grapheme)| (squared_distance(grapheme, x, y), i))
+                        .filter(|&(distance, _)| {
+                            distance <= max_distance as u64 * max_distance as u64
+                        })
+                        .min()
+                        .map(|(_, i)| GraphemeId(i as u32));
+                    assert_eq!(
+                        analyzed_image.nearest_grapheme(x, y, max_distance),
+                        expected,
+                        "({x}, {y}) within {max_distance}"
+                    );
+                }
+            }
+            assert_eq!(
+                analyzed_image.nearest_grapheme(0, 199, u32::MAX).is_some(),
+                count > 0
+            );
+        }
+
+        let analyzed_image = page(3);
+        assert_eq!(
+            analyzed_image.nearest_grapheme(12, 12, 0),
+            Some(GraphemeId(0))
+        );
+        assert_eq!(
+            analyzed_image.nearest_grapheme(100, 100, 0),
+            Some(GraphemeId(1))
+        );
+        assert_eq!(analyzed_image.nearest_grapheme(30, 30, 10), None);
+        assert_eq!(
+            analyzed_image.nearest_grapheme(30, 30, 30),
+            Some(GraphemeId(0))
+        );
+        assert_eq!(
+            analyzed_image.nearest_grapheme(170, 170, 30),
+            Some(GraphemeId(2))
+        );
+    }
+
+    #[test]
+    fn near_gives_each_grapheme_once() {
+        let analyzed_image = page(3);
+        for cell_size in [1, 7, CELL_SIZE, 500] {
+            let grid = GraphemeGrid::new(&analyzed_image.graphemes, cell_size);
+            for area in [
+                (0, 0, 199, 199),
+                (0, 0, u32::MAX, u32::MAX),
+                (70, 40, 120, 110),
+                (12, 12, 12, 12),
+                (150, 150, 199, 199),
+            ] {
+                let mut near: Vec<_> = grid.near(&analyzed_image.graphemes, area).collect();
+                let count = near.len();
+                near.sort_unstable();
+                near.dedup();
+                assert_eq!(near.len(), count, "{area:?} in cells of {cell_size}");
+            }
+        }
+    }
+
+    #[test]
+    fn cleaning_few_graphemes() {
+        // The small graphemes are 45 and 55 pixels from the big one.
+        let cleaner = |isolation_distance_threshold| ImageCleaner {
+            page_margins: Margins::from((0, 0)),
+            speck_size_threshold: 0,
+            isolation_size_threshold: 100,
+            isolation_distance_threshold,
+            ..ImageCleaner::default()
+        };
+        let expected: [(u32, &[&[Decision]]); 2] = [
+            (
+                40,
+                &[
+                    &[],
+                    &[Decision::RemovedIsolated],
+                    &[Decision::RemovedIsolated, Decision::Kept],
+                    &[
+                        Decision::RemovedIsolated,
+                        Decision::Kept,
+                        Decision::RemovedIsolated,
+                    ],
+                ],
+            ),
+            (
+                50,
+                &[
+                    &[],
+                    &[Decision::RemovedIsolated],
+                    &[Decision::Kept, Decision::Kept],
+                    &[Decision::Kept, Decision::Kept, Decision::RemovedIsolated],
+                ],
+            ),
+        ];
+        for (distance, expected) in expected {
+            for (count, expected) in expected.iter().enumerate() {
+                let analyzed_image = page(count);
+                let cleaner = cleaner(distance);
+                assert_eq!(
+                    cleaner.classify(&analyzed_image),
+                    *expected,
+                    "{count} graphemes within {distance}"
+                );
+
+                let cleaned = cleaner.clean(&analyzed_image);
+                for (x, y, pixel) in cleaned.enumerate_pixels() {
+                    let kept = analyzed_image
+                        .grapheme_id_at(x, y)
+                        .is_some_and(|id| !expected[id.index()].is_removed());
+                    assert_eq!(pixel.0[0] == 0, kept, "({x}, {y})");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn graphemes_in_rect_with_few_graphemes() {
+        for count in 0..=3 {
+            let analyzed_image = page(count);
+            for (x, y, width, height) in [
+                (0, 0, 200, 200),
+                (0, 0, 0, 0),
+                (12, 12, 1, 1),
+                (0, 0, 61, 31),
+                (14, 14, 47, 17),
+                (100, 100, 100, 100),
+                (139, 129, 46, 56),
+                (199, 199, u32::MAX, u32::MAX),
+            ] {
+                let rect = math::Rect {
+                    x,
+                    y,
+                    width,
+                    height,
+                };
+                let mut found: Vec<_> = analyzed_image
+                    .graphemes_in_rect(rect)
+                    .map(|(id, _)| id.index())
+                    .collect();
+                found.sort_unstable();
+                let expected: Vec<_> = (0..count)
+                    .filter(|&i| {
+                        let grapheme = &analyzed_image.graphemes[i];
+                        width > 0
+                            && height > 0
+                            && (grapheme.left as u64) < x as u64 + width as u64
+                            && grapheme.right >= x
+                            && (grapheme.top as u64) < y as u64 + height as u64
+                            && grapheme.bottom >= y
+                    })
+                    .collect();
+                assert_eq!(found, expected, "{count} graphemes in {rect:?}");
+            }
+        }
+    }
+}
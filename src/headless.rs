@@ -0,0 +1,397 @@
+// Cleaning a batch of pages from the command line without opening a window, for servers and scripted pipelines:
+//
+//     image_cleanup --headless --output DIRECTORY [--preset FILE] [--format FORMAT] [--deskew] [--mirror-margins]
+//         [--threads N] [--min-foreground FRACTION] INPUT...
+//
+// Each input is an image, a folder (every image anywhere inside it), or a path with * and ? in its file name, for
+// when the shell doesn't expand them. FORMAT is original, png, jpeg[:QUALITY], or webp (webp:QUALITY for lossy with
+// the webp-lossy feature).
+//
+// Each exported page's path and foreground fraction (how much of it is ink that was kept) go to stdout separated by a
+// tab, for checking a batch's results. Pages under --min-foreground are listed again at the end to be checked by hand,
+// they might have come out blank.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use image_cleanup::*;
+
+const USAGE: &str =
+    "usage: image_cleanup --headless --output DIRECTORY [--preset FILE] [--format FORMAT] \
+    [--deskew] [--mirror-margins] [--threads N] [--min-foreground FRACTION] INPUT...";
+
+struct Arguments {
+    inputs: Vec<String>,
+    output: PathBuf,
+    preset: Option<PathBuf>,
+    output_format: OutputFormat,
+    deskew: bool,
+    mirror_margins: bool,
+    threads: usize,
+    min_foreground_fraction: Option<f32>,
+}
+
+// The exit code, 2 for bad arguments and 1 if any page failed.
+pub fn run(args: impl Iterator<Item = String>) -> i32 {
+    let arguments = match parse_arguments(args) {
+        Ok(arguments) => arguments,
+        Err(error) => {
+            eprintln!("{error}\n{USAGE}");
+            return 2;
+        }
+    };
+
+    let (analyzer, cleaner, page_settings) = match &arguments.preset {
+        Some(path) => match read_preset(path) {
+            Ok(settings) => settings,
+            Err(error) => {
+                eprintln!("couldn't load the preset {}: {error}", path.display());
+                return 1;
+            }
+        },
+        None => Default::default(),
+    };
+
+    let mut image_paths = Vec::new();
+    for input in &arguments.inputs {
+        let paths = expand_input(input);
+        if paths.is_empty() {
+            eprintln!("no images found for {input}");
+        }
+        image_paths.extend(paths);
+    }
+    // Page order matters for mirrored margins.
+    image_paths.sort_by(|a, b| natural_cmp(&a.to_string_lossy(), &b.to_string_lossy()));
+    image_paths.dedup();
+    if image_paths.is_empty() {
+        return 1;
+    }
+
+    if let Err(error) = std::fs::create_dir_all(&arguments.output) {
+        eprintln!("couldn't create {}: {error}", arguments.output.display());
+        return 1;
+    }
+
+    let options = ExportOptions {
+        analyzer,
+        cleaner,
+        page_settings,
+        output_format: arguments.output_format,
+        auto_deskew: arguments.deskew,
+        mirror_margins: arguments.mirror_margins,
+        min_foreground_fraction: arguments.min_foreground_fraction,
+        #[cfg(feature = "parallel")]
+        threads: arguments.threads,
+    };
+    #[cfg(not(feature = "parallel"))]
+    let _ = arguments.threads;
+    let export_paths = export_paths(&image_paths, &arguments.output);
+    let total = image_paths.len();
+    let summary = export_all(
+        &image_paths,
+        &export_paths,
+        &options,
+        |exported| {
+            let mut stderr = std::io::stderr().lock();
+            let _ = write!(stderr, "\rcleaned {exported}/{total}");
+            if exported == total {
+                let _ = writeln!(stderr);
+            }
+        },
+        &CancelToken::new(),
+    );
+
+    let mut stdout = std::io::stdout().lock();
+    for (path, fraction) in &summary.foreground_fractions {
+        let _ = writeln!(stdout, "{}\t{fraction:.6}", path.display());
+    }
+    if !summary.flagged.is_empty() {
+        eprintln!(
+            "{} of {total} pages have little foreground left:",
+            summary.flagged.len()
+        );
+        for (path, fraction) in &summary.flagged {
+            eprintln!("{}: {:.3}%", path.display(), fraction * 100.0);
+        }
+    }
+
+    for (path, error) in &summary.failed {
+        eprintln!("{}: {error}", path.display());
+    }
+    if summary.failed.is_empty() {
+        0
+    } else {
+        eprintln!("{} of {total} pages failed", summary.failed.len());
+        1
+    }
+}
+
+fn parse_arguments(mut args: impl Iterator<Item = String>) -> Result<Arguments, String> {
+    let mut inputs = Vec::new();
+    let mut output = None;
+    let mut preset = None;
+    let mut output_format = OutputFormat::Original;
+    let (mut deskew, mut mirror_margins) = (false, false);
+    let mut threads = std::thread::available_parallelism().map_or(1, usize::from);
+    let mut min_foreground_fraction = None;
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{arg} needs a value"));
+        match arg.as_str() {
+            "--output" => output = Some(PathBuf::from(value()?)),
+            "--preset" => preset = Some(PathBuf::from(value()?)),
+            "--format" => output_format = parse_format(&value()?)?,
+            "--threads" => {
+                threads = value()?
+                    .parse()
+                    .ok()
+                    .filter(|&threads| threads > 0)
+                    .ok_or("--threads needs a number above 0")?
+            }
+            "--min-foreground" => {
+                min_foreground_fraction = Some(
+                    value()?
+                        .parse()
+                        .ok()
+                        .filter(|fraction| (0.0..=1.0).contains(fraction))
+                        .ok_or("--min-foreground needs a fraction from 0 to 1")?,
+                )
+            }
+            "--deskew" => deskew = true,
+            "--mirror-margins" => mirror_margins = true,
+            _ if arg.starts_with("--") => return Err(format!("unknown option {arg}")),
+            _ => inputs.push(arg),
+        }
+    }
+
+    if inputs.is_empty() {
+        return Err("no inputs given".to_string());
+    }
+    Ok(Arguments {
+        inputs,
+        output: output.ok_or("no --output directory given")?,
+        preset,
+        output_format,
+        deskew,
+        mirror_margins,
+        threads,
+        min_foreground_fraction,
+    })
+}
+
+fn parse_format(format: &str) -> Result<OutputFormat, String> {
+    let (name, quality) = match format.split_once(':') {
+        Some((name, quality)) => {
+            let quality = quality
+                .parse::<u8>()
+                .ok()
+                .filter(|&quality| quality <= 100)
+                .ok_or(format!("bad quality in {format}"))?;
+            (name, Some(quality))
+        }
+        None => (format, None),
+    };
+
+    match (name.to_ascii_lowercase().as_str(), quality) {
+        ("original", None) => Ok(OutputFormat::Original),
+        ("png", None) => Ok(OutputFormat::Png),
+        ("jpeg" | "jpg", quality) => Ok(OutputFormat::Jpeg {
+            quality: quality.unwrap_or(90),
+        }),
+        ("webp", None) => Ok(OutputFormat::WebPLossless),
+        #[cfg(feature = "webp-lossy")]
+        ("webp", Some(quality)) => Ok(OutputFormat::WebPLossy { quality }),
+        _ => Err(format!("unknown format {format}")),
+    }
+}
+
+#[cfg(feature = "serde")]
+fn read_preset(path: &Path) -> std::io::Result<(ImageAnalyzer, ImageCleaner, PageSettings)> {
+    load_preset_with_pages(path)
+}
+
+#[cfg(not(feature = "serde"))]
+fn read_preset(_path: &Path) -> std::io::Result<(ImageAnalyzer, ImageCleaner, PageSettings)> {
+    Err(std::io::Error::other("presets need the serde feature"))
+}
+
+// The images input names, see the top of the file.
+fn expand_input(input: &str) -> Vec<PathBuf> {
+    let path = Path::new(input);
+    let pattern = path.file_name().and_then(|name| name.to_str());
+    let Some(pattern) = pattern.filter(|pattern| pattern.contains(['*', '?'])) else {
+        return collect_image_paths([path.to_path_buf()]);
+    };
+
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let Ok(entries) = std::fs::read_dir(directory) else {
+        return Vec::new();
+    };
+    let matches = entries.flatten().map(|entry| entry.path()).filter(|path| {
+        path.file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| wildcard_match(pattern, name))
+    });
+    collect_image_paths(matches)
+}
+
+// Whether name matches pattern, where * matches any run of characters and ? matches any one.
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let (pattern, name): (Vec<char>, Vec<char>) =
+        (pattern.chars().collect(), name.chars().collect());
+    // Where the last * was and where in name it's matched up to, to go back to when the rest doesn't match.
+    let mut star = None;
+    let (mut p, mut n) = (0, 0);
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    star = Some((star_p, star_n + 1));
+                    (p, n) = (star_p + 1, star_n + 1);
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Arguments, String> {
+        parse_arguments(args.iter().map(|arg| arg.to_string()))
+    }
+
+    fn parse_error(args: &[&str]) -> String {
+        match parse(args) {
+            Ok(_) => panic!("{args:?} parsed"),
+            Err(error) => error,
+        }
+    }
+
+    #[test]
+    fn wildcards() {
+        for (pattern, name, matches) in [
+            ("page.png", "page.png", true),
+            ("page.png", "page.jpg", false),
+            ("*", "", true),
+            ("*", "anything", true),
+            ("*.png", "page 1.png", true),
+            ("*.png", "page.png.bak", false),
+            ("page?.png", "page1.png", true),
+            ("page?.png", "page.png", false),
+            ("page?.png", "page12.png", false),
+            ("??", "ab", true),
+            ("??", "abc", false),
+            // Backtracking past earlier matches of b and c.
+            ("a*b*c", "abc", true),
+            ("a*b*c", "axxbyyc", true),
+            ("a*b*c", "abcbcbc", true),
+            ("a*b*c", "abcbcbx", false),
+            ("a*b*c", "acb", false),
+            ("*a*", "bab", true),
+            ("**?", "x", true),
+            ("**?", "", false),
+            ("p*.??g", "page 10.png", true),
+            ("p*.??g", "page 10.tiff", false),
+            ("é*", "école.png", true),
+        ] {
+            assert_eq!(
+                wildcard_match(pattern, name),
+                matches,
+                "{pattern} against {name}"
+            );
+        }
+    }
+
+    #[test]
+    fn arguments() {
+        let arguments = parse(&[
+            "--output",
+            "out",
+            "--format",
+            "JPEG:75",
+            "--threads",
+            "3",
+            "--min-foreground",
+            "0.01",
+            "--deskew",
+            "a.png",
+            "b/*.png",
+        ])
+        .unwrap();
+        assert_eq!(arguments.inputs, ["a.png", "b/*.png"]);
+        assert_eq!(arguments.output, PathBuf::from("out"));
+        assert!(matches!(
+            arguments.output_format,
+            OutputFormat::Jpeg { quality: 75 }
+        ));
+        assert_eq!(arguments.threads, 3);
+        assert_eq!(arguments.min_foreground_fraction, Some(0.01));
+        assert!(arguments.deskew && !arguments.mirror_margins);
+        assert!(arguments.preset.is_none());
+
+        let arguments = parse(&["a.png", "--output", "out", "--format", "jpg"]).unwrap();
+        assert!(matches!(
+            arguments.output_format,
+            OutputFormat::Jpeg { quality: 90 }
+        ));
+    }
+
+    #[test]
+    fn bad_arguments() {
+        assert_eq!(parse_error(&["a.png"]), "no --output directory given");
+        assert_eq!(parse_error(&["--output", "out"]), "no inputs given");
+        assert_eq!(
+            parse_error(&["a.png", "--output"]),
+            "--output needs a value"
+        );
+        assert_eq!(
+            parse_error(&["a.png", "--output", "out", "--verbose"]),
+            "unknown option --verbose"
+        );
+
+        for threads in ["0", "-1", "two", "1.5", ""] {
+            assert_eq!(
+                parse_error(&["a.png", "--output", "out", "--threads", threads]),
+                "--threads needs a number above 0"
+            );
+        }
+        for fraction in ["-0.1", "1.5", "NaN", "half", "5%"] {
+            assert_eq!(
+                parse_error(&["a.png", "--output", "out", "--min-foreground", fraction]),
+                "--min-foreground needs a fraction from 0 to 1"
+            );
+        }
+        for format in ["jpeg:101", "jpeg:-1", "jpeg:high", "jpeg:"] {
+            assert_eq!(
+                parse_error(&["a.png", "--output", "out", "--format", format]),
+                format!("bad quality in {format}")
+            );
+        }
+        for format in ["gif", "png:50", "original:90"] {
+            assert_eq!(
+                parse_error(&["a.png", "--output", "out", "--format", format]),
+                format!("unknown format {format}")
+            );
+        }
+        #[cfg(not(feature = "webp-lossy"))]
+        assert_eq!(
+            parse_error(&["a.png", "--output", "out", "--format", "webp:80"]),
+            "unknown format webp:80"
+        );
+    }
+}
@@ -1,326 +1,4760 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
 use image::*;
 
-#[derive(Clone, Copy)]
+use grid::GraphemeGrid;
+use pixel::{Canvas, RgbaCanvas};
+
+mod background;
+mod batch;
+mod builder;
+mod cache;
+mod deskew;
+mod grid;
+mod lines;
+mod output;
+mod overrides;
+#[cfg(feature = "parallel")]
+mod parallel;
+mod pdf;
+mod pixel;
+#[cfg(feature = "serde")]
+mod preset;
+mod report;
+mod tiled;
+
+pub use background::normalize_background;
+pub use batch::{clean_page, collect_image_paths, export_all, export_paths, image_extensions};
+pub use batch::{natural_cmp, page_cleaner, ExportOptions, ExportSummary, PageSettings};
+pub use builder::{BuildError, ImageAnalyzerBuilder, ImageCleanerBuilder};
+pub use cache::{CacheError, CacheKey};
+pub use deskew::{auto_deskew, deskew, deskew_with_fill_color, estimate_skew_angle};
+pub use lines::TextLine;
+pub use output::OutputFormat;
+pub use pdf::PdfWriter;
+pub use pixel::{DocumentImage, DocumentPixel, Sample};
+#[cfg(feature = "serde")]
+pub use preset::{load_preset, load_preset_with_pages, save_preset, save_preset_with_pages};
+#[cfg(feature = "serde")]
+pub use report::{save_clean_report_json, save_component_report_json};
+pub use report::{save_component_report_csv, CleanReport, ComponentStat, Decision};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum LuminanceMode {
+    // The plain average of the three channels.
+    #[default]
+    Mean,
+    // 0.2126·R + 0.7152·G + 0.0722·B
+    Rec709,
+    // 0.299·R + 0.587·G + 0.114·B
+    Rec601,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Connectivity {
+    // Only orthogonally touching pixels belong to the same grapheme.
+    #[default]
+    Four,
+    // Diagonally touching pixels belong to the same grapheme too, which keeps thin slanted strokes together.
+    Eight,
+}
+
+impl Connectivity {
+    fn neighbors(self) -> &'static [(i32, i32)] {
+        const FOUR: [(i32, i32); 4] = [(1, 0), (0, 1), (-1, 0), (0, -1)];
+        const EIGHT: [(i32, i32); 8] = [
+            (1, 0),
+            (0, 1),
+            (-1, 0),
+            (0, -1),
+            (1, 1),
+            (-1, 1),
+            (-1, -1),
+            (1, -1),
+        ];
+
+        match self {
+            Connectivity::Four => &FOUR,
+            Connectivity::Eight => &EIGHT,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ThresholdMode {
+    // Pixels lighter than off_white_threshold are off-white.
+    #[default]
+    Global,
+    // Pixels lighter than a threshold computed from the mean and standard deviation of the window (in pixels) around them
+    // are off-white, which copes with uneven lighting far better than a single threshold.
+    Sauvola {
+        window: u32,
+        k: f32,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq)]
+// Missing fields are filled in from the defaults, so presets saved before a field was added still load.
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
 pub struct ImageAnalyzer {
     pub off_white_threshold: u8,
+    // When set, a pixel is off-white if every channel is at least as light as its own threshold (useful for yellowed
+    // paper), instead of comparing its value against off_white_threshold.
+    pub off_white_threshold_rgb: Option<[u8; 3]>,
     pub lightness_threshold: u8,
     pub lightness_distance: u32,
+    pub luminance_mode: LuminanceMode,
+    pub connectivity: Connectivity,
+    pub threshold_mode: ThresholdMode,
+    // Partially transparent pixels are analyzed as if they were drawn over this color.
+    pub transparent_background_color: [u8; 3],
+    // Graphemes whose bounding boxes are at most this many pixels apart are merged into one, so the dot of an i or an
+    // accent stays with its letter instead of being cleaned away as a speck. 0 doesn't merge anything.
+    pub merge_distance: u32,
+    // The radius of a closing (a dilation then an erosion) of the foreground before graphemes are detected, which joins
+    // letters broken up by hairline gaps on faint scans. 0 leaves the foreground as it is.
+    pub morph_close: u32,
+    // When set, the image is evened out with normalize_background using blocks this many pixels across before it's
+    // analyzed, for scans that get darker toward the spine.
+    pub auto_flatten: Option<u32>,
+    // Treats light pixels as the foreground and dark ones as the background, for white ink on dark paper like
+    // blackboards and negatives. Every threshold then measures darkness instead of lightness.
+    pub invert: bool,
+    // Images with more pixels than this fail to analyze instead of allocating their map, which takes 4 bytes a pixel.
+    // export_all cleans a page on each thread at once, so lowering it bounds what a single bad page can take.
+    pub max_pixels: u64,
 }
 
 impl Default for ImageAnalyzer {
     fn default() -> Self {
         Self {
             off_white_threshold: 240,
+            off_white_threshold_rgb: None,
             lightness_threshold: 100,
             lightness_distance: 1,
+            luminance_mode: LuminanceMode::Mean,
+            connectivity: Connectivity::Four,
+            threshold_mode: ThresholdMode::Global,
+            transparent_background_color: [255, 255, 255],
+            merge_distance: 0,
+            morph_close: 0,
+            auto_flatten: None,
+            invert: false,
+            max_pixels: MAX_PIXELS,
+        }
+    }
+}
+
+// The thresholds of an ImageAnalyzer in the range of an image's channels, so for 16 bit images they can be set more
+// finely than the analyzer's own 8 bit ones.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Thresholds<S> {
+    pub off_white_threshold: S,
+    pub off_white_threshold_rgb: Option<[S; 3]>,
+    pub lightness_threshold: S,
+}
+
+pub struct SuggestedThresholds {
+    pub off_white_threshold: u8,
+    pub lightness_threshold: u8,
+    // How many pixels of each value the image has.
+    pub histogram: [u32; 256],
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum OutputMode {
+    // Kept graphemes are drawn in their original colors.
+    #[default]
+    Original,
+    // Every pixel of the kept graphemes is drawn pure black (or pure white over a dark background fill) whatever its
+    // original color, which some OCR engines need. binarize_threshold leaves out their lighter edges.
+    Binarized,
+}
+
+// How big a grapheme is, for telling specks apart from everything else.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SizeMetric {
+    // How many pixels it has.
+    #[default]
+    PixelCount,
+    // The area of its bounding box, so thin broken lines like a faint underline count as big even though they're made
+    // of only a few pixels. Never smaller than the pixel count, since every pixel is inside the box.
+    BoundingBoxArea,
+}
+
+// How the distance from a speck to a big grapheme is measured for isolation_distance_threshold.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum IsolationMetric {
+    // The blank pixels between their bounding boxes, along whichever axis they're farthest apart on, 0 if the boxes
+    // touch or overlap.
+    #[default]
+    BboxGap,
+    // How the distance used to be measured: near when the top or bottom edges of the boxes are less than the distance
+    // apart, and so are the left or right edges. A speck beside the middle of a long line is far from it, while one
+    // diagonally off a corner can be near.
+    BboxEdges,
+    // Between the centroids, in a straight line, so a big grapheme's size doesn't reach out toward specks.
+    CenterEuclidean,
+}
+
+// What removed graphemes are filled with.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum FillStyle {
+    // speck_fill_color.
+    #[default]
+    Solid,
+    // The median color of the background pixels within radius pixels of the grapheme's bounding box, so fills don't
+    // stand out on paper that isn't evenly white. Only cleaning over the original (see clean_over) has background
+    // pixels to sample, otherwise the background is all background_fill_color and that's what's used. Graphemes with no
+    // background around them are filled with speck_fill_color.
+    SampledBackground {
+        radius: u32,
+    },
+}
+
+// Which graphemes a region applies to.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum RegionMatch {
+    // Graphemes whose bounding box is entirely inside it.
+    #[default]
+    Inside,
+    // Graphemes whose bounding box overlaps it at all.
+    Overlapping,
+}
+
+// How far in from each edge of the page graphemes are filled, in pixels, or as a fraction of the page's size.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+// Presets from before the sides could differ have the margins as an (x, y) pair, which still loads.
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(
+        from = "preset::MarginsFormat<T>",
+        bound(deserialize = "T: Copy + serde::Deserialize<'de>")
+    )
+)]
+pub struct Margins<T = u32> {
+    pub left: T,
+    pub right: T,
+    pub top: T,
+    pub bottom: T,
+}
+
+impl<T> Margins<T> {
+    // With left and right swapped, for the facing page of a book whose gutter is on the other side.
+    pub fn mirrored(self) -> Self {
+        Self {
+            left: self.right,
+            right: self.left,
+            ..self
+        }
+    }
+}
+
+impl Margins {
+    pub fn max(self) -> u32 {
+        self.left.max(self.right).max(self.top).max(self.bottom)
+    }
+
+    pub fn to_fractions(self, width: u32, height: u32) -> Margins<f32> {
+        let fraction = |pixels: u32, size: u32| pixels as f32 / size.max(1) as f32;
+        Margins {
+            left: fraction(self.left, width),
+            right: fraction(self.right, width),
+            top: fraction(self.top, height),
+            bottom: fraction(self.bottom, height),
+        }
+    }
+}
+
+impl Margins<f32> {
+    // The left and right are fractions of the width, the top and bottom of the height.
+    pub fn to_pixels(self, width: u32, height: u32) -> Margins {
+        let pixels = |fraction: f32, size: u32| (fraction * size as f32).round() as u32;
+        Margins {
+            left: pixels(self.left, width),
+            right: pixels(self.right, width),
+            top: pixels(self.top, height),
+            bottom: pixels(self.bottom, height),
         }
     }
 }
 
-#[derive(Clone, Copy)]
+// x for the left and right, y for the top and bottom.
+impl<T: Copy> From<(T, T)> for Margins<T> {
+    fn from((x, y): (T, T)) -> Self {
+        Self {
+            left: x,
+            right: x,
+            top: y,
+            bottom: y,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
 pub struct ImageCleaner {
     pub speck_size_threshold: usize,
-    pub page_margins: (u32, u32),
+    // What speck_size_threshold is compared against.
+    pub size_metric: SizeMetric,
+    pub page_margins: Margins,
+    // When set, the margins are these fractions of each page's width and height instead of page_margins, so pages
+    // scanned at different resolutions get the same margins.
+    pub page_margin_fractions: Option<Margins<f32>>,
+    // When set, graphemes in the margins bigger than this (measured with size_metric) are kept instead of filled, for
+    // page numbers, running headers, and signature marks. Only the sides set in margin_keep_sides exempt them, a
+    // grapheme reaching into the corner of two margins needs both.
+    pub margin_keep_size: Option<usize>,
+    pub margin_keep_sides: Margins<bool>,
+    // Fills graphemes that touch the edge of the image whatever their size, for the scanner's edges, page turn shadows,
+    // and binder clips, which are too big for the margins to catch.
+    pub remove_border_touching: bool,
+    // Graphemes that overlap any of these are always drawn, unless they're manually overridden to be removed, for
+    // signatures and notes written in the margins. They're different on every page, so presets don't save them.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub protected_regions: Vec<math::Rect>,
+    // Graphemes in any of these are always filled, unless they're protected or manually overridden to be kept, for
+    // things like a library stamp in the same place on every page.
+    #[cfg_attr(feature = "serde", serde(with = "preset::rects"))]
+    pub removal_regions: Vec<math::Rect>,
+    pub removal_region_match: RegionMatch,
+    // How many blank pixels there can be between a speck's bounding box and a big grapheme's, horizontally or
+    // vertically, for the speck to not be isolated. This used to compare the boxes' edges to each other instead, so a
+    // speck right beside the middle of a long line counted as far from it, and presets from then may need a smaller
+    // distance now.
     pub isolation_distance_threshold: u32,
     pub isolation_size_threshold: u32,
+    pub isolation_metric: IsolationMetric,
+    // Keeps graphemes the speck rule would fill when they line up with a letter within punctuation_distance pixels,
+    // beside it at the same height or right above it. Then speck_size_threshold can be raised enough to catch fly
+    // specks without eating periods, colons, and the dots on i's. The other rules still apply to them.
+    pub protect_punctuation: bool,
+    pub punctuation_distance: u32,
+    // When set, graphemes whose average value is lighter than this are filled, even if they'd otherwise be kept. Ink
+    // bleeding through from the other side of the page is a lot paler than the text on this side, and so are smudges
+    // like light gray thumb prints, however big they are. Presets from before it was renamed have it as
+    // bleedthrough_threshold, which also filled graphemes exactly this light.
+    #[cfg_attr(feature = "serde", serde(alias = "bleedthrough_threshold"))]
+    pub max_average_value: Option<u8>,
+    // When set, graphemes whose density (the fraction of their bounding box they cover) is above this are filled, for
+    // solid round ink blots. Solid letters like l and periods are dense too, so it's off by default.
+    pub max_fill_ratio: Option<f32>,
+    // When set, graphemes whose density is below this are filled, for sparse tangles like hair and fibers.
+    pub min_fill_ratio: Option<f32>,
+    // When set, only graphemes no bigger than this (measured with size_metric) are filled for their density, so big
+    // solid letters and sparse drawings are left alone whatever max_fill_ratio and min_fill_ratio are.
+    pub fill_ratio_size_limit: Option<usize>,
+    // When set, graphemes whose bounding box is more than this many times longer than it is wide are filled, for
+    // scanner streaks. Rules in tables and underlines are long and thin too, so it's off by default.
+    pub max_aspect_ratio: Option<f32>,
+    // Only graphemes at most this many pixels thick (the short side of the bounding box) count as streaks, when set.
+    // Streaks from dust on the scanner's glass are a pixel or two wide, table rules and em dashes are usually thicker.
+    pub max_streak_thickness: Option<u32>,
+    // Only graphemes at least this long (the long side of the bounding box) count as streaks, so short dashes with
+    // streak-like proportions stay.
+    pub min_streak_length: u32,
+    // Which way streaks can run. Sheet-fed scanners leave streaks along the direction the paper moves, so turning off
+    // the other keeps horizontal rules, or vertical ones.
+    pub remove_vertical_streaks: bool,
+    pub remove_horizontal_streaks: bool,
+    // When set, graphemes with more pixels than this fraction of the whole page's are filled, for the dark scanner lid
+    // around a page that's smaller than the platen and for huge smudges.
+    pub max_grapheme_area_fraction: Option<f32>,
+    // Holes of at most this many pixels inside kept graphemes are filled in, for pinholes the scanner left in heavy
+    // letters. Counters like the inside of an o are a lot bigger. 0 doesn't fill any.
+    pub hole_fill_threshold: usize,
+    // Fills every enclosed hole of every grapheme (like the inside of an o) in the grapheme's own color, so each one
+    // shows up as a solid blob. Useful with a debug speck fill color for seeing which marks are solid stamps.
+    pub fill_grapheme_holes: bool,
     pub speck_fill_color: [u8; 3],
+    pub speck_fill_style: FillStyle,
     pub background_fill_color: [u8; 3],
+    pub output_mode: OutputMode,
+    // Darkens the kept graphemes when they're drawn in their original colors, for faded ink. Each channel at or below
+    // ink_black_point becomes black, and the rest are stretched from there up to white and raised to the power of
+    // ink_gamma, so above 1 the grays get darker while white stays white and the soft edges of letters stay soft. Over
+    // a dark background fill it works the other way, making light ink lighter. 1 and 0 leave the colors as they are.
+    pub ink_gamma: f32,
+    pub ink_black_point: u8,
+    // When set, binarized output only draws the pixels of kept graphemes that are darker than this (going by the plain
+    // average of their channels), and leaves the rest as background, so the soft gray edges of letters don't come out
+    // as black. Over a dark background fill, it's the pixels lighter than 255 minus this instead. When it's not set,
+    // like by default, every pixel of a kept grapheme is drawn. The analyzer's lightness_threshold, the value below
+    // which a pixel counts as ink on its own, is a good place to start.
+    pub binarize_threshold: Option<u8>,
+    // What clean_rgba fills the background and the removed graphemes with. Transparent white by default, so tools that
+    // don't handle transparency well show white instead of black.
+    pub rgba_fill_color: [u8; 4],
+    // Makes clean_onto leave every pixel that isn't part of a removed grapheme exactly as it was in the source, like
+    // clean_over, instead of drawing the kept graphemes over background_fill_color. output_mode doesn't apply then.
+    pub preserve_original: bool,
 }
 
 impl Default for ImageCleaner {
     fn default() -> Self {
         Self {
             speck_size_threshold: 15,
-            page_margins: (50, 50),
+            size_metric: SizeMetric::PixelCount,
+            page_margins: Margins::from((50, 50)),
+            page_margin_fractions: None,
+            margin_keep_size: None,
+            margin_keep_sides: Margins::from((true, true)),
+            remove_border_touching: false,
+            protected_regions: Vec::new(),
+            removal_regions: Vec::new(),
+            removal_region_match: RegionMatch::Inside,
             isolation_distance_threshold: 50,
             isolation_size_threshold: 80,
+            isolation_metric: IsolationMetric::BboxGap,
+            protect_punctuation: false,
+            punctuation_distance: 20,
+            max_average_value: None,
+            max_fill_ratio: None,
+            min_fill_ratio: None,
+            fill_ratio_size_limit: None,
+            max_aspect_ratio: None,
+            max_streak_thickness: None,
+            min_streak_length: 0,
+            remove_vertical_streaks: true,
+            remove_horizontal_streaks: true,
+            max_grapheme_area_fraction: None,
+            hole_fill_threshold: 0,
+            fill_grapheme_holes: false,
             speck_fill_color: [255, 255, 255],
+            speck_fill_style: FillStyle::Solid,
             background_fill_color: [255, 255, 255],
+            output_mode: OutputMode::Original,
+            ink_gamma: 1.0,
+            ink_black_point: 0,
+            binarize_threshold: None,
+            rgba_fill_color: [255, 255, 255, 0],
+            preserve_original: false,
+        }
+    }
+}
+
+// Lets another thread stop an analyze or clean part way through. Clones share the same flag.
+#[derive(Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+// Why an analysis stopped before it was done.
+enum AnalyzeError {
+    Cancelled,
+    // The image is too big to analyze.
+    Image(ImageError),
+}
+
+impl From<Cancelled> for AnalyzeError {
+    fn from(_: Cancelled) -> Self {
+        Self::Cancelled
+    }
+}
+
+impl AnalyzeError {
+    // For the analyses without a cancel token.
+    fn into_image_error(self) -> ImageError {
+        match self {
+            Self::Cancelled => unreachable!("there's no token to cancel it"),
+            Self::Image(error) => error,
         }
     }
 }
 
-pub struct AnalyzedImage {
-    pub graphemes: Vec<Grapheme>,
+// Ok(None) when the analysis was cancelled.
+fn cancelled_to_none<T>(result: Result<T, AnalyzeError>) -> ImageResult<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(AnalyzeError::Cancelled) => Ok(None),
+        Err(AnalyzeError::Image(error)) => Err(error),
+    }
+}
+
+// The analyses that don't return an ImageResult panic on images too big to analyze, like allocating too much would.
+fn expect_analyzed<T>(result: ImageResult<T>) -> T {
+    result.unwrap_or_else(|error| panic!("can't analyze the image: {error}"))
+}
+
+pub struct AnalyzedImage<P = Rgb<u8>> {
+    pub graphemes: Vec<Grapheme<P>>,
     pub map: Vec<u32>,
     pub width: u32,
     pub height: u32,
+    grid: GraphemeGrid,
+    // Kept between analyses into the same image so analyze_into doesn't allocate it again.
+    visited_map: VisitedMap,
 }
 
-impl AnalyzedImage {
-    fn new(image: &RgbImage) -> Self {
+// An empty 0x0 image, for analyze_into to fill in.
+impl<P> Default for AnalyzedImage<P> {
+    fn default() -> Self {
         Self {
-            map: vec![u32::MAX; (image.width() * image.height()) as usize],
             graphemes: Vec::new(),
-            width: image.width(),
-            height: image.height(),
+            map: Vec::new(),
+            width: 0,
+            height: 0,
+            grid: GraphemeGrid::default(),
+            visited_map: VisitedMap::default(),
         }
     }
+}
+
+impl<P> AnalyzedImage<P> {
+    // Empties the image for analyzing a new one into it, keeping the map's allocation if it's big enough. Fails if the
+    // image has more than max_pixels pixels or the map for it can't be allocated.
+    fn reset(&mut self, width: u32, height: u32, max_pixels: u64) -> ImageResult<()> {
+        let pixel_count = pixel_count(width, height, max_pixels)?;
+        self.graphemes.clear();
+        self.map.clear();
+        self.map.try_reserve_exact(pixel_count).map_err(|_| {
+            ImageError::Limits(error::LimitError::from_kind(
+                error::LimitErrorKind::InsufficientMemory,
+            ))
+        })?;
+        self.map.resize(pixel_count, u32::MAX);
+        self.width = width;
+        self.height = height;
+        self.grid = GraphemeGrid::default();
+        self.visited_map.resize(width, height);
+
+        Ok(())
+    }
+
+    // Which grapheme covers (x, y), if any. Ids stay valid as long as the analyzed image does, so they can be kept
+    // around to look the grapheme up again later.
+    pub fn grapheme_id_at(&self, x: u32, y: u32) -> Option<GraphemeId> {
+        if x >= self.width || y >= self.height {
+            return None;
+        }
 
-    pub fn get_grapheme_at(&self, x: u32, y: u32) -> Option<&Grapheme> {
-        let i = match self.map[(self.width * y + x) as usize] {
+        match self.map[index(self.width, x, y)] {
             u32::MAX => None,
-            i => Some(i),
-        }? as usize;
-        Some(&self.graphemes[i])
+            i => Some(GraphemeId(i)),
+        }
+    }
+
+    pub fn get(&self, id: GraphemeId) -> Option<&Grapheme<P>> {
+        self.graphemes.get(id.index())
+    }
+
+    pub fn get_mut(&mut self, id: GraphemeId) -> Option<&mut Grapheme<P>> {
+        self.graphemes.get_mut(id.index())
+    }
+
+    pub fn get_grapheme_at(&self, x: u32, y: u32) -> Option<&Grapheme<P>> {
+        self.get(self.grapheme_id_at(x, y)?)
+    }
+
+    // Every grapheme with its id, in order.
+    pub fn iter(&self) -> GraphemeIterator<'_, P> {
+        GraphemeIterator {
+            graphemes: self.graphemes.iter().enumerate(),
+        }
+    }
+
+    // Groups the graphemes whose bounding boxes are at most max_gap blank pixels apart into clusters like words, for
+    // layout analysis. Each cluster is in the order of its graphemes, and the clusters in the order of their first ones.
+    // This leaves the graphemes themselves alone, unlike ImageAnalyzer::merge_distance.
+    pub fn merge_graphemes(&self, max_gap: u32) -> Vec<Vec<GraphemeId>> {
+        let mut parents = union_nearby(&self.graphemes, max_gap);
+
+        let mut cluster_indices = vec![usize::MAX; parents.len()];
+        let mut clusters: Vec<Vec<GraphemeId>> = Vec::new();
+        for i in 0..parents.len() {
+            let root = find(&mut parents, i);
+            if cluster_indices[root] == usize::MAX {
+                cluster_indices[root] = clusters.len();
+                clusters.push(Vec::new());
+            }
+            clusters[cluster_indices[root]].push(GraphemeId(i as u32));
+        }
+
+        clusters
+    }
+}
+
+pub struct GraphemeIterator<'a, P> {
+    graphemes: std::iter::Enumerate<std::slice::Iter<'a, Grapheme<P>>>,
+}
+
+impl<'a, P> Iterator for GraphemeIterator<'a, P> {
+    type Item = (GraphemeId, &'a Grapheme<P>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (i, grapheme) = self.graphemes.next()?;
+        Some((GraphemeId(i as u32), grapheme))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.graphemes.size_hint()
+    }
+}
+
+impl<P> ExactSizeIterator for GraphemeIterator<'_, P> {}
+
+impl<'a, P> IntoIterator for &'a AnalyzedImage<P> {
+    type Item = (GraphemeId, &'a Grapheme<P>);
+    type IntoIter = GraphemeIterator<'a, P>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+// Refers to one of an analyzed image's graphemes by its position in graphemes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct GraphemeId(u32);
+
+impl GraphemeId {
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+// An image analyzed by analyze_dynamic, in whichever pixel type it was analyzed as. Cleaning it gives back an image of
+// the same type.
+pub enum DynamicAnalyzedImage {
+    Luma8(AnalyzedImage<Luma<u8>>),
+    Rgb8(AnalyzedImage<Rgb<u8>>),
+    Rgba8(AnalyzedImage<Rgba<u8>>),
+    Luma16(AnalyzedImage<Luma<u16>>),
+    Rgb16(AnalyzedImage<Rgb<u16>>),
+    Rgba16(AnalyzedImage<Rgba<u16>>),
+}
+
+impl Default for DynamicAnalyzedImage {
+    fn default() -> Self {
+        Self::Rgb8(AnalyzedImage::default())
     }
+}
 
-    fn set_grapheme_at(&mut self, x: u32, y: u32, i: Option<u32>) {
-        self.map[(self.width * y + x) as usize] = i.unwrap_or(u32::MAX);
+impl DynamicAnalyzedImage {
+    // (width, height)
+    pub fn dimensions(&self) -> (u32, u32) {
+        match self {
+            DynamicAnalyzedImage::Luma8(image) => (image.width, image.height),
+            DynamicAnalyzedImage::Rgb8(image) => (image.width, image.height),
+            DynamicAnalyzedImage::Rgba8(image) => (image.width, image.height),
+            DynamicAnalyzedImage::Luma16(image) => (image.width, image.height),
+            DynamicAnalyzedImage::Rgb16(image) => (image.width, image.height),
+            DynamicAnalyzedImage::Rgba16(image) => (image.width, image.height),
+        }
     }
 }
 
+// Which pixels of an image are background, everything else is part of a grapheme.
+#[derive(Default)]
 struct VisitedMap {
-    map: Vec<bool>,
+    // One bit per pixel, with each row starting on a new word so rows can be worked on separately.
+    words: Vec<u64>,
+    words_per_row: usize,
     width: u32,
-    _height: u32,
+    height: u32,
 }
 
 impl VisitedMap {
-    fn new(width: u32, height: u32) -> Self {
-        Self {
-            map: vec![false; (width * height) as usize],
-            width,
-            _height: height,
-        }
+    // Resizes the map and marks every pixel unvisited, keeping the allocation if it's big enough.
+    fn resize(&mut self, width: u32, height: u32) {
+        self.words_per_row = (width as usize).div_ceil(64);
+        self.width = width;
+        self.height = height;
+        self.clear();
+    }
+
+    fn clear(&mut self) {
+        self.words.clear();
+        self.words
+            .resize(self.words_per_row * self.height as usize, 0);
     }
 
+    #[inline]
     fn is_visited(&self, x: u32, y: u32) -> bool {
-        self.map[(y * self.width + x) as usize]
+        let word = self.words[y as usize * self.words_per_row + x as usize / 64];
+        word >> (x % 64) & 1 == 1
     }
 
+    #[cfg(not(feature = "parallel"))]
+    #[inline]
     fn set_visited(&mut self, x: u32, y: u32, b: bool) {
-        self.map[(y * self.width + x) as usize] = b;
+        let word = &mut self.words[y as usize * self.words_per_row + x as usize / 64];
+        let bit = 1 << (x % 64);
+        if b {
+            *word |= bit;
+        } else {
+            *word &= !bit;
+        }
     }
-}
-
-impl ImageAnalyzer {
-    pub fn analyze(&self, image: &RgbImage) -> AnalyzedImage {
-        let mut analyzed_image = AnalyzedImage::new(image);
-        let mut visited_map = VisitedMap::new(image.width(), image.height());
 
-        // Whiten
-        for (x, y, pixel) in image.enumerate_pixels() {
-            let value = pixel_value(*pixel);
+    // The first x from start on in row y that's visited (or not), or the width if there isn't one.
+    fn next(&self, y: u32, start: u32, visited: bool) -> u32 {
+        let row = &self.words[y as usize * self.words_per_row..][..self.words_per_row];
+        let mut i = start as usize / 64;
+        // Bits before start are skipped by pretending they don't match.
+        let mut skipped = !0 << (start % 64);
+        while i < row.len() {
+            let matching = if visited { row[i] } else { !row[i] } & skipped;
+            if matching != 0 {
+                return (i as u32 * 64 + matching.trailing_zeros()).min(self.width);
+            }
+            i += 1;
+            skipped = !0;
+        }
 
-            // If the pixel isn't very dark and it's not next to other really dark pixels (like letter borders), fill it.
-            let offwhite = value >= self.off_white_threshold;
-            let too_light_and_distant = value >= self.lightness_threshold
-                && darkest_pixel_within(x, y, self.lightness_distance, image)
-                    >= self.lightness_threshold;
+        self.width
+    }
 
-            if offwhite || too_light_and_distant {
-                visited_map.set_visited(x, y, true);
-            }
+    // The part of the map inside rect, as a map of its own.
+    fn crop(&self, rect: math::Rect) -> VisitedMap {
+        let mut cropped = VisitedMap::default();
+        cropped.resize(rect.width, rect.height);
+        for (y, row) in cropped
+            .words
+            .chunks_mut(cropped.words_per_row.max(1))
+            .enumerate()
+        {
+            let y = rect.y + y as u32;
+            pack_row(
+                row,
+                (rect.x..rect.x + rect.width).map(|x| self.is_visited(x, y)),
+            );
         }
 
-        for (x, y, _) in image.enumerate_pixels() {
-            if visited_map.is_visited(x, y) {
-                continue;
-            }
+        cropped
+    }
 
-            let grapheme = Grapheme::detect(x, y, image, &mut visited_map);
-            for (x, y, _) in grapheme.pixels.iter() {
-                analyzed_image.set_grapheme_at(*x, *y, Some(analyzed_image.graphemes.len() as u32));
-            }
-            analyzed_image.graphemes.push(grapheme);
+    // A morphological closing of the unvisited pixels with a square of radius, which bridges gaps in them up to twice
+    // radius wide and otherwise only fills in narrow notches.
+    fn close(&mut self, radius: u32) {
+        let mut map: Vec<bool> = (0..self.height)
+            .flat_map(|y| (0..self.width).map(move |x| (x, y)))
+            .map(|(x, y)| self.is_visited(x, y))
+            .collect();
+        // Dilating the unvisited pixels, a pixel stays visited only if its whole window is (false < true).
+        minimum_filter(&mut map, self.width, self.height, radius);
+        // Then eroding them back, a pixel becomes visited if anything in its window is.
+        for visited in map.iter_mut() {
+            *visited = !*visited;
         }
+        minimum_filter(&mut map, self.width, self.height, radius);
 
-        analyzed_image
+        self.clear();
+        for (row, visited) in self
+            .words
+            .chunks_mut(self.words_per_row.max(1))
+            .zip(map.chunks(self.width.max(1) as usize))
+        {
+            pack_row(row, visited.iter().map(|unvisited| !unvisited));
+        }
     }
 }
 
-impl ImageCleaner {
-    pub fn clean(&self, analyzed_image: &AnalyzedImage) -> RgbImage {
-        let mut new_image: RgbImage = ImageBuffer::new(analyzed_image.width, analyzed_image.height);
-        for p in new_image.pixels_mut() {
-            *p = self.background_fill_color.into();
-        }
+// Sets the bits of a row of words from the visited flags of its pixels, left to right.
+fn pack_row(row: &mut [u64], visited: impl Iterator<Item = bool>) {
+    for (x, visited) in visited.enumerate() {
+        row[x / 64] |= (visited as u64) << (x % 64);
+    }
+}
 
-        for (i, grapheme) in analyzed_image.graphemes.iter().enumerate() {
-            if let Some(manual_override) = grapheme.manual_override {
-                match manual_override {
-                    false => grapheme.fill(&mut new_image, self.speck_fill_color.into()),
-                    true => grapheme.draw(&mut new_image),
-                }
+// Summed-area tables of pixel values and their squares, for constant time window means and deviations.
+struct IntegralImage {
+    sums: Vec<u64>,
+    squared_sums: Vec<u64>,
+    width: u32,
+    height: u32,
+}
 
-                continue;
-            }
+impl IntegralImage {
+    fn new<P: DocumentPixel>(image: &DocumentImage<P>, value: impl Fn(&P) -> P::Subpixel) -> Self {
+        let (width, height) = image.dimensions();
+        // Padded with a row and column of zeros on the top and left so lookups don't need bounds checks.
+        let stride = width as usize + 1;
+        let mut sums = vec![0; stride * (height as usize + 1)];
+        let mut squared_sums = vec![0; stride * (height as usize + 1)];
 
-            let too_small = grapheme.pixels.len() <= self.speck_size_threshold;
-            let inside_margins = grapheme.top < self.page_margins.1
-                || grapheme.bottom >= analyzed_image.height - self.page_margins.1
-                || grapheme.left < self.page_margins.0
-                || grapheme.right >= analyzed_image.width - self.page_margins.0;
-            let is_isolated = self.is_isolated(i, &analyzed_image.graphemes);
+        for y in 0..height {
+            let mut row_sum = 0;
+            let mut row_squared_sum = 0;
+            for x in 0..width {
+                let value: u64 = value(image.get_pixel(x, y)).into();
+                row_sum += value;
+                row_squared_sum += value * value;
 
-            if too_small || inside_margins || is_isolated {
-                // A speck/smudge probably.
-                grapheme.fill(&mut new_image, self.speck_fill_color.into())
-            } else {
-                grapheme.draw(&mut new_image);
+                let i = (y as usize + 1) * stride + x as usize + 1;
+                sums[i] = sums[i - stride] + row_sum;
+                squared_sums[i] = squared_sums[i - stride] + row_squared_sum;
             }
         }
 
-        new_image
+        Self {
+            sums,
+            squared_sums,
+            width,
+            height,
+        }
     }
 
-    fn is_isolated(&self, grapheme_index: usize, graphemes: &[Grapheme]) -> bool {
-        let grapheme = &graphemes[grapheme_index];
-        if grapheme.pixels.len() > self.isolation_size_threshold as usize {
-            return false;
-        }
+    // The mean and standard deviation of the pixel values within radius of (x, y), clipped to the image.
+    fn window_statistics(&self, x: u32, y: u32, radius: u32) -> (f32, f32) {
+        let stride = self.width as usize + 1;
+        let left = x.saturating_sub(radius) as usize;
+        let top = y.saturating_sub(radius) as usize;
+        let right = x.saturating_add(radius).min(self.width - 1) as usize + 1;
+        let bottom = y.saturating_add(radius).min(self.height - 1) as usize + 1;
 
-        for i in 0..graphemes.len() - 1 {
-            // Iterate back and forth as an optimization, this way it searches by proximity.
-            let negative = i % 2 == 1;
-            let index =
-                grapheme_index as i64 + ((1 + i / 2) as i64 * if negative { -1 } else { 1 });
-            let index = if index < 0 {
-                graphemes.len() - index.unsigned_abs() as usize
-            } else if index >= graphemes.len() as i64 {
-                index as usize - graphemes.len()
-            } else {
-                index as usize
-            };
+        let window_sum = |table: &[u64]| {
+            table[bottom * stride + right] + table[top * stride + left]
+                - table[top * stride + right]
+                - table[bottom * stride + left]
+        };
 
-            let other_grapheme = &graphemes[index];
-            let not_big_enough =
-                other_grapheme.pixels.len() < self.isolation_size_threshold as usize;
+        let area = ((right - left) * (bottom - top)) as f64;
+        let mean = window_sum(&self.sums) as f64 / area;
+        let variance = (window_sum(&self.squared_sums) as f64 / area - mean * mean).max(0.0);
 
-            // A speck needs to be close to a big grapheme to survive this, 2 small specks together won't survive.
-            if not_big_enough {
-                continue;
-            }
+        (mean as f32, variance.sqrt() as f32)
+    }
+}
 
-            let within_distance_threshold = (positive_difference(grapheme.top, other_grapheme.top)
-                < self.isolation_distance_threshold
-                || positive_difference(grapheme.bottom, other_grapheme.bottom)
-                    < self.isolation_distance_threshold)
-                && (positive_difference(grapheme.left, other_grapheme.left)
-                    < self.isolation_distance_threshold
-                    || positive_difference(grapheme.right, other_grapheme.right)
-                        < self.isolation_distance_threshold);
+// The darkest pixel value within distance of every pixel (a square window clipped to the image).
+struct DarkestMap<S> {
+    values: Vec<S>,
+    width: u32,
+}
 
-            if within_distance_threshold {
-                return false;
-            }
+impl<S: Sample> DarkestMap<S> {
+    fn new<P: DocumentPixel<Subpixel = S>>(
+        image: &DocumentImage<P>,
+        distance: u32,
+        value: impl Fn(&P) -> S,
+    ) -> Self {
+        let mut values: Vec<S> = image.pixels().map(value).collect();
+        minimum_filter(&mut values, image.width(), image.height(), distance);
+
+        Self {
+            values,
+            width: image.width(),
         }
+    }
 
-        true
+    fn get(&self, x: u32, y: u32) -> S {
+        self.values[index(self.width, x, y)]
     }
 }
 
-pub struct Grapheme {
-    pixels: Vec<(u32, u32, Rgb<u8>)>,
-    top: u32,
-    bottom: u32,
-    left: u32,
-    right: u32,
-    // If true, always draw no matter what, if false, never draw no matter what.
-    manual_override: Option<bool>,
+// Everything is_background needs to decide about a pixel, besides the analyzer's own settings.
+struct BackgroundTest<S> {
+    thresholds: Thresholds<S>,
+    // Everything outside of it is background.
+    region: math::Rect,
+    integral_image: Option<IntegralImage>,
+    darkest_map: DarkestMap<S>,
 }
 
-impl Grapheme {
-    fn detect(x: u32, y: u32, image: &RgbImage, visited_map: &mut VisitedMap) -> Self {
-        const NEIGHBORS: [(i32, i32); 4] = [(1, 0), (0, 1), (-1, 0), (0, -1)];
+impl ImageAnalyzer {
+    // Panics if the image has more than max_pixels pixels, see try_analyze.
+    pub fn analyze<P: DocumentPixel>(&self, image: &DocumentImage<P>) -> AnalyzedImage<P> {
+        expect_analyzed(self.try_analyze(image))
+    }
 
-        let mut grapheme = Self {
-            pixels: Vec::new(),
-            top: y,
-            bottom: y,
-            left: x,
-            right: x,
-            manual_override: None,
-        };
+    // Fails instead of panicking if the image has more than max_pixels pixels or its map can't be allocated, e.g. for
+    // batches where one bad page shouldn't stop the rest.
+    pub fn try_analyze<P: DocumentPixel>(
+        &self,
+        image: &DocumentImage<P>,
+    ) -> ImageResult<AnalyzedImage<P>> {
+        self.try_analyze_with_progress(image, |_| {})
+    }
 
-        let mut stack = Vec::new();
-        visited_map.set_visited(x, y, true);
-        stack.push((x, y));
+    // Calls progress with how far along the analysis is (from 0 to 1) after every row of each pass.
+    pub fn analyze_with_progress<P: DocumentPixel>(
+        &self,
+        image: &DocumentImage<P>,
+        progress: impl FnMut(f32),
+    ) -> AnalyzedImage<P> {
+        expect_analyzed(self.try_analyze_with_progress(image, progress))
+    }
 
-        while let Some((x, y)) = stack.pop() {
-            grapheme.pixels.push((x, y, *image.get_pixel(x, y)));
+    pub fn try_analyze_with_progress<P: DocumentPixel>(
+        &self,
+        image: &DocumentImage<P>,
+        progress: impl FnMut(f32),
+    ) -> ImageResult<AnalyzedImage<P>> {
+        let mut analyzed_image = AnalyzedImage::default();
+        self.analyze_with(
+            image,
+            None,
+            self.thresholds(),
+            &mut analyzed_image,
+            progress,
+            None,
+        )
+        .map_err(AnalyzeError::into_image_error)?;
+        Ok(analyzed_image)
+    }
 
-            if x < grapheme.left {
-                grapheme.left = x;
-            }
-            if x > grapheme.right {
-                grapheme.right = x;
-            }
-            if y < grapheme.top {
-                grapheme.top = y;
-            }
-            if y > grapheme.bottom {
-                grapheme.bottom = y;
+    // Stops and returns Err(Cancelled) soon after cancel_token is cancelled.
+    pub fn analyze_cancellable<P: DocumentPixel>(
+        &self,
+        image: &DocumentImage<P>,
+        cancel_token: &CancelToken,
+    ) -> Result<AnalyzedImage<P>, Cancelled> {
+        expect_analyzed(self.try_analyze_cancellable(image, cancel_token)).ok_or(Cancelled)
+    }
+
+    // Ok(None) if it was cancelled.
+    pub fn try_analyze_cancellable<P: DocumentPixel>(
+        &self,
+        image: &DocumentImage<P>,
+        cancel_token: &CancelToken,
+    ) -> ImageResult<Option<AnalyzedImage<P>>> {
+        let mut analyzed_image = AnalyzedImage::default();
+        let result = self.analyze_with(
+            image,
+            None,
+            self.thresholds(),
+            &mut analyzed_image,
+            |_| {},
+            Some(cancel_token),
+        );
+        Ok(cancelled_to_none(result)?.map(|()| analyzed_image))
+    }
+
+    // Analyzes into analyzed_image, reusing its allocations instead of making new ones when it's already the size of
+    // image, e.g. to analyze the same page over and over as the parameters change.
+    pub fn analyze_into<P: DocumentPixel>(
+        &self,
+        image: &DocumentImage<P>,
+        analyzed_image: &mut AnalyzedImage<P>,
+    ) {
+        expect_analyzed(
+            self.analyze_with(image, None, self.thresholds(), analyzed_image, |_| {}, None)
+                .map_err(AnalyzeError::into_image_error),
+        )
+    }
+
+    // Analyzes with thresholds in place of the analyzer's own, for setting them more finely on 16 bit images.
+    pub fn analyze_with_thresholds<P: DocumentPixel>(
+        &self,
+        image: &DocumentImage<P>,
+        thresholds: Thresholds<P::Subpixel>,
+    ) -> AnalyzedImage<P> {
+        let mut analyzed_image = AnalyzedImage::default();
+        expect_analyzed(
+            self.analyze_with(image, None, thresholds, &mut analyzed_image, |_| {}, None)
+                .map_err(AnalyzeError::into_image_error),
+        );
+        analyzed_image
+    }
+
+    // Only looks for graphemes inside region (in image coordinates), treating the rest of the image as background, e.g.
+    // to skip the dark borders around a scanned page. The analyzed image still covers the whole image.
+    pub fn analyze_region<P: DocumentPixel>(
+        &self,
+        image: &DocumentImage<P>,
+        region: math::Rect,
+    ) -> AnalyzedImage<P> {
+        let mut analyzed_image = AnalyzedImage::default();
+        expect_analyzed(
+            self.analyze_with(
+                image,
+                Some(region),
+                self.thresholds(),
+                &mut analyzed_image,
+                |_| {},
+                None,
+            )
+            .map_err(AnalyzeError::into_image_error),
+        );
+        analyzed_image
+    }
+
+    // The analyzer's thresholds mapped onto the range of S, e.g. thresholds::<u16>() for 16 bit images.
+    pub fn thresholds<S: Sample>(&self) -> Thresholds<S> {
+        Thresholds {
+            off_white_threshold: S::from_u8(self.off_white_threshold),
+            off_white_threshold_rgb: self.off_white_threshold_rgb.map(|t| t.map(S::from_u8)),
+            lightness_threshold: S::from_u8(self.lightness_threshold),
+        }
+    }
+
+    // Analyzes the whole image if region is None. analyzed_image is reset first, so cancelling leaves it empty.
+    fn analyze_with<P: DocumentPixel>(
+        &self,
+        image: &DocumentImage<P>,
+        region: Option<math::Rect>,
+        thresholds: Thresholds<P::Subpixel>,
+        analyzed_image: &mut AnalyzedImage<P>,
+        mut progress: impl FnMut(f32),
+        cancel_token: Option<&CancelToken>,
+    ) -> Result<(), AnalyzeError> {
+        let is_cancelled = || cancel_token.is_some_and(CancelToken::is_cancelled);
+
+        // The graphemes keep the flattened pixels, so the cleaned image comes out flattened too.
+        let flattened;
+        let image = match self.auto_flatten {
+            Some(block_size) => {
+                flattened = normalize_background(image, block_size);
+                &flattened
             }
+            None => image,
+        };
 
-            for neighbor in NEIGHBORS {
-                let (x, y) = (
-                    (x as i32 + neighbor.0) as u32,
-                    (y as i32 + neighbor.1) as u32,
-                );
+        // The image itself is already in memory, and it takes at least a quarter of what the map does.
+        analyzed_image
+            .reset(image.width(), image.height(), self.max_pixels)
+            .map_err(AnalyzeError::Image)?;
+        let mut visited_map = std::mem::take(&mut analyzed_image.visited_map);
+        // Clamped to the image, an empty region (or one entirely outside the image) leaves no graphemes.
+        let region = region.unwrap_or(math::Rect {
+            x: 0,
+            y: 0,
+            width: image.width(),
+            height: image.height(),
+        });
+        let x = region.x.min(image.width());
+        let y = region.y.min(image.height());
+        let region = math::Rect {
+            x,
+            y,
+            width: region.width.min(image.width() - x),
+            height: region.height.min(image.height() - y),
+        };
+        self.mark_background(
+            image,
+            region,
+            thresholds,
+            &mut visited_map,
+            &mut progress,
+            &is_cancelled,
+        )?;
 
-                if x >= image.width() || y >= image.height() {
-                    continue;
-                }
+        let graphemes = self.detect_graphemes(image, &visited_map, &mut progress, &is_cancelled)?;
+        analyzed_image.visited_map = visited_map;
+        self.store_graphemes(graphemes, analyzed_image);
 
-                if visited_map.is_visited(x, y) {
-                    continue;
-                }
+        Ok(())
+    }
+
+    // Marks every pixel of image that's background (including everything outside region) as visited, then closes the
+    // gaps between the rest.
+    fn mark_background<P: DocumentPixel>(
+        &self,
+        image: &DocumentImage<P>,
+        region: math::Rect,
+        thresholds: Thresholds<P::Subpixel>,
+        visited_map: &mut VisitedMap,
+        progress: &mut impl FnMut(f32),
+        is_cancelled: &(impl Fn() -> bool + Sync),
+    ) -> Result<(), Cancelled> {
+        // Building the maps takes a while on big images, so don't start on them if there's no need.
+        if is_cancelled() {
+            return Err(Cancelled);
+        }
+        let background_test = BackgroundTest {
+            thresholds,
+            region,
+            integral_image: matches!(self.threshold_mode, ThresholdMode::Sauvola { .. })
+                .then(|| IntegralImage::new(image, |p| self.value(p))),
+            darkest_map: DarkestMap::new(image, self.lightness_distance, |p| self.value(p)),
+        };
 
-                visited_map.set_visited(x, y, true);
-                stack.push((x, y));
+        self.whiten(image, &background_test, visited_map, progress, is_cancelled)?;
+
+        if self.morph_close > 0 {
+            if is_cancelled() {
+                return Err(Cancelled);
             }
+            visited_map.close(self.morph_close);
         }
 
-        grapheme
+        Ok(())
     }
 
-    fn _average_value(&self) -> u8 {
-        let mut total: u32 = 0;
-        for (_, _, v) in self.pixels.iter() {
-            total += pixel_value(*v) as u32;
+    // Merges the graphemes (in the order their first pixels appear) if they're near enough, and adds them to
+    // analyzed_image, whose map has to be empty.
+    fn store_graphemes<P>(
+        &self,
+        graphemes: Vec<Grapheme<P>>,
+        analyzed_image: &mut AnalyzedImage<P>,
+    ) {
+        let graphemes = self.merge_nearby(graphemes);
+        for grapheme in graphemes {
+            let id = analyzed_image.graphemes.len() as u32;
+            let width = analyzed_image.width;
+            for &(y, start, end) in &grapheme.runs {
+                analyzed_image.map[index(width, start, y)..index(width, end, y)].fill(id);
+            }
+            analyzed_image.graphemes.push(grapheme);
         }
 
-        (total / self.pixels.len() as u32) as u8
+        // Perimeters can only be measured now that every pixel's grapheme is known, strips and merging included.
+        let AnalyzedImage {
+            graphemes,
+            map,
+            width,
+            height,
+            ..
+        } = analyzed_image;
+        for (i, grapheme) in graphemes.iter_mut().enumerate() {
+            grapheme.perimeter = grapheme
+                .positions()
+                .map(|(x, y)| {
+                    let neighbors = [
+                        (x > 0).then(|| (x - 1, y)),
+                        (x + 1 < *width).then(|| (x + 1, y)),
+                        (y > 0).then(|| (x, y - 1)),
+                        (y + 1 < *height).then(|| (x, y + 1)),
+                    ];
+                    neighbors
+                        .into_iter()
+                        .filter(|neighbor| {
+                            neighbor.is_none_or(|(x, y)| map[index(*width, x, y)] != i as u32)
+                        })
+                        .count()
+                })
+                .sum();
+        }
+        analyzed_image.rebuild_spatial_index();
     }
 
-    fn fill(&self, image: &mut RgbImage, color: Rgb<u8>) {
-        for (x, y, _) in &self.pixels {
-            image.put_pixel(*x, *y, color);
+    fn merge_nearby<P>(&self, graphemes: Vec<Grapheme<P>>) -> Vec<Grapheme<P>> {
+        let distance = self.merge_distance;
+        if distance == 0 {
+            return graphemes;
         }
+
+        let mut parents = union_nearby(&graphemes, distance);
+        merge_sets(graphemes, &mut parents)
     }
 
-    fn draw(&self, image: &mut RgbImage) {
-        for (x, y, c) in &self.pixels {
-            image.put_pixel(*x, *y, *c);
+    // Marks every background pixel as visited, so only the graphemes are left.
+    #[cfg(not(feature = "parallel"))]
+    fn whiten<P: DocumentPixel>(
+        &self,
+        image: &DocumentImage<P>,
+        background_test: &BackgroundTest<P::Subpixel>,
+        visited_map: &mut VisitedMap,
+        progress: &mut impl FnMut(f32),
+        is_cancelled: &(impl Fn() -> bool + Sync),
+    ) -> Result<(), Cancelled> {
+        for (y, row) in image.enumerate_rows() {
+            if is_cancelled() {
+                return Err(Cancelled);
+            }
+
+            for (x, _, _) in row {
+                if self.is_background(x, y, image, background_test) {
+                    visited_map.set_visited(x, y, true);
+                }
+            }
+            progress((y + 1) as f32 / image.height() as f32 / 2.0);
         }
+
+        Ok(())
     }
-}
 
-fn positive_difference(a: u32, b: u32) -> u32 {
-    if a >= b {
-        a - b
-    } else {
-        b - a
+    // Groups every pixel that hasn't been visited yet into graphemes, in the order their first pixel appears.
+    #[cfg(not(feature = "parallel"))]
+    fn detect_graphemes<P: DocumentPixel>(
+        &self,
+        image: &DocumentImage<P>,
+        visited_map: &VisitedMap,
+        progress: &mut impl FnMut(f32),
+        is_cancelled: &(impl Fn() -> bool + Sync),
+    ) -> Result<Vec<Grapheme<P>>, Cancelled> {
+        detect_rows(
+            image,
+            visited_map,
+            0..image.height(),
+            self.connectivity,
+            |p| self.value(p),
+            |y| {
+                if is_cancelled() {
+                    return Err(Cancelled);
+                }
+                progress(0.5 + y as f32 / image.height() as f32 / 2.0);
+                Ok(())
+            },
+        )
     }
-}
 
-fn darkest_pixel_within(x: u32, y: u32, distance: u32, image: &RgbImage) -> u8 {
-    //for pixel in image.view(x - distance, y - distance, distance * 2, distance * 2);
-    let mut darkest: u8 = 255;
-    for y in (y - distance).max(0)..=(y + distance).min(image.height() - 1) {
-        for x in (x - distance).max(0)..=(x + distance).min(image.width() - 1) {
-            let pixel = pixel_value(*image.get_pixel(x, y));
-            if pixel < darkest {
-                darkest = pixel;
-            }
+    fn is_background<P: DocumentPixel>(
+        &self,
+        x: u32,
+        y: u32,
+        image: &DocumentImage<P>,
+        background_test: &BackgroundTest<P::Subpixel>,
+    ) -> bool {
+        let thresholds = &background_test.thresholds;
+        let region = &background_test.region;
+        if x < region.x
+            || x - region.x >= region.width
+            || y < region.y
+            || y - region.y >= region.height
+        {
+            return true;
+        }
+
+        let pixel = image.get_pixel(x, y);
+        if pixel.is_transparent() {
+            return true;
         }
+        let pixel = pixel.composite(self.transparent_background_color);
+        let value = self.orient(pixel.value(self.luminance_mode));
+
+        // If the pixel isn't very dark and it's not next to other really dark pixels (like letter borders), fill it.
+        let offwhite = match (self.threshold_mode, &background_test.integral_image) {
+            (ThresholdMode::Sauvola { window, k }, Some(integral_image)) => {
+                let (mean, deviation) = integral_image.window_statistics(x, y, window / 2);
+                // The dynamic range of the standard deviation, 128 for 8 bit values.
+                let range = (P::Subpixel::DEFAULT_MAX_VALUE.into() + 1) as f32 / 2.0;
+                value.into() as f32 >= mean * (1.0 + k * (deviation / range - 1.0))
+            }
+            _ => match thresholds.off_white_threshold_rgb {
+                Some(channel_thresholds) => pixel
+                    .rgb()
+                    .into_iter()
+                    .zip(channel_thresholds)
+                    .all(|(c, t)| self.orient(c) >= t),
+                None => value >= thresholds.off_white_threshold,
+            },
+        };
+        let too_light_and_distant = value >= thresholds.lightness_threshold
+            && background_test.darkest_map.get(x, y) >= thresholds.lightness_threshold;
+
+        offwhite || too_light_and_distant
+    }
+
+    fn value<P: DocumentPixel>(&self, pixel: &P) -> P::Subpixel {
+        self.orient(
+            pixel
+                .composite(self.transparent_background_color)
+                .value(self.luminance_mode),
+        )
     }
 
-    darkest
+    // Turns a channel or value around when inverting, so the rest of the analysis can always treat light as
+    // background.
+    fn orient<S: Sample>(&self, value: S) -> S {
+        if self.invert {
+            S::DEFAULT_MAX_VALUE - value
+        } else {
+            value
+        }
+    }
 }
 
-fn pixel_value(pixel: Rgb<u8>) -> u8 {
-    ((pixel[0] as u32 + pixel[1] as u32 + pixel[2] as u32) / 3) as u8
+impl ImageAnalyzer {
+    // Analyzes any image, as grayscale, RGB, or RGBA depending on which channels it has, with 8 or 16 bits per channel.
+    // Anything else is converted to the closest of those first.
+    pub fn analyze_dynamic(&self, image: &DynamicImage) -> DynamicAnalyzedImage {
+        expect_analyzed(self.try_analyze_dynamic(image))
+    }
+
+    pub fn try_analyze_dynamic(&self, image: &DynamicImage) -> ImageResult<DynamicAnalyzedImage> {
+        self.try_analyze_dynamic_with_progress(image, |_| {})
+    }
+
+    pub fn analyze_dynamic_with_progress(
+        &self,
+        image: &DynamicImage,
+        progress: impl FnMut(f32),
+    ) -> DynamicAnalyzedImage {
+        expect_analyzed(self.try_analyze_dynamic_with_progress(image, progress))
+    }
+
+    pub fn try_analyze_dynamic_with_progress(
+        &self,
+        image: &DynamicImage,
+        progress: impl FnMut(f32),
+    ) -> ImageResult<DynamicAnalyzedImage> {
+        let mut analyzed_image = DynamicAnalyzedImage::default();
+        self.analyze_dynamic_with(image, &mut analyzed_image, progress, None)
+            .map_err(AnalyzeError::into_image_error)?;
+        Ok(analyzed_image)
+    }
+
+    pub fn analyze_dynamic_cancellable(
+        &self,
+        image: &DynamicImage,
+        cancel_token: &CancelToken,
+    ) -> Result<DynamicAnalyzedImage, Cancelled> {
+        expect_analyzed(self.try_analyze_dynamic_cancellable(image, cancel_token)).ok_or(Cancelled)
+    }
+
+    // Ok(None) if it was cancelled.
+    pub fn try_analyze_dynamic_cancellable(
+        &self,
+        image: &DynamicImage,
+        cancel_token: &CancelToken,
+    ) -> ImageResult<Option<DynamicAnalyzedImage>> {
+        let mut analyzed_image = DynamicAnalyzedImage::default();
+        let result =
+            self.analyze_dynamic_with(image, &mut analyzed_image, |_| {}, Some(cancel_token));
+        Ok(cancelled_to_none(result)?.map(|()| analyzed_image))
+    }
+
+    // Like analyze_into, the allocations are reused when analyzed_image was analyzed from an image of the same type and
+    // size.
+    pub fn analyze_dynamic_into_cancellable(
+        &self,
+        image: &DynamicImage,
+        analyzed_image: &mut DynamicAnalyzedImage,
+        cancel_token: &CancelToken,
+    ) -> Result<(), Cancelled> {
+        let result = self.analyze_dynamic_with(image, analyzed_image, |_| {}, Some(cancel_token));
+        expect_analyzed(cancelled_to_none(result)).ok_or(Cancelled)
+    }
+
+    fn analyze_dynamic_with(
+        &self,
+        image: &DynamicImage,
+        analyzed_image: &mut DynamicAnalyzedImage,
+        progress: impl FnMut(f32),
+        cancel_token: Option<&CancelToken>,
+    ) -> Result<(), AnalyzeError> {
+        use DynamicAnalyzedImage::*;
+
+        let converted;
+        let image = match image {
+            DynamicImage::ImageLuma8(_)
+            | DynamicImage::ImageRgb8(_)
+            | DynamicImage::ImageRgba8(_)
+            | DynamicImage::ImageLuma16(_)
+            | DynamicImage::ImageRgb16(_)
+            | DynamicImage::ImageRgba16(_) => image,
+            // Grayscale with alpha, and floating point images.
+            image if image.color().has_alpha() => {
+                converted = DynamicImage::ImageRgba16(image.to_rgba16());
+                &converted
+            }
+            image => {
+                converted = DynamicImage::ImageRgb16(image.to_rgb16());
+                &converted
+            }
+        };
+
+        let thresholds_8 = self.thresholds();
+        let thresholds_16 = self.thresholds();
+        match (image, &mut *analyzed_image) {
+            (DynamicImage::ImageLuma8(image), Luma8(analyzed_image)) => self.analyze_with(
+                image,
+                None,
+                thresholds_8,
+                analyzed_image,
+                progress,
+                cancel_token,
+            ),
+            (DynamicImage::ImageRgb8(image), Rgb8(analyzed_image)) => self.analyze_with(
+                image,
+                None,
+                thresholds_8,
+                analyzed_image,
+                progress,
+                cancel_token,
+            ),
+            (DynamicImage::ImageRgba8(image), Rgba8(analyzed_image)) => self.analyze_with(
+                image,
+                None,
+                thresholds_8,
+                analyzed_image,
+                progress,
+                cancel_token,
+            ),
+            (DynamicImage::ImageLuma16(image), Luma16(analyzed_image)) => self.analyze_with(
+                image,
+                None,
+                thresholds_16,
+                analyzed_image,
+                progress,
+                cancel_token,
+            ),
+            (DynamicImage::ImageRgb16(image), Rgb16(analyzed_image)) => self.analyze_with(
+                image,
+                None,
+                thresholds_16,
+                analyzed_image,
+                progress,
+                cancel_token,
+            ),
+            (DynamicImage::ImageRgba16(image), Rgba16(analyzed_image)) => self.analyze_with(
+                image,
+                None,
+                thresholds_16,
+                analyzed_image,
+                progress,
+                cancel_token,
+            ),
+            // Analyzed from another type of image before, so there's nothing to reuse.
+            (image, _) => {
+                *analyzed_image = match image {
+                    DynamicImage::ImageLuma8(_) => Luma8(AnalyzedImage::default()),
+                    DynamicImage::ImageRgba8(_) => Rgba8(AnalyzedImage::default()),
+                    DynamicImage::ImageLuma16(_) => Luma16(AnalyzedImage::default()),
+                    DynamicImage::ImageRgb16(_) => Rgb16(AnalyzedImage::default()),
+                    DynamicImage::ImageRgba16(_) => Rgba16(AnalyzedImage::default()),
+                    _ => Rgb8(AnalyzedImage::default()),
+                };
+                self.analyze_dynamic_with(image, analyzed_image, progress, cancel_token)
+            }
+        }
+    }
+}
+
+impl ImageAnalyzer {
+    // An analyzer with the thresholds suggested for the image, and every other parameter left at its default.
+    pub fn from_otsu<P: DocumentPixel<Subpixel = u8>>(image: &DocumentImage<P>) -> Self {
+        let analyzer = Self::default();
+        let suggested = analyzer.suggest_thresholds(image);
+        Self {
+            off_white_threshold: suggested.off_white_threshold,
+            lightness_threshold: suggested.lightness_threshold,
+            ..analyzer
+        }
+    }
+
+    // Uses Otsu's method to split the image's pixels into ink and paper. Anything above the split is too light to be
+    // ink (lightness), and anything lighter than halfway between the split and the average paper color is off-white.
+    pub fn suggest_thresholds<P: DocumentPixel<Subpixel = u8>>(
+        &self,
+        image: &DocumentImage<P>,
+    ) -> SuggestedThresholds {
+        let mut histogram = [0; 256];
+        for pixel in image.pixels().filter(|p| !p.is_transparent()) {
+            histogram[self.value(pixel) as usize] += 1;
+        }
+
+        let Some(split) = otsu_threshold(&histogram) else {
+            // Every pixel has the same value (or there are none), so there's nothing to split.
+            let default = Self::default();
+            return match histogram.iter().position(|&n| n > 0) {
+                // The whole page is ink, so keep all of it.
+                Some(value) if (value as u8) < default.lightness_threshold => SuggestedThresholds {
+                    off_white_threshold: value as u8 + 1,
+                    lightness_threshold: value as u8 + 1,
+                    histogram,
+                },
+                // A blank page.
+                _ => SuggestedThresholds {
+                    off_white_threshold: default.off_white_threshold,
+                    lightness_threshold: default.lightness_threshold,
+                    histogram,
+                },
+            };
+        };
+
+        let (mut count, mut sum) = (0u64, 0u64);
+        for (value, &n) in histogram.iter().enumerate().skip(split as usize + 1) {
+            count += n as u64;
+            sum += value as u64 * n as u64;
+        }
+        let paper_mean = (sum / count) as u8;
+
+        SuggestedThresholds {
+            off_white_threshold: split + (paper_mean - split).div_ceil(2),
+            lightness_threshold: split + 1,
+            histogram,
+        }
+    }
+}
+
+// How many graphemes are cleaned between progress updates.
+#[cfg(not(feature = "parallel"))]
+const PROGRESS_INTERVAL: usize = 256;
+
+impl ImageCleaner {
+    pub fn clean<P: DocumentPixel>(&self, analyzed_image: &AnalyzedImage<P>) -> DocumentImage<P> {
+        let mut image = ImageBuffer::default();
+        self.clean_into(analyzed_image, &mut image);
+        image
+    }
+
+    // Calls progress with how far along the cleaning is (from 0 to 1) every so many graphemes.
+    pub fn clean_with_progress<P: DocumentPixel>(
+        &self,
+        analyzed_image: &AnalyzedImage<P>,
+        progress: impl FnMut(f32),
+    ) -> DocumentImage<P> {
+        let mut image = ImageBuffer::default();
+        match self.clean_with(analyzed_image, None, &mut image, progress, None, None) {
+            Ok(()) => image,
+            Err(Cancelled) => unreachable!("there's no token to cancel it"),
+        }
+    }
+
+    // Cleans into image, reusing its allocation instead of making a new one when it's already the size of the analyzed
+    // image.
+    pub fn clean_into<P: DocumentPixel>(
+        &self,
+        analyzed_image: &AnalyzedImage<P>,
+        image: &mut DocumentImage<P>,
+    ) {
+        match self.clean_with(analyzed_image, None, image, |_| {}, None, None) {
+            Ok(()) => {}
+            Err(Cancelled) => unreachable!("there's no token to cancel it"),
+        }
+    }
+
+    // Stops and returns Err(Cancelled) soon after cancel_token is cancelled.
+    pub fn clean_cancellable<P: DocumentPixel>(
+        &self,
+        analyzed_image: &AnalyzedImage<P>,
+        cancel_token: &CancelToken,
+    ) -> Result<DocumentImage<P>, Cancelled> {
+        let mut image = ImageBuffer::default();
+        self.clean_with(
+            analyzed_image,
+            None,
+            &mut image,
+            |_| {},
+            Some(cancel_token),
+            None,
+        )?;
+        Ok(image)
+    }
+
+    // Also gives back what was done with every grapheme and why, see CleanReport.
+    pub fn clean_with_report<P: DocumentPixel>(
+        &self,
+        analyzed_image: &AnalyzedImage<P>,
+    ) -> (DocumentImage<P>, CleanReport) {
+        let mut image = ImageBuffer::default();
+        let mut report = CleanReport::default();
+        match self.clean_with(
+            analyzed_image,
+            None,
+            &mut image,
+            |_| {},
+            None,
+            Some(&mut report),
+        ) {
+            Ok(()) => (image, report),
+            Err(Cancelled) => unreachable!("there's no token to cancel it"),
+        }
+    }
+
+    // Starts from a copy of original, the image that was analyzed, and only fills in the graphemes that get removed, so
+    // the paper's texture and tone and the edges around the letters are left as they were scanned.
+    pub fn clean_over<P: DocumentPixel>(
+        &self,
+        analyzed_image: &AnalyzedImage<P>,
+        original: &DocumentImage<P>,
+    ) -> ImageResult<DocumentImage<P>> {
+        check_dimensions(
+            analyzed_image.width,
+            analyzed_image.height,
+            original.dimensions(),
+        )?;
+        let mut image = ImageBuffer::default();
+        match self.clean_with(
+            analyzed_image,
+            Some(original),
+            &mut image,
+            |_| {},
+            None,
+            None,
+        ) {
+            Ok(()) => Ok(image),
+            Err(Cancelled) => unreachable!("there's no token to cancel it"),
+        }
+    }
+
+    // Cleans like clean_over if preserve_original is set, otherwise like clean. source is the image that was analyzed.
+    pub fn clean_onto<P: DocumentPixel>(
+        &self,
+        analyzed_image: &AnalyzedImage<P>,
+        source: &DocumentImage<P>,
+    ) -> ImageResult<DocumentImage<P>> {
+        check_dimensions(
+            analyzed_image.width,
+            analyzed_image.height,
+            source.dimensions(),
+        )?;
+        if self.preserve_original {
+            self.clean_over(analyzed_image, source)
+        } else {
+            Ok(self.clean(analyzed_image))
+        }
+    }
+
+    // Cleans into 8 bit RGBA, with the kept graphemes fully opaque and everything else rgba_fill_color (transparent by
+    // default), for laying the page over a background in another program.
+    pub fn clean_rgba<P: DocumentPixel>(&self, analyzed_image: &AnalyzedImage<P>) -> RgbaImage {
+        let fill_color = Rgba(self.rgba_fill_color);
+        let mut image =
+            RgbaImage::from_pixel(analyzed_image.width, analyzed_image.height, fill_color);
+        let decisions = self.classify(analyzed_image);
+        for (i, (grapheme, decision)) in analyzed_image.graphemes.iter().zip(decisions).enumerate()
+        {
+            if !decision.is_removed() {
+                self.draw(i, analyzed_image, &mut RgbaCanvas(&mut image));
+            } else if self.fill_grapheme_holes {
+                // The removed grapheme itself is already the fill color, but its holes might have been drawn in.
+                for (x, y) in grapheme.holes().into_iter().flatten() {
+                    image.put_pixel(x, y, fill_color);
+                }
+            }
+        }
+
+        image
+    }
+
+    pub fn clean_dynamic_rgba(&self, analyzed_image: &DynamicAnalyzedImage) -> RgbaImage {
+        match analyzed_image {
+            DynamicAnalyzedImage::Luma8(analyzed_image) => self.clean_rgba(analyzed_image),
+            DynamicAnalyzedImage::Rgb8(analyzed_image) => self.clean_rgba(analyzed_image),
+            DynamicAnalyzedImage::Rgba8(analyzed_image) => self.clean_rgba(analyzed_image),
+            DynamicAnalyzedImage::Luma16(analyzed_image) => self.clean_rgba(analyzed_image),
+            DynamicAnalyzedImage::Rgb16(analyzed_image) => self.clean_rgba(analyzed_image),
+            DynamicAnalyzedImage::Rgba16(analyzed_image) => self.clean_rgba(analyzed_image),
+        }
+    }
+
+    pub fn clean_dynamic(&self, analyzed_image: &DynamicAnalyzedImage) -> DynamicImage {
+        self.clean_dynamic_with_progress(analyzed_image, |_| {})
+    }
+
+    pub fn clean_dynamic_onto(
+        &self,
+        analyzed_image: &DynamicAnalyzedImage,
+        source: &DynamicImage,
+    ) -> ImageResult<DynamicImage> {
+        self.clean_dynamic_onto_with_report(analyzed_image, source)
+            .map(|(image, _)| image)
+    }
+
+    pub fn clean_dynamic_onto_with_report(
+        &self,
+        analyzed_image: &DynamicAnalyzedImage,
+        source: &DynamicImage,
+    ) -> ImageResult<(DynamicImage, CleanReport)> {
+        let (width, height) = analyzed_image.dimensions();
+        check_dimensions(width, height, source.dimensions())?;
+        let source = self.preserve_original.then_some(source);
+        let mut image = DynamicImage::default();
+        let mut report = CleanReport::default();
+        match self.clean_dynamic_with(
+            analyzed_image,
+            source,
+            &mut image,
+            |_| {},
+            None,
+            Some(&mut report),
+        ) {
+            Ok(()) => Ok((image, report)),
+            Err(Cancelled) => unreachable!("there's no token to cancel it"),
+        }
+    }
+
+    pub fn clean_dynamic_with_progress(
+        &self,
+        analyzed_image: &DynamicAnalyzedImage,
+        progress: impl FnMut(f32),
+    ) -> DynamicImage {
+        let mut image = DynamicImage::default();
+        match self.clean_dynamic_with(analyzed_image, None, &mut image, progress, None, None) {
+            Ok(()) => image,
+            Err(Cancelled) => unreachable!("there's no token to cancel it"),
+        }
+    }
+
+    pub fn clean_dynamic_cancellable(
+        &self,
+        analyzed_image: &DynamicAnalyzedImage,
+        cancel_token: &CancelToken,
+    ) -> Result<DynamicImage, Cancelled> {
+        let mut image = DynamicImage::default();
+        self.clean_dynamic_with(
+            analyzed_image,
+            None,
+            &mut image,
+            |_| {},
+            Some(cancel_token),
+            None,
+        )?;
+        Ok(image)
+    }
+
+    pub fn clean_dynamic_with_report(
+        &self,
+        analyzed_image: &DynamicAnalyzedImage,
+    ) -> (DynamicImage, CleanReport) {
+        let mut image = DynamicImage::default();
+        let mut report = CleanReport::default();
+        match self.clean_dynamic_with(
+            analyzed_image,
+            None,
+            &mut image,
+            |_| {},
+            None,
+            Some(&mut report),
+        ) {
+            Ok(()) => (image, report),
+            Err(Cancelled) => unreachable!("there's no token to cancel it"),
+        }
+    }
+
+    // Like clean_into, the allocation is reused when image is already the same type and size as the cleaned image.
+    pub fn clean_dynamic_into_cancellable(
+        &self,
+        analyzed_image: &DynamicAnalyzedImage,
+        image: &mut DynamicImage,
+        cancel_token: &CancelToken,
+    ) -> Result<(), Cancelled> {
+        self.clean_dynamic_with(
+            analyzed_image,
+            None,
+            image,
+            |_| {},
+            Some(cancel_token),
+            None,
+        )
+    }
+
+    // Like clean_dynamic_into_cancellable, but also fills report in with what was done with every grapheme.
+    pub fn clean_dynamic_into_cancellable_with_report(
+        &self,
+        analyzed_image: &DynamicAnalyzedImage,
+        image: &mut DynamicImage,
+        report: &mut CleanReport,
+        cancel_token: &CancelToken,
+    ) -> Result<(), Cancelled> {
+        self.clean_dynamic_with(
+            analyzed_image,
+            None,
+            image,
+            |_| {},
+            Some(cancel_token),
+            Some(report),
+        )
+    }
+
+    fn clean_dynamic_with(
+        &self,
+        analyzed_image: &DynamicAnalyzedImage,
+        source: Option<&DynamicImage>,
+        image: &mut DynamicImage,
+        progress: impl FnMut(f32),
+        cancel_token: Option<&CancelToken>,
+        report: Option<&mut CleanReport>,
+    ) -> Result<(), Cancelled> {
+        // The source needs to be the same type as the analyzed image to be copied into the cleaned one.
+        let source = source.map(|source| same_type(analyzed_image, source));
+        match (analyzed_image, &mut *image) {
+            (DynamicAnalyzedImage::Luma8(analyzed_image), DynamicImage::ImageLuma8(image)) => {
+                let source = source.as_deref().and_then(DynamicImage::as_luma8);
+                self.clean_with(
+                    analyzed_image,
+                    source,
+                    image,
+                    progress,
+                    cancel_token,
+                    report,
+                )
+            }
+            (DynamicAnalyzedImage::Rgb8(analyzed_image), DynamicImage::ImageRgb8(image)) => {
+                let source = source.as_deref().and_then(DynamicImage::as_rgb8);
+                self.clean_with(
+                    analyzed_image,
+                    source,
+                    image,
+                    progress,
+                    cancel_token,
+                    report,
+                )
+            }
+            (DynamicAnalyzedImage::Rgba8(analyzed_image), DynamicImage::ImageRgba8(image)) => {
+                let source = source.as_deref().and_then(DynamicImage::as_rgba8);
+                self.clean_with(
+                    analyzed_image,
+                    source,
+                    image,
+                    progress,
+                    cancel_token,
+                    report,
+                )
+            }
+            (DynamicAnalyzedImage::Luma16(analyzed_image), DynamicImage::ImageLuma16(image)) => {
+                let source = source.as_deref().and_then(DynamicImage::as_luma16);
+                self.clean_with(
+                    analyzed_image,
+                    source,
+                    image,
+                    progress,
+                    cancel_token,
+                    report,
+                )
+            }
+            (DynamicAnalyzedImage::Rgb16(analyzed_image), DynamicImage::ImageRgb16(image)) => {
+                let source = source.as_deref().and_then(DynamicImage::as_rgb16);
+                self.clean_with(
+                    analyzed_image,
+                    source,
+                    image,
+                    progress,
+                    cancel_token,
+                    report,
+                )
+            }
+            (DynamicAnalyzedImage::Rgba16(analyzed_image), DynamicImage::ImageRgba16(image)) => {
+                let source = source.as_deref().and_then(DynamicImage::as_rgba16);
+                self.clean_with(
+                    analyzed_image,
+                    source,
+                    image,
+                    progress,
+                    cancel_token,
+                    report,
+                )
+            }
+            // A different type of image, so there's nothing to reuse.
+            (analyzed_image, _) => {
+                *image = match analyzed_image {
+                    DynamicAnalyzedImage::Luma8(_) => {
+                        DynamicImage::ImageLuma8(ImageBuffer::default())
+                    }
+                    DynamicAnalyzedImage::Rgb8(_) => {
+                        DynamicImage::ImageRgb8(ImageBuffer::default())
+                    }
+                    DynamicAnalyzedImage::Rgba8(_) => {
+                        DynamicImage::ImageRgba8(ImageBuffer::default())
+                    }
+                    DynamicAnalyzedImage::Luma16(_) => {
+                        DynamicImage::ImageLuma16(ImageBuffer::default())
+                    }
+                    DynamicAnalyzedImage::Rgb16(_) => {
+                        DynamicImage::ImageRgb16(ImageBuffer::default())
+                    }
+                    DynamicAnalyzedImage::Rgba16(_) => {
+                        DynamicImage::ImageRgba16(ImageBuffer::default())
+                    }
+                };
+                let source = source.as_deref();
+                self.clean_dynamic_with(
+                    analyzed_image,
+                    source,
+                    image,
+                    progress,
+                    cancel_token,
+                    report,
+                )
+            }
+        }
+    }
+
+    // With a source, which has to be the size of the analyzed image, it starts from a copy of it and only the removed
+    // graphemes are filled in.
+    fn clean_with<P: DocumentPixel>(
+        &self,
+        analyzed_image: &AnalyzedImage<P>,
+        source: Option<&DocumentImage<P>>,
+        new_image: &mut DocumentImage<P>,
+        mut progress: impl FnMut(f32),
+        cancel_token: Option<&CancelToken>,
+        mut report: Option<&mut CleanReport>,
+    ) -> Result<(), Cancelled> {
+        if let Some(report) = report.as_deref_mut() {
+            report.clear();
+        }
+        let background_fill_color = P::from_rgb(self.background_fill_color);
+        if let Some(source) = source {
+            new_image.clone_from(source);
+        } else {
+            if new_image.dimensions() != (analyzed_image.width, analyzed_image.height) {
+                *new_image = ImageBuffer::new(analyzed_image.width, analyzed_image.height);
+            }
+            for pixel in new_image.pixels_mut() {
+                *pixel = background_fill_color;
+            }
+        }
+
+        let decisions = self.classify(analyzed_image);
+        if let Some(report) = report {
+            for (grapheme, &decision) in analyzed_image.graphemes.iter().zip(&decisions) {
+                report.record(decision, grapheme.pixel_count());
+            }
+        }
+        self.render(
+            analyzed_image,
+            source,
+            new_image,
+            &decisions,
+            &mut progress,
+            cancel_token,
+        )?;
+        progress(1.0);
+
+        Ok(())
+    }
+
+    // Draws or fills every grapheme in order, as decided.
+    #[cfg(not(feature = "parallel"))]
+    fn render<P: DocumentPixel>(
+        &self,
+        analyzed_image: &AnalyzedImage<P>,
+        source: Option<&DocumentImage<P>>,
+        image: &mut DocumentImage<P>,
+        decisions: &[Decision],
+        progress: &mut impl FnMut(f32),
+        cancel_token: Option<&CancelToken>,
+    ) -> Result<(), Cancelled> {
+        for (i, &decision) in decisions.iter().enumerate() {
+            if cancel_token.is_some_and(CancelToken::is_cancelled) {
+                return Err(Cancelled);
+            }
+            if i % PROGRESS_INTERVAL == 0 {
+                progress(i as f32 / decisions.len() as f32);
+            }
+
+            self.render_grapheme(i, decision, analyzed_image, source, image);
+        }
+
+        Ok(())
+    }
+
+    // Only ever paints inside the grapheme's bounding box.
+    fn render_grapheme<P: DocumentPixel>(
+        &self,
+        grapheme_index: usize,
+        decision: Decision,
+        analyzed_image: &AnalyzedImage<P>,
+        source: Option<&DocumentImage<P>>,
+        image: &mut impl Canvas<P>,
+    ) {
+        let grapheme = &analyzed_image.graphemes[grapheme_index];
+        if decision.is_removed() {
+            let speck_fill_color = P::from_rgb(self.speck_fill_color);
+            let color = match (self.speck_fill_style, source) {
+                (FillStyle::Solid, _) => speck_fill_color,
+                (FillStyle::SampledBackground { radius }, Some(source)) => {
+                    sample_background(grapheme, analyzed_image, source, radius)
+                        .unwrap_or(speck_fill_color)
+                }
+                (FillStyle::SampledBackground { .. }, None) => {
+                    P::from_rgb(self.background_fill_color)
+                }
+            };
+            grapheme.fill(image, color);
+            if self.fill_grapheme_holes {
+                grapheme.paint_holes(image, color);
+            }
+        } else if source.is_none() {
+            self.draw(grapheme_index, analyzed_image, image);
+        }
+    }
+
+    // What clean does with each grapheme (by index, like GraphemeId), without drawing anything.
+    pub fn classify<P: DocumentPixel>(&self, analyzed_image: &AnalyzedImage<P>) -> Vec<Decision> {
+        let neighbors = self.build_neighbors(&analyzed_image.graphemes);
+        self.decide_all(analyzed_image, &neighbors)
+    }
+
+    #[cfg(not(feature = "parallel"))]
+    fn decide_all<P: DocumentPixel>(
+        &self,
+        analyzed_image: &AnalyzedImage<P>,
+        neighbors: &Neighbors,
+    ) -> Vec<Decision> {
+        (0..analyzed_image.graphemes.len())
+            .map(|i| self.decide(i, analyzed_image, neighbors))
+            .collect()
+    }
+
+    // What clean does with the grapheme, whether it's drawn or it's a speck/smudge that gets filled in.
+    fn decide<P: DocumentPixel>(
+        &self,
+        grapheme_index: usize,
+        analyzed_image: &AnalyzedImage<P>,
+        neighbors: &Neighbors,
+    ) -> Decision {
+        let grapheme = &analyzed_image.graphemes[grapheme_index];
+        // A manual override always decides, otherwise the grapheme is filled by the first of the rules below that
+        // catches it.
+        match grapheme.manual_override {
+            Some(true) => return Decision::ManualKeep,
+            Some(false) => return Decision::ManualRemove,
+            None => {}
+        }
+        if self.is_protected(grapheme) {
+            return Decision::Protected;
+        }
+        if self.is_in_removal_region(grapheme) {
+            return Decision::RemovedRegion;
+        }
+
+        let margins = self.margins_for(analyzed_image.width, analyzed_image.height);
+        // Which of the margins the grapheme reaches into.
+        let in_margins = Margins {
+            left: grapheme.left < margins.left,
+            right: grapheme.right >= analyzed_image.width.saturating_sub(margins.right),
+            top: grapheme.top < margins.top,
+            bottom: grapheme.bottom >= analyzed_image.height.saturating_sub(margins.bottom),
+        };
+        let inside_margins =
+            in_margins.left || in_margins.right || in_margins.top || in_margins.bottom;
+        let touches_border = self.remove_border_touching
+            && (grapheme.top == 0
+                || grapheme.left == 0
+                || grapheme.bottom == analyzed_image.height - 1
+                || grapheme.right == analyzed_image.width - 1);
+
+        if grapheme.size(self.size_metric) <= self.speck_size_threshold as u64
+            && !self.is_punctuation(grapheme_index, &analyzed_image.graphemes, neighbors)
+        {
+            return Decision::RemovedSpeck;
+        }
+        // Graphemes big enough to keep in the margins are only exempt from the margins, the rest of the rules still
+        // apply to them.
+        if inside_margins && !self.is_kept_in_margins(grapheme, in_margins) {
+            return Decision::RemovedMargin;
+        }
+
+        if touches_border {
+            Decision::RemovedBorder
+        } else if self.max_grapheme_area_fraction.is_some_and(|max| {
+            grapheme.pixel_count() as f64
+                > max as f64 * analyzed_image.width as f64 * analyzed_image.height as f64
+        }) {
+            Decision::RemovedLarge
+        } else if self.is_isolated(grapheme_index, &analyzed_image.graphemes, neighbors) {
+            Decision::RemovedIsolated
+        } else if self
+            .max_average_value
+            .is_some_and(|max| grapheme.average_value() > Sample::from_u8(max))
+        {
+            Decision::RemovedFaint
+        } else if self.fill_ratio_applies(grapheme)
+            && self
+                .max_fill_ratio
+                .is_some_and(|max| grapheme.density() > max)
+        {
+            Decision::RemovedDense
+        } else if self.fill_ratio_applies(grapheme)
+            && self
+                .min_fill_ratio
+                .is_some_and(|min| grapheme.density() < min)
+        {
+            Decision::RemovedSparse
+        } else if self.is_streak(grapheme) {
+            Decision::RemovedLong
+        } else if inside_margins {
+            Decision::KeptMargin
+        } else {
+            Decision::Kept
+        }
+    }
+
+    fn is_kept_in_margins<P>(&self, grapheme: &Grapheme<P>, in_margins: Margins<bool>) -> bool {
+        let sides = self.margin_keep_sides;
+        self.margin_keep_size
+            .is_some_and(|size| grapheme.size(self.size_metric) > size as u64)
+            && (sides.left || !in_margins.left)
+            && (sides.right || !in_margins.right)
+            && (sides.top || !in_margins.top)
+            && (sides.bottom || !in_margins.bottom)
+    }
+
+    fn is_streak<P>(&self, grapheme: &Grapheme<P>) -> bool {
+        let (width, height) = (grapheme.width(), grapheme.height());
+        let (thickness, length) = (width.min(height), width.max(height));
+        // Square graphemes count as horizontal, they're never long enough to matter.
+        let direction_allowed = if height > width {
+            self.remove_vertical_streaks
+        } else {
+            self.remove_horizontal_streaks
+        };
+
+        self.max_aspect_ratio
+            .is_some_and(|max| grapheme.aspect_ratio() > max)
+            && self.max_streak_thickness.is_none_or(|max| thickness <= max)
+            && length >= self.min_streak_length
+            && direction_allowed
+    }
+
+    fn fill_ratio_applies<P>(&self, grapheme: &Grapheme<P>) -> bool {
+        self.fill_ratio_size_limit
+            .is_none_or(|limit| grapheme.size(self.size_metric) <= limit as u64)
+    }
+
+    fn draw<P: DocumentPixel>(
+        &self,
+        grapheme_index: usize,
+        analyzed_image: &AnalyzedImage<P>,
+        image: &mut impl Canvas<P>,
+    ) {
+        let grapheme = &analyzed_image.graphemes[grapheme_index];
+        match self.output_mode {
+            OutputMode::Original if self.changes_ink() => {
+                for (x, y, color) in grapheme.pixels() {
+                    image.paint(x, y, self.adjust_ink(color));
+                }
+            }
+            OutputMode::Original => grapheme.draw(image),
+            OutputMode::Binarized => self.draw_binarized(grapheme, image),
+        }
+
+        if self.hole_fill_threshold == 0 && !self.fill_grapheme_holes {
+            return;
+        }
+        let color = match self.output_mode {
+            OutputMode::Original => self.adjust_ink(grapheme.mean_color()),
+            OutputMode::Binarized => P::from_rgb(self.binary_color()),
+        };
+        if self.fill_grapheme_holes {
+            grapheme.paint_holes(image, color);
+            return;
+        }
+        for hole in grapheme.holes() {
+            // Holes with other graphemes in them (like a dot inside a circle) aren't pinholes.
+            let is_pinhole = hole.len() <= self.hole_fill_threshold
+                && hole.iter().all(|&(x, y)| {
+                    analyzed_image.map[index(analyzed_image.width, x, y)] == u32::MAX
+                });
+            if is_pinhole {
+                for (x, y) in hole {
+                    image.paint(x, y, color);
+                }
+            }
+        }
+    }
+
+    fn changes_ink(&self) -> bool {
+        self.ink_gamma != 1.0 || self.ink_black_point != 0
+    }
+
+    // The color with ink_gamma and ink_black_point applied, see them.
+    fn adjust_ink<P: DocumentPixel>(&self, color: P) -> P {
+        if !self.changes_ink() {
+            return color;
+        }
+
+        let light_ink = self.binary_color() == [255, 255, 255];
+        let black_point = self.ink_black_point as f32 / 255.0;
+        let max: u64 = P::Subpixel::DEFAULT_MAX_VALUE.into();
+        color.map_without_alpha(|channel| {
+            let mut value = channel.into() as f32 / max as f32;
+            if light_ink {
+                value = 1.0 - value;
+            }
+            let mut value = ((value - black_point) / (1.0 - black_point).max(f32::EPSILON))
+                .clamp(0.0, 1.0)
+                .powf(self.ink_gamma);
+            if light_ink {
+                value = 1.0 - value;
+            }
+            pixel::from_u64((value * max as f32).round() as u64)
+        })
+    }
+
+    fn draw_binarized<P: DocumentPixel>(&self, grapheme: &Grapheme<P>, image: &mut impl Canvas<P>) {
+        let binary_color = self.binary_color();
+        let color = P::from_rgb(binary_color);
+        let Some(threshold) = self.binarize_threshold else {
+            grapheme.fill(image, color);
+            return;
+        };
+
+        // White is drawn over dark backgrounds, where the ink is light.
+        let light_ink = binary_color == [255, 255, 255];
+        let threshold = P::Subpixel::from_u8(if light_ink {
+            255 - threshold
+        } else {
+            threshold
+        });
+        for (x, y, pixel) in grapheme.pixels() {
+            let value = pixel.value(LuminanceMode::Mean);
+            if (light_ink && value > threshold) || (!light_ink && value < threshold) {
+                image.paint(x, y, color);
+            }
+        }
+    }
+
+    fn is_protected<P>(&self, grapheme: &Grapheme<P>) -> bool {
+        self.protected_regions
+            .iter()
+            .any(|&region| grapheme.overlaps(region))
+    }
+
+    fn is_in_removal_region<P>(&self, grapheme: &Grapheme<P>) -> bool {
+        self.removal_regions
+            .iter()
+            .any(|&region| match self.removal_region_match {
+                RegionMatch::Inside => grapheme.is_inside(region),
+                RegionMatch::Overlapping => grapheme.overlaps(region),
+            })
+    }
+
+    // The margins in pixels on a page this size.
+    pub fn margins_for(&self, width: u32, height: u32) -> Margins {
+        match self.page_margin_fractions {
+            Some(fractions) => fractions.to_pixels(width, height),
+            None => self.page_margins,
+        }
+    }
+
+    // The most common color of the pixels that aren't part of any grapheme, for filling with the paper's own color
+    // instead of white. source is the image that was analyzed, and white is given back if it's all graphemes. Fails if
+    // source isn't the size of the analyzed image.
+    pub fn detect_background<P>(
+        analyzed_image: &AnalyzedImage<P>,
+        source: &RgbImage,
+    ) -> ImageResult<[u8; 3]> {
+        check_dimensions(
+            analyzed_image.width,
+            analyzed_image.height,
+            source.dimensions(),
+        )?;
+        let mut counts: HashMap<[u8; 3], u32> = HashMap::new();
+        for (&i, pixel) in analyzed_image.map.iter().zip(source.pixels()) {
+            if i == u32::MAX {
+                *counts.entry(pixel.0).or_default() += 1;
+            }
+        }
+
+        // Ties go to the lighter color so the same image always gives the same color.
+        Ok(counts
+            .into_iter()
+            .max_by_key(|&(color, count)| (count, color))
+            .map_or([255, 255, 255], |(color, _)| color))
+    }
+
+    pub fn detect_background_dynamic(
+        analyzed_image: &DynamicAnalyzedImage,
+        source: &RgbImage,
+    ) -> ImageResult<[u8; 3]> {
+        match analyzed_image {
+            DynamicAnalyzedImage::Luma8(analyzed_image) => {
+                Self::detect_background(analyzed_image, source)
+            }
+            DynamicAnalyzedImage::Rgb8(analyzed_image) => {
+                Self::detect_background(analyzed_image, source)
+            }
+            DynamicAnalyzedImage::Rgba8(analyzed_image) => {
+                Self::detect_background(analyzed_image, source)
+            }
+            DynamicAnalyzedImage::Luma16(analyzed_image) => {
+                Self::detect_background(analyzed_image, source)
+            }
+            DynamicAnalyzedImage::Rgb16(analyzed_image) => {
+                Self::detect_background(analyzed_image, source)
+            }
+            DynamicAnalyzedImage::Rgba16(analyzed_image) => {
+                Self::detect_background(analyzed_image, source)
+            }
+        }
+    }
+
+    // Black, or white over a dark background fill (like for inverted documents), so binarized text stays readable.
+    fn binary_color(&self) -> [u8; 3] {
+        let lightness: u32 = self.background_fill_color.iter().map(|&c| c as u32).sum();
+        if lightness < 3 * 128 {
+            [255, 255, 255]
+        } else {
+            [0, 0, 0]
+        }
+    }
+
+    fn is_isolated<P>(
+        &self,
+        grapheme_index: usize,
+        graphemes: &[Grapheme<P>],
+        neighbors: &Neighbors,
+    ) -> bool {
+        let grapheme = &graphemes[grapheme_index];
+        if grapheme.pixel_count() > self.isolation_size_threshold as usize {
+            return false;
+        }
+
+        let distance = self.isolation_distance_threshold;
+        // The big grapheme's nearest pixel can be distance blank pixels past the speck's box, so one further.
+        let reach = distance.saturating_add(1);
+        let area = (
+            grapheme.left.saturating_sub(reach),
+            grapheme.top.saturating_sub(reach),
+            grapheme.right.saturating_add(reach),
+            grapheme.bottom.saturating_add(reach),
+        );
+        !neighbors
+            .isolation
+            .near(graphemes, area)
+            .any(|i| i != grapheme_index && self.is_near(grapheme, &graphemes[i]))
+    }
+
+    fn is_near<P>(&self, grapheme: &Grapheme<P>, other: &Grapheme<P>) -> bool {
+        let distance = self.isolation_distance_threshold;
+        match self.isolation_metric {
+            IsolationMetric::BboxGap => grapheme.box_distance(other) <= distance,
+            IsolationMetric::BboxEdges => {
+                (grapheme.top.abs_diff(other.top) < distance
+                    || grapheme.bottom.abs_diff(other.bottom) < distance)
+                    && (grapheme.left.abs_diff(other.left) < distance
+                        || grapheme.right.abs_diff(other.right) < distance)
+            }
+            IsolationMetric::CenterEuclidean => {
+                let ((x, y), (other_x, other_y)) = (grapheme.centroid(), other.centroid());
+                (x - other_x).hypot(y - other_y) <= distance as f32
+            }
+        }
+    }
+
+    // Whether the grapheme lines up with a letter, either beside it at the same height (like a period or a comma), or
+    // right above it (like the dot on an i).
+    fn is_punctuation<P>(
+        &self,
+        grapheme_index: usize,
+        graphemes: &[Grapheme<P>],
+        neighbors: &Neighbors,
+    ) -> bool {
+        let Some(letters) = &neighbors.letters else {
+            return false;
+        };
+
+        let grapheme = &graphemes[grapheme_index];
+        let distance = self.punctuation_distance;
+        let area = (
+            grapheme.left.saturating_sub(distance.saturating_add(1)),
+            grapheme.top,
+            grapheme.right.saturating_add(distance.saturating_add(1)),
+            grapheme.bottom.saturating_add(distance.saturating_add(1)),
+        );
+        letters.near(graphemes, area).any(|i| {
+            let letter = &graphemes[i];
+            let overlaps_rows = letter.top <= grapheme.bottom && grapheme.top <= letter.bottom;
+            let overlaps_columns = letter.left <= grapheme.right && grapheme.left <= letter.right;
+            let beside = overlaps_rows && grapheme.box_distance(letter) <= distance;
+            let above = overlaps_columns
+                && letter.top > grapheme.bottom
+                && letter.top - grapheme.bottom - 1 <= distance;
+            i != grapheme_index && (beside || above)
+        })
+    }
+
+    fn build_neighbors<P>(&self, graphemes: &[Grapheme<P>]) -> Neighbors {
+        // Cells at least as big as the distance looked around each speck, so that only takes a few of them however far
+        // it is, like in union_nearby.
+        let isolation_cell_size = self.isolation_distance_threshold.max(grid::CELL_SIZE);
+        Neighbors {
+            // A speck needs to be close to a big grapheme to survive, 2 small specks together won't survive, so only
+            // the big ones go in the grid.
+            isolation: GraphemeGrid::with_filter(graphemes, isolation_cell_size, |grapheme| {
+                grapheme.pixel_count() >= self.isolation_size_threshold as usize
+            }),
+            letters: self.protect_punctuation.then(|| {
+                GraphemeGrid::with_filter(graphemes, grid::CELL_SIZE, |grapheme| {
+                    grapheme.size(self.size_metric) > self.speck_size_threshold as u64
+                })
+            }),
+        }
+    }
+}
+
+// The graphemes the rules look for around each grapheme, in grids so only the nearby ones are gone through.
+pub(crate) struct Neighbors {
+    isolation: GraphemeGrid,
+    // Everything too big to be a speck, when protect_punctuation is on.
+    letters: Option<GraphemeGrid>,
+}
+
+// P is the pixel type of the image it came from, so the original colors can be drawn back.
+pub struct Grapheme<P = Rgb<u8>> {
+    // The pixels as horizontal runs of (y, start x, end x) with the end exclusive, and the pixels' colors run by run,
+    // which takes a lot less memory than keeping every pixel's coordinates for big graphemes.
+    runs: Vec<(u32, u32, u32)>,
+    colors: Vec<P>,
+    // Measured once every pixel's grapheme is known, see perimeter().
+    perimeter: usize,
+    // The sum of the pixels' values, kept so the average doesn't have to go over every pixel again.
+    value_sum: u64,
+    top: u32,
+    bottom: u32,
+    left: u32,
+    right: u32,
+    // If true, always draw no matter what, if false, never draw no matter what.
+    manual_override: Option<bool>,
+}
+
+impl<P: DocumentPixel> Grapheme<P> {
+    // The mean value of the pixels, as the analyzer saw them.
+    pub fn average_value(&self) -> P::Subpixel {
+        pixel::from_u64(self.value_sum / self.pixel_count() as u64)
+    }
+
+    // Each pixel as (x, y, color).
+    pub fn pixels(&self) -> impl Iterator<Item = (u32, u32, P)> + '_ {
+        self.positions()
+            .zip(self.colors.iter())
+            .map(|((x, y), &color)| (x, y, color))
+    }
+
+    fn fill(&self, image: &mut impl Canvas<P>, color: P) {
+        for (x, y) in self.positions() {
+            image.paint(x, y, color);
+        }
+    }
+
+    fn draw(&self, image: &mut impl Canvas<P>) {
+        for (x, y, color) in self.pixels() {
+            image.paint(x, y, color);
+        }
+    }
+
+    // The average of each channel over the pixels.
+    fn mean_color(&self) -> P {
+        let mut sums = [0u64; 4];
+        for pixel in &self.colors {
+            for (sum, &channel) in sums.iter_mut().zip(pixel.channels()) {
+                *sum += channel.into();
+            }
+        }
+
+        let count = self.pixel_count() as u64;
+        let channels = sums.map(|sum| pixel::from_u64((sum + count / 2) / count));
+        *P::from_slice(&channels[..P::CHANNEL_COUNT as usize])
+    }
+
+    // Paints every hole of the grapheme (see holes) in color, including any other graphemes inside them.
+    pub fn fill_holes(&self, image: &mut DocumentImage<P>, color: P) {
+        self.paint_holes(image, color);
+    }
+
+    fn paint_holes(&self, image: &mut impl Canvas<P>, color: P) {
+        for (x, y) in self.holes().into_iter().flatten() {
+            image.paint(x, y, color);
+        }
+    }
+
+    // The pixels inside the bounding box that aren't part of the grapheme and can't be reached from outside of it
+    // without crossing it, split into 4-connected holes.
+    fn holes(&self) -> Vec<Vec<(u32, u32)>> {
+        let (width, height) = (self.width(), self.height());
+        // Starts out true for the grapheme's own pixels, and every pixel is set once it's been flooded.
+        let mut flooded = vec![false; width as usize * height as usize];
+        for (x, y) in self.positions() {
+            flooded[index(width, x - self.left, y - self.top)] = true;
+        }
+
+        // Whatever can be reached from the edges of the bounding box is outside.
+        for x in 0..width {
+            flood_fill(&mut flooded, width, x, 0, |_, _| {});
+            flood_fill(&mut flooded, width, x, height - 1, |_, _| {});
+        }
+        for y in 0..height {
+            flood_fill(&mut flooded, width, 0, y, |_, _| {});
+            flood_fill(&mut flooded, width, width - 1, y, |_, _| {});
+        }
+
+        let mut holes = Vec::new();
+        for y in 1..height.saturating_sub(1) {
+            for x in 1..width.saturating_sub(1) {
+                let mut hole = Vec::new();
+                flood_fill(&mut flooded, width, x, y, |x, y| {
+                    hole.push((x + self.left, y + self.top))
+                });
+                if !hole.is_empty() {
+                    holes.push(hole);
+                }
+            }
+        }
+
+        holes
+    }
+}
+
+impl<P> Grapheme<P> {
+    // (top, bottom, left, right), all inclusive.
+    pub fn bounds(&self) -> (u32, u32, u32, u32) {
+        (self.top, self.bottom, self.left, self.right)
+    }
+
+    pub fn width(&self) -> u32 {
+        self.right - self.left + 1
+    }
+
+    pub fn height(&self) -> u32 {
+        self.bottom - self.top + 1
+    }
+
+    pub fn pixel_count(&self) -> usize {
+        self.colors.len()
+    }
+
+    // Each pixel's (x, y), in the same order as pixels().
+    pub fn positions(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        self.runs
+            .iter()
+            .flat_map(|&(y, start, end)| (start..end).map(move |x| (x, y)))
+    }
+
+    // The center of mass (x, y) of the pixels.
+    pub fn centroid(&self) -> (f32, f32) {
+        let (mut x_sum, mut y_sum) = (0u64, 0u64);
+        for &(y, start, end) in &self.runs {
+            let length = (end - start) as u64;
+            // The sum of start..end.
+            x_sum += (start as u64 + end as u64 - 1) * length / 2;
+            y_sum += y as u64 * length;
+        }
+
+        let count = self.pixel_count() as f64;
+        ((x_sum as f64 / count) as f32, (y_sum as f64 / count) as f32)
+    }
+
+    // The area of the bounding box.
+    pub fn area(&self) -> u64 {
+        self.width() as u64 * self.height() as u64
+    }
+
+    pub fn size(&self, metric: SizeMetric) -> u64 {
+        match metric {
+            SizeMetric::PixelCount => self.pixel_count() as u64,
+            SizeMetric::BoundingBoxArea => self.area(),
+        }
+    }
+
+    // How much of the bounding box the pixels cover (its fill ratio), from 0 to 1. Solid blobs like stamps and smudges
+    // are close to 1, letters are a lot sparser.
+    pub fn density(&self) -> f32 {
+        self.pixel_count() as f32 / self.area() as f32
+    }
+
+    // Whether the bounding box and rect share any pixels.
+    pub fn overlaps(&self, rect: math::Rect) -> bool {
+        rect.width > 0
+            && rect.height > 0
+            && self.left < rect.x.saturating_add(rect.width)
+            && self.right >= rect.x
+            && self.top < rect.y.saturating_add(rect.height)
+            && self.bottom >= rect.y
+    }
+
+    // Whether the whole bounding box is inside rect.
+    pub fn is_inside(&self, rect: math::Rect) -> bool {
+        self.left >= rect.x
+            && self.top >= rect.y
+            && (self.right as u64) < rect.x as u64 + rect.width as u64
+            && (self.bottom as u64) < rect.y as u64 + rect.height as u64
+    }
+
+    // The long side of the bounding box over the short side, 1 for squares and more the longer and thinner it is.
+    pub fn aspect_ratio(&self) -> f32 {
+        self.width().max(self.height()) as f32 / self.width().min(self.height()) as f32
+    }
+
+    // How many pixel edges separate the grapheme from the background and other graphemes (or the edge of the image).
+    // Compared to the pixel count, it's high for thin strokes and low for round blobs.
+    pub fn perimeter(&self) -> usize {
+        self.perimeter
+    }
+
+    pub fn manual_override(&self) -> Option<bool> {
+        self.manual_override
+    }
+
+    // Some(true) always draws the grapheme, Some(false) always fills it, and None leaves it up to the cleaner.
+    pub fn set_override(&mut self, manual_override: Option<bool>) {
+        self.manual_override = manual_override;
+    }
+
+    // Combines other into this grapheme, for when they turn out to be connected.
+    fn merge(&mut self, other: Grapheme<P>) {
+        self.runs.extend(other.runs);
+        self.colors.extend(other.colors);
+        self.value_sum += other.value_sum;
+        self.top = self.top.min(other.top);
+        self.bottom = self.bottom.max(other.bottom);
+        self.left = self.left.min(other.left);
+        self.right = self.right.max(other.right);
+    }
+
+    // How many pixels are between the bounding boxes, along whichever axis they're farthest apart on. 0 if they touch
+    // or overlap.
+    fn box_distance(&self, other: &Grapheme<P>) -> u32 {
+        let x = other
+            .left
+            .saturating_sub(self.right + 1)
+            .max(self.left.saturating_sub(other.right + 1));
+        let y = other
+            .top
+            .saturating_sub(self.bottom + 1)
+            .max(self.top.saturating_sub(other.bottom + 1));
+        x.max(y)
+    }
+}
+
+// The value that best splits the histogram into two classes (the values up to and including it, and the values above
+// it) by maximizing the variance between them. None if there is only one class.
+fn otsu_threshold(histogram: &[u32; 256]) -> Option<u8> {
+    let total_count: u64 = histogram.iter().map(|&n| n as u64).sum();
+    let total_sum: u64 = histogram
+        .iter()
+        .enumerate()
+        .map(|(value, &n)| value as u64 * n as u64)
+        .sum();
+
+    let mut best = None;
+    let mut best_variance = 0.0;
+    let (mut count, mut sum) = (0u64, 0u64);
+    for (value, &n) in histogram.iter().enumerate() {
+        count += n as u64;
+        sum += value as u64 * n as u64;
+        if count == 0 {
+            continue;
+        }
+        if count == total_count {
+            break;
+        }
+
+        let below_mean = sum as f64 / count as f64;
+        let above_mean = (total_sum - sum) as f64 / (total_count - count) as f64;
+        let variance =
+            count as f64 * (total_count - count) as f64 * (below_mean - above_mean).powi(2);
+        if variance > best_variance {
+            best_variance = variance;
+            best = Some(value as u8);
+        }
+    }
+
+    best
+}
+
+// Finds the graphemes among the pixels in rows that haven't been visited, in the order their first pixel appears. Each
+// row is split into runs of unvisited pixels, and the runs touching each other from one row to the next are joined
+// with union-find, which goes through the image in order instead of jumping around it like a flood fill. on_row is
+// called before each row.
+fn detect_rows<P: DocumentPixel>(
+    image: &DocumentImage<P>,
+    visited_map: &VisitedMap,
+    rows: Range<u32>,
+    connectivity: Connectivity,
+    value: impl Fn(&P) -> P::Subpixel,
+    mut on_row: impl FnMut(u32) -> Result<(), Cancelled>,
+) -> Result<Vec<Grapheme<P>>, Cancelled> {
+    // Diagonal neighbors count for 8-connectivity, so runs reach one pixel further along the row above.
+    let reach = match connectivity {
+        Connectivity::Four => 0,
+        Connectivity::Eight => 1,
+    };
+
+    // (y, start, end) with end exclusive, in the order they appear.
+    let mut runs: Vec<(u32, u32, u32)> = Vec::new();
+    let mut parents: Vec<usize> = Vec::new();
+    let mut previous_row = 0..0;
+    for y in rows {
+        on_row(y)?;
+
+        let row_start = runs.len();
+        // The first run on the row above that could still touch this row's runs, they're in order so it only moves
+        // forward.
+        let mut above = previous_row.start;
+        let mut x = 0;
+        loop {
+            let start = visited_map.next(y, x, false);
+            if start == visited_map.width {
+                break;
+            }
+            x = visited_map.next(y, start, true);
+
+            let run = runs.len();
+            runs.push((y, start, x));
+            parents.push(run);
+            while above < previous_row.end && runs[above].2 + reach <= start {
+                above += 1;
+            }
+            let mut other = above;
+            while other < previous_row.end && runs[other].1 < x + reach {
+                union(&mut parents, other, run);
+                other += 1;
+            }
+        }
+        previous_row = row_start..runs.len();
+    }
+
+    // The root of each set is its first run, so the graphemes come out in the order their first pixels appear.
+    let mut grapheme_indices = vec![usize::MAX; runs.len()];
+    let mut graphemes: Vec<Grapheme<P>> = Vec::new();
+    for (i, &(y, start, end)) in runs.iter().enumerate() {
+        let root = find(&mut parents, i);
+        if root == i {
+            grapheme_indices[i] = graphemes.len();
+            graphemes.push(Grapheme {
+                runs: Vec::new(),
+                colors: Vec::new(),
+                perimeter: 0,
+                value_sum: 0,
+                top: y,
+                bottom: y,
+                left: start,
+                right: end - 1,
+                manual_override: None,
+            });
+        }
+
+        let grapheme = &mut graphemes[grapheme_indices[root]];
+        grapheme.runs.push((y, start, end));
+        for x in start..end {
+            let pixel = *image.get_pixel(x, y);
+            grapheme.value_sum += value(&pixel).into();
+            grapheme.colors.push(pixel);
+        }
+        grapheme.bottom = y;
+        grapheme.left = grapheme.left.min(start);
+        grapheme.right = grapheme.right.max(end - 1);
+    }
+
+    Ok(graphemes)
+}
+
+fn find(parents: &mut [usize], mut i: usize) -> usize {
+    while parents[i] != i {
+        parents[i] = parents[parents[i]];
+        i = parents[i];
+    }
+
+    i
+}
+
+fn union(parents: &mut [usize], a: usize, b: usize) {
+    let (a, b) = (find(parents, a), find(parents, b));
+    // Keep the earlier grapheme as the root.
+    parents[a.max(b)] = a.min(b);
+}
+
+// Sets every pixel of a row-major buffer that's 4-connected to (x, y) through unset pixels, calling visit on each, if
+// (x, y) isn't set already. Fills a whole span of a row at once and only keeps one seed per span on the rows above and
+// below, so big regions don't need a stack entry for every pixel.
+fn flood_fill(flooded: &mut [bool], width: u32, x: u32, y: u32, mut visit: impl FnMut(u32, u32)) {
+    let height = (flooded.len() / width as usize) as u32;
+    let mut seeds = vec![(x, y)];
+    while let Some((x, y)) = seeds.pop() {
+        if flooded[index(width, x, y)] {
+            continue;
+        }
+
+        let mut start = x;
+        while start > 0 && !flooded[index(width, start - 1, y)] {
+            start -= 1;
+        }
+        let mut end = x + 1;
+        while end < width && !flooded[index(width, end, y)] {
+            end += 1;
+        }
+        for x in start..end {
+            flooded[index(width, x, y)] = true;
+            visit(x, y);
+        }
+
+        for y in [y.wrapping_sub(1), y + 1] {
+            if y >= height {
+                continue;
+            }
+            // The first pixel of each run of unset pixels next to the span.
+            let mut in_run = false;
+            for x in start..end {
+                let unset = !flooded[index(width, x, y)];
+                if unset && !in_run {
+                    seeds.push((x, y));
+                }
+                in_run = unset;
+            }
+        }
+    }
+}
+
+// Union-find over the graphemes whose bounding boxes are at most distance blank pixels apart, so chains of graphemes
+// that are each near the next all end up together.
+fn union_nearby<P>(graphemes: &[Grapheme<P>], distance: u32) -> Vec<usize> {
+    let grid = GraphemeGrid::new(graphemes, distance.max(grid::CELL_SIZE));
+    let mut parents: Vec<usize> = (0..graphemes.len()).collect();
+    for (i, grapheme) in graphemes.iter().enumerate() {
+        let area = (
+            grapheme.left.saturating_sub(distance),
+            grapheme.top.saturating_sub(distance),
+            grapheme.right.saturating_add(distance),
+            grapheme.bottom.saturating_add(distance),
+        );
+        for j in grid.near(graphemes, area) {
+            if j > i && grapheme.box_distance(&graphemes[j]) <= distance {
+                union(&mut parents, i, j);
+            }
+        }
+    }
+
+    parents
+}
+
+// Merges the graphemes joined by union into the first of each set. That's the one with the first pixel if the
+// graphemes are in order, so they stay in the same order as a single flood fill over the image would find them.
+fn merge_sets<P>(
+    graphemes: impl IntoIterator<Item = Grapheme<P>>,
+    parents: &mut [usize],
+) -> Vec<Grapheme<P>> {
+    let mut merged_indices = vec![usize::MAX; parents.len()];
+    let mut merged: Vec<Grapheme<P>> = Vec::new();
+    for (i, grapheme) in graphemes.into_iter().enumerate() {
+        let root = find(parents, i);
+        if merged_indices[root] == usize::MAX {
+            merged_indices[root] = merged.len();
+            merged.push(grapheme);
+        } else {
+            merged[merged_indices[root]].merge(grapheme);
+        }
+    }
+
+    merged
+}
+
+// The median of each channel over the pixels of source within radius of the grapheme's bounding box that aren't part of
+// any grapheme, or None if there aren't any.
+fn sample_background<P: DocumentPixel>(
+    grapheme: &Grapheme<P>,
+    analyzed_image: &AnalyzedImage<P>,
+    source: &DocumentImage<P>,
+    radius: u32,
+) -> Option<P> {
+    let width = analyzed_image.width;
+    let right = grapheme.right.saturating_add(radius).min(width - 1);
+    let bottom = grapheme
+        .bottom
+        .saturating_add(radius)
+        .min(analyzed_image.height - 1);
+    let mut samples = Vec::new();
+    for y in grapheme.top.saturating_sub(radius)..=bottom {
+        for x in grapheme.left.saturating_sub(radius)..=right {
+            if analyzed_image.map[index(width, x, y)] == u32::MAX {
+                samples.push(*source.get_pixel(x, y));
+            }
+        }
+    }
+    if samples.is_empty() {
+        return None;
+    }
+
+    let mut channel = Vec::with_capacity(samples.len());
+    let mut median = samples[0];
+    for (c, value) in median.channels_mut().iter_mut().enumerate() {
+        channel.clear();
+        channel.extend(samples.iter().map(|sample| sample.channels()[c]));
+        let middle = channel.len() / 2;
+        *value = *channel.select_nth_unstable(middle).1;
+    }
+
+    Some(median)
+}
+
+// For images that have to line up pixel for pixel with an analyzed image.
+fn check_dimensions(width: u32, height: u32, dimensions: (u32, u32)) -> ImageResult<()> {
+    if dimensions != (width, height) {
+        return Err(ImageError::Parameter(error::ParameterError::from_kind(
+            error::ParameterErrorKind::DimensionMismatch,
+        )));
+    }
+
+    Ok(())
+}
+
+// image converted to the pixel type analyzed_image was analyzed as, if it isn't already.
+fn same_type<'a>(
+    analyzed_image: &DynamicAnalyzedImage,
+    image: &'a DynamicImage,
+) -> Cow<'a, DynamicImage> {
+    let converted = match (analyzed_image, image) {
+        (DynamicAnalyzedImage::Luma8(_), DynamicImage::ImageLuma8(_))
+        | (DynamicAnalyzedImage::Rgb8(_), DynamicImage::ImageRgb8(_))
+        | (DynamicAnalyzedImage::Rgba8(_), DynamicImage::ImageRgba8(_))
+        | (DynamicAnalyzedImage::Luma16(_), DynamicImage::ImageLuma16(_))
+        | (DynamicAnalyzedImage::Rgb16(_), DynamicImage::ImageRgb16(_))
+        | (DynamicAnalyzedImage::Rgba16(_), DynamicImage::ImageRgba16(_)) => {
+            return Cow::Borrowed(image)
+        }
+        (DynamicAnalyzedImage::Luma8(_), _) => DynamicImage::ImageLuma8(image.to_luma8()),
+        (DynamicAnalyzedImage::Rgb8(_), _) => DynamicImage::ImageRgb8(image.to_rgb8()),
+        (DynamicAnalyzedImage::Rgba8(_), _) => DynamicImage::ImageRgba8(image.to_rgba8()),
+        (DynamicAnalyzedImage::Luma16(_), _) => DynamicImage::ImageLuma16(image.to_luma16()),
+        (DynamicAnalyzedImage::Rgb16(_), _) => DynamicImage::ImageRgb16(image.to_rgb16()),
+        (DynamicAnalyzedImage::Rgba16(_), _) => DynamicImage::ImageRgba16(image.to_rgba16()),
+    };
+
+    Cow::Owned(converted)
+}
+
+// More pixels than any scan has, so a wrong size (like from a damaged file) is an error instead of an attempt to
+// allocate a map that big. The map alone takes 4 bytes a pixel, 64 GiB at this size.
+pub(crate) const MAX_PIXELS: u64 = 1 << 34;
+
+// How many pixels a width by height image has, as long as it's at most max_pixels and fits in a usize.
+fn pixel_count(width: u32, height: u32, max_pixels: u64) -> ImageResult<usize> {
+    let count = width as u64 * height as u64;
+    match usize::try_from(count) {
+        Ok(count) if count as u64 <= max_pixels => Ok(count),
+        _ => Err(ImageError::Limits(error::LimitError::from_kind(
+            error::LimitErrorKind::DimensionError,
+        ))),
+    }
+}
+
+// Where (x, y) is in a row-major buffer, in usize so big images can't overflow it.
+fn index(width: u32, x: u32, y: u32) -> usize {
+    y as usize * width as usize + x as usize
+}
+
+// Replaces every value of a row-major buffer with the minimum within radius of it (a square window clipped to the
+// buffer), as a row pass followed by a column pass of sliding minimums so each value costs the same no matter how big
+// the radius is.
+fn minimum_filter<S: Ord + Copy>(values: &mut [S], width: u32, height: u32, radius: u32) {
+    let (width, height) = (width as usize, height as usize);
+    for row in values.chunks_mut(width.max(1)) {
+        let minimums = sliding_minimum(row, radius as usize);
+        row.copy_from_slice(&minimums);
+    }
+
+    let mut column = Vec::with_capacity(height);
+    for x in 0..width {
+        column.clear();
+        column.extend((0..height).map(|y| values[y * width + x]));
+        for (y, minimum) in sliding_minimum(&column, radius as usize)
+            .into_iter()
+            .enumerate()
+        {
+            values[y * width + x] = minimum;
+        }
+    }
+}
+
+// The minimum of the values within radius of each index, clipped to the ends of the slice. Keeps a queue of the
+// indices that could still be the minimum of a later window, whose values are always increasing.
+fn sliding_minimum<S: Ord + Copy>(values: &[S], radius: usize) -> Vec<S> {
+    let radius = radius.min(values.len());
+    let mut minimums = Vec::with_capacity(values.len());
+    let mut window: VecDeque<usize> = VecDeque::new();
+    for i in 0..values.len() + radius {
+        if i < values.len() {
+            while window.back().is_some_and(|&j| values[j] >= values[i]) {
+                window.pop_back();
+            }
+            window.push_back(i);
+        }
+
+        if i >= radius {
+            let center = i - radius;
+            while window.front().is_some_and(|&j| j + radius < center) {
+                window.pop_front();
+            }
+            minimums.push(values[window[0]]);
+        }
+    }
+
+    minimums
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GrayImage, Luma, Rgb, RgbImage};
+
+    use super::*;
+
+    pub(crate) fn demo_page() -> RgbImage {
+        image::load_from_memory(include_bytes!("../assets/demo_page.png"))
+            .unwrap()
+            .to_rgb8()
+    }
+
+    // A xorshift generator, for tests over random images that come out the same every run.
+    pub(crate) struct Rng(pub(crate) u64);
+
+    impl Rng {
+        pub(crate) fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        pub(crate) fn below(&mut self, max: u32) -> u32 {
+            (self.next() % max as u64) as u32
+        }
+    }
+
+    // The darkest value within distance of (x, y), by looking at every pixel of the window.
+    fn naive_darkest(image: &GrayImage, x: u32, y: u32, distance: u32) -> u8 {
+        let (right, bottom) = (
+            x.saturating_add(distance).min(image.width() - 1),
+            y.saturating_add(distance).min(image.height() - 1),
+        );
+        (y.saturating_sub(distance)..=bottom)
+            .flat_map(|y| (x.saturating_sub(distance)..=right).map(move |x| (x, y)))
+            .map(|(x, y)| image.get_pixel(x, y).0[0])
+            .min()
+            .unwrap()
+    }
+
+    fn assert_darkest_map_matches(image: &GrayImage, distance: u32) {
+        let darkest_map = DarkestMap::new(image, distance, |pixel: &Luma<u8>| pixel.0[0]);
+        for (x, y, _) in image.enumerate_pixels() {
+            assert_eq!(
+                darkest_map.get(x, y),
+                naive_darkest(image, x, y, distance),
+                "({x}, {y}) of a {}x{} image within {distance}",
+                image.width(),
+                image.height()
+            );
+        }
+    }
+
+    pub(crate) fn blank_page(width: u32, height: u32) -> GrayImage {
+        GrayImage::from_pixel(width, height, Luma([255]))
+    }
+
+    // Draws the #s in rows in black, with the top left corner at (x, y).
+    pub(crate) fn draw(image: &mut GrayImage, x: u32, y: u32, rows: &[&str]) {
+        for (dy, row) in rows.iter().enumerate() {
+            for (dx, c) in row.chars().enumerate() {
+                if c == '#' {
+                    image.put_pixel(x + dx as u32, y + dy as u32, Luma([0]));
+                }
+            }
+        }
+    }
+
+    pub(crate) fn fill_rect(image: &mut GrayImage, x: u32, y: u32, width: u32, height: u32) {
+        for y in y..y + height {
+            for x in x..x + width {
+                image.put_pixel(x, y, Luma([0]));
+            }
+        }
+    }
+
+    pub(crate) const FOURTEEN: &[&str] = &[
+        "  ##    ##  ##",
+        " ###    ##  ##",
+        "####    ##  ##",
+        "  ##    ##  ##",
+        "  ##    ########",
+        "  ##    ########",
+        "  ##        ##",
+        "  ##        ##",
+        "######      ##",
+        "######      ##",
+    ];
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn index_past_u32() {
+        let max = u32::MAX as usize;
+        assert_eq!(index(u32::MAX, 0, 1), max);
+        assert_eq!(index(1 << 16, 0, 1 << 16), 1 << 32);
+        assert_eq!(index(1 << 16, 1, 1 << 16), (1 << 32) + 1);
+        assert_eq!(index(u32::MAX, u32::MAX - 1, u32::MAX), max * max + max - 1);
+    }
+
+    #[test]
+    fn pixel_count_limit() {
+        assert_eq!(pixel_count(0, u32::MAX, MAX_PIXELS).unwrap(), 0);
+        assert_eq!(pixel_count(3, 2, MAX_PIXELS).unwrap(), 6);
+        assert!(pixel_count(3, 2, 5).is_err());
+        assert!(pixel_count(u32::MAX, u32::MAX, MAX_PIXELS).is_err());
+        assert!(pixel_count(1 << 17, (1 << 17) + 1, MAX_PIXELS).is_err());
+        #[cfg(target_pointer_width = "64")]
+        assert_eq!(pixel_count(1 << 17, 1 << 17, MAX_PIXELS).unwrap(), 1 << 34);
+    }
+
+    #[test]
+    fn reset_refuses_sizes_too_big_for_the_map() {
+        let mut analyzed_image = AnalyzedImage::<Luma<u8>>::default();
+        assert!(analyzed_image
+            .reset(u32::MAX, u32::MAX, MAX_PIXELS)
+            .is_err());
+        assert!(analyzed_image.reset(1 << 20, 1 << 20, MAX_PIXELS).is_err());
+        analyzed_image.reset(3, 2, MAX_PIXELS).unwrap();
+        assert_eq!(analyzed_image.map, vec![u32::MAX; 6]);
+    }
+
+    #[test]
+    fn too_big_images_fail_to_analyze() {
+        let mut image = blank_page(20, 10);
+        fill_rect(&mut image, 5, 5, 2, 2);
+        let analyzer = ImageAnalyzer {
+            max_pixels: 199,
+            ..ImageAnalyzer::default()
+        };
+        let cancel_token = CancelToken::default();
+        let is_limit_error = |error| matches!(error, ImageError::Limits(_));
+
+        assert!(analyzer.try_analyze(&image).is_err_and(is_limit_error));
+        assert!(analyzer
+            .try_analyze_cancellable(&image, &cancel_token)
+            .is_err_and(is_limit_error));
+        let dynamic_image = DynamicImage::ImageLuma8(image.clone());
+        assert!(analyzer
+            .try_analyze_dynamic(&dynamic_image)
+            .is_err_and(is_limit_error));
+        assert!(analyzer
+            .try_analyze_dynamic_cancellable(&dynamic_image, &cancel_token)
+            .is_err_and(is_limit_error));
+
+        let analyzer = ImageAnalyzer {
+            max_pixels: 200,
+            ..analyzer
+        };
+        assert_eq!(analyzer.try_analyze(&image).unwrap().graphemes.len(), 1);
+        cancel_token.cancel();
+        assert!(analyzer
+            .try_analyze_dynamic_cancellable(&dynamic_image, &cancel_token)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn very_wide_image() {
+        let width = 100_000;
+        let mut image = GrayImage::from_pixel(width, 2, Luma([255]));
+        image.put_pixel(width - 1, 1, Luma([0]));
+        image.put_pixel(width - 2, 1, Luma([0]));
+        let analyzed_image = ImageAnalyzer::default().analyze(&image);
+
+        assert_eq!(analyzed_image.graphemes.len(), 1);
+        assert_eq!(
+            analyzed_image.graphemes[0].bounds(),
+            (1, 1, width - 2, width - 1)
+        );
+        assert_eq!(analyzed_image.map[index(width, width - 1, 1)], 0);
+        assert_eq!(analyzed_image.map[index(width, width - 1, 0)], u32::MAX);
+    }
+
+    #[test]
+    fn graphemes_straddling_each_margin() {
+        let margins = Margins {
+            left: 20,
+            right: 30,
+            top: 40,
+            bottom: 50,
+        };
+        // Straddling each margin's inner edge, then just inside the part of the page the margins leave.
+        let straddling = [(15, 90), (165, 90), (90, 35), (90, 145)];
+        let inside = [(20, 60), (160, 60), (60, 40), (60, 140)];
+        let mut image = blank_page(200, 200);
+        for (x, y) in straddling.into_iter().chain(inside) {
+            fill_rect(&mut image, x, y, 10, 10);
+        }
+        let analyzed_image = ImageAnalyzer::default().analyze(&image);
+        assert_eq!(analyzed_image.graphemes.len(), 8);
+        let removed = |page_margins| {
+            let cleaner = ImageCleaner {
+                page_margins,
+                isolation_size_threshold: 0,
+                ..ImageCleaner::default()
+            };
+            let decisions = cleaner.classify(&analyzed_image);
+            let removed = |(x, y)| {
+                let id = analyzed_image.grapheme_id_at(x + 5, y + 5).unwrap();
+                match decisions[id.index()] {
+                    Decision::RemovedMargin => true,
+                    Decision::Kept => false,
+                    decision => panic!("{decision:?} at ({x}, {y})"),
+                }
+            };
+            (straddling.map(removed), inside.map(removed))
+        };
+
+        assert_eq!(removed(margins), ([true; 4], [false; 4]));
+        // Each margin on its own only removes what straddles it.
+        for side in 0..4 {
+            let only = |i, margin| if i == side { margin } else { 0 };
+            let margins = Margins {
+                left: only(0, margins.left),
+                right: only(1, margins.right),
+                top: only(2, margins.top),
+                bottom: only(3, margins.bottom),
+            };
+            let (straddling, inside) = removed(margins);
+            assert_eq!(straddling, [0, 1, 2, 3].map(|i| i == side), "{margins:?}");
+            assert_eq!(inside, [false; 4], "{margins:?}");
+        }
+        // The left and right swapped, for the other side of the spread.
+        assert_eq!(
+            removed(margins.mirrored()),
+            ([true, false, true, true], [true, false, false, false])
+        );
+    }
+
+    #[test]
+    fn margin_fractions_on_two_page_sizes() {
+        // The same page at 2 resolutions.
+        let blocks = [
+            (15, 40),
+            (25, 40),
+            (185, 40),
+            (100, 15),
+            (100, 85),
+            (100, 50),
+        ];
+        let page = |scale| {
+            let mut image = blank_page(200 * scale, 100 * scale);
+            for (x, y) in blocks {
+                fill_rect(&mut image, x * scale, y * scale, 8 * scale, 8 * scale);
+            }
+            ImageAnalyzer::default().analyze(&image)
+        };
+        let (small, large) = (page(1), page(2));
+        let cleaner = ImageCleaner {
+            page_margin_fractions: Some(Margins {
+                left: 0.1,
+                right: 0.05,
+                top: 0.2,
+                bottom: 0.1,
+            }),
+            isolation_size_threshold: 0,
+            ..ImageCleaner::default()
+        };
+        assert_eq!(
+            cleaner.margins_for(200, 100),
+            Margins {
+                left: 20,
+                right: 10,
+                top: 20,
+                bottom: 10
+            }
+        );
+        assert_eq!(
+            cleaner.margins_for(400, 200),
+            Margins {
+                left: 40,
+                right: 20,
+                top: 40,
+                bottom: 20
+            }
+        );
+
+        let expected = [
+            Decision::RemovedMargin,
+            Decision::Kept,
+            Decision::RemovedMargin,
+            Decision::RemovedMargin,
+            Decision::RemovedMargin,
+            Decision::Kept,
+        ];
+        let decisions = |analyzed_image: &AnalyzedImage<Luma<u8>>, cleaner: &ImageCleaner| {
+            let decisions = cleaner.classify(analyzed_image);
+            let scale = analyzed_image.width / 200;
+            blocks.map(|(x, y)| {
+                let id = analyzed_image.grapheme_id_at(x * scale, y * scale).unwrap();
+                decisions[id.index()]
+            })
+        };
+        assert_eq!(decisions(&small, &cleaner), expected);
+        assert_eq!(decisions(&large, &cleaner), expected);
+        assert!(
+            cleaner.clean(&large)
+                == image::imageops::resize(
+                    &cleaner.clean(&small),
+                    400,
+                    200,
+                    image::imageops::FilterType::Nearest
+                )
+        );
+
+        // The small page's margins in pixels miss most of the large page's.
+        let cleaner = ImageCleaner {
+            page_margins: cleaner.margins_for(200, 100),
+            page_margin_fractions: None,
+            ..cleaner
+        };
+        assert_eq!(decisions(&small, &cleaner), expected);
+        assert_eq!(decisions(&large, &cleaner), [Decision::Kept; 6]);
+    }
+
+    #[test]
+    fn margin_keep_size_keeps_page_numbers() {
+        let mut image = blank_page(200, 300);
+        fill_rect(&mut image, 60, 60, 80, 120);
+        // A page number and a fly speck in the footer.
+        draw(&mut image, 90, 270, FOURTEEN);
+        draw(&mut image, 150, 275, &["###"]);
+        let analyzed_image = ImageAnalyzer::default().analyze(&image);
+        let cleaner = ImageCleaner {
+            speck_size_threshold: 2,
+            margin_keep_size: Some(20),
+            isolation_size_threshold: 0,
+            ..ImageCleaner::default()
+        };
+
+        assert_eq!(
+            cleaner.classify(&analyzed_image),
+            [
+                Decision::Kept,
+                Decision::KeptMargin,
+                Decision::KeptMargin,
+                Decision::RemovedMargin
+            ]
+        );
+        // Being big enough for the margins doesn't exempt them from the other rules.
+        let cleaner = ImageCleaner {
+            max_grapheme_area_fraction: Some(0.0005),
+            ..cleaner
+        };
+        assert_eq!(
+            cleaner.classify(&analyzed_image)[1..],
+            [
+                Decision::RemovedLarge,
+                Decision::RemovedLarge,
+                Decision::RemovedMargin
+            ]
+        );
+    }
+
+    #[test]
+    fn darkest_map_on_demo_page() {
+        let image = image::imageops::grayscale(&demo_page());
+        let image = image::imageops::crop_imm(&image, 300, 400, 300, 200).to_image();
+        for distance in [0, 1, 3, 10] {
+            assert_darkest_map_matches(&image, distance);
+        }
+    }
+
+    #[test]
+    fn darkest_map_on_random_images() {
+        let mut rng = Rng(0x9e3779b97f4a7c15);
+        for _ in 0..200 {
+            let (width, height) = (1 + rng.below(24), 1 + rng.below(24));
+            // Few distinct values, so there are ties.
+            let image = GrayImage::from_fn(width, height, |_, _| Luma([rng.below(8) as u8 * 32]));
+            assert_darkest_map_matches(&image, rng.below(30));
+        }
+    }
+
+    #[test]
+    fn minimum_filter_matches_brute_force() {
+        let mut rng = Rng(0x2545f4914f6cdd1d);
+        for _ in 0..500 {
+            let (width, height) = (rng.below(20), rng.below(20));
+            let radius = rng.below(25);
+            let values: Vec<u16> = (0..width * height)
+                .map(|_| rng.below(1000) as u16)
+                .collect();
+            let mut filtered = values.clone();
+            minimum_filter(&mut filtered, width, height, radius);
+
+            for y in 0..height {
+                for x in 0..width {
+                    let minimum = (y.saturating_sub(radius)..=(y + radius).min(height - 1))
+                        .flat_map(|y| {
+                            (x.saturating_sub(radius)..=(x + radius).min(width - 1))
+                                .map(move |x| (x, y))
+                        })
+                        .map(|(x, y)| values[(y * width + x) as usize])
+                        .min();
+                    assert_eq!(
+                        Some(filtered[(y * width + x) as usize]),
+                        minimum,
+                        "({x}, {y}) of {width}x{height} within {radius}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sliding_minimum_edges() {
+        assert!(sliding_minimum::<u8>(&[], 3).is_empty());
+        assert_eq!(sliding_minimum(&[5], 0), [5]);
+        assert_eq!(sliding_minimum(&[3, 1, 2], 0), [3, 1, 2]);
+        assert_eq!(sliding_minimum(&[1, 3, 4, 5, 2], 1), [1, 1, 3, 2, 2]);
+        assert_eq!(sliding_minimum(&[4, 3, 5], 10), [3, 3, 3]);
+    }
+
+    #[test]
+    fn region_precedence() {
+        let mut image = blank_page(100, 100);
+        fill_rect(&mut image, 45, 45, 10, 10);
+        let mut analyzed_image = ImageAnalyzer::default().analyze(&image);
+        let region = |x, y, size| math::Rect {
+            x,
+            y,
+            width: size,
+            height: size,
+        };
+        // Both regions cover the grapheme, and it's small enough to be a speck.
+        let mut cleaner = ImageCleaner {
+            speck_size_threshold: 1000,
+            page_margins: Margins::from((0, 0)),
+            protected_regions: vec![region(40, 40, 20)],
+            removal_regions: vec![region(30, 30, 40)],
+            ..ImageCleaner::default()
+        };
+        let mut decide = |cleaner: &ImageCleaner, manual_override| {
+            analyzed_image.graphemes[0].manual_override = manual_override;
+            cleaner.classify(&analyzed_image)[0]
+        };
+
+        assert_eq!(decide(&cleaner, Some(false)), Decision::ManualRemove);
+        cleaner.protected_regions.clear();
+        assert_eq!(decide(&cleaner, Some(true)), Decision::ManualKeep);
+        cleaner.protected_regions = vec![region(40, 40, 20)];
+        assert_eq!(decide(&cleaner, None), Decision::Protected);
+        cleaner.protected_regions.clear();
+        assert_eq!(decide(&cleaner, None), Decision::RemovedRegion);
+        cleaner.removal_regions.clear();
+        assert_eq!(decide(&cleaner, None), Decision::RemovedSpeck);
+
+        // Only partly covered by the removal region.
+        cleaner.speck_size_threshold = 0;
+        cleaner.removal_regions = vec![region(50, 50, 20)];
+        assert_eq!(decide(&cleaner, None), Decision::Kept);
+        cleaner.removal_region_match = RegionMatch::Overlapping;
+        assert_eq!(decide(&cleaner, None), Decision::RemovedRegion);
+    }
+
+    #[test]
+    fn visited_map_word_boundaries() {
+        // 130 pixels wide takes 2 full words and 2 bits of a third per row.
+        let mut visited_map = VisitedMap::default();
+        visited_map.resize(130, 3);
+        assert_eq!((visited_map.words_per_row, visited_map.words.len()), (3, 9));
+        let set = [0, 1, 62, 63, 64, 65, 127, 128, 129];
+        let row = &mut visited_map.words[3..6];
+        pack_row(row, (0..130).map(|x| set.contains(&x)));
+        // Nothing past the last pixel.
+        assert_eq!(row[2], 0b11);
+
+        for x in 0..130 {
+            assert_eq!(visited_map.is_visited(x, 1), set.contains(&x), "{x}");
+            assert!(!visited_map.is_visited(x, 0) && !visited_map.is_visited(x, 2));
+        }
+        let mut found = Vec::new();
+        let mut x = 0;
+        while x < 130 {
+            x = visited_map.next(1, x, true);
+            if x < 130 {
+                found.push(x);
+                x += 1;
+            }
+        }
+        assert_eq!(found, set);
+        assert_eq!(visited_map.next(1, 62, false), 66);
+        assert_eq!(visited_map.next(1, 127, false), 130);
+        assert_eq!(visited_map.next(0, 129, true), 130);
+        assert_eq!(visited_map.next(1, 130, true), 130);
+
+        let cropped = visited_map.crop(math::Rect {
+            x: 63,
+            y: 1,
+            width: 67,
+            height: 1,
+        });
+        assert_eq!(cropped.words_per_row, 2);
+        for x in 0..67 {
+            assert_eq!(cropped.is_visited(x, 0), set.contains(&(x + 63)), "{x}");
+        }
+
+        #[cfg(not(feature = "parallel"))]
+        {
+            visited_map.set_visited(64, 1, false);
+            visited_map.set_visited(129, 2, true);
+            assert!(!visited_map.is_visited(64, 1) && visited_map.is_visited(65, 1));
+            assert_eq!(visited_map.words[8], 0b10);
+        }
+
+        // Smaller again, with every pixel unvisited.
+        visited_map.resize(64, 2);
+        assert_eq!((visited_map.words_per_row, visited_map.words.len()), (1, 2));
+        assert_eq!(visited_map.next(1, 0, true), 64);
+        assert_eq!(visited_map.next(1, 0, false), 0);
+    }
+
+    #[test]
+    fn analyze_into_and_clean_into_reuse_their_buffers() {
+        let mut first = blank_page(120, 80);
+        draw(&mut first, 10, 10, FOURTEEN);
+        let mut second = blank_page(120, 80);
+        fill_rect(&mut second, 50, 30, 20, 20);
+        draw(&mut second, 80, 60, FOURTEEN);
+        let analyzer = ImageAnalyzer::default();
+        let cleaner = ImageCleaner {
+            page_margins: Margins::from((0, 0)),
+            ..ImageCleaner::default()
+        };
+
+        let mut analyzed_image = AnalyzedImage::default();
+        let mut cleaned = GrayImage::new(0, 0);
+        analyzer.analyze_into(&first, &mut analyzed_image);
+        cleaner.clean_into(&analyzed_image, &mut cleaned);
+        let map = analyzed_image.map.as_ptr();
+        let visited_map = analyzed_image.visited_map.words.as_ptr();
+        let pixels = cleaned.as_ptr();
+
+        for image in [&second, &first, &second] {
+            analyzer.analyze_into(image, &mut analyzed_image);
+            cleaner.clean_into(&analyzed_image, &mut cleaned);
+            assert_eq!(analyzed_image.map.as_ptr(), map);
+            assert_eq!(analyzed_image.visited_map.words.as_ptr(), visited_map);
+            assert_eq!(cleaned.as_ptr(), pixels);
+
+            // The same as starting from scratch.
+            let fresh = analyzer.analyze(image);
+            assert_eq!(analyzed_image.map, fresh.map);
+            assert_eq!(analyzed_image.graphemes.len(), fresh.graphemes.len());
+            assert!(cleaned == cleaner.clean(&fresh));
+        }
+    }
+
+    #[test]
+    fn red_and_blue_ink_under_each_luminance_mode() {
+        // Pure red on the left and pure blue on the right, 85 by their mean but 54 and 18 by Rec. 709.
+        let mut image = RgbImage::from_pixel(60, 30, Rgb([255, 255, 255]));
+        for (x, y) in (10..20).flat_map(|x| (10..20).map(move |y| (x, y))) {
+            image.put_pixel(x, y, Rgb([255, 0, 0]));
+            image.put_pixel(x + 30, y, Rgb([0, 0, 255]));
+        }
+        let lefts = |luminance_mode, threshold| {
+            let analyzer = ImageAnalyzer {
+                luminance_mode,
+                off_white_threshold: threshold,
+                lightness_threshold: threshold,
+                ..ImageAnalyzer::default()
+            };
+            let analyzed_image = analyzer.analyze(&image);
+            analyzed_image
+                .graphemes
+                .iter()
+                .map(|grapheme| grapheme.left)
+                .collect::<Vec<_>>()
+        };
+
+        // The mean can't tell them apart.
+        assert!(lefts(LuminanceMode::Mean, 40).is_empty());
+        assert_eq!(lefts(LuminanceMode::Mean, 100), [10, 40]);
+        // Blue looks a lot darker than red.
+        assert_eq!(lefts(LuminanceMode::Rec709, 40), [40]);
+        assert_eq!(lefts(LuminanceMode::Rec709, 100), [10, 40]);
+        assert!(lefts(LuminanceMode::Rec709, 10).is_empty());
+    }
+
+    #[test]
+    fn pale_grapheme_beside_a_dark_one() {
+        // The same 14 in black ink and in pale bleed-through gray.
+        let mut image = blank_page(100, 40);
+        draw(&mut image, 10, 15, FOURTEEN);
+        draw(&mut image, 60, 15, FOURTEEN);
+        for (x, y) in (60..76).flat_map(|x| (15..25).map(move |y| (x, y))) {
+            if image.get_pixel(x, y).0[0] == 0 {
+                image.put_pixel(x, y, Luma([180]));
+            }
+        }
+        let analyzer = ImageAnalyzer {
+            off_white_threshold: 200,
+            lightness_threshold: 200,
+            ..ImageAnalyzer::default()
+        };
+        let analyzed_image = analyzer.analyze(&image);
+        assert_eq!(analyzed_image.graphemes.len(), 4);
+        let (dark, pale) = analyzed_image.graphemes.split_at(2);
+        for (dark, pale) in dark.iter().zip(pale) {
+            assert_eq!(dark.pixel_count(), pale.pixel_count());
+            assert_eq!((dark.width(), dark.height()), (pale.width(), pale.height()));
+            assert_eq!((dark.average_value(), pale.average_value()), (0, 180));
+        }
+
+        let cleaner = ImageCleaner {
+            page_margins: Margins::from((0, 0)),
+            isolation_size_threshold: 0,
+            ..ImageCleaner::default()
+        };
+        assert_eq!(cleaner.classify(&analyzed_image), [Decision::Kept; 4]);
+        let cleaner = ImageCleaner {
+            max_average_value: Some(160),
+            ..cleaner
+        };
+        assert_eq!(
+            cleaner.classify(&analyzed_image),
+            [
+                Decision::Kept,
+                Decision::Kept,
+                Decision::RemovedFaint,
+                Decision::RemovedFaint
+            ]
+        );
+        let cleaned = cleaner.clean(&analyzed_image);
+        assert_eq!(
+            cleaned.view(10, 15, 16, 10).to_image(),
+            image.view(10, 15, 16, 10).to_image()
+        );
+        assert!(cleaned
+            .view(60, 15, 16, 10)
+            .pixels()
+            .all(|(_, _, pixel)| pixel.0[0] == 255));
+    }
+
+    #[test]
+    fn faint_graphemes_are_removed_unless_protected() {
+        let mut image = blank_page(100, 100);
+        for (x, y) in (40..60).flat_map(|x| (40..60).map(move |y| (x, y))) {
+            image.put_pixel(x, y, Luma([90]));
+        }
+        let mut analyzed_image = ImageAnalyzer::default().analyze(&image);
+        let mut cleaner = ImageCleaner {
+            page_margins: Margins::from((0, 0)),
+            isolation_size_threshold: 0,
+            protected_regions: vec![math::Rect {
+                x: 30,
+                y: 30,
+                width: 40,
+                height: 40,
+            }],
+            ..ImageCleaner::default()
+        };
+        let mut decide = |cleaner: &ImageCleaner, manual_override| {
+            analyzed_image.graphemes[0].manual_override = manual_override;
+            cleaner.classify(&analyzed_image)[0]
+        };
+
+        cleaner.max_average_value = Some(89);
+        assert_eq!(decide(&cleaner, None), Decision::Protected);
+        cleaner.protected_regions.clear();
+        assert_eq!(decide(&cleaner, None), Decision::RemovedFaint);
+        assert_eq!(decide(&cleaner, Some(true)), Decision::ManualKeep);
+        cleaner.max_average_value = Some(90);
+        assert_eq!(decide(&cleaner, None), Decision::Kept);
+
+        #[cfg(feature = "serde")]
+        {
+            let cleaner: ImageCleaner =
+                serde_json::from_str(r#"{"bleedthrough_threshold": 160}"#).unwrap();
+            assert_eq!(cleaner.max_average_value, Some(160));
+        }
+    }
+
+    #[test]
+    fn clean_fills_what_classify_removes() {
+        let image = demo_page();
+        let analyzed_image = ImageAnalyzer::default().analyze(&image);
+        let cleaner = ImageCleaner {
+            speck_size_threshold: 40,
+            max_aspect_ratio: Some(8.0),
+            speck_fill_color: [255, 0, 255],
+            ..ImageCleaner::default()
+        };
+        let removed: Vec<bool> = cleaner
+            .classify(&analyzed_image)
+            .into_iter()
+            .map(Decision::is_removed)
+            .collect();
+        assert!(removed.contains(&true) && removed.contains(&false));
+
+        let cleaned = cleaner.clean(&analyzed_image);
+        for (x, y, pixel) in cleaned.enumerate_pixels() {
+            let i = analyzed_image.map[index(image.width(), x, y)];
+            let expected = match i {
+                u32::MAX => Rgb([255; 3]),
+                i if removed[i as usize] => Rgb([255, 0, 255]),
+                _ => *image.get_pixel(x, y),
+            };
+            assert_eq!(*pixel, expected, "({x}, {y})");
+        }
+    }
+
+    // The demo page on paper with some texture to it, and a cleaner that removes some of its graphemes and not others.
+    fn textured_demo_page() -> (RgbImage, AnalyzedImage, ImageCleaner, Vec<bool>) {
+        let mut image = demo_page();
+        let mut rng = Rng(11);
+        for pixel in image.pixels_mut() {
+            if pixel.0.iter().all(|&c| c >= 235) {
+                *pixel = Rgb([235 + rng.below(21) as u8; 3]);
+            }
+        }
+        let analyzed_image = ImageAnalyzer::default().analyze(&image);
+        let cleaner = ImageCleaner {
+            speck_size_threshold: 40,
+            max_aspect_ratio: Some(8.0),
+            speck_fill_color: [255, 0, 255],
+            ..ImageCleaner::default()
+        };
+        let removed = cleaner
+            .classify(&analyzed_image)
+            .into_iter()
+            .map(Decision::is_removed)
+            .collect();
+        (image, analyzed_image, cleaner, removed)
+    }
+
+    #[test]
+    fn preserve_original_only_changes_removed_graphemes() {
+        let (image, analyzed_image, cleaner, removed) = textured_demo_page();
+        let cleaner = ImageCleaner {
+            preserve_original: true,
+            ..cleaner
+        };
+        let cleaned = cleaner.clean_onto(&analyzed_image, &image).unwrap();
+        for (x, y, pixel) in cleaned.enumerate_pixels() {
+            let expected = match analyzed_image.map[index(image.width(), x, y)] {
+                i if i != u32::MAX && removed[i as usize] => Rgb([255, 0, 255]),
+                _ => *image.get_pixel(x, y),
+            };
+            assert_eq!(*pixel, expected, "({x}, {y})");
+        }
+        assert!(cleaned == cleaner.clean_over(&analyzed_image, &image).unwrap());
+        let dynamic_image = DynamicImage::ImageRgb8(image.clone());
+        let dynamic_analyzed_image = DynamicAnalyzedImage::Rgb8(analyzed_image);
+        assert_eq!(
+            cleaner
+                .clean_dynamic_onto(&dynamic_analyzed_image, &dynamic_image)
+                .unwrap(),
+            DynamicImage::ImageRgb8(cleaned)
+        );
+
+        // Without it, clean_onto is the same as clean.
+        let DynamicAnalyzedImage::Rgb8(analyzed_image) = dynamic_analyzed_image else {
+            unreachable!()
+        };
+        let cleaner = ImageCleaner {
+            preserve_original: false,
+            ..cleaner
+        };
+        assert!(
+            cleaner.clean_onto(&analyzed_image, &image).unwrap() == cleaner.clean(&analyzed_image)
+        );
+    }
+
+    #[test]
+    fn cleaning_onto_another_size_of_image_fails() {
+        let image = blank_page(30, 20);
+        let analyzed_image = ImageAnalyzer::default().analyze(&image);
+        let dynamic_analyzed_image =
+            DynamicAnalyzedImage::Luma8(ImageAnalyzer::default().analyze(&image));
+        let is_mismatch = |error: ImageError| match error {
+            ImageError::Parameter(error) => {
+                error.kind() == error::ParameterErrorKind::DimensionMismatch
+            }
+            _ => false,
+        };
+        for preserve_original in [false, true] {
+            let cleaner = ImageCleaner {
+                preserve_original,
+                ..ImageCleaner::default()
+            };
+            for source in [blank_page(20, 30), blank_page(30, 21), blank_page(0, 0)] {
+                assert!(cleaner
+                    .clean_over(&analyzed_image, &source)
+                    .is_err_and(is_mismatch));
+                assert!(cleaner
+                    .clean_onto(&analyzed_image, &source)
+                    .is_err_and(is_mismatch));
+                assert!(cleaner
+                    .clean_dynamic_onto(&dynamic_analyzed_image, &DynamicImage::ImageLuma8(source))
+                    .is_err_and(is_mismatch));
+            }
+        }
+    }
+
+    #[test]
+    fn clean_rgba_survives_saving_as_png() {
+        let (image, analyzed_image, cleaner, removed) = textured_demo_page();
+        let cleaned = cleaner.clean_rgba(&analyzed_image);
+        for (x, y, pixel) in cleaned.enumerate_pixels() {
+            let expected = match analyzed_image.map[index(image.width(), x, y)] {
+                i if i == u32::MAX || removed[i as usize] => Rgba(cleaner.rgba_fill_color),
+                _ => image.get_pixel(x, y).to_rgba(),
+            };
+            assert_eq!(*pixel, expected, "({x}, {y})");
+        }
+
+        let path = std::env::temp_dir().join(format!(
+            "document_cleaner_{}_clean_rgba.png",
+            std::process::id()
+        ));
+        cleaned.save(&path).unwrap();
+        let loaded = image::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(loaded.color() == ColorType::Rgba8);
+        assert!(loaded.to_rgba8() == cleaned);
+    }
+
+    #[test]
+    fn ink_curve() {
+        let cleaner = ImageCleaner::default();
+        for value in [0u8, 1, 100, 128, 254, 255] {
+            assert_eq!(cleaner.adjust_ink(Luma([value])), Luma([value]));
+        }
+        assert_eq!(
+            cleaner.adjust_ink(Rgb([1000u16, 30000, 65535])),
+            Rgb([1000, 30000, 65535])
+        );
+
+        // A mid gray goes from 128/255 to (128/255)^2 of the way to white, and from there the black point stretches it.
+        let cleaner = ImageCleaner {
+            ink_gamma: 2.0,
+            ..ImageCleaner::default()
+        };
+        assert_eq!(cleaner.adjust_ink(Luma([128u8])), Luma([64]));
+        assert_eq!(cleaner.adjust_ink(Luma([0u8])), Luma([0]));
+        assert_eq!(cleaner.adjust_ink(Luma([255u8])), Luma([255]));
+        let cleaner = ImageCleaner {
+            ink_black_point: 51,
+            ..cleaner
+        };
+        assert_eq!(cleaner.adjust_ink(Luma([128u8])), Luma([36]));
+        assert_eq!(cleaner.adjust_ink(Luma([40u8])), Luma([0]));
+        assert_eq!(cleaner.adjust_ink(Rgb([128u8, 51, 255])), Rgb([36, 0, 255]));
+        // Light ink over a dark fill is made lighter instead.
+        let cleaner = ImageCleaner {
+            ink_black_point: 0,
+            background_fill_color: [0; 3],
+            ..cleaner
+        };
+        assert_eq!(cleaner.adjust_ink(Luma([127u8])), Luma([191]));
+
+        // Drawn by clean, a gray edge next to black ink.
+        let mut image = blank_page(20, 20);
+        fill_rect(&mut image, 5, 5, 5, 10);
+        for y in 5..15 {
+            image.put_pixel(10, y, Luma([128]));
+        }
+        let analyzed_image = ImageAnalyzer::default().analyze(&image);
+        let cleaner = ImageCleaner {
+            speck_size_threshold: 0,
+            page_margins: Margins::from((0, 0)),
+            isolation_size_threshold: 0,
+            ..ImageCleaner::default()
+        };
+        assert!(cleaner.clean(&analyzed_image) == image);
+        let cleaner = ImageCleaner {
+            ink_gamma: 2.0,
+            ..cleaner
+        };
+        let cleaned = cleaner.clean(&analyzed_image);
+        assert_eq!(*cleaned.get_pixel(10, 8), Luma([64]));
+        assert_eq!(*cleaned.get_pixel(7, 8), Luma([0]));
+        assert_eq!(*cleaned.get_pixel(15, 8), Luma([255]));
+    }
+
+    #[test]
+    fn detect_background_of_cream_paper() {
+        let mut image = RgbImage::from_pixel(60, 40, Rgb([240, 230, 200]));
+        for (x, y) in (10..30).flat_map(|x| (10..20).map(move |y| (x, y))) {
+            image.put_pixel(x, y, Rgb([20, 20, 20]));
+        }
+        let analyzed_image = ImageAnalyzer::default().analyze(&image);
+        assert_eq!(
+            ImageCleaner::detect_background(&analyzed_image, &image).unwrap(),
+            [240, 230, 200]
+        );
+
+        for (width, height) in [(59, 40), (60, 41), (0, 0)] {
+            let source = RgbImage::new(width, height);
+            let error = ImageCleaner::detect_background(&analyzed_image, &source).unwrap_err();
+            assert!(matches!(
+                error,
+                ImageError::Parameter(error)
+                    if error.kind() == error::ParameterErrorKind::DimensionMismatch
+            ));
+        }
+    }
+
+    #[test]
+    fn sampled_background_fill() {
+        // Even gray paper on the left, and mottled white paper on the right, with a speck on each.
+        let mut rng = Rng(5);
+        let mut image = GrayImage::from_fn(100, 50, |x, _| match x < 50 {
+            true => Luma([200]),
+            false => Luma([240 + rng.below(16) as u8]),
+        });
+        fill_rect(&mut image, 20, 20, 3, 3);
+        fill_rect(&mut image, 70, 20, 3, 3);
+        let analyzed_image = ImageAnalyzer::default().analyze(&image);
+        assert_eq!(analyzed_image.graphemes.len(), 2);
+        let solid = ImageCleaner {
+            speck_size_threshold: 20,
+            page_margins: Margins::from((0, 0)),
+            speck_fill_color: [128; 3],
+            ..ImageCleaner::default()
+        };
+        let sampled = ImageCleaner {
+            speck_fill_style: FillStyle::SampledBackground { radius: 3 },
+            ..solid.clone()
+        };
+
+        let cleaned = solid.clean_over(&analyzed_image, &image).unwrap();
+        assert_eq!(*cleaned.get_pixel(21, 21), Luma([128]));
+        assert_eq!(*cleaned.get_pixel(71, 21), Luma([128]));
+        let cleaned = sampled.clean_over(&analyzed_image, &image).unwrap();
+        assert_eq!(*cleaned.get_pixel(21, 21), Luma([200]));
+        let fill = *cleaned.get_pixel(71, 21);
+        assert!((240..256).contains(&(fill.0[0] as u32)), "{fill:?}");
+        // The whole speck is one color.
+        for (x, y) in (70..73).flat_map(|x| (20..23).map(move |y| (x, y))) {
+            assert_eq!(*cleaned.get_pixel(x, y), fill);
+        }
+
+        // With no background within the radius, it falls back on the speck fill color.
+        let cleaner = ImageCleaner {
+            speck_fill_style: FillStyle::SampledBackground { radius: 0 },
+            ..solid.clone()
+        };
+        let cleaned = cleaner.clean_over(&analyzed_image, &image).unwrap();
+        assert_eq!(*cleaned.get_pixel(21, 21), Luma([128]));
+        // And without the original, the background is all background_fill_color.
+        let cleaner = ImageCleaner {
+            background_fill_color: [100; 3],
+            ..sampled
+        };
+        let cleaned = cleaner.clean(&analyzed_image);
+        assert_eq!(*cleaned.get_pixel(21, 21), Luma([100]));
+    }
+
+    #[test]
+    fn inverted_demo_page() {
+        let image = demo_page();
+        let mut inverted_image = image.clone();
+        imageops::invert(&mut inverted_image);
+        let analyzed_image = ImageAnalyzer::default().analyze(&image);
+        let inverted = ImageAnalyzer {
+            invert: true,
+            ..ImageAnalyzer::default()
+        }
+        .analyze(&inverted_image);
+
+        assert_eq!(analyzed_image.map, inverted.map);
+        assert_eq!(analyzed_image.graphemes.len(), inverted.graphemes.len());
+        for (a, b) in analyzed_image.graphemes.iter().zip(&inverted.graphemes) {
+            assert_eq!(a.bounds(), b.bounds());
+            assert_eq!(a.pixel_count(), b.pixel_count());
+        }
+    }
+
+    #[test]
+    fn otsu_on_bimodal_histograms() {
+        assert_eq!(otsu_threshold(&[0; 256]), None);
+        let mut histogram = [0; 256];
+        histogram[200] = 10;
+        assert_eq!(otsu_threshold(&histogram), None);
+        histogram[50] = 10;
+        assert_eq!(otsu_threshold(&histogram), Some(50));
+
+        // Ink around 30 and paper around 220, with 9 times as much paper.
+        let mut rng = Rng(7);
+        let mut image = GrayImage::new(100, 100);
+        for pixel in image.pixels_mut() {
+            *pixel = match rng.below(10) {
+                0 => Luma([20 + rng.below(21) as u8]),
+                _ => Luma([200 + rng.below(41) as u8]),
+            };
+        }
+        let suggested = ImageAnalyzer::default().suggest_thresholds(&image);
+        let split = suggested.lightness_threshold - 1;
+        assert!((40..200).contains(&split), "{split}");
+        assert!((split..=240).contains(&suggested.off_white_threshold));
+        assert_eq!(suggested.histogram.iter().sum::<u32>(), 100 * 100);
+    }
+
+    #[test]
+    fn otsu_on_demo_page() {
+        let image = demo_page();
+        let analyzer = ImageAnalyzer::from_otsu(&image);
+        // Black text on white paper, so the split is somewhere in the middle.
+        assert!((100..200).contains(&analyzer.lightness_threshold));
+        assert!((analyzer.lightness_threshold..240).contains(&analyzer.off_white_threshold));
+
+        // Close to the same text as with the hand picked defaults.
+        let count = analyzer.analyze(&image).graphemes.len();
+        let default_count = ImageAnalyzer::default().analyze(&image).graphemes.len();
+        assert!(
+            count.abs_diff(default_count) < default_count / 20,
+            "{count} {default_count}"
+        );
+    }
+
+    #[test]
+    fn otsu_on_blank_and_black_pages() {
+        let analyzer = ImageAnalyzer::from_otsu(&blank_page(20, 20));
+        assert_eq!(
+            (analyzer.off_white_threshold, analyzer.lightness_threshold),
+            (240, 100)
+        );
+        let analyzer = ImageAnalyzer::from_otsu(&GrayImage::new(20, 20));
+        assert_eq!(
+            (analyzer.off_white_threshold, analyzer.lightness_threshold),
+            (1, 1)
+        );
+        // The whole page is one grapheme.
+        let analyzed_image = analyzer.analyze(&GrayImage::new(20, 20));
+        assert_eq!(analyzed_image.graphemes.len(), 1);
+        assert_eq!(analyzed_image.graphemes[0].pixel_count(), 400);
+    }
+
+    #[test]
+    fn cancelled_analysis_returns_promptly() {
+        // Noise, which is full of graphemes and takes a lot longer to analyze than to cancel.
+        let mut rng = Rng(3);
+        let image = GrayImage::from_fn(3000, 3000, |_, _| match rng.below(4) {
+            0 => Luma([0]),
+            _ => Luma([255]),
+        });
+        let analyzer = ImageAnalyzer::default();
+
+        let cancel_token = CancelToken::default();
+        cancel_token.cancel();
+        let start = std::time::Instant::now();
+        assert!(matches!(
+            analyzer.analyze_cancellable(&image, &cancel_token),
+            Err(Cancelled)
+        ));
+        assert!(start.elapsed() < std::time::Duration::from_millis(500));
+
+        let cancel_token = CancelToken::default();
+        let result = std::thread::scope(|scope| {
+            let analysis = scope.spawn(|| analyzer.analyze_cancellable(&image, &cancel_token));
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            cancel_token.cancel();
+            analysis.join().unwrap()
+        });
+        assert!(matches!(result, Err(Cancelled)));
+
+        // Cancelled part way through each pass, nothing more is done.
+        for cancel_at in [0.25, 0.75] {
+            let cancel_token = CancelToken::default();
+            let mut progress_after_cancelling = 0;
+            let mut analyzed_image = AnalyzedImage::default();
+            let result = analyzer.analyze_with(
+                &image,
+                None,
+                analyzer.thresholds(),
+                &mut analyzed_image,
+                |progress| {
+                    if cancel_token.is_cancelled() {
+                        progress_after_cancelling += 1;
+                    } else if progress >= cancel_at {
+                        cancel_token.cancel();
+                    }
+                },
+                Some(&cancel_token),
+            );
+            assert!(matches!(result, Err(AnalyzeError::Cancelled)));
+            assert_eq!(progress_after_cancelling, 0);
+            assert!(analyzed_image.graphemes.is_empty());
+        }
+    }
+
+    #[test]
+    fn diagonal_connectivity() {
+        let mut image = blank_page(30, 30);
+        for i in 5..25 {
+            image.put_pixel(i, i, Luma([0]));
+        }
+        let analyze = |connectivity| {
+            ImageAnalyzer {
+                connectivity,
+                ..ImageAnalyzer::default()
+            }
+            .analyze(&image)
+        };
+
+        let analyzed_image = analyze(Connectivity::Eight);
+        assert_eq!(analyzed_image.graphemes.len(), 1);
+        assert_eq!(analyzed_image.graphemes[0].bounds(), (5, 24, 5, 24));
+        assert_eq!(analyzed_image.graphemes[0].pixel_count(), 20);
+        let analyzed_image = analyze(Connectivity::Four);
+        assert_eq!(analyzed_image.graphemes.len(), 20);
+        assert!(analyzed_image
+            .graphemes
+            .iter()
+            .all(|grapheme| grapheme.pixel_count() == 1));
+    }
+
+    #[test]
+    fn graphemes_on_the_edges() {
+        let mut image = blank_page(20, 20);
+        // 2x2 in each corner, then 2 pixels on the middle of each edge.
+        let marks = [
+            (0, 0, 2, 2),
+            (18, 0, 2, 2),
+            (0, 18, 2, 2),
+            (18, 18, 2, 2),
+            (9, 0, 2, 1),
+            (0, 9, 1, 2),
+            (19, 9, 1, 2),
+            (9, 19, 2, 1),
+        ];
+        for (x, y, width, height) in marks {
+            fill_rect(&mut image, x, y, width, height);
+        }
+        let mut expected: Vec<_> = marks
+            .iter()
+            .map(|&(x, y, width, height)| (y, y + height - 1, x, x + width - 1))
+            .collect();
+        expected.sort_unstable();
+
+        // Including windows reaching past the whole image.
+        for (lightness_distance, threshold_mode) in [
+            (0, ThresholdMode::Global),
+            (1, ThresholdMode::Global),
+            (3, ThresholdMode::Global),
+            (100, ThresholdMode::Global),
+            (1, ThresholdMode::Sauvola { window: 9, k: 0.2 }),
+            (1, ThresholdMode::Sauvola { window: 51, k: 0.2 }),
+        ] {
+            let analyzer = ImageAnalyzer {
+                lightness_distance,
+                threshold_mode,
+                ..ImageAnalyzer::default()
+            };
+            let analyzed_image = analyzer.analyze(&image);
+            let mut bounds: Vec<_> = analyzed_image
+                .graphemes
+                .iter()
+                .map(Grapheme::bounds)
+                .collect();
+            bounds.sort_unstable();
+            assert_eq!(bounds, expected, "{lightness_distance} {threshold_mode:?}");
+        }
+    }
+
+    #[test]
+    fn isolation_metrics() {
+        // Whether a 3x3 speck at (x, y) is kept beside a 400x20 frame at (50, 100), measured by each metric.
+        let decide = |x, y, metric| {
+            let mut image = blank_page(600, 400);
+            fill_rect(&mut image, 50, 100, 400, 2);
+            fill_rect(&mut image, 50, 118, 400, 2);
+            fill_rect(&mut image, 50, 100, 2, 20);
+            fill_rect(&mut image, 448, 100, 2, 20);
+            fill_rect(&mut image, x, y, 3, 3);
+            let analyzed_image = ImageAnalyzer::default().analyze(&image);
+            let cleaner = ImageCleaner {
+                speck_size_threshold: 0,
+                page_margins: Margins::from((0, 0)),
+                isolation_size_threshold: 50,
+                isolation_distance_threshold: 10,
+                isolation_metric: metric,
+                ..ImageCleaner::default()
+            };
+            let speck = analyzed_image
+                .graphemes
+                .iter()
+                .position(|grapheme| grapheme.pixel_count() == 9)
+                .unwrap();
+            cleaner.classify(&analyzed_image)[speck]
+        };
+
+        use Decision::{Kept, RemovedIsolated as Filled};
+        for (x, y, expected) in [
+            // 5px below the middle of the frame.
+            (248, 125, [Kept, Filled, Filled]),
+            // 5px past its end.
+            (455, 108, [Kept, Kept, Filled]),
+            // Inside it, far from the middle.
+            (100, 108, [Kept, Filled, Filled]),
+            // 8px off its corner diagonally.
+            (458, 128, [Kept, Filled, Filled]),
+            (250, 300, [Filled, Filled, Filled]),
+        ] {
+            let decisions = [
+                IsolationMetric::BboxGap,
+                IsolationMetric::BboxEdges,
+                IsolationMetric::CenterEuclidean,
+            ]
+            .map(|metric| decide(x, y, metric));
+            assert_eq!(decisions, expected, "speck at ({x}, {y})");
+        }
+
+        // Right beside something small, every metric agrees.
+        let mut image = blank_page(100, 100);
+        fill_rect(&mut image, 40, 40, 10, 10);
+        fill_rect(&mut image, 53, 44, 2, 2);
+        let analyzed_image = ImageAnalyzer::default().analyze(&image);
+        for isolation_metric in [
+            IsolationMetric::BboxGap,
+            IsolationMetric::BboxEdges,
+            IsolationMetric::CenterEuclidean,
+        ] {
+            let cleaner = ImageCleaner {
+                speck_size_threshold: 0,
+                page_margins: Margins::from((0, 0)),
+                isolation_size_threshold: 50,
+                isolation_distance_threshold: 10,
+                isolation_metric,
+                ..ImageCleaner::default()
+            };
+            assert_eq!(cleaner.classify(&analyzed_image), [Kept, Kept]);
+        }
+    }
+
+    #[test]
+    fn isolation_matches_brute_force() {
+        let mut rng = Rng(0x853c49e6748cea9b);
+        let mut image = blank_page(600, 600);
+        // Letter sized graphemes on a regular grid, and specks and blots scattered around them.
+        for y in (40..280).step_by(20) {
+            for x in (40..280).step_by(14) {
+                fill_rect(&mut image, x, y, 9, 12);
+            }
+        }
+        for _ in 0..400 {
+            let size = if rng.below(10) == 0 { 12 } else { 3 };
+            let (width, height) = (1 + rng.below(size), 1 + rng.below(size));
+            fill_rect(
+                &mut image,
+                rng.below(600 - width),
+                rng.below(600 - height),
+                width,
+                height,
+            );
+        }
+        let analyzed_image = ImageAnalyzer::default().analyze(&image);
+        let graphemes = &analyzed_image.graphemes;
+
+        for isolation_metric in [
+            IsolationMetric::BboxGap,
+            IsolationMetric::BboxEdges,
+            IsolationMetric::CenterEuclidean,
+        ] {
+            for (isolation_distance_threshold, isolation_size_threshold) in
+                [(0, 80), (10, 80), (30, 40), (50, 120), (200, 80), (400, 80)]
+            {
+                // Nothing but the isolation rule.
+                let cleaner = ImageCleaner {
+                    speck_size_threshold: 0,
+                    page_margins: Margins::from((0, 0)),
+                    isolation_distance_threshold,
+                    isolation_size_threshold,
+                    isolation_metric,
+                    ..ImageCleaner::default()
+                };
+                let removed: Vec<bool> = cleaner
+                    .classify(&analyzed_image)
+                    .into_iter()
+                    .map(|decision| decision == Decision::RemovedIsolated)
+                    .collect();
+                let brute_force: Vec<bool> = graphemes
+                    .iter()
+                    .map(|grapheme| {
+                        grapheme.pixel_count() <= isolation_size_threshold as usize
+                            && !graphemes.iter().any(|other| {
+                                !std::ptr::eq(other, grapheme)
+                                    && other.pixel_count() >= isolation_size_threshold as usize
+                                    && cleaner.is_near(grapheme, other)
+                            })
+                    })
+                    .collect();
+                assert_eq!(
+                    removed, brute_force,
+                    "{isolation_metric:?} {isolation_distance_threshold} {isolation_size_threshold}"
+                );
+                assert!(removed.contains(&true) || isolation_distance_threshold >= 200);
+            }
+        }
+    }
+
+    #[test]
+    fn square_and_l_shape_measurements() {
+        let mut image = blank_page(60, 60);
+        fill_rect(&mut image, 20, 30, 10, 10);
+        let analyzed_image = ImageAnalyzer::default().analyze(&image);
+        let square = &analyzed_image.graphemes[0];
+        assert_eq!(square.centroid(), (24.5, 34.5));
+        assert_eq!(square.density(), 1.0);
+        assert_eq!(square.perimeter(), 40);
+
+        let mut image = blank_page(60, 60);
+        let stem = "###";
+        draw(
+            &mut image,
+            10,
+            10,
+            &[
+                stem, stem, stem, stem, stem, stem, stem, "########", "########", "########",
+            ],
+        );
+        let analyzed_image = ImageAnalyzer::default().analyze(&image);
+        let l_shape = &analyzed_image.graphemes[0];
+        assert_eq!(l_shape.pixel_count(), 45);
+        // The stem's 30 pixels centered on (11, 14.5), and the foot's other 15 centered on (15, 18).
+        let (x, y) = l_shape.centroid();
+        assert!(
+            (x - 37.0 / 3.0).abs() < 1e-4 && (y - 47.0 / 3.0).abs() < 1e-4,
+            "({x}, {y})"
+        );
+        assert_eq!(l_shape.density(), 45.0 / 80.0);
+        assert_eq!(l_shape.perimeter(), 36);
+    }
+
+    #[test]
+    fn i_dot_merges_with_its_stem() {
+        let mut image = blank_page(60, 60);
+        // A 3x3 dot 3 pixels above a 3x12 stem.
+        fill_rect(&mut image, 20, 14, 3, 3);
+        fill_rect(&mut image, 20, 20, 3, 12);
+        // A chain of 3 specks 3 pixels apart, only the first near the stem.
+        for x in [26, 31, 36] {
+            fill_rect(&mut image, x, 25, 2, 2);
+        }
+        // Only the speck rule.
+        let cleaner = ImageCleaner {
+            page_margins: Margins::from((0, 0)),
+            isolation_size_threshold: 0,
+            ..ImageCleaner::default()
+        };
+
+        let analyzed_image = ImageAnalyzer::default().analyze(&image);
+        assert_eq!(analyzed_image.graphemes.len(), 5);
+        assert_eq!(
+            cleaner.classify(&analyzed_image)[..2],
+            [Decision::RemovedSpeck, Decision::Kept]
+        );
+
+        let analyzed_image = ImageAnalyzer {
+            merge_distance: 3,
+            ..ImageAnalyzer::default()
+        }
+        .analyze(&image);
+        assert_eq!(analyzed_image.graphemes.len(), 1);
+        let grapheme = &analyzed_image.graphemes[0];
+        assert_eq!(grapheme.bounds(), (14, 31, 20, 37));
+        assert_eq!(grapheme.pixel_count(), 9 + 36 + 12);
+        assert!(grapheme
+            .positions()
+            .all(|(x, y)| analyzed_image.map[index(60, x, y)] == 0));
+        assert_eq!(cleaner.classify(&analyzed_image), [Decision::Kept]);
+    }
+
+    #[test]
+    fn merge_distance_leaves_far_letters_alone() {
+        let mut image = blank_page(60, 30);
+        // Two 6x10 letters 3 pixels apart and a third 15 pixels further on.
+        fill_rect(&mut image, 10, 10, 6, 10);
+        fill_rect(&mut image, 19, 10, 6, 10);
+        fill_rect(&mut image, 40, 10, 6, 10);
+        let analyze = |merge_distance| {
+            ImageAnalyzer {
+                merge_distance,
+                ..ImageAnalyzer::default()
+            }
+            .analyze(&image)
+        };
+
+        assert_eq!(analyze(2).graphemes.len(), 3);
+        for merge_distance in [3, 14] {
+            let analyzed_image = analyze(merge_distance);
+            let bounds: Vec<_> = analyzed_image
+                .graphemes
+                .iter()
+                .map(Grapheme::bounds)
+                .collect();
+            assert_eq!(bounds, [(10, 19, 10, 24), (10, 19, 40, 45)]);
+            for (i, grapheme) in analyzed_image.graphemes.iter().enumerate() {
+                assert_eq!(grapheme.pixel_count(), 60 * (2 - i));
+                assert!(grapheme
+                    .positions()
+                    .all(|(x, y)| analyzed_image.map[index(60, x, y)] == i as u32));
+            }
+        }
+        assert_eq!(analyze(15).graphemes.len(), 1);
+    }
+
+    #[test]
+    fn morph_close_joins_a_broken_stroke() {
+        let mut image = blank_page(40, 40);
+        // A 2x21 stroke with its middle row missing.
+        fill_rect(&mut image, 15, 10, 2, 10);
+        fill_rect(&mut image, 15, 21, 2, 10);
+
+        let analyzed_image = ImageAnalyzer::default().analyze(&image);
+        assert_eq!(analyzed_image.graphemes.len(), 2);
+
+        let analyzed_image = ImageAnalyzer {
+            morph_close: 1,
+            ..ImageAnalyzer::default()
+        }
+        .analyze(&image);
+        assert_eq!(analyzed_image.graphemes.len(), 1);
+        assert_eq!(analyzed_image.graphemes[0].bounds(), (10, 30, 15, 16));
+    }
+
+    #[test]
+    fn pinholes_are_filled_but_counters_are_not() {
+        let mut image = blank_page(60, 60);
+        // An o with an 8x8 counter, and a solid square with a 2 pixel pinhole.
+        fill_rect(&mut image, 5, 20, 12, 12);
+        fill_rect(&mut image, 30, 20, 10, 10);
+        for (x, y) in (7..15).flat_map(|x| (22..30).map(move |y| (x, y))) {
+            image.put_pixel(x, y, Luma([255]));
+        }
+        image.put_pixel(34, 24, Luma([255]));
+        image.put_pixel(35, 24, Luma([255]));
+        let analyzed_image = ImageAnalyzer::default().analyze(&image);
+        assert_eq!(analyzed_image.graphemes.len(), 2);
+
+        let cleaner = ImageCleaner {
+            page_margins: Margins::from((0, 0)),
+            isolation_size_threshold: 0,
+            hole_fill_threshold: 10,
+            ..ImageCleaner::default()
+        };
+        let cleaned = cleaner.clean(&analyzed_image);
+        assert_eq!(*cleaned.get_pixel(10, 25), Luma([255]));
+        assert_eq!(*cleaned.get_pixel(34, 24), Luma([0]));
+        assert_eq!(*cleaned.get_pixel(35, 24), Luma([0]));
+        let changed = image
+            .pixels()
+            .zip(cleaned.pixels())
+            .filter(|(a, b)| a != b)
+            .count();
+        assert_eq!(changed, 2);
+
+        // Unless the threshold is as big as the counter.
+        let cleaner = ImageCleaner {
+            hole_fill_threshold: 64,
+            ..cleaner
+        };
+        assert_eq!(*cleaner.clean(&analyzed_image).get_pixel(10, 25), Luma([0]));
+    }
+
+    #[test]
+    fn binarized_output_has_two_colors() {
+        let analyzer = ImageAnalyzer::default();
+        let analyzed_image = analyzer.analyze(&demo_page());
+        let kept_pixels: usize = ImageCleaner::default()
+            .classify(&analyzed_image)
+            .iter()
+            .zip(&analyzed_image.graphemes)
+            .filter(|(decision, _)| !decision.is_removed())
+            .map(|(_, grapheme)| grapheme.pixel_count())
+            .sum();
+        for binarize_threshold in [None, Some(analyzer.lightness_threshold)] {
+            for cleaner in [
+                ImageCleaner::default(),
+                ImageCleaner {
+                    background_fill_color: [0; 3],
+                    speck_fill_color: [0; 3],
+                    ..ImageCleaner::default()
+                },
+            ] {
+                let cleaner = ImageCleaner {
+                    output_mode: OutputMode::Binarized,
+                    binarize_threshold,
+                    ..cleaner
+                };
+                let cleaned_image = cleaner.clean(&analyzed_image);
+                let mut colors: Vec<_> = cleaned_image.pixels().copied().collect();
+                colors.sort_unstable_by_key(|pixel| pixel.0);
+                colors.dedup();
+                assert_eq!(colors, [Rgb([0; 3]), Rgb([255; 3])]);
+
+                // Without a threshold, every pixel of the kept graphemes is drawn whatever its color.
+                let ink = Rgb(cleaner.binary_color());
+                let ink_pixels = cleaned_image
+                    .pixels()
+                    .filter(|&&pixel| pixel == ink)
+                    .count();
+                match binarize_threshold {
+                    None => assert_eq!(ink_pixels, kept_pixels),
+                    Some(_) => assert!(ink_pixels < kept_pixels),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn long_streak_is_removed() {
+        let mut image = blank_page(100, 600);
+        fill_rect(&mut image, 50, 50, 1, 500);
+        draw(&mut image, 20, 100, FOURTEEN);
+        let analyzed_image = ImageAnalyzer::default().analyze(&image);
+        let cleaner = ImageCleaner {
+            page_margins: Margins::from((0, 0)),
+            isolation_size_threshold: 0,
+            max_aspect_ratio: Some(10.0),
+            ..ImageCleaner::default()
+        };
+
+        assert_eq!(
+            cleaner.classify(&analyzed_image),
+            [Decision::RemovedLong, Decision::Kept, Decision::Kept]
+        );
+        let cleaner = ImageCleaner {
+            remove_vertical_streaks: false,
+            ..cleaner
+        };
+        assert_eq!(cleaner.classify(&analyzed_image)[0], Decision::Kept);
+    }
+
+    #[test]
+    fn streak_limits() {
+        let mut image = blank_page(300, 300);
+        // A vertical streak running down between the words of a line of text.
+        fill_rect(&mut image, 150, 20, 1, 250);
+        draw(&mut image, 120, 100, FOURTEEN);
+        draw(&mut image, 160, 100, FOURTEEN);
+        // A thick horizontal rule and a short dash.
+        fill_rect(&mut image, 50, 280, 200, 4);
+        fill_rect(&mut image, 20, 200, 12, 1);
+        let analyzed_image = ImageAnalyzer::default().analyze(&image);
+        let index = |x, y| {
+            analyzed_image
+                .graphemes
+                .iter()
+                .position(|grapheme| (grapheme.left, grapheme.top) == (x, y))
+                .unwrap()
+        };
+        let (streak, rule, dash) = (index(150, 20), index(50, 280), index(20, 200));
+        // Which of the streak, the rule, and the dash are removed, checking that the text never is.
+        let removed = |cleaner: &ImageCleaner| {
+            let decisions = cleaner.classify(&analyzed_image);
+            for (i, decision) in decisions.iter().enumerate() {
+                if ![streak, rule, dash].contains(&i) {
+                    assert_eq!(*decision, Decision::Kept);
+                }
+            }
+            [streak, rule, dash].map(|i| decisions[i] == Decision::RemovedLong)
+        };
+        let cleaner = ImageCleaner {
+            page_margins: Margins::from((0, 0)),
+            isolation_size_threshold: 0,
+            speck_size_threshold: 0,
+            max_aspect_ratio: Some(10.0),
+            ..ImageCleaner::default()
+        };
+        assert_eq!(analyzed_image.graphemes.len(), 7);
+        assert_eq!(removed(&cleaner), [true, true, true]);
+
+        let thin = ImageCleaner {
+            max_streak_thickness: Some(2),
+            ..cleaner.clone()
+        };
+        assert_eq!(removed(&thin), [true, false, true]);
+        let long = ImageCleaner {
+            min_streak_length: 50,
+            ..cleaner.clone()
+        };
+        assert_eq!(removed(&long), [true, true, false]);
+        let horizontal_only = ImageCleaner {
+            remove_vertical_streaks: false,
+            ..cleaner.clone()
+        };
+        assert_eq!(removed(&horizontal_only), [false, true, true]);
+        let vertical_only = ImageCleaner {
+            remove_horizontal_streaks: false,
+            ..cleaner
+        };
+        assert_eq!(removed(&vertical_only), [true, false, false]);
+    }
+
+    #[test]
+    fn fill_ratios() {
+        let mut image = blank_page(60, 30);
+        fill_rect(&mut image, 10, 10, 10, 10);
+        // A comma, 13 pixels in a 4x6 box.
+        draw(
+            &mut image,
+            40,
+            10,
+            &[" ###", " ###", "  ##", "  # ", " ## ", "##  "],
+        );
+        let analyzed_image = ImageAnalyzer::default().analyze(&image);
+        assert_eq!(analyzed_image.graphemes[0].density(), 1.0);
+        assert!((analyzed_image.graphemes[1].density() - 13.0 / 24.0).abs() < 1e-6);
+        let cleaner = ImageCleaner {
+            page_margins: Margins::from((0, 0)),
+            isolation_size_threshold: 0,
+            speck_size_threshold: 0,
+            ..ImageCleaner::default()
+        };
+        assert_eq!(
+            cleaner.classify(&analyzed_image),
+            [Decision::Kept, Decision::Kept]
+        );
+
+        let cleaner = ImageCleaner {
+            max_fill_ratio: Some(0.9),
+            min_fill_ratio: Some(0.6),
+            ..cleaner
+        };
+        assert_eq!(
+            cleaner.classify(&analyzed_image),
+            [Decision::RemovedDense, Decision::RemovedSparse]
+        );
+        // The square is too big to be filled for its density, the comma isn't.
+        let cleaner = ImageCleaner {
+            fill_ratio_size_limit: Some(50),
+            ..cleaner
+        };
+        assert_eq!(
+            cleaner.classify(&analyzed_image),
+            [Decision::Kept, Decision::RemovedSparse]
+        );
+        let cleaner = ImageCleaner {
+            size_metric: SizeMetric::BoundingBoxArea,
+            fill_ratio_size_limit: Some(20),
+            ..cleaner
+        };
+        assert_eq!(
+            cleaner.classify(&analyzed_image),
+            [Decision::Kept, Decision::Kept]
+        );
+    }
+
+    #[test]
+    fn bounding_box_area_counts_sparse_graphemes_as_big() {
+        // A 1px outline of a 30x10 box, 76 pixels in a box of 300.
+        let mut image = blank_page(50, 40);
+        fill_rect(&mut image, 10, 10, 30, 1);
+        fill_rect(&mut image, 10, 19, 30, 1);
+        fill_rect(&mut image, 10, 10, 1, 10);
+        fill_rect(&mut image, 39, 10, 1, 10);
+        // And a solid 9x9 square, 81 pixels either way.
+        fill_rect(&mut image, 20, 25, 9, 9);
+        let analyzed_image = ImageAnalyzer::default().analyze(&image);
+        assert_eq!(analyzed_image.graphemes[0].pixel_count(), 76);
+        assert_eq!(
+            analyzed_image.graphemes[0].size(SizeMetric::BoundingBoxArea),
+            300
+        );
+        let cleaner = |size_metric| ImageCleaner {
+            speck_size_threshold: 100,
+            size_metric,
+            page_margins: Margins::from((0, 0)),
+            isolation_size_threshold: 0,
+            ..ImageCleaner::default()
+        };
+
+        assert_eq!(
+            cleaner(SizeMetric::PixelCount).classify(&analyzed_image),
+            [Decision::RemovedSpeck, Decision::RemovedSpeck]
+        );
+        assert_eq!(
+            cleaner(SizeMetric::BoundingBoxArea).classify(&analyzed_image),
+            [Decision::Kept, Decision::RemovedSpeck]
+        );
+    }
+
+    #[test]
+    fn large_grapheme_fraction() {
+        let mut image = blank_page(100, 100);
+        // 40% of the page.
+        fill_rect(&mut image, 10, 10, 80, 50);
+        let analyzed_image = ImageAnalyzer::default().analyze(&image);
+        let cleaner = |max_grapheme_area_fraction| ImageCleaner {
+            page_margins: Margins::from((0, 0)),
+            max_grapheme_area_fraction,
+            ..ImageCleaner::default()
+        };
+
+        assert_eq!(
+            cleaner(Some(0.3)).classify(&analyzed_image),
+            [Decision::RemovedLarge]
+        );
+        assert_eq!(
+            cleaner(Some(0.5)).classify(&analyzed_image),
+            [Decision::Kept]
+        );
+        assert_eq!(cleaner(None).classify(&analyzed_image), [Decision::Kept]);
+    }
+
+    #[test]
+    fn demo_page_periods_survive_a_high_speck_threshold() {
+        let mut image = demo_page();
+        let analyzed_image = ImageAnalyzer::default().analyze(&image);
+        // The demo page's periods are all the same 35 pixel dot.
+        let periods: Vec<_> = analyzed_image
+            .graphemes
+            .iter()
+            .zip(ImageCleaner::default().classify(&analyzed_image))
+            .filter(|(grapheme, decision)| grapheme.pixel_count() == 35 && !decision.is_removed())
+            .map(|(grapheme, _)| grapheme.bounds())
+            .collect();
+        assert_eq!(periods.len(), 6);
+
+        // Copies of one in blank areas, or near text but not beside a letter, which are specks.
+        let (top, bottom, left, right) = periods[0];
+        let specks = [
+            (1000, 120),
+            (150, 1500),
+            (2200, 2000),
+            (1200, 3050),
+            (1100, 1330),
+        ];
+        for (x, y) in specks {
+            for (dx, dy) in
+                (0..=right - left).flat_map(|dx| (0..=bottom - top).map(move |dy| (dx, dy)))
+            {
+                let pixel = *image.get_pixel(left + dx, top + dy);
+                image.put_pixel(x + dx, y + dy, pixel);
+            }
+        }
+        let analyzed_image = ImageAnalyzer::default().analyze(&image);
+        let decisions = |cleaner: ImageCleaner| {
+            let decisions = cleaner.classify(&analyzed_image);
+            let decision = |(x, y)| {
+                let i = analyzed_image.map[index(image.width(), x, y)];
+                decisions[i as usize]
+            };
+            // Going by the dots' middle pixels.
+            let period_decisions: Vec<_> = periods
+                .iter()
+                .map(|&(top, bottom, left, right)| {
+                    decision(((left + right) / 2, (top + bottom) / 2))
+                })
+                .collect();
+            let speck_decisions: Vec<_> = specks
+                .iter()
+                .map(|&(x, y)| decision((x + (right - left) / 2, y + (bottom - top) / 2)))
+                .collect();
+            (period_decisions, speck_decisions)
+        };
+
+        let cleaner = ImageCleaner {
+            speck_size_threshold: 40,
+            ..ImageCleaner::default()
+        };
+        assert_eq!(
+            decisions(cleaner.clone()),
+            (
+                vec![Decision::RemovedSpeck; 6],
+                vec![Decision::RemovedSpeck; 5]
+            )
+        );
+        let cleaner = ImageCleaner {
+            protect_punctuation: true,
+            ..cleaner
+        };
+        assert_eq!(
+            decisions(cleaner),
+            (vec![Decision::Kept; 6], vec![Decision::RemovedSpeck; 5])
+        );
+    }
 }
@@ -0,0 +1,219 @@
+// Groups an analyzed image's graphemes into the lines of text they're on.
+
+use crate::{AnalyzedImage, Grapheme, GraphemeId};
+
+// How many times taller than the typical grapheme one can be and still be text, anything taller is an illustration or
+// a rule.
+const MAX_HEIGHT_RATIO: u32 = 3;
+// How far apart neighboring graphemes on a line can be, in typical grapheme heights. Gaps between words are a lot
+// smaller, gaps between columns are bigger.
+const MAX_GAP_RATIO: u32 = 3;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TextLine {
+    // From left to right.
+    pub graphemes: Vec<GraphemeId>,
+    pub top: u32,
+    pub bottom: u32,
+    pub left: u32,
+    pub right: u32,
+    // The line through the bottoms of the line's letters, as y = baseline_slope * x + baseline_offset.
+    pub baseline_slope: f32,
+    pub baseline_offset: f32,
+}
+
+impl TextLine {
+    pub fn baseline_at(&self, x: f32) -> f32 {
+        self.baseline_slope * x + self.baseline_offset
+    }
+}
+
+impl<P> AnalyzedImage<P> {
+    // The lines of text on the page from top to bottom, found by following graphemes of about the same height from left
+    // to right while they overlap vertically. Graphemes that don't line up with any others (like illustrations, rules,
+    // and stray marks) aren't in any line.
+    pub fn detect_lines(&self) -> Vec<TextLine> {
+        let mut heights: Vec<u32> = self.graphemes.iter().map(Grapheme::height).collect();
+        if heights.is_empty() {
+            return Vec::new();
+        }
+        let middle = heights.len() / 2;
+        let (_, &mut typical_height, _) = heights.select_nth_unstable(middle);
+        let max_gap = typical_height * MAX_GAP_RATIO;
+
+        let mut candidates: Vec<usize> = (0..self.graphemes.len())
+            .filter(|&i| self.graphemes[i].height() <= typical_height * MAX_HEIGHT_RATIO)
+            .collect();
+        candidates.sort_by_key(|&i| self.graphemes[i].left);
+
+        // Each line follows the last full height grapheme added to it, so it keeps up with lines that aren't quite
+        // level. Small ones like periods and the dots on i's join lines but don't lead them.
+        struct Line {
+            graphemes: Vec<usize>,
+            right: u32,
+            top: u32,
+            bottom: u32,
+        }
+        let mut lines: Vec<Line> = Vec::new();
+        for i in candidates {
+            let grapheme = &self.graphemes[i];
+            let is_full_height = grapheme.height() * 2 >= typical_height;
+
+            // The nearby line the grapheme overlaps the most, by at least half of whichever is shorter.
+            let best = lines
+                .iter()
+                .enumerate()
+                .filter(|(_, line)| grapheme.left <= line.right.saturating_add(max_gap))
+                .map(|(j, line)| {
+                    let overlap = (grapheme.bottom.min(line.bottom) + 1)
+                        .saturating_sub(grapheme.top.max(line.top));
+                    (
+                        j,
+                        overlap,
+                        (line.bottom - line.top + 1).min(grapheme.height()),
+                    )
+                })
+                .filter(|&(_, overlap, shorter)| overlap * 2 >= shorter)
+                .max_by_key(|&(_, overlap, _)| overlap);
+
+            match best {
+                Some((j, _, _)) => {
+                    let line = &mut lines[j];
+                    line.graphemes.push(i);
+                    line.right = line.right.max(grapheme.right);
+                    if is_full_height {
+                        line.top = grapheme.top;
+                        line.bottom = grapheme.bottom;
+                    }
+                }
+                None if is_full_height => lines.push(Line {
+                    graphemes: vec![i],
+                    right: grapheme.right,
+                    top: grapheme.top,
+                    bottom: grapheme.bottom,
+                }),
+                None => {}
+            }
+        }
+
+        let mut text_lines: Vec<TextLine> = lines
+            .into_iter()
+            .filter(|line| line.graphemes.len() >= 2)
+            .map(|line| self.text_line(line.graphemes, typical_height))
+            .collect();
+        text_lines.sort_by_key(|line| (line.top, line.left));
+
+        text_lines
+    }
+
+    fn text_line(&self, graphemes: Vec<usize>, typical_height: u32) -> TextLine {
+        let members = || graphemes.iter().map(|&i| &self.graphemes[i]);
+        let top = members().map(|g| g.top).min().unwrap_or(0);
+        let bottom = members().map(|g| g.bottom).max().unwrap_or(0);
+        let left = members().map(|g| g.left).min().unwrap_or(0);
+        let right = members().map(|g| g.right).max().unwrap_or(0);
+
+        // A least squares fit through the bottom middles of the full height graphemes, or all of them if there aren't
+        // two of those.
+        let mut points: Vec<(f32, f32)> = members()
+            .filter(|g| g.height() * 2 >= typical_height)
+            .map(|g| ((g.left + g.right) as f32 / 2.0, g.bottom as f32))
+            .collect();
+        if points.len() < 2 {
+            points = members()
+                .map(|g| ((g.left + g.right) as f32 / 2.0, g.bottom as f32))
+                .collect();
+        }
+        let count = points.len() as f32;
+        let mean_x = points.iter().map(|p| p.0).sum::<f32>() / count;
+        let mean_y = points.iter().map(|p| p.1).sum::<f32>() / count;
+        let covariance: f32 = points.iter().map(|p| (p.0 - mean_x) * (p.1 - mean_y)).sum();
+        let variance: f32 = points.iter().map(|p| (p.0 - mean_x).powi(2)).sum();
+        let baseline_slope = if variance > 0.0 {
+            covariance / variance
+        } else {
+            0.0
+        };
+
+        TextLine {
+            graphemes: graphemes
+                .into_iter()
+                .map(|i| GraphemeId(i as u32))
+                .collect(),
+            top,
+            bottom,
+            left,
+            right,
+            baseline_slope,
+            baseline_offset: mean_y - baseline_slope * mean_x,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{blank_page, demo_page, draw, fill_rect, FOURTEEN};
+    use crate::ImageAnalyzer;
+
+    #[test]
+    fn two_lines_and_a_rule() {
+        let mut image = blank_page(200, 100);
+        for x in [20, 40, 60] {
+            draw(&mut image, x, 20, FOURTEEN);
+            draw(&mut image, x, 50, FOURTEEN);
+        }
+        // A period at the end of the first line, and a rule too tall to be text.
+        fill_rect(&mut image, 78, 27, 3, 3);
+        fill_rect(&mut image, 150, 10, 2, 80);
+        let analyzed_image = ImageAnalyzer::default().analyze(&image);
+        let lines = analyzed_image.detect_lines();
+
+        assert_eq!(lines.len(), 2);
+        for (line, top) in lines.iter().zip([20, 50]) {
+            assert_eq!((line.top, line.bottom, line.left), (top, top + 9, 20));
+            assert!(line.baseline_slope.abs() < 1e-6);
+            assert!((line.baseline_at(100.0) - (top + 9) as f32).abs() < 1e-3);
+            let lefts: Vec<u32> = line
+                .graphemes
+                .iter()
+                .map(|&id| analyzed_image.graphemes[id.index()].left)
+                .collect();
+            assert!(lefts.windows(2).all(|pair| pair[0] <= pair[1]));
+        }
+        assert_eq!((lines[0].graphemes.len(), lines[0].right), (7, 80));
+        assert_eq!((lines[1].graphemes.len(), lines[1].right), (6, 75));
+    }
+
+    #[test]
+    fn demo_page_lines() {
+        let analyzed_image = ImageAnalyzer::default().analyze(&demo_page());
+        let lines = analyzed_image.detect_lines();
+        assert!((40..=60).contains(&lines.len()), "{}", lines.len());
+        assert!(lines.windows(2).all(|pair| pair[0].top <= pair[1].top));
+
+        let mut line_counts = vec![0; analyzed_image.graphemes.len()];
+        for line in &lines {
+            for id in &line.graphemes {
+                line_counts[id.index()] += 1;
+            }
+        }
+        assert!(line_counts.iter().all(|&count| count <= 1));
+        // Every letter at least as tall as the typical one (ascenders and capitals) in the body of the page is on a
+        // line. The stray marks below it are on their own.
+        let mut heights: Vec<u32> = analyzed_image
+            .graphemes
+            .iter()
+            .map(Grapheme::height)
+            .collect();
+        heights.sort_unstable();
+        let typical_height = heights[heights.len() / 2];
+        for (grapheme, count) in analyzed_image.graphemes.iter().zip(line_counts) {
+            if grapheme.top < 2950
+                && (typical_height..=typical_height * MAX_HEIGHT_RATIO).contains(&grapheme.height())
+            {
+                assert_eq!(count, 1, "{:?}", grapheme.bounds());
+            }
+        }
+    }
+}
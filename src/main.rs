@@ -1,16 +1,34 @@
 //#![cfg_attr(not(debug_assertions), windows_subsystem = "windows")] // hide console window on Windows in release
 
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use eframe::egui::*;
 use image::*;
 use image_cleanup::*;
 use tokio::task::JoinHandle;
 
+mod headless;
+
+const OPEN_SHORTCUT: KeyboardShortcut = KeyboardShortcut::new(Modifiers::COMMAND, Key::O);
+const REIMPORT_SHORTCUT: KeyboardShortcut = KeyboardShortcut::new(Modifiers::COMMAND, Key::R);
+const EXPORT_SHORTCUT: KeyboardShortcut = KeyboardShortcut::new(Modifiers::COMMAND, Key::E);
+const FIT_SHORTCUT: KeyboardShortcut = KeyboardShortcut::new(Modifiers::NONE, Key::F);
+const UNDO_SHORTCUT: KeyboardShortcut = KeyboardShortcut::new(Modifiers::COMMAND, Key::Z);
+const REDO_SHORTCUT: KeyboardShortcut =
+    KeyboardShortcut::new(Modifiers::COMMAND.plus(Modifiers::SHIFT), Key::Z);
+
 #[tokio::main]
 async fn main() -> Result<(), eframe::Error> {
     env_logger::init(); // Log to stderr (if you run with `RUST_LOG=debug`).
+
+    let mut args = std::env::args().skip(1).peekable();
+    if args.next_if(|arg| arg == "--headless").is_some() {
+        std::process::exit(headless::run(args));
+    }
+
     let options = eframe::NativeOptions {
         viewport: ViewportBuilder::default()
             .with_maximized(true)
@@ -26,25 +44,78 @@ async fn main() -> Result<(), eframe::Error> {
 }
 
 struct ImageCleanup {
+    // The preview page's settings, which the parameters edit.
     analyzer: ImageAnalyzer,
     cleaner: ImageCleaner,
+    // The settings of the pages that have their own, by page number. The preview page's are only brought up to date
+    // here when another page is shown, until then they're in analyzer and cleaner.
+    page_settings: PageSettings,
+    // The settings of every other page, while the preview page has its own.
+    global_settings: Option<(ImageAnalyzer, ImageCleaner)>,
 
     preview_image_width: u32,
     preview_image_height: u32,
-    analyzed_preview_image: Arc<Mutex<AnalyzedImage>>,
-    cleaned_preview_image: Arc<Mutex<RgbImage>>,
+    analyzed_preview_image: Arc<Mutex<DynamicAnalyzedImage>>,
+    cleaned_preview_image: Arc<Mutex<DynamicImage>>,
+    // The preview page as it was analyzed, for cleaning over with preserve_original.
+    source_preview_image: Arc<DynamicImage>,
+    // What the preview clean did with each grapheme, filled in while it's cleaning and then copied to preview_report.
+    cleaned_preview_report: Arc<Mutex<CleanReport>>,
+    preview_report: CleanReport,
+    // Of the analyzer, the cleaner, and the preview colors.
+    history: History,
     preview_image_handle: TextureHandle,
+    // The preview page before cleaning, for comparing against.
+    original_image_handle: TextureHandle,
+    // Pixels clicked in the preview whose graphemes' overrides haven't been cycled yet.
+    override_clicks: Vec<(u32, u32)>,
+    // The corners of the rectangle shift-dragged over the preview, in image pixels.
+    selection: Option<(Vec2, Vec2)>,
+    // Whether the drag in progress is drawing the selection instead of panning.
+    selecting: bool,
+    // Selections whose graphemes' overrides haven't been set yet, with what to set them to.
+    override_selections: Vec<(math::Rect, Option<bool>)>,
+    // What went wrong saving or loading the preview page's overrides file, or how many of its overrides were skipped.
+    overrides_error: Arc<Mutex<Option<String>>>,
+    // How many of the preview page's graphemes have from 2^i up to 2^(i + 1) pixels, for picking the speck size
+    // threshold.
+    size_histogram: Vec<usize>,
+    thumbnails: Thumbnails,
+    // The page the filmstrip was last scrolled to, so it only follows the preview page when that changes.
+    filmstrip_page: u16,
 
     image_paths: Vec<PathBuf>,
+    // Straighten pages before analyzing them.
+    auto_deskew: bool,
+    // Swap the left and right margins on even pages, for books scanned a page at a time.
+    mirror_margins: bool,
 
     analyze_preview_task: Option<JoinHandle<()>>,
+    analyze_preview_cancel_token: CancelToken,
     clean_preview_task: Option<JoinHandle<()>>,
+    clean_preview_cancel_token: CancelToken,
     export_task: Option<JoinHandle<()>>,
+    export_cancel_token: CancelToken,
+    // How many pages get exported at once.
+    #[cfg(feature = "parallel")]
+    export_threads: usize,
     export_progess: Arc<Mutex<f32>>,
+    // Of the last export, filled in once it's done.
+    export_summary: Arc<Mutex<ExportSummary>>,
+    // Pages that come out with less of their area in kept clusters than this are listed after exporting.
+    min_foreground_fraction: Option<f32>,
+    export_directory: Option<PathBuf>,
+    output_format: OutputFormat,
+    confirming_overwrite: bool,
+    #[cfg(feature = "serde")]
+    preset_error: Option<String>,
+    stats_error: Option<String>,
 
     // Preview settings
     previews_needs_analyze: bool,
     previews_needs_clean: bool,
+    // The next analysis is of a different page, so the overrides on the current one don't carry over.
+    previews_new_page: bool,
     preview_page: u16,
     preview_speck_fill_color: [u8; 3],
     preview_background_fill_color: [u8; 3],
@@ -54,7 +125,20 @@ struct ImageCleanup {
     preview_max_zoom: f32,
     preview_offset: Vec2,   // In image pixels
     preview_velocity: Vec2, // In image pixels
+    // How many image pixels there are to a ui point at zoom 0, as of the last time the preview was drawn.
+    preview_largest_dimension: f32,
     preview_margin_color: Color32,
+    preview_view: PreviewView,
+    // Where the divider is in the split view, as a fraction of the page's width.
+    preview_split: f32,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum PreviewView {
+    Cleaned,
+    Original,
+    // The original left of a divider and the cleaned page right of it.
+    Split,
 }
 
 fn rgb_image_to_color_image(image: &RgbImage) -> ColorImage {
@@ -76,10 +160,409 @@ fn rgb_image_to_handle(ctx: &Context, name: impl Into<String>, image: &RgbImage)
     )
 }
 
-fn demo_image() -> RgbImage {
+// The resolution pages are placed in exported PDFs at, since the images don't say what they were scanned at.
+const PDF_DPI: f32 = 300.0;
+
+// How big the filmstrip's thumbnails are on their longest side.
+const THUMBNAIL_SIZE: u32 = 96;
+// At most this many thumbnails are kept, the ones that have been out of view the longest are dropped first.
+const MAX_THUMBNAILS: usize = 120;
+// How many pages get decoded for thumbnails at once.
+const THUMBNAIL_THREADS: usize = 4;
+
+// A page's path and its thumbnail, or None if it couldn't be read.
+type Thumbnail = (PathBuf, Option<RgbImage>);
+
+// Small previews of the pages for the filmstrip, decoded in the background as they're scrolled into view.
+#[derive(Default)]
+struct Thumbnails {
+    // With the frame they were last shown in.
+    textures: HashMap<PathBuf, (TextureHandle, u64)>,
+    // Pages being decoded, including the ones in decoded that haven't been made into textures yet.
+    decoding: HashSet<PathBuf>,
+    decoded: Arc<Mutex<Vec<Thumbnail>>>,
+    failed: HashSet<PathBuf>,
+    frame: u64,
+}
+
+impl Thumbnails {
+    // Draws the filmstrip, and gives back the index of the page that was clicked if any.
+    fn show(
+        &mut self,
+        ui: &mut Ui,
+        image_paths: &[PathBuf],
+        current: usize,
+        scroll_to_current: bool,
+    ) -> Option<usize> {
+        self.frame += 1;
+        for (path, image) in std::mem::take(&mut *self.decoded.lock().unwrap()) {
+            self.decoding.remove(&path);
+            match image {
+                Some(image) => {
+                    let name = format!("thumbnail {}", path.display());
+                    let handle = rgb_image_to_handle(ui.ctx(), name, &image);
+                    self.textures.insert(path, (handle, self.frame));
+                }
+                None => {
+                    self.failed.insert(path);
+                }
+            }
+        }
+
+        let item_size = Vec2::splat(THUMBNAIL_SIZE as f32 + 8.0);
+        let mut clicked = None;
+        ScrollArea::horizontal().show_viewport(ui, |ui, viewport| {
+            let (strip, _) = ui.allocate_exact_size(
+                vec2(item_size.x * image_paths.len() as f32, item_size.y),
+                Sense::hover(),
+            );
+            let item_rect = |i: usize| {
+                Rect::from_min_size(strip.min + vec2(i as f32 * item_size.x, 0.0), item_size)
+            };
+
+            // Only the pages in view are drawn, and only their thumbnails get decoded.
+            let first = (viewport.min.x / item_size.x).max(0.0) as usize;
+            let last = ((viewport.max.x / item_size.x).ceil() as usize).min(image_paths.len());
+            for (i, path) in image_paths.iter().enumerate().take(last).skip(first) {
+                let rect = item_rect(i);
+                let response = ui
+                    .interact(rect, ui.id().with(i), Sense::click())
+                    .on_hover_text(path.file_name().unwrap_or_default().to_string_lossy());
+                let inner = rect.shrink(4.0);
+                if let Some((handle, last_shown)) = self.textures.get_mut(path) {
+                    *last_shown = self.frame;
+                    let size = handle.size_vec2();
+                    let scale = (inner.width() / size.x).min(inner.height() / size.y);
+                    ui.painter().image(
+                        handle.id(),
+                        Rect::from_center_size(inner.center(), size * scale),
+                        Rect::from_min_max(pos2(0.0, 0.0), pos2(1.0, 1.0)),
+                        Color32::WHITE,
+                    );
+                } else {
+                    ui.painter()
+                        .rect_filled(inner, 2.0, ui.visuals().extreme_bg_color);
+                    let label = if self.failed.contains(path) {
+                        "?".to_string()
+                    } else {
+                        self.request(ui.ctx(), path);
+                        (i + 1).to_string()
+                    };
+                    ui.painter().text(
+                        inner.center(),
+                        Align2::CENTER_CENTER,
+                        label,
+                        TextStyle::Body.resolve(ui.style()),
+                        ui.visuals().weak_text_color(),
+                    );
+                }
+
+                if i == current {
+                    let color = ui.visuals().selection.stroke.color;
+                    ui.painter()
+                        .rect_stroke(rect.shrink(1.0), 2.0, Stroke::new(2.0, color));
+                } else if response.hovered() {
+                    let stroke = ui.visuals().widgets.hovered.fg_stroke;
+                    ui.painter().rect_stroke(rect.shrink(1.0), 2.0, stroke);
+                }
+                if response.clicked() {
+                    clicked = Some(i);
+                }
+            }
+
+            if scroll_to_current {
+                ui.scroll_to_rect(item_rect(current), Some(Align::Center));
+            }
+        });
+        self.evict();
+
+        clicked
+    }
+
+    fn request(&mut self, ctx: &Context, path: &Path) {
+        if self.decoding.len() >= THUMBNAIL_THREADS || self.decoding.contains(path) {
+            return;
+        }
+
+        self.decoding.insert(path.to_path_buf());
+        let (path, decoded, ctx) = (path.to_path_buf(), self.decoded.clone(), ctx.clone());
+        tokio::task::spawn_blocking(move || {
+            let image = image::open(&path)
+                .ok()
+                .map(|image| image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE).to_rgb8());
+            decoded.lock().unwrap().push((path, image));
+            ctx.request_repaint();
+        });
+    }
+
+    // Drops the thumbnails that have been out of view the longest, so big imports don't keep every page's around.
+    fn evict(&mut self) {
+        let excess = self.textures.len().saturating_sub(MAX_THUMBNAILS);
+        if excess == 0 {
+            return;
+        }
+
+        let mut hidden: Vec<(u64, PathBuf)> = self
+            .textures
+            .iter()
+            .filter(|(_, &(_, last_shown))| last_shown < self.frame)
+            .map(|(path, &(_, last_shown))| (last_shown, path.clone()))
+            .collect();
+        hidden.sort_unstable();
+        for (_, path) in hidden.into_iter().take(excess) {
+            self.textures.remove(&path);
+        }
+    }
+}
+
+// How many parameter changes can be undone.
+const MAX_UNDO: usize = 100;
+// Changes closer together than this, like the steps of dragging a slider, are undone all at once.
+const UNDO_DEBOUNCE: Duration = Duration::from_millis(500);
+
+// Everything undo and redo go back and forth between.
+#[derive(Clone, PartialEq)]
+struct Parameters {
+    analyzer: ImageAnalyzer,
+    cleaner: ImageCleaner,
+    preview_speck_fill_color: [u8; 3],
+    preview_background_fill_color: [u8; 3],
+}
+
+struct History {
+    // Oldest first.
+    undo: VecDeque<Parameters>,
+    redo: Vec<Parameters>,
+    // The parameters as of the last change that was recorded.
+    current: Parameters,
+    // A change that hasn't been recorded yet because it might still be changing, with when it last changed.
+    pending: Option<(Parameters, Instant)>,
+}
+
+impl History {
+    fn new(current: Parameters) -> Self {
+        Self {
+            undo: VecDeque::new(),
+            redo: Vec::new(),
+            current,
+            pending: None,
+        }
+    }
+
+    // Called every frame with the parameters as they are. A change is recorded once it's stayed the same for
+    // UNDO_DEBOUNCE, and until then this gives back how much longer that is, so there's a frame to record it in.
+    fn update(&mut self, parameters: Parameters) -> Option<Duration> {
+        if parameters == self.current {
+            self.pending = None;
+            return None;
+        }
+        match &self.pending {
+            Some((pending, changed_at)) if *pending == parameters => {
+                let elapsed = changed_at.elapsed();
+                if elapsed < UNDO_DEBOUNCE {
+                    return Some(UNDO_DEBOUNCE - elapsed);
+                }
+                self.record(parameters);
+                None
+            }
+            _ => {
+                self.pending = Some((parameters, Instant::now()));
+                Some(UNDO_DEBOUNCE)
+            }
+        }
+    }
+
+    fn record(&mut self, parameters: Parameters) {
+        let previous = std::mem::replace(&mut self.current, parameters);
+        self.undo.push_back(previous);
+        if self.undo.len() > MAX_UNDO {
+            self.undo.pop_front();
+        }
+        self.redo.clear();
+        self.pending = None;
+    }
+
+    // Gives back the parameters to go back to, given the ones there are now.
+    fn undo(&mut self, parameters: Parameters) -> Option<Parameters> {
+        // A change that hasn't been recorded yet is the first to be undone.
+        if parameters != self.current {
+            self.record(parameters);
+        }
+        let previous = self.undo.pop_back()?;
+        self.redo
+            .push(std::mem::replace(&mut self.current, previous.clone()));
+        Some(previous)
+    }
+
+    fn redo(&mut self, parameters: Parameters) -> Option<Parameters> {
+        // Anything changed since the last undo replaces what could be redone.
+        if parameters != self.current {
+            self.record(parameters);
+            return None;
+        }
+        let next = self.redo.pop()?;
+        self.undo
+            .push_back(std::mem::replace(&mut self.current, next.clone()));
+        Some(next)
+    }
+
+    fn can_undo(&self, parameters: &Parameters) -> bool {
+        !self.undo.is_empty() || *parameters != self.current
+    }
+
+    fn can_redo(&self, parameters: &Parameters) -> bool {
+        !self.redo.is_empty() && *parameters == self.current
+    }
+}
+
+// Cycles the manual override of the grapheme at (x, y), if there is one, from none to always keep to always remove.
+fn cycle_override(analyzed_image: &mut DynamicAnalyzedImage, x: u32, y: u32) {
+    match analyzed_image {
+        DynamicAnalyzedImage::Luma8(analyzed_image) => {
+            cycle_grapheme_override(analyzed_image, x, y)
+        }
+        DynamicAnalyzedImage::Rgb8(analyzed_image) => cycle_grapheme_override(analyzed_image, x, y),
+        DynamicAnalyzedImage::Rgba8(analyzed_image) => {
+            cycle_grapheme_override(analyzed_image, x, y)
+        }
+        DynamicAnalyzedImage::Luma16(analyzed_image) => {
+            cycle_grapheme_override(analyzed_image, x, y)
+        }
+        DynamicAnalyzedImage::Rgb16(analyzed_image) => {
+            cycle_grapheme_override(analyzed_image, x, y)
+        }
+        DynamicAnalyzedImage::Rgba16(analyzed_image) => {
+            cycle_grapheme_override(analyzed_image, x, y)
+        }
+    }
+}
+
+// Sets the manual override of every grapheme whose bounding box overlaps rect.
+fn set_overrides_in_rect(
+    analyzed_image: &mut DynamicAnalyzedImage,
+    rect: math::Rect,
+    manual_override: Option<bool>,
+) {
+    fn set<P>(
+        analyzed_image: &mut AnalyzedImage<P>,
+        rect: math::Rect,
+        manual_override: Option<bool>,
+    ) {
+        let ids: Vec<GraphemeId> = analyzed_image
+            .graphemes_in_rect(rect)
+            .map(|(id, _)| id)
+            .collect();
+        for id in ids {
+            if let Some(grapheme) = analyzed_image.get_mut(id) {
+                grapheme.set_override(manual_override);
+            }
+        }
+    }
+
+    match analyzed_image {
+        DynamicAnalyzedImage::Luma8(analyzed_image) => set(analyzed_image, rect, manual_override),
+        DynamicAnalyzedImage::Rgb8(analyzed_image) => set(analyzed_image, rect, manual_override),
+        DynamicAnalyzedImage::Rgba8(analyzed_image) => set(analyzed_image, rect, manual_override),
+        DynamicAnalyzedImage::Luma16(analyzed_image) => set(analyzed_image, rect, manual_override),
+        DynamicAnalyzedImage::Rgb16(analyzed_image) => set(analyzed_image, rect, manual_override),
+        DynamicAnalyzedImage::Rgba16(analyzed_image) => set(analyzed_image, rect, manual_override),
+    }
+}
+
+fn size_histogram(analyzed_image: &DynamicAnalyzedImage, metric: SizeMetric) -> Vec<usize> {
+    fn histogram<P>(analyzed_image: &AnalyzedImage<P>, metric: SizeMetric) -> Vec<usize> {
+        let mut histogram = Vec::new();
+        for grapheme in &analyzed_image.graphemes {
+            let bucket = grapheme.size(metric).ilog2() as usize;
+            if bucket >= histogram.len() {
+                histogram.resize(bucket + 1, 0);
+            }
+            histogram[bucket] += 1;
+        }
+
+        histogram
+    }
+
+    match analyzed_image {
+        DynamicAnalyzedImage::Luma8(analyzed_image) => histogram(analyzed_image, metric),
+        DynamicAnalyzedImage::Rgb8(analyzed_image) => histogram(analyzed_image, metric),
+        DynamicAnalyzedImage::Rgba8(analyzed_image) => histogram(analyzed_image, metric),
+        DynamicAnalyzedImage::Luma16(analyzed_image) => histogram(analyzed_image, metric),
+        DynamicAnalyzedImage::Rgb16(analyzed_image) => histogram(analyzed_image, metric),
+        DynamicAnalyzedImage::Rgba16(analyzed_image) => histogram(analyzed_image, metric),
+    }
+}
+
+fn size_metric_name(metric: SizeMetric) -> &'static str {
+    match metric {
+        SizeMetric::PixelCount => "Pixel count",
+        SizeMetric::BoundingBoxArea => "Bounding box area",
+    }
+}
+
+fn isolation_metric_name(metric: IsolationMetric) -> &'static str {
+    match metric {
+        IsolationMetric::BboxGap => "Box gap",
+        IsolationMetric::BboxEdges => "Box edges",
+        IsolationMetric::CenterEuclidean => "Center distance",
+    }
+}
+
+// Where the overrides of the page at image_path are saved, next to it with .dcl on the end.
+fn overrides_path(image_path: &Path) -> PathBuf {
+    let mut path = image_path.as_os_str().to_owned();
+    path.push(".dcl");
+    PathBuf::from(path)
+}
+
+// Saves the preview page's overrides, or deletes the file if it doesn't have any so pages that were never touched don't
+// get one.
+fn save_overrides(analyzed_image: &DynamicAnalyzedImage, path: &Path) -> std::io::Result<()> {
+    if has_overrides(analyzed_image) {
+        return analyzed_image.save_overrides(path);
+    }
+    match std::fs::remove_file(path) {
+        Err(error) if error.kind() != std::io::ErrorKind::NotFound => Err(error),
+        _ => Ok(()),
+    }
+}
+
+fn has_overrides(analyzed_image: &DynamicAnalyzedImage) -> bool {
+    fn any_override<P>(analyzed_image: &AnalyzedImage<P>) -> bool {
+        analyzed_image
+            .graphemes
+            .iter()
+            .any(|grapheme| grapheme.manual_override().is_some())
+    }
+
+    match analyzed_image {
+        DynamicAnalyzedImage::Luma8(analyzed_image) => any_override(analyzed_image),
+        DynamicAnalyzedImage::Rgb8(analyzed_image) => any_override(analyzed_image),
+        DynamicAnalyzedImage::Rgba8(analyzed_image) => any_override(analyzed_image),
+        DynamicAnalyzedImage::Luma16(analyzed_image) => any_override(analyzed_image),
+        DynamicAnalyzedImage::Rgb16(analyzed_image) => any_override(analyzed_image),
+        DynamicAnalyzedImage::Rgba16(analyzed_image) => any_override(analyzed_image),
+    }
+}
+
+fn cycle_grapheme_override<P>(analyzed_image: &mut AnalyzedImage<P>, x: u32, y: u32) {
+    let Some(id) = analyzed_image.grapheme_id_at(x, y) else {
+        return;
+    };
+    let Some(grapheme) = analyzed_image.get_mut(id) else {
+        return;
+    };
+
+    grapheme.set_override(match grapheme.manual_override() {
+        None => Some(true),
+        Some(true) => Some(false),
+        Some(false) => None,
+    });
+}
+
+fn demo_image() -> DynamicImage {
     image::load_from_memory_with_format(include_bytes!("../assets/demo_page.png"), ImageFormat::Png)
         .unwrap()
-        .to_rgb8()
 }
 
 fn icon_image() -> IconData {
@@ -98,7 +581,7 @@ impl ImageCleanup {
     fn new(ctx: &Context) -> Self {
         let original_preview_image = demo_image();
         let analyzer = ImageAnalyzer::default();
-        let analyzed_image = analyzer.analyze(&original_preview_image);
+        let analyzed_image = analyzer.analyze_dynamic(&original_preview_image);
         let cleaner = ImageCleaner::default();
 
         let preview_speck_fill_color = [255, 0, 255];
@@ -106,25 +589,67 @@ impl ImageCleanup {
         let preview_cleaner = ImageCleaner {
             speck_fill_color: preview_speck_fill_color,
             background_fill_color: preview_background_fill_color,
-            ..cleaner
+            ..cleaner.clone()
         };
-        let cleaned_image = preview_cleaner.clean(&analyzed_image);
-        let preview_image_handle = rgb_image_to_handle(ctx, "preview_image", &cleaned_image);
+        let (cleaned_image, preview_report) =
+            preview_cleaner.clean_dynamic_with_report(&analyzed_image);
+        let size_histogram = size_histogram(&analyzed_image, cleaner.size_metric);
+        let preview_image_handle =
+            rgb_image_to_handle(ctx, "preview_image", &cleaned_image.to_rgb8());
+        let original_image_handle =
+            rgb_image_to_handle(ctx, "original_image", &original_preview_image.to_rgb8());
+        let history = History::new(Parameters {
+            analyzer,
+            cleaner: cleaner.clone(),
+            preview_speck_fill_color,
+            preview_background_fill_color,
+        });
 
         Self {
             analyzer,
             cleaner,
+            page_settings: PageSettings::new(),
+            global_settings: None,
             preview_page: 1,
             analyzed_preview_image: Arc::new(Mutex::new(analyzed_image)),
             cleaned_preview_image: Arc::new(Mutex::new(cleaned_image)),
+            source_preview_image: Arc::new(original_preview_image.clone()),
+            cleaned_preview_report: Arc::new(Mutex::new(preview_report.clone())),
+            preview_report,
+            history,
             preview_image_handle,
+            original_image_handle,
+            override_clicks: Vec::new(),
+            selection: None,
+            selecting: false,
+            override_selections: Vec::new(),
+            overrides_error: Arc::default(),
+            size_histogram,
+            thumbnails: Thumbnails::default(),
+            filmstrip_page: 0,
             image_paths: Vec::new(),
+            auto_deskew: false,
+            mirror_margins: false,
             analyze_preview_task: None,
+            analyze_preview_cancel_token: CancelToken::new(),
             clean_preview_task: None,
+            clean_preview_cancel_token: CancelToken::new(),
             export_task: None,
+            export_cancel_token: CancelToken::new(),
+            #[cfg(feature = "parallel")]
+            export_threads: std::thread::available_parallelism().map_or(1, usize::from),
             export_progess: Arc::new(Mutex::new(0.0)),
+            export_summary: Arc::new(Mutex::new(ExportSummary::default())),
+            min_foreground_fraction: None,
+            export_directory: None,
+            output_format: OutputFormat::default(),
+            confirming_overwrite: false,
+            #[cfg(feature = "serde")]
+            preset_error: None,
+            stats_error: None,
             previews_needs_analyze: false,
             previews_needs_clean: false,
+            previews_new_page: false,
             preview_speck_fill_color,
             preview_background_fill_color,
             preview_zoom: 0.0,
@@ -133,54 +658,469 @@ impl ImageCleanup {
             preview_zoom_speed: 0.0025,
             preview_offset: Vec2::ZERO,
             preview_velocity: Vec2::ZERO,
+            preview_largest_dimension: 1.0,
             preview_margin_color: Color32::from_rgba_unmultiplied(0, 0, 255, 128),
+            preview_view: PreviewView::Cleaned,
+            preview_split: 0.5,
             preview_image_width: original_preview_image.width(),
             preview_image_height: original_preview_image.height(),
         }
     }
 
-    fn on_images_update(&mut self, paths: Vec<PathBuf>) {
+    fn on_images_update(&mut self, mut paths: Vec<PathBuf>) {
+        // File dialogs and folders list files in whatever order the OS keeps them in, which is rarely page order.
+        paths.sort_by(|a, b| natural_cmp(&a.to_string_lossy(), &b.to_string_lossy()));
         self.image_paths = paths;
+        self.thumbnails = Thumbnails::default();
+        self.set_preview_page(1);
+    }
+
+    // Shows the page-th page, switching to its own settings if it has them.
+    fn set_preview_page(&mut self, page: u16) {
+        let mut switched = false;
+        if let Some((analyzer, cleaner)) = self.global_settings.take() {
+            let own = (
+                std::mem::replace(&mut self.analyzer, analyzer),
+                std::mem::replace(&mut self.cleaner, cleaner),
+            );
+            self.page_settings.insert(self.preview_page as usize, own);
+            switched = true;
+        }
+        self.preview_page = page;
+        switched |= self.use_own_page_settings();
+        // Undoing would otherwise take settings from one page to another.
+        if switched {
+            self.history = History::new(self.parameters());
+        }
         self.new_preview_image();
     }
 
+    // Switches to the preview page's own settings if it has them, keeping the global ones aside.
+    fn use_own_page_settings(&mut self) -> bool {
+        let Some((analyzer, cleaner)) = self.page_settings.get(&(self.preview_page as usize))
+        else {
+            return false;
+        };
+        let global = (
+            std::mem::replace(&mut self.analyzer, *analyzer),
+            std::mem::replace(&mut self.cleaner, cleaner.clone()),
+        );
+        self.global_settings = Some(global);
+        true
+    }
+
+    // Gives the preview page its own settings, starting from the global ones, or goes back to the global ones.
+    fn set_custom_page_settings(&mut self, custom: bool) {
+        if custom {
+            self.global_settings = Some((self.analyzer, self.cleaner.clone()));
+            return;
+        }
+
+        self.page_settings.remove(&(self.preview_page as usize));
+        if let Some((analyzer, cleaner)) = self.global_settings.take() {
+            self.restore(Parameters {
+                analyzer,
+                cleaner,
+                ..self.parameters()
+            });
+            self.history = History::new(self.parameters());
+        }
+    }
+
+    // The global settings and every page's own, with the preview page's changes.
+    fn all_settings(&self) -> (ImageAnalyzer, ImageCleaner, PageSettings) {
+        let mut page_settings = self.page_settings.clone();
+        match &self.global_settings {
+            Some((analyzer, cleaner)) => {
+                let own = (self.analyzer, self.cleaner.clone());
+                page_settings.insert(self.preview_page as usize, own);
+                (*analyzer, cleaner.clone(), page_settings)
+            }
+            None => (self.analyzer, self.cleaner.clone(), page_settings),
+        }
+    }
+
     fn new_preview_image(&mut self) {
         self.queue_analyze_preview();
+        self.previews_new_page = true;
+        self.selection = None;
+        *self.overrides_error.lock().unwrap() = None;
+    }
+
+    fn original_preview_image(&self) -> DynamicImage {
+        let image = if !self.image_paths.is_empty() {
+            image::io::Reader::open(&self.image_paths[(self.preview_page - 1) as usize])
+                .unwrap()
+                .decode()
+                .unwrap()
+        } else {
+            demo_image()
+        };
+
+        if self.auto_deskew {
+            auto_deskew(image)
+        } else {
+            image
+        }
     }
 
     fn queue_analyze_preview(&mut self) {
         self.previews_needs_analyze = true;
+        // The graphemes are about to be replaced. Their overrides are carried over, but clicks that haven't been
+        // applied yet could land on different graphemes.
+        self.override_clicks.clear();
+        self.override_selections.clear();
+    }
+
+    // Bars for the size histogram's buckets, their heights on a logarithmic scale so the few big letters show up next to
+    // lots of specks.
+    fn size_histogram_ui(&self, ui: &mut Ui) {
+        let (response, painter) = ui.allocate_painter(vec2(200.0, 60.0), Sense::hover());
+        let rect = response.rect;
+        painter.rect_filled(rect, 0.0, ui.visuals().extreme_bg_color);
+
+        let buckets = self.size_histogram.len().max(1) as f32;
+        let highest = self.size_histogram.iter().copied().max().unwrap_or(0);
+        let bar_width = rect.width() / buckets;
+        for (i, &count) in self.size_histogram.iter().enumerate() {
+            if count == 0 {
+                continue;
+            }
+            let height = rect.height() * (count as f32).ln_1p() / (highest as f32).ln_1p();
+            let left = rect.left() + i as f32 * bar_width;
+            painter.rect_filled(
+                Rect::from_min_max(
+                    pos2(left + 1.0, rect.bottom() - height),
+                    pos2(left + bar_width - 1.0, rect.bottom()),
+                ),
+                0.0,
+                ui.visuals().widgets.inactive.fg_stroke.color,
+            );
+        }
+
+        // Graphemes with up to speck_size_threshold pixels are filled, so the line goes just past its bucket position.
+        let threshold = ((self.cleaner.speck_size_threshold + 1) as f32).log2();
+        let x = (rect.left() + threshold * bar_width).min(rect.right());
+        painter.vline(x, rect.y_range(), Stroke::new(1.5, Color32::RED));
+
+        if let Some(position) = response.hover_pos() {
+            let i = ((position.x - rect.left()) / bar_width) as usize;
+            if let Some(&count) = self.size_histogram.get(i) {
+                response.on_hover_text_at_pointer(format!(
+                    "{}–{}px²: {count}",
+                    1usize << i,
+                    (1usize << (i + 1)) - 1
+                ));
+            }
+        }
+    }
+
+    // The cleaner with the margins the preview page gets.
+    fn preview_cleaner(&self) -> ImageCleaner {
+        page_cleaner(
+            &self.cleaner,
+            self.mirror_margins,
+            self.preview_page as usize,
+        )
     }
 
     fn queue_clean_preview(&mut self) {
         self.previews_needs_clean = true;
     }
 
-    async fn export_all(
+    // The selection as whole pixels of the preview page, if there's any of it on the page.
+    fn selection_rect(&self) -> Option<math::Rect> {
+        let (start, end) = self.selection?;
+        let size = vec2(
+            self.preview_image_width as f32,
+            self.preview_image_height as f32,
+        );
+        let min = start.min(end).max(Vec2::ZERO).floor();
+        let max = start.max(end).min(size).ceil();
+        (min.x < max.x && min.y < max.y).then_some(math::Rect {
+            x: min.x as u32,
+            y: min.y as u32,
+            width: (max.x - min.x) as u32,
+            height: (max.y - min.y) as u32,
+        })
+    }
+
+    fn parameters(&self) -> Parameters {
+        Parameters {
+            analyzer: self.analyzer,
+            cleaner: self.cleaner.clone(),
+            preview_speck_fill_color: self.preview_speck_fill_color,
+            preview_background_fill_color: self.preview_background_fill_color,
+        }
+    }
+
+    // Goes back to parameters from the history, only analyzing the preview again if the analyzer's changed.
+    fn restore(&mut self, parameters: Parameters) {
+        if parameters.analyzer != self.analyzer {
+            self.queue_analyze_preview();
+        }
+        self.queue_clean_preview();
+        self.analyzer = parameters.analyzer;
+        self.cleaner = parameters.cleaner;
+        self.preview_speck_fill_color = parameters.preview_speck_fill_color;
+        self.preview_background_fill_color = parameters.preview_background_fill_color;
+    }
+
+    fn undo(&mut self) {
+        if let Some(parameters) = self.history.undo(self.parameters()) {
+            self.restore(parameters);
+        }
+    }
+
+    fn redo(&mut self) {
+        if let Some(parameters) = self.history.redo(self.parameters()) {
+            self.restore(parameters);
+        }
+    }
+
+    fn open_images(&mut self) {
+        if let Some(paths) = rfd::FileDialog::new()
+            .add_filter("Image files", &image_extensions())
+            .pick_files()
+        {
+            self.on_images_update(paths);
+        }
+    }
+
+    fn can_export(&self) -> bool {
+        !self.image_paths.is_empty() && self.export_task.is_none()
+    }
+
+    // Exports straight away if there's an output folder, otherwise asks before overwriting the originals.
+    fn request_export(&mut self) {
+        if self.export_directory.is_some() {
+            self.start_export();
+        } else {
+            self.confirming_overwrite = true;
+        }
+    }
+
+    // Zooms and pans the preview so the whole page fits the window.
+    fn fit_preview(&mut self) {
+        self.preview_zoom = 0.0;
+        self.preview_offset = Vec2::ZERO;
+        self.preview_velocity = Vec2::ZERO;
+    }
+
+    // Zooms the preview so one pixel of the page is one pixel on the screen.
+    fn actual_size_preview(&mut self, pixels_per_point: f32) {
+        self.preview_zoom = (self.preview_largest_dimension / pixels_per_point)
+            .log2()
+            .clamp(self.preview_min_zoom, self.preview_max_zoom);
+        self.preview_velocity = Vec2::ZERO;
+    }
+
+    // How pages get cleaned and saved, with every page's settings.
+    fn export_options(&self) -> ExportOptions {
+        let (analyzer, cleaner, page_settings) = self.all_settings();
+        ExportOptions {
+            analyzer,
+            cleaner,
+            page_settings,
+            output_format: self.output_format,
+            auto_deskew: self.auto_deskew,
+            mirror_margins: self.mirror_margins,
+            min_foreground_fraction: self.min_foreground_fraction,
+            #[cfg(feature = "parallel")]
+            threads: self.export_threads,
+        }
+    }
+
+    fn start_pdf_export(&mut self, pdf_path: PathBuf) {
+        self.export_cancel_token = CancelToken::new();
+        let image_paths = self.image_paths.clone();
+        let options = self.export_options();
+        let progress = self.export_progess.clone();
+        let summary = self.export_summary.clone();
+        let cancel_token = self.export_cancel_token.clone();
+        self.export_task = Some(tokio::task::spawn_blocking(move || {
+            Self::export_pdf(
+                image_paths,
+                pdf_path,
+                options,
+                progress,
+                summary,
+                cancel_token,
+            )
+        }));
+    }
+
+    fn start_export(&mut self) {
+        self.export_cancel_token = CancelToken::new();
+        let image_paths = self.image_paths.clone();
+        let export_directory = self.export_directory.clone();
+        let options = self.export_options();
+        let progress = self.export_progess.clone();
+        let summary = self.export_summary.clone();
+        let cancel_token = self.export_cancel_token.clone();
+        self.export_task = Some(tokio::task::spawn_blocking(move || {
+            Self::export_all(
+                image_paths,
+                export_directory,
+                options,
+                progress,
+                summary,
+                cancel_token,
+            )
+        }));
+    }
+
+    fn export_all(
         image_paths: Vec<PathBuf>,
-        analyzer: ImageAnalyzer,
-        cleaner: ImageCleaner,
+        export_directory: Option<PathBuf>,
+        options: ExportOptions,
         progress: Arc<Mutex<f32>>,
+        summary: Arc<Mutex<ExportSummary>>,
+        cancel_token: CancelToken,
     ) {
         *progress.lock().unwrap() = 0.0;
+        *summary.lock().unwrap() = ExportSummary::default();
 
-        for (i, path) in image_paths.iter().enumerate() {
-            tokio::task::yield_now().await;
-            *progress.lock().unwrap() = (i + 1) as f32 / image_paths.len() as f32;
-            let image = image::io::Reader::open(path)
-                .unwrap()
-                .decode()
+        let export_paths = match export_directory {
+            Some(directory) => export_paths(&image_paths, &directory),
+            None => image_paths.clone(),
+        };
+        let new_summary = image_cleanup::export_all(
+            &image_paths,
+            &export_paths,
+            &options,
+            |exported| *progress.lock().unwrap() = exported as f32 / image_paths.len() as f32,
+            &cancel_token,
+        );
+        *summary.lock().unwrap() = new_summary;
+    }
+
+    // Cleans every page into one PDF, in order. Pages that fail are left out and listed in the summary like in
+    // export_all, but if the PDF itself can't be written or none of the pages could be cleaned nothing is saved.
+    fn export_pdf(
+        image_paths: Vec<PathBuf>,
+        pdf_path: PathBuf,
+        options: ExportOptions,
+        progress: Arc<Mutex<f32>>,
+        summary: Arc<Mutex<ExportSummary>>,
+        cancel_token: CancelToken,
+    ) {
+        *progress.lock().unwrap() = 0.0;
+        *summary.lock().unwrap() = ExportSummary::default();
+
+        let write_pages = || -> std::io::Result<()> {
+            let mut pdf = PdfWriter::create(&pdf_path)?;
+            let mut added_pages = 0;
+            for (i, path) in image_paths.iter().enumerate() {
+                if cancel_token.is_cancelled() {
+                    return Ok(());
+                }
+
+                let (analyzer, cleaner) = options.settings(i + 1);
+                let page_cleaner = page_cleaner(cleaner, options.mirror_margins, i + 1);
+                match clean_page(
+                    path,
+                    analyzer,
+                    page_cleaner,
+                    options.auto_deskew,
+                    &cancel_token,
+                ) {
+                    Ok(Some((cleaned_image, fraction))) => {
+                        pdf.add_page(&cleaned_image, PDF_DPI)?;
+                        added_pages += 1;
+                        let summary = &mut *summary.lock().unwrap();
+                        summary.foreground_fractions.push((path.clone(), fraction));
+                        if options
+                            .min_foreground_fraction
+                            .is_some_and(|min| fraction < min)
+                        {
+                            summary.flagged.push((path.clone(), fraction));
+                        }
+                    }
+                    Ok(None) => return Ok(()),
+                    Err(error) => summary
+                        .lock()
+                        .unwrap()
+                        .failed
+                        .push((path.clone(), error.to_string())),
+                }
+                *progress.lock().unwrap() = (i + 1) as f32 / image_paths.len() as f32;
+            }
+            if added_pages == 0 {
+                return Err(std::io::Error::other("none of the pages could be cleaned"));
+            }
+            pdf.finish()?;
+
+            Ok(())
+        };
+
+        let result = write_pages();
+        // A cancelled or failed export would only leave a PDF that can't be opened.
+        if result.is_err() || cancel_token.is_cancelled() {
+            let _ = std::fs::remove_file(&pdf_path);
+        }
+        if let Err(error) = result {
+            summary
+                .lock()
                 .unwrap()
-                .to_rgb8();
-            let analyzed_image = analyzer.analyze(&image);
-            let cleaned_image = cleaner.clean(&analyzed_image);
-            cleaned_image.save(path).unwrap();
+                .failed
+                .push((pdf_path, error.to_string()));
         }
     }
 }
 
 impl eframe::App for ImageCleanup {
     fn update(&mut self, ctx: &Context, _frame: &mut eframe::Frame) {
+        // Images and folders dropped onto the window are opened like they were picked in the dialog.
+        let dropped_paths: Vec<PathBuf> = ctx.input(|i| {
+            i.raw
+                .dropped_files
+                .iter()
+                .filter_map(|file| file.path.clone())
+                .collect()
+        });
+        if !dropped_paths.is_empty() {
+            let image_paths = collect_image_paths(dropped_paths);
+            if !image_paths.is_empty() {
+                self.on_images_update(image_paths);
+            }
+        }
+        if ctx.input(|i| !i.raw.hovered_files.is_empty()) {
+            let painter =
+                ctx.layer_painter(LayerId::new(Order::Foreground, Id::new("file_drop_target")));
+            let screen_rect = ctx.screen_rect();
+            painter.rect_filled(screen_rect, 0.0, Color32::from_black_alpha(192));
+            painter.text(
+                screen_rect.center(),
+                Align2::CENTER_CENTER,
+                "Drop images or folders to open them",
+                TextStyle::Heading.resolve(&ctx.style()),
+                Color32::WHITE,
+            );
+        }
+
+        if ctx.input_mut(|i| i.consume_shortcut(&OPEN_SHORTCUT)) {
+            self.open_images();
+        }
+        if ctx.input_mut(|i| i.consume_shortcut(&REIMPORT_SHORTCUT)) {
+            self.queue_analyze_preview();
+        }
+        if ctx.input_mut(|i| i.consume_shortcut(&EXPORT_SHORTCUT)) && self.can_export() {
+            self.request_export();
+        }
+        // F on its own would otherwise be taken from whatever's being typed.
+        if !ctx.wants_keyboard_input() && ctx.input_mut(|i| i.consume_shortcut(&FIT_SHORTCUT)) {
+            self.fit_preview();
+        }
+        // Text fields have their own undo. Redo first, since Ctrl+Z also matches Ctrl+Shift+Z.
+        if !ctx.wants_keyboard_input() {
+            if ctx.input_mut(|i| i.consume_shortcut(&REDO_SHORTCUT)) {
+                self.redo();
+            } else if ctx.input_mut(|i| i.consume_shortcut(&UNDO_SHORTCUT)) {
+                self.undo();
+            }
+        }
+
         // Here's how it works:
         // When the preview image is changed, it gets analyzed.
         if let Some(analyze_task) = &self.analyze_preview_task {
@@ -193,45 +1133,116 @@ impl eframe::App for ImageCleanup {
         }
 
         let is_analyzing = self.analyze_preview_task.is_some();
+        if self.previews_needs_analyze && is_analyzing {
+            // The analysis in progress is already out of date, so stop it and start over once it's done.
+            self.analyze_preview_cancel_token.cancel();
+        }
         if self.previews_needs_analyze && !is_analyzing {
             self.previews_needs_analyze = false;
 
-            let original_preview_image = if !self.image_paths.is_empty() {
-                image::io::Reader::open(&self.image_paths[(self.preview_page - 1) as usize])
-                    .unwrap()
-                    .decode()
-                    .unwrap()
-                    .to_rgb8()
-            } else {
-                demo_image()
-            };
+            let original_preview_image = Arc::new(self.original_preview_image());
+            self.source_preview_image = original_preview_image.clone();
 
             self.preview_image_width = original_preview_image.width();
             self.preview_image_height = original_preview_image.height();
+            self.original_image_handle =
+                rgb_image_to_handle(ctx, "original_image", &original_preview_image.to_rgb8());
 
             let analyzer = self.analyzer;
             let analyzed_handle = self.analyzed_preview_image.clone();
+            self.analyze_preview_cancel_token = CancelToken::new();
+            let cancel_token = self.analyze_preview_cancel_token.clone();
+            let same_page = !std::mem::take(&mut self.previews_new_page);
+            let overrides_path = self
+                .image_paths
+                .get(self.preview_page as usize - 1)
+                .map(|path| overrides_path(path));
+            let overrides_error = self.overrides_error.clone();
             self.analyze_preview_task = Some(tokio::spawn(async move {
-                let analyzed = analyzer.analyze(&original_preview_image);
-                *analyzed_handle.lock().unwrap() = analyzed;
+                // Analyzed into the same buffers every time, so changing a parameter doesn't allocate a whole new map,
+                // unless the old analysis has overrides to carry over.
+                let analyzed = &mut *analyzed_handle.lock().unwrap();
+                let previous =
+                    (same_page && has_overrides(analyzed)).then(|| std::mem::take(analyzed));
+                let result = analyzer.analyze_dynamic_into_cancellable(
+                    &original_preview_image,
+                    analyzed,
+                    &cancel_token,
+                );
+                match (result, previous) {
+                    (Ok(()), Some(previous)) => analyzed.transfer_overrides(&previous),
+                    // Cancelled, so the next analysis gets to carry them over instead.
+                    (Err(Cancelled), Some(previous)) => *analyzed = previous,
+                    // Nothing to carry over, so either the page was just opened or it doesn't have any overrides, in
+                    // which case it doesn't have a file either.
+                    (Ok(()), None) => {
+                        let Some(path) = overrides_path.filter(|path| path.exists()) else {
+                            return;
+                        };
+                        *overrides_error.lock().unwrap() = match analyzed.load_overrides(&path) {
+                            Ok(0) => None,
+                            Ok(skipped) => Some(format!(
+                                "{skipped} saved overrides don't match any cluster on this page anymore"
+                            )),
+                            Err(error) => Some(format!("Couldn't load {}: {error}", path.display())),
+                        };
+                    }
+                    (Err(Cancelled), None) => {}
+                }
             }));
         }
 
         if let Some(clean_task) = &self.clean_preview_task {
             if clean_task.is_finished() {
                 self.clean_preview_task = None;
-                // Then once it's done cleaning, update the user's preview.
-                self.preview_image_handle = rgb_image_to_handle(
-                    ctx,
-                    "preview_image",
-                    &*self.cleaned_preview_image.lock().unwrap(),
-                );
+                // Then once it's done cleaning, update the user's preview. A cancelled clean leaves the image half
+                // done, but there's another one on the way.
+                if !self.clean_preview_cancel_token.is_cancelled() {
+                    self.preview_image_handle = rgb_image_to_handle(
+                        ctx,
+                        "preview_image",
+                        &self.cleaned_preview_image.lock().unwrap().to_rgb8(),
+                    );
+                    self.preview_report = self.cleaned_preview_report.lock().unwrap().clone();
+                    // Here too instead of after analyzing, since the size metric is a cleaner parameter.
+                    self.size_histogram = size_histogram(
+                        &self.analyzed_preview_image.lock().unwrap(),
+                        self.cleaner.size_metric,
+                    );
+                }
+            }
+        }
+
+        // The analyzed image is locked while it's being analyzed or cleaned, so clicks wait until it's free.
+        if !self.override_clicks.is_empty() || !self.override_selections.is_empty() {
+            if let Ok(mut analyzed_image) = self.analyzed_preview_image.try_lock() {
+                for (x, y) in self.override_clicks.drain(..) {
+                    cycle_override(&mut analyzed_image, x, y);
+                }
+                for (rect, manual_override) in self.override_selections.drain(..) {
+                    set_overrides_in_rect(&mut analyzed_image, rect, manual_override);
+                }
+                if let Some(image_path) = self.image_paths.get(self.preview_page as usize - 1) {
+                    let path = overrides_path(image_path);
+                    *self.overrides_error.lock().unwrap() = save_overrides(&analyzed_image, &path)
+                        .err()
+                        .map(|error| format!("Couldn't save {}: {error}", path.display()));
+                }
+                self.previews_needs_clean = true;
+            } else {
+                ctx.request_repaint();
             }
         }
 
         let is_cleaning = self.clean_preview_task.is_some();
 
-        let mut processing = is_analyzing || is_cleaning;
+        if (self.previews_needs_clean || self.previews_needs_analyze) && is_cleaning {
+            // Same for cleaning.
+            self.clean_preview_cancel_token.cancel();
+        }
+
+        // Not is_analyzing, an analysis might've just started, and the cleaner has to wait for it to finish.
+        let mut processing = self.analyze_preview_task.is_some() || is_cleaning;
         if self.previews_needs_clean && !processing {
             processing = true;
             self.previews_needs_clean = false;
@@ -239,48 +1250,211 @@ impl eframe::App for ImageCleanup {
             let cleaner = ImageCleaner {
                 speck_fill_color: self.preview_speck_fill_color,
                 background_fill_color: self.preview_background_fill_color,
-                ..self.cleaner
+                ..self.preview_cleaner()
             };
 
             let analyzed_handle = self.analyzed_preview_image.clone();
             let cleaned_handle = self.cleaned_preview_image.clone();
+            let report_handle = self.cleaned_preview_report.clone();
+            let source = self.source_preview_image.clone();
+            self.clean_preview_cancel_token = CancelToken::new();
+            let cancel_token = self.clean_preview_cancel_token.clone();
             self.clean_preview_task = Some(tokio::spawn(async move {
                 let analyzed = &*analyzed_handle.lock().unwrap();
-                *cleaned_handle.lock().unwrap() = cleaner.clean(analyzed);
+                let cleaned = &mut *cleaned_handle.lock().unwrap();
+                let report = &mut *report_handle.lock().unwrap();
+                if cleaner.preserve_original {
+                    // Only fails if the analysis is of a different page, and then there's another clean on the way.
+                    if let Ok((image, new_report)) =
+                        cleaner.clean_dynamic_onto_with_report(analyzed, &source)
+                    {
+                        *cleaned = image;
+                        *report = new_report;
+                    }
+                } else {
+                    let _ = cleaner.clean_dynamic_into_cancellable_with_report(
+                        analyzed,
+                        cleaned,
+                        report,
+                        &cancel_token,
+                    );
+                }
             }));
         }
 
         SidePanel::right("parameters").resizable(false).show(ctx, |ui| {
+            if !self.image_paths.is_empty() {
+                let mut custom = self.global_settings.is_some();
+                if ui.checkbox(&mut custom, "Use custom settings for this page")
+                    .on_hover_text("Changes to the parameters only apply to this page, for pages like title pages and plates that need different ones from the rest of the book. Unchecking goes back to the settings every other page uses")
+                    .changed()
+                {
+                    self.set_custom_page_settings(custom);
+                }
+                ui.separator();
+            }
+
             ui.heading("Import parameters");
             Grid::new("import_parameters")
                 .spacing([40.0, 4.0])
                 .striped(true)
                 .show(ui, |ui| {
-                    ui.label("Off-white threshold")
-                        .on_hover_text("Pixels whose mean rgb value is lighter than this are considered off-white, and will be filled");
-                    ui.add(Slider::new(&mut self.analyzer.off_white_threshold, 0..=255));
+                    ui.label("Threshold mode")
+                        .on_hover_text("Global compares every pixel against the off-white threshold. Sauvola compares every pixel against a threshold computed from the pixels around it, which works better on unevenly lit pages");
+                    let is_sauvola = matches!(self.analyzer.threshold_mode, ThresholdMode::Sauvola { .. });
+                    ComboBox::from_id_source("threshold_mode")
+                        .selected_text(if is_sauvola { "Sauvola" } else { "Global" })
+                        .show_ui(ui, |ui| {
+                            if ui.selectable_label(!is_sauvola, "Global").clicked() {
+                                self.analyzer.threshold_mode = ThresholdMode::Global;
+                            }
+                            if ui.selectable_label(is_sauvola, "Sauvola").clicked() && !is_sauvola {
+                                self.analyzer.threshold_mode = ThresholdMode::Sauvola { window: 51, k: 0.2 };
+                            }
+                        });
+                    ui.end_row();
+
+                    match &mut self.analyzer.threshold_mode {
+                        ThresholdMode::Global => {
+                            ui.label("Off-white threshold")
+                                .on_hover_text("Pixels whose mean rgb value is lighter than this are considered off-white, and will be filled");
+                            if self.analyzer.off_white_threshold_rgb.is_none() {
+                                ui.add(Slider::new(&mut self.analyzer.off_white_threshold, 0..=255));
+                            }
+                            ui.end_row();
+
+                            let mut per_channel = self.analyzer.off_white_threshold_rgb.is_some();
+                            ui.label("\t- Per channel")
+                                .on_hover_text("Pixels whose red, green, and blue values are each lighter than their own threshold are considered off-white, and will be filled (useful for yellowed paper)");
+                            if ui.checkbox(&mut per_channel, "").changed() {
+                                let threshold = self.analyzer.off_white_threshold;
+                                self.analyzer.off_white_threshold_rgb = per_channel.then_some([threshold; 3]);
+                            }
+                            ui.end_row();
+
+                            if let Some(thresholds) = &mut self.analyzer.off_white_threshold_rgb {
+                                for (channel, threshold) in ["\t- Red", "\t- Green", "\t- Blue"].into_iter().zip(thresholds) {
+                                    ui.label(channel);
+                                    ui.add(Slider::new(threshold, 0..=255));
+                                    ui.end_row();
+                                }
+                            }
+                        }
+                        ThresholdMode::Sauvola { window, k } => {
+                            ui.label("\t- Window")
+                                .on_hover_text("The size of the area around each pixel used to compute its threshold");
+                            ui.add(Slider::new(window, 3..=255).suffix("px"));
+                            ui.end_row();
+
+                            ui.label("\t- k")
+                                .on_hover_text("How much the contrast of the area lowers the threshold, higher values fill more");
+                            ui.add(Slider::new(k, 0.0..=1.0));
+                            ui.end_row();
+                        }
+                    }
+
+                    ui.label("Lightness thresholds")
+                        .on_hover_text("Pixels whose mean rgb value is lighter than lightness and that don't have another pixel within distance (perpendicular distance) that is lighter than lightness will be filled");
+                    ui.end_row();
+
+                    ui.label("\t- Lightness");
+                    ui.add(Slider::new(&mut self.analyzer.lightness_threshold, 0..=255));
+                    ui.end_row();
+
+                    ui.label("\t- Distance");
+                    ui.add(Slider::new(&mut self.analyzer.lightness_distance, 0..=50).clamp_to_range(false).suffix("px"));
+                    ui.end_row();
+
+                    ui.label("Luminance mode")
+                        .on_hover_text("How a pixel's rgb values are combined into a single lightness value. Rec. 709 and Rec. 601 weight the channels by how bright they appear, which keeps colored ink (like blue stamps) from being mistaken for background");
+                    ComboBox::from_id_source("luminance_mode")
+                        .selected_text(format!("{:?}", self.analyzer.luminance_mode))
+                        .show_ui(ui, |ui| {
+                            for mode in [LuminanceMode::Mean, LuminanceMode::Rec709, LuminanceMode::Rec601] {
+                                ui.selectable_value(&mut self.analyzer.luminance_mode, mode, format!("{:?}", mode));
+                            }
+                        });
+                    ui.end_row();
+
+                    ui.label("Transparent background color")
+                        .on_hover_text("What color partially transparent pixels are treated as being drawn over. Fully transparent pixels are always filled");
+                    ui.color_edit_button_srgb(&mut self.analyzer.transparent_background_color);
                     ui.end_row();
 
-                    ui.label("Lightness thresholds")
-                        .on_hover_text("Pixels whose mean rgb value is lighter than lightness and that don't have another pixel within distance (perpendicular distance) that is lighter than lightness will be filled");
+                    ui.label("Connectivity")
+                        .on_hover_text("Whether pixels that only touch diagonally are part of the same cluster (Eight) or not (Four)");
+                    ComboBox::from_id_source("connectivity")
+                        .selected_text(format!("{:?}", self.analyzer.connectivity))
+                        .show_ui(ui, |ui| {
+                            for connectivity in [Connectivity::Four, Connectivity::Eight] {
+                                ui.selectable_value(&mut self.analyzer.connectivity, connectivity, format!("{:?}", connectivity));
+                            }
+                        });
                     ui.end_row();
 
-                    ui.label("\t- Lightness");
-                    ui.add(Slider::new(&mut self.analyzer.lightness_threshold, 0..=255));
+                    ui.label("Auto-deskew on import")
+                        .on_hover_text("Straighten pages that were scanned at a slight angle before cleaning them, so text near the edges doesn't slant into the margins");
+                    if ui.checkbox(&mut self.auto_deskew, "").changed() {
+                        self.queue_analyze_preview();
+                    }
                     ui.end_row();
 
-                    ui.label("\t- Distance");
-                    ui.add(Slider::new(&mut self.analyzer.lightness_distance, 0..=10));
+                    ui.label("Merge distance")
+                        .on_hover_text("Clusters whose bounding boxes are at most this far apart are merged into one, so dots and accents stay with their letters. 0 doesn't merge anything");
+                    ui.add(Slider::new(&mut self.analyzer.merge_distance, 0..=20).suffix("px"));
                     ui.end_row();
 
-                    if ui.button("Open images…").clicked() {
-                        let extensions: Vec<&str> = [ImageFormat::Png, ImageFormat::Jpeg, ImageFormat::Tiff, ImageFormat::WebP].into_iter().flat_map(|f| f.extensions_str().iter().copied()).collect();
-                        if let Some(paths) = rfd::FileDialog::new().add_filter("Image files", extensions.as_slice()).pick_files() {
-                            self.on_images_update(paths);
+                    ui.label("Close gaps")
+                        .on_hover_text("Joins clusters separated by gaps up to twice this wide before they're detected, for letters broken up on faint scans. 0 leaves them as they are");
+                    ui.add(Slider::new(&mut self.analyzer.morph_close, 0..=5).suffix("px"));
+                    ui.end_row();
+
+                    let mut auto_flatten = self.analyzer.auto_flatten.is_some();
+                    ui.label("Flatten background")
+                        .on_hover_text("Evens out the paper's brightness over blocks this wide before analyzing, for scans that get darker toward the spine or are lit unevenly");
+                    ui.horizontal(|ui| {
+                        if ui.checkbox(&mut auto_flatten, "").changed() {
+                            self.analyzer.auto_flatten = auto_flatten.then_some(64);
+                        }
+                        if let Some(block_size) = &mut self.analyzer.auto_flatten {
+                            ui.add(Slider::new(block_size, 16..=256).suffix("px"));
+                        }
+                    });
+                    ui.end_row();
+
+                    ui.label("Light ink on dark paper")
+                        .on_hover_text("Treats light pixels as the text and dark ones as the paper, for blackboards and negatives. The thresholds then measure darkness instead of lightness");
+                    if ui.checkbox(&mut self.analyzer.invert, "").changed() {
+                        // The fills follow the paper, unless they've been changed from its color.
+                        let paper = if self.analyzer.invert { [0, 0, 0] } else { [255, 255, 255] };
+                        let old_paper = paper.map(|c| 255 - c);
+                        for color in [
+                            &mut self.cleaner.background_fill_color,
+                            &mut self.preview_background_fill_color,
+                            &mut self.analyzer.transparent_background_color,
+                        ] {
+                            if *color == old_paper {
+                                *color = paper;
+                            }
                         }
+                        self.queue_analyze_preview();
                     }
+                    ui.end_row();
+
+                    if ui.button("Open images…").on_hover_text(ctx.format_shortcut(&OPEN_SHORTCUT)).clicked() {
+                        self.open_images();
+                    }
+
+                    if ui.button("Reimport").on_hover_text(format!("Analyze the preview page again ({})", ctx.format_shortcut(&REIMPORT_SHORTCUT))).clicked() {
+                        self.queue_analyze_preview();
+                    }
+                    ui.end_row();
 
-                    if ui.button("Reimport").clicked() {
+                    if ui.button("Suggest thresholds").on_hover_text("Pick the off-white and lightness thresholds automatically using the preview page").clicked() {
+                        // As RGBA so transparent pixels are left out, the other channels' values come out the same.
+                        let suggested = self.analyzer.suggest_thresholds(&self.original_preview_image().to_rgba8());
+                        self.analyzer.off_white_threshold = suggested.off_white_threshold;
+                        self.analyzer.lightness_threshold = suggested.lightness_threshold;
                         self.queue_analyze_preview();
                     }
                 });
@@ -299,25 +1473,144 @@ impl eframe::App for ImageCleanup {
                     }
                     ui.end_row();
 
+                    ui.label("\t- Measured by")
+                        .on_hover_text("Pixel count is how many pixels a cluster has. Bounding box area is the width times the height of the box around it, so thin broken lines like faint underlines aren't mistaken for specks");
+                    ComboBox::from_id_source("size_metric")
+                        .selected_text(size_metric_name(self.cleaner.size_metric))
+                        .show_ui(ui, |ui| {
+                            for metric in [SizeMetric::PixelCount, SizeMetric::BoundingBoxArea] {
+                                if ui.selectable_value(&mut self.cleaner.size_metric, metric, size_metric_name(metric)).changed() {
+                                    self.queue_clean_preview();
+                                }
+                            }
+                        });
+                    ui.end_row();
+
+                    ui.label("\t- Sizes")
+                        .on_hover_text("How many clusters on the preview page there are of each size, on a logarithmic scale. Specks and letters usually make two humps, the threshold (the red line) goes in the valley between them");
+                    self.size_histogram_ui(ui);
+                    ui.end_row();
+
+                    ui.label("\t- Keep punctuation")
+                        .on_hover_text("Keep clusters this close to a letter that line up with it, beside it at the same height like periods and commas, or right above it like the dots on i's. Lets the threshold go high enough for fly specks without eating punctuation");
+                    ui.horizontal(|ui| {
+                        if ui.checkbox(&mut self.cleaner.protect_punctuation, "").changed() {
+                            self.queue_clean_preview();
+                        }
+                        ui.add_enabled_ui(self.cleaner.protect_punctuation, |ui| {
+                            if ui.add(Slider::new(&mut self.cleaner.punctuation_distance, 0..=50).clamp_to_range(false).suffix("px")).changed() {
+                                self.queue_clean_preview();
+                            }
+                        });
+                    });
+                    ui.end_row();
+
                     ui.label("Speck margins")
-                        .on_hover_text("Clusters that are within these margins will be filled");
+                        .on_hover_text("Clusters that are within these margins will be filled. Margins in percent are a percentage of each page's width (left and right) or height (top and bottom), so they're the same on pages scanned at different resolutions");
+                    // Switching units converts the margins using the preview page's size, so they stay where they are on it.
+                    let (width, height) = (self.preview_image_width, self.preview_image_height);
+                    let is_percent = self.cleaner.page_margin_fractions.is_some();
+                    ComboBox::from_id_source("margin_unit")
+                        .selected_text(if is_percent { "Percent" } else { "Pixels" })
+                        .show_ui(ui, |ui| {
+                            if ui.selectable_label(!is_percent, "Pixels").clicked() && is_percent {
+                                self.cleaner.page_margins = self.cleaner.margins_for(width, height);
+                                self.cleaner.page_margin_fractions = None;
+                                self.queue_clean_preview();
+                            }
+                            if ui.selectable_label(is_percent, "Percent").clicked() && !is_percent {
+                                self.cleaner.page_margin_fractions = Some(self.cleaner.page_margins.to_fractions(width, height));
+                                self.queue_clean_preview();
+                            }
+                        });
                     ui.end_row();
 
-                    ui.label("\t- x");
-                    if ui.add(Slider::new(&mut self.cleaner.page_margins.0, 0..=100).clamp_to_range(false).suffix("px")).changed() {
+                    let mut changed = false;
+                    match &mut self.cleaner.page_margin_fractions {
+                        Some(fractions) => {
+                            for (name, fraction) in [
+                                ("\t- Left", &mut fractions.left),
+                                ("\t- Right", &mut fractions.right),
+                                ("\t- Top", &mut fractions.top),
+                                ("\t- Bottom", &mut fractions.bottom),
+                            ] {
+                                ui.label(name);
+                                let slider = Slider::new(fraction, 0.0..=0.1)
+                                    .clamp_to_range(false)
+                                    .custom_formatter(|fraction, _| format!("{:.1}%", fraction * 100.0))
+                                    .custom_parser(|text| text.trim_end_matches('%').trim().parse::<f64>().ok().map(|percent| percent / 100.0));
+                                changed |= ui.add(slider).changed();
+                                *fraction = fraction.clamp(0.0, 0.5);
+                                ui.end_row();
+                            }
+                        }
+                        None => {
+                            let margins = &mut self.cleaner.page_margins;
+                            for (name, margin) in [
+                                ("\t- Left", &mut margins.left),
+                                ("\t- Right", &mut margins.right),
+                                ("\t- Top", &mut margins.top),
+                                ("\t- Bottom", &mut margins.bottom),
+                            ] {
+                                ui.label(name);
+                                changed |= ui.add(Slider::new(margin, 0..=100).clamp_to_range(false).suffix("px")).changed();
+                                ui.end_row();
+                            }
+                        }
+                    }
+                    if changed {
+                        self.queue_clean_preview();
+                    }
+
+                    ui.label("\t- Mirror on even pages")
+                        .on_hover_text("Swap the left and right margins on every other page, for books where the binding is on the left of one page and the right of the next");
+                    if ui.checkbox(&mut self.mirror_margins, "").changed() {
                         self.queue_clean_preview();
                     }
                     ui.end_row();
 
-                    ui.label("\t- y");
-                    if ui.add(Slider::new(&mut self.cleaner.page_margins.1, 0..=100).clamp_to_range(false).suffix("px")).changed() {
+                    let mut keep_in_margins = self.cleaner.margin_keep_size.is_some();
+                    ui.label("\t- Keep bigger than")
+                        .on_hover_text("Clusters in the margins bigger than this are kept, for page numbers, running headers and signature marks. Smaller ones are still filled");
+                    ui.horizontal(|ui| {
+                        if ui.checkbox(&mut keep_in_margins, "").changed() {
+                            self.cleaner.margin_keep_size = keep_in_margins.then_some(60);
+                            self.queue_clean_preview();
+                        }
+                        if let Some(size) = &mut self.cleaner.margin_keep_size {
+                            if ui.add(Slider::new(size, 0..=500).logarithmic(true).suffix("px²")).changed() {
+                                self.queue_clean_preview();
+                            }
+                        }
+                    });
+                    ui.end_row();
+
+                    ui.label("\t- In the margins on the")
+                        .on_hover_text("Which margins clusters are kept in. Page numbers and headers are usually only at the top and bottom");
+                    ui.add_enabled_ui(self.cleaner.margin_keep_size.is_some(), |ui| {
+                        ui.horizontal(|ui| {
+                            let sides = &mut self.cleaner.margin_keep_sides;
+                            let mut changed = false;
+                            for (name, side) in [("Left", &mut sides.left), ("Right", &mut sides.right), ("Top", &mut sides.top), ("Bottom", &mut sides.bottom)] {
+                                changed |= ui.checkbox(side, name).changed();
+                            }
+                            if changed {
+                                self.queue_clean_preview();
+                            }
+                        });
+                    });
+                    ui.end_row();
+
+                    ui.label("Remove clusters touching the edge")
+                        .on_hover_text("Clusters that touch the edge of the page will be filled however big they are, like the scanner's edges, shadows from the page turning, and binder clips");
+                    if ui.checkbox(&mut self.cleaner.remove_border_touching, "").changed() {
                         self.queue_clean_preview();
                     }
                     ui.end_row();
 
 
                     ui.label("Isolation thresholds")
-                        .on_hover_text("(Clusters that have an area smaller than this and aren't within this distance of another cluster that is will be filled");
+                        .on_hover_text("(Clusters that have an area smaller than this and aren't within this distance of another cluster that is will be filled. How the distance is measured is picked below");
                     ui.end_row();
 
                     ui.label("\t- Size");
@@ -330,6 +1623,170 @@ impl eframe::App for ImageCleanup {
                         self.queue_clean_preview();
                     }
                     ui.end_row();
+                    ui.label("\t- Measured by")
+                        .on_hover_text("Box gap is the blank space between the clusters' bounding boxes. Box edges compares the boxes' edges to each other, like older versions did. Center distance is the straight line between the clusters' centers");
+                    ComboBox::from_id_source("isolation_metric")
+                        .selected_text(isolation_metric_name(self.cleaner.isolation_metric))
+                        .show_ui(ui, |ui| {
+                            for metric in [IsolationMetric::BboxGap, IsolationMetric::BboxEdges, IsolationMetric::CenterEuclidean] {
+                                if ui.selectable_value(&mut self.cleaner.isolation_metric, metric, isolation_metric_name(metric)).changed() {
+                                    self.queue_clean_preview();
+                                }
+                            }
+                        });
+                    ui.end_row();
+
+                    let mut limit_average_value = self.cleaner.max_average_value.is_some();
+                    ui.label("Max average color")
+                        .on_hover_text("Clusters whose average color is lighter than this will be filled, for removing text showing through from the other side of the page and large light smudges");
+                    ui.horizontal(|ui| {
+                        if ui.checkbox(&mut limit_average_value, "").changed() {
+                            self.cleaner.max_average_value = limit_average_value.then_some(160);
+                            self.queue_clean_preview();
+                        }
+                        if let Some(max) = &mut self.cleaner.max_average_value {
+                            if ui.add(Slider::new(max, 0..=255)).changed() {
+                                self.queue_clean_preview();
+                            }
+                        }
+                    });
+                    ui.end_row();
+
+                    let mut limit_fill_ratio = self.cleaner.max_fill_ratio.is_some();
+                    ui.label("Max fill ratio")
+                        .on_hover_text("Clusters that cover more of their bounding box than this will be filled, for removing solid round ink blots. Solid letters like l and periods are dense too");
+                    ui.horizontal(|ui| {
+                        if ui.checkbox(&mut limit_fill_ratio, "").changed() {
+                            self.cleaner.max_fill_ratio = limit_fill_ratio.then_some(0.9);
+                            self.queue_clean_preview();
+                        }
+                        if let Some(max) = &mut self.cleaner.max_fill_ratio {
+                            if ui.add(Slider::new(max, 0.0..=1.0)).changed() {
+                                self.queue_clean_preview();
+                            }
+                        }
+                    });
+                    ui.end_row();
+
+                    let mut limit_min_fill_ratio = self.cleaner.min_fill_ratio.is_some();
+                    ui.label("Min fill ratio")
+                        .on_hover_text("Clusters that cover less of their bounding box than this will be filled, for removing sparse tangles like hairs and fibers. Thin slanted strokes are sparse too");
+                    ui.horizontal(|ui| {
+                        if ui.checkbox(&mut limit_min_fill_ratio, "").changed() {
+                            self.cleaner.min_fill_ratio = limit_min_fill_ratio.then_some(0.1);
+                            self.queue_clean_preview();
+                        }
+                        if let Some(min) = &mut self.cleaner.min_fill_ratio {
+                            if ui.add(Slider::new(min, 0.0..=1.0)).changed() {
+                                self.queue_clean_preview();
+                            }
+                        }
+                    });
+                    ui.end_row();
+
+                    let mut limit_fill_ratio_size = self.cleaner.fill_ratio_size_limit.is_some();
+                    ui.label("\t- Only up to")
+                        .on_hover_text("Only clusters up to this size are filled for their fill ratio, so big solid letters and drawings are left alone");
+                    ui.horizontal(|ui| {
+                        if ui.checkbox(&mut limit_fill_ratio_size, "").changed() {
+                            self.cleaner.fill_ratio_size_limit = limit_fill_ratio_size.then_some(200);
+                            self.queue_clean_preview();
+                        }
+                        if let Some(limit) = &mut self.cleaner.fill_ratio_size_limit {
+                            if ui.add(Slider::new(limit, 0..=2000).logarithmic(true).suffix("px²")).changed() {
+                                self.queue_clean_preview();
+                            }
+                        }
+                    });
+                    ui.end_row();
+
+                    let mut limit_aspect_ratio = self.cleaner.max_aspect_ratio.is_some();
+                    ui.label("Max aspect ratio")
+                        .on_hover_text("Clusters whose bounding box is more than this many times longer than it is wide will be filled, for removing scanner streaks. Table rules and underlines are long and thin too");
+                    ui.horizontal(|ui| {
+                        if ui.checkbox(&mut limit_aspect_ratio, "").changed() {
+                            self.cleaner.max_aspect_ratio = limit_aspect_ratio.then_some(20.0);
+                            self.queue_clean_preview();
+                        }
+                        if let Some(max) = &mut self.cleaner.max_aspect_ratio {
+                            if ui.add(Slider::new(max, 1.0..=100.0).logarithmic(true)).changed() {
+                                self.queue_clean_preview();
+                            }
+                        }
+                    });
+                    ui.end_row();
+
+                    let removes_streaks = self.cleaner.max_aspect_ratio.is_some();
+                    let mut limit_streak_thickness = self.cleaner.max_streak_thickness.is_some();
+                    ui.label("\t- Max thickness")
+                        .on_hover_text("Only clusters at most this thick are filled for being long, so table rules and em dashes thicker than scanner streaks stay");
+                    ui.add_enabled_ui(removes_streaks, |ui| {
+                        ui.horizontal(|ui| {
+                            if ui.checkbox(&mut limit_streak_thickness, "").changed() {
+                                self.cleaner.max_streak_thickness = limit_streak_thickness.then_some(2);
+                                self.queue_clean_preview();
+                            }
+                            if let Some(max) = &mut self.cleaner.max_streak_thickness {
+                                if ui.add(Slider::new(max, 1..=20).suffix("px")).changed() {
+                                    self.queue_clean_preview();
+                                }
+                            }
+                        });
+                    });
+                    ui.end_row();
+
+                    ui.label("\t- Min length")
+                        .on_hover_text("Only clusters at least this long are filled for being long, so short dashes stay");
+                    ui.add_enabled_ui(removes_streaks, |ui| {
+                        if ui.add(Slider::new(&mut self.cleaner.min_streak_length, 0..=1000).clamp_to_range(false).suffix("px")).changed() {
+                            self.queue_clean_preview();
+                        }
+                    });
+                    ui.end_row();
+
+                    ui.label("\t- Directions")
+                        .on_hover_text("Which way the long clusters that are filled can run. Sheet-fed scanners leave streaks in the direction the paper moves");
+                    ui.add_enabled_ui(removes_streaks, |ui| {
+                        ui.horizontal(|ui| {
+                            if ui.checkbox(&mut self.cleaner.remove_vertical_streaks, "Vertical").changed() {
+                                self.queue_clean_preview();
+                            }
+                            if ui.checkbox(&mut self.cleaner.remove_horizontal_streaks, "Horizontal").changed() {
+                                self.queue_clean_preview();
+                            }
+                        });
+                    });
+                    ui.end_row();
+
+                    let mut limit_area_fraction = self.cleaner.max_grapheme_area_fraction.is_some();
+                    ui.label("Max cluster area")
+                        .on_hover_text("Clusters covering more than this fraction of the page will be filled, for removing the dark scanner lid around small pages and huge smudges");
+                    ui.horizontal(|ui| {
+                        if ui.checkbox(&mut limit_area_fraction, "").changed() {
+                            self.cleaner.max_grapheme_area_fraction = limit_area_fraction.then_some(0.3);
+                            self.queue_clean_preview();
+                        }
+                        if let Some(max) = &mut self.cleaner.max_grapheme_area_fraction {
+                            if ui.add(Slider::new(max, 0.0..=1.0)).changed() {
+                                self.queue_clean_preview();
+                            }
+                        }
+                    });
+                    ui.end_row();
+
+                    ui.label("Hole fill threshold")
+                        .on_hover_text("Holes inside kept clusters with an area smaller than this will be filled in, for pinholes in letters from bad scans. Keep it below the size of the holes in letters like o and e");
+                    if ui.add(Slider::new(&mut self.cleaner.hole_fill_threshold, 0..=50).clamp_to_range(false).suffix("px²")).changed() {
+                        self.queue_clean_preview();
+                    }
+                    ui.end_row();
+
+                    ui.label("Fill all holes")
+                        .on_hover_text("Fills in every enclosed hole of every cluster (like the inside of an o) in the cluster's color, so solid stamps and blots stand out from text");
+                    if ui.checkbox(&mut self.cleaner.fill_grapheme_holes, "").changed() {
+                        self.queue_clean_preview();
+                    }
+                    ui.end_row();
 
                     ui.label("Speck fill color")
                         .on_hover_text("What color to fill in specks (useful for debugging).");
@@ -338,16 +1795,214 @@ impl eframe::App for ImageCleanup {
                     }
                     ui.end_row();
 
+                    ui.label("Speck fill")
+                        .on_hover_text("Solid fills specks with the speck fill color. Sampled fills each one with the median color of the paper around it, so fills don't stand out on cream or textured paper when the original is preserved");
+                    ui.horizontal(|ui| {
+                        let sampled = matches!(self.cleaner.speck_fill_style, FillStyle::SampledBackground { .. });
+                        ComboBox::from_id_source("speck_fill_style")
+                            .selected_text(if sampled { "Sampled" } else { "Solid" })
+                            .show_ui(ui, |ui| {
+                                if ui.selectable_label(!sampled, "Solid").clicked() {
+                                    self.cleaner.speck_fill_style = FillStyle::Solid;
+                                    self.queue_clean_preview();
+                                }
+                                if ui.selectable_label(sampled, "Sampled").clicked() && !sampled {
+                                    self.cleaner.speck_fill_style = FillStyle::SampledBackground { radius: 5 };
+                                    self.queue_clean_preview();
+                                }
+                            });
+                        if let FillStyle::SampledBackground { radius } = &mut self.cleaner.speck_fill_style {
+                            if ui.add(DragValue::new(radius).clamp_range(1..=100).suffix("px")).on_hover_text("How far around each speck to sample").changed() {
+                                self.queue_clean_preview();
+                            }
+                        }
+                    });
+                    ui.end_row();
+
                     ui.label("Background fill color")
                         .on_hover_text("What color to fill in the background (useful for debugging).");
-                    if ui.color_edit_button_srgb(&mut self.cleaner.background_fill_color).changed() {
+                    ui.horizontal(|ui| {
+                        if ui.color_edit_button_srgb(&mut self.cleaner.background_fill_color).changed() {
+                            self.queue_clean_preview();
+                        }
+                        // The analysis has to be of the page that's loaded now.
+                        if ui.add_enabled(self.analyze_preview_task.is_none(), Button::new("Auto")).on_hover_text("Use the most common color of the preview page's background, for paper that isn't white").clicked() {
+                            let source = self.original_preview_image().to_rgb8();
+                            let color = ImageCleaner::detect_background_dynamic(&self.analyzed_preview_image.lock().unwrap(), &source);
+                            if let Ok(color) = color {
+                                self.cleaner.background_fill_color = color;
+                                self.preview_background_fill_color = color;
+                                self.queue_clean_preview();
+                            }
+                        }
+                    });
+                    ui.end_row();
+
+                    ui.label("Preserve original")
+                        .on_hover_text("Leaves the page exactly as it was scanned except for the removed specks, keeping the paper's texture and the edges of the letters instead of redrawing the kept clusters over the background fill color");
+                    if ui.checkbox(&mut self.cleaner.preserve_original, "").changed() {
                         self.queue_clean_preview();
                     }
                     ui.end_row();
 
-					if ui.add_enabled(!self.image_paths.is_empty() && self.export_task.is_none(), Button::new("Export all")).on_disabled_hover_text("No images have been opened or they are currently exporting").clicked() {
-                        self.export_task = Some(tokio::spawn(Self::export_all(self.image_paths.clone(), self.analyzer, self.cleaner, self.export_progess.clone())));
+                    ui.label("Output mode")
+                        .on_hover_text("Original draws the kept clusters in their original colors, Binarized draws them pure black, or pure white over a dark background fill (useful for OCR)");
+                    // Nothing is redrawn when the original is preserved.
+                    ui.add_enabled_ui(!self.cleaner.preserve_original, |ui| {
+                        ComboBox::from_id_source("output_mode")
+                            .selected_text(format!("{:?}", self.cleaner.output_mode))
+                            .show_ui(ui, |ui| {
+                                for mode in [OutputMode::Original, OutputMode::Binarized] {
+                                    if ui.selectable_value(&mut self.cleaner.output_mode, mode, format!("{:?}", mode)).changed() {
+                                        self.queue_clean_preview();
+                                    }
+                                }
+                            });
+                    });
+                    ui.end_row();
+
+                    // Only the original colors are darkened.
+                    let adjusts_ink = self.cleaner.output_mode == OutputMode::Original && !self.cleaner.preserve_original;
+                    ui.label("Ink gamma")
+                        .on_hover_text("Darkens the gray parts of the kept clusters, for faded ink, while leaving white as it is. 1 leaves them as they are");
+                    ui.add_enabled_ui(adjusts_ink, |ui| {
+                        if ui.add(Slider::new(&mut self.cleaner.ink_gamma, 0.2..=5.0).logarithmic(true)).changed() {
+                            self.queue_clean_preview();
+                        }
+                    });
+                    ui.end_row();
+
+                    ui.label("Ink black point")
+                        .on_hover_text("Kept pixels at least this dark become pure black, and the lighter ones are darkened to match. 0 leaves them as they are");
+                    ui.add_enabled_ui(adjusts_ink, |ui| {
+                        if ui.add(Slider::new(&mut self.cleaner.ink_black_point, 0..=254)).changed() {
+                            self.queue_clean_preview();
+                        }
+                    });
+                    ui.end_row();
+
+                    let mut use_binarize_threshold = self.cleaner.binarize_threshold.is_some();
+                    ui.label("Binarize threshold")
+                        .on_hover_text("When binarizing, only pixels darker than this are drawn, so the gray edges of letters don't come out black. Starts at the lightness threshold, which every cluster has at least one pixel darker than");
+                    ui.add_enabled_ui(self.cleaner.output_mode == OutputMode::Binarized && !self.cleaner.preserve_original, |ui| {
+                        ui.horizontal(|ui| {
+                            if ui.checkbox(&mut use_binarize_threshold, "").changed() {
+                                self.cleaner.binarize_threshold = use_binarize_threshold.then_some(self.analyzer.lightness_threshold);
+                                self.queue_clean_preview();
+                            }
+                            if let Some(threshold) = &mut self.cleaner.binarize_threshold {
+                                if ui.add(Slider::new(threshold, 0..=255)).changed() {
+                                    self.queue_clean_preview();
+                                }
+                            }
+                        });
+                    });
+                    ui.end_row();
+
+                    #[cfg(feature = "parallel")]
+                    {
+                        ui.label("Export threads")
+                            .on_hover_text("How many images to export at once, more is faster but takes more memory");
+                        ui.add(DragValue::new(&mut self.export_threads).clamp_range(1..=256));
+                        ui.end_row();
+                    }
+
+                    ui.label("Output format")
+                        .on_hover_text("What format to save the cleaned images in. Original keeps each image's own format, PNG saves pages with few colors (like binarized ones) with a palette of just those colors. Any other format than Original changes the file extension, so the originals aren't overwritten");
+                    ui.horizontal(|ui| {
+                        let formats = [
+                            (OutputFormat::Original, "Original"),
+                            (OutputFormat::Png, "PNG"),
+                            (OutputFormat::Jpeg { quality: 90 }, "JPEG"),
+                            (OutputFormat::WebPLossless, "WebP lossless"),
+                            #[cfg(feature = "webp-lossy")]
+                            (OutputFormat::WebPLossy { quality: 80 }, "WebP lossy"),
+                        ];
+                        let current = std::mem::discriminant(&self.output_format);
+                        let is_current = |format: &OutputFormat| std::mem::discriminant(format) == current;
+                        let current_name = formats.iter().find(|(format, _)| is_current(format)).map_or("", |(_, name)| name);
+                        ComboBox::from_id_source("output_format")
+                            .selected_text(current_name)
+                            .show_ui(ui, |ui| {
+                                for (format, name) in formats {
+                                    // Picking the same format again keeps its quality.
+                                    if ui.selectable_label(is_current(&format), name).clicked() && !is_current(&format) {
+                                        self.output_format = format;
+                                    }
+                                }
+                            });
+                        match &mut self.output_format {
+                            OutputFormat::Jpeg { quality } => {
+                                ui.add(Slider::new(quality, 1..=100).text("Quality"));
+                            }
+                            #[cfg(feature = "webp-lossy")]
+                            OutputFormat::WebPLossy { quality } => {
+                                ui.add(Slider::new(quality, 0..=100).text("Quality"));
+                            }
+                            _ => {}
+                        }
+                    });
+                    ui.end_row();
+
+                    if ui.button("Choose output folder…").on_hover_text("Where to save the cleaned images, if no folder is chosen the original images are overwritten").clicked() {
+                        if let Some(directory) = rfd::FileDialog::new().pick_folder() {
+                            self.export_directory = Some(directory);
+                        }
+                    }
+                    match self.export_directory.clone() {
+                        Some(directory) => {
+                            ui.horizontal(|ui| {
+                                ui.label(directory.display().to_string());
+                                if ui.small_button("✖").on_hover_text("Overwrite the original images instead").clicked() {
+                                    self.export_directory = None;
+                                }
+                            });
+                        }
+                        None => {
+                            ui.label("Overwrite originals");
+                        }
+                    }
+                    ui.end_row();
+
+                    let mut flag_blank_pages = self.min_foreground_fraction.is_some();
+                    ui.label("Flag pages with less ink than")
+                        .on_hover_text("Pages where less than this much of the page is kept clusters are listed after exporting, since they might have come out blank from thresholds that are too aggressive");
+                    ui.horizontal(|ui| {
+                        if ui.checkbox(&mut flag_blank_pages, "").changed() {
+                            self.min_foreground_fraction = flag_blank_pages.then_some(0.005);
+                        }
+                        if let Some(fraction) = &mut self.min_foreground_fraction {
+                            let slider = Slider::new(fraction, 0.0..=0.05)
+                                .custom_formatter(|fraction, _| format!("{:.1}%", fraction * 100.0))
+                                .custom_parser(|text| text.trim_end_matches('%').trim().parse::<f64>().ok().map(|percent| percent / 100.0));
+                            ui.add(slider);
+                        }
+                    });
+                    ui.end_row();
+
+					if ui.add_enabled(self.can_export(), Button::new("Export all")).on_hover_text(ctx.format_shortcut(&EXPORT_SHORTCUT)).on_disabled_hover_text("No images have been opened or they are currently exporting").clicked() {
+                        self.request_export();
 					}
+                    if ui.add_enabled(self.can_export(), Button::new("Export to PDF…")).on_hover_text("Clean every page into a single PDF, with each page sized to its image").on_disabled_hover_text("No images have been opened or they are currently exporting").clicked() {
+                        if let Some(path) = rfd::FileDialog::new().add_filter("PDF", &["pdf"]).set_file_name("cleaned.pdf").save_file() {
+                            self.start_pdf_export(path);
+                        }
+                    }
+
+                    if self.confirming_overwrite {
+                        Window::new("Overwrite originals?").collapsible(false).resizable(false).show(ctx, |ui| {
+                            ui.label("No output folder was chosen, so the original images will be replaced with the cleaned ones.");
+                            ui.horizontal(|ui| {
+                                if ui.button("Overwrite").clicked() {
+                                    self.confirming_overwrite = false;
+                                    self.start_export();
+                                }
+                                if ui.button("Cancel").clicked() {
+                                    self.confirming_overwrite = false;
+                                }
+                            });
+                        });
+                    }
 
 
                     if let Some(task) = &self.export_task {
@@ -359,18 +2014,124 @@ impl eframe::App for ImageCleanup {
                                 ctx.request_repaint();
 
                                 if ui.button("Cancel").clicked() {
-                                    task.abort();
+                                    // Pages already being exported stop too, but finished ones stay saved.
+                                    self.export_cancel_token.cancel();
                                 }
                             });
                         }
                     }
 
+                    let has_summary = {
+                        let summary = self.export_summary.lock().unwrap();
+                        !summary.failed.is_empty() || !summary.flagged.is_empty()
+                    };
+                    if self.export_task.is_none() && has_summary {
+                        Window::new("Exporting...").show(ctx, |ui| {
+                            let mut summary = self.export_summary.lock().unwrap();
+                            if !summary.failed.is_empty() {
+                                ui.label(format!("{} images couldn't be exported:", summary.failed.len()));
+                                ScrollArea::vertical().id_source("failed").max_height(200.0).show(ui, |ui| {
+                                    for (path, error) in &summary.failed {
+                                        ui.label(format!("{}: {}", path.display(), error));
+                                    }
+                                });
+                            }
+                            if !summary.flagged.is_empty() {
+                                ui.label(format!("{} images have little ink left and might need checking:", summary.flagged.len()));
+                                ScrollArea::vertical().id_source("flagged").max_height(200.0).show(ui, |ui| {
+                                    for (path, fraction) in &summary.flagged {
+                                        ui.label(format!("{}: {:.2}%", path.display(), fraction * 100.0));
+                                    }
+                                });
+                            }
+
+                            if ui.button("Close").clicked() {
+                                *summary = ExportSummary::default();
+                            }
+                        });
+                    }
+
 
 
                     ui.end_row();
 
 
                 });
+
+            ui.separator();
+
+            ui.horizontal(|ui| {
+                let parameters = self.parameters();
+                if ui.add_enabled(self.history.can_undo(&parameters), Button::new("Undo")).on_hover_text(format!("Go back to the parameters before the last change ({})", ctx.format_shortcut(&UNDO_SHORTCUT))).clicked() {
+                    self.undo();
+                }
+                if ui.add_enabled(self.history.can_redo(&parameters), Button::new("Redo")).on_hover_text(format!("Put back the last change that was undone ({})", ctx.format_shortcut(&REDO_SHORTCUT))).clicked() {
+                    self.redo();
+                }
+            });
+
+            #[cfg(feature = "serde")]
+            {
+                ui.separator();
+
+                ui.horizontal(|ui| {
+                    if ui.button("Save preset…").on_hover_text("Save the import and cleanup parameters to a file, along with the pages that have their own").clicked() {
+                        if let Some(path) = rfd::FileDialog::new().add_filter("Presets", &["json"]).set_file_name("preset.json").save_file() {
+                            let (analyzer, cleaner, page_settings) = self.all_settings();
+                            self.preset_error = save_preset_with_pages(&path, &analyzer, &cleaner, &page_settings).err().map(|error| error.to_string());
+                        }
+                    }
+
+                    if ui.button("Load preset…").on_hover_text("Replace the import and cleanup parameters, and the pages' own, with ones saved to a file").clicked() {
+                        if let Some(path) = rfd::FileDialog::new().add_filter("Presets", &["json"]).pick_file() {
+                            match load_preset_with_pages(&path) {
+                                Ok((analyzer, cleaner, page_settings)) => {
+                                    self.analyzer = analyzer;
+                                    self.cleaner = cleaner;
+                                    self.page_settings = page_settings;
+                                    self.global_settings = None;
+                                    if self.use_own_page_settings() {
+                                        self.history = History::new(self.parameters());
+                                    }
+                                    self.preset_error = None;
+                                    self.queue_analyze_preview();
+                                }
+                                Err(error) => self.preset_error = Some(error.to_string()),
+                            }
+                        }
+                    }
+                });
+
+                if let Some(error) = &self.preset_error {
+                    ui.colored_label(ui.visuals().error_fg_color, error);
+                }
+            }
+
+            ui.separator();
+
+            if ui.button("Export stats…").on_hover_text("Save the size, position, and color of every cluster on the preview page, and whether it gets filled").clicked() {
+                let dialog = rfd::FileDialog::new().add_filter("CSV", &["csv"]);
+                #[cfg(feature = "serde")]
+                let dialog = dialog.add_filter("JSON", &["json"]);
+                if let Some(path) = dialog.set_file_name("stats.csv").save_file() {
+                    self.stats_error = match self.analyzed_preview_image.try_lock() {
+                        Ok(analyzed_image) => {
+                            let report = analyzed_image.component_report(&self.preview_cleaner());
+                            let result = match path.extension().and_then(|extension| extension.to_str()) {
+                                #[cfg(feature = "serde")]
+                                Some("json") => save_component_report_json(&path, &report),
+                                _ => save_component_report_csv(&path, &report),
+                            };
+                            result.err().map(|error| error.to_string())
+                        }
+                        Err(_) => Some("The preview page is still being analyzed".to_string()),
+                    };
+                }
+            }
+
+            if let Some(error) = &self.stats_error {
+                ui.colored_label(ui.visuals().error_fg_color, error);
+            }
         });
 
         SidePanel::left("preview_tools")
@@ -394,6 +2155,24 @@ impl eframe::App for ImageCleanup {
                         }
                         ui.end_row();
 
+                        ui.label("View").on_hover_text(
+                            "Split shows the original page left of a divider you can drag and the cleaned page right of it",
+                        );
+                        ComboBox::from_id_source("preview_view")
+                            .selected_text(format!("{:?}", self.preview_view))
+                            .show_ui(ui, |ui| {
+                                for view in
+                                    [PreviewView::Cleaned, PreviewView::Original, PreviewView::Split]
+                                {
+                                    ui.selectable_value(
+                                        &mut self.preview_view,
+                                        view,
+                                        format!("{view:?}"),
+                                    );
+                                }
+                            });
+                        ui.end_row();
+
                         ui.label("Preview speck fill color");
                         if ui
                             .color_edit_button_srgb(&mut self.preview_speck_fill_color)
@@ -417,13 +2196,32 @@ impl eframe::App for ImageCleanup {
                         ui.end_row();
 
                         ui.label("Zoom");
-                        ui.add(
-                            Slider::new(
-                                &mut self.preview_zoom,
-                                self.preview_min_zoom..=self.preview_max_zoom,
-                            )
-                            .step_by(0.01),
-                        );
+                        ui.horizontal(|ui| {
+                            ui.add(
+                                Slider::new(
+                                    &mut self.preview_zoom,
+                                    self.preview_min_zoom..=self.preview_max_zoom,
+                                )
+                                .step_by(0.01),
+                            );
+                            if ui
+                                .button("Fit")
+                                .on_hover_text(format!(
+                                    "Show the whole page ({})",
+                                    ctx.format_shortcut(&FIT_SHORTCUT)
+                                ))
+                                .clicked()
+                            {
+                                self.fit_preview();
+                            }
+                            if ui
+                                .button("1:1")
+                                .on_hover_text("Show the page at its actual size")
+                                .clicked()
+                            {
+                                self.actual_size_preview(ctx.pixels_per_point());
+                            }
+                        });
                         ui.end_row();
 
                         ui.label("Offset");
@@ -438,7 +2236,47 @@ impl eframe::App for ImageCleanup {
                         ui.add(DragValue::new(&mut self.preview_offset.y).suffix("px"));
                         ui.end_row();
                     });
+
+                ui.separator();
+                let report = &self.preview_report;
+                let reasons: Vec<String> = report
+                    .counts
+                    .iter()
+                    .filter(|(decision, _)| decision.is_removed())
+                    .map(|(decision, count)| format!("{decision:?}: {count}"))
+                    .collect();
+                ui.label(format!(
+                    "{} of {} clusters removed ({} px)",
+                    report.removed_count,
+                    report.decisions.len(),
+                    report.removed_pixels
+                ))
+                .on_hover_text(if reasons.is_empty() {
+                    "Nothing was removed".to_string()
+                } else {
+                    reasons.join("\n")
+                });
+                if let Some(error) = &*self.overrides_error.lock().unwrap() {
+                    ui.colored_label(ui.visuals().error_fg_color, error);
+                }
+            });
+
+        if !self.image_paths.is_empty() {
+            TopBottomPanel::bottom("filmstrip").show(ctx, |ui| {
+                let current = self.preview_page as usize - 1;
+                let scroll_to_current = self.filmstrip_page != self.preview_page;
+                self.filmstrip_page = self.preview_page;
+                if let Some(page) =
+                    self.thumbnails
+                        .show(ui, &self.image_paths, current, scroll_to_current)
+                {
+                    if page != current {
+                        self.set_preview_page(page as u16 + 1);
+                        self.filmstrip_page = self.preview_page;
+                    }
+                }
             });
+        }
 
         CentralPanel::default()
             .frame(eframe::egui::Frame::none().fill(Color32::from_rgb(27, 26, 31)))
@@ -452,11 +2290,9 @@ impl eframe::App for ImageCleanup {
                 if ctx.input(|i| i.key_pressed(Key::ArrowRight))
                     && (self.preview_page as usize) < self.image_paths.len()
                 {
-                    self.preview_page += 1;
-                    self.new_preview_image();
+                    self.set_preview_page(self.preview_page + 1);
                 } else if ctx.input(|i| i.key_pressed(Key::ArrowLeft)) && self.preview_page > 1 {
-                    self.preview_page -= 1;
-                    self.new_preview_image();
+                    self.set_preview_page(self.preview_page - 1);
                 }
 
                 ui.set_clip_rect(ui.max_rect());
@@ -469,6 +2305,7 @@ impl eframe::App for ImageCleanup {
                 // The ratio of whichever dimension has the largest difference between it and the available ui space (usually vertical for portrait pages)
                 let largest_dimension = (image_dimensions.x / ui.available_width())
                     .max(image_dimensions.y / ui.available_height());
+                self.preview_largest_dimension = largest_dimension;
                 let mut zoom = 2f32.powf(self.preview_zoom);
                 let mut rect = Rect::ZERO;
 
@@ -544,9 +2381,45 @@ impl eframe::App for ImageCleanup {
                     self.preview_offset += new_mouse_hover_pixel - mouse_hover_pixel;
                 }
 
-                // Drag to pan
-                let content_response = ui.interact(ui.max_rect(), ui.id(), Sense::drag());
-                if content_response.dragged() {
+                // The split view's divider, interacted with before the page so dragging it doesn't pan.
+                let divider_x = rect.left() + self.preview_split * rect.width();
+                if self.preview_view == PreviewView::Split {
+                    let divider_response = ui.interact(
+                        Rect::from_x_y_ranges(
+                            divider_x - 8.0..=divider_x + 8.0,
+                            ui.max_rect().y_range(),
+                        ),
+                        ui.id().with("split_divider"),
+                        Sense::drag(),
+                    );
+                    if divider_response.dragged() {
+                        self.preview_split = (self.preview_split
+                            + divider_response.drag_delta().x / rect.width())
+                        .clamp(0.0, 1.0);
+                    }
+                    if divider_response.hovered() || divider_response.dragged() {
+                        ctx.set_cursor_icon(CursorIcon::ResizeHorizontal);
+                    }
+                }
+
+                // Drag to pan, or hold shift and drag to select a rectangle of the page
+                let content_response = ui.interact(ui.max_rect(), ui.id(), Sense::click_and_drag());
+                if content_response.drag_started() {
+                    self.selecting = ui.input(|i| i.modifiers.shift);
+                    if self.selecting {
+                        let start = ui
+                            .input(|i| i.pointer.press_origin())
+                            .map(|pos| ui_to_image_pixels!(pos));
+                        self.selection = start.map(|start| (start, start));
+                    }
+                }
+                if content_response.dragged() && self.selecting {
+                    if let (Some((start, _)), Some(pos)) =
+                        (self.selection, content_response.interact_pointer_pos())
+                    {
+                        self.selection = Some((start, ui_to_image_pixels!(pos)));
+                    }
+                } else if content_response.dragged() {
                     ui.input(|input| {
                         self.preview_offset += ui_to_image_scale!(input.pointer.delta());
                         self.preview_velocity = ui_to_image_scale!(input.pointer.velocity());
@@ -579,31 +2452,106 @@ impl eframe::App for ImageCleanup {
 
                 rect = calc_ui_rect!();
 
+                // Click a grapheme to always keep it, again to always remove it, and again to leave it up to the
+                // cleaner.
+                if content_response.clicked() {
+                    if let Some(pos) = content_response.interact_pointer_pos() {
+                        let pixel = ui_to_image_pixels!(pos);
+                        if pixel.x >= 0.0
+                            && pixel.y >= 0.0
+                            && pixel.x < image_dimensions.x
+                            && pixel.y < image_dimensions.y
+                        {
+                            self.override_clicks.push((pixel.x as u32, pixel.y as u32));
+                            // Stops a clean in progress, which would be out of date anyway.
+                            self.queue_clean_preview();
+                        }
+                    }
+                }
+
+                // Right click a selection to keep or remove everything that overlaps it.
+                if let Some(selection) = self.selection_rect() {
+                    let mut chosen = None;
+                    content_response.context_menu(|ui| {
+                        for (label, manual_override) in [
+                            ("Always keep", Some(true)),
+                            ("Always remove", Some(false)),
+                            ("Leave to the cleaner", None),
+                        ] {
+                            if ui.button(label).clicked() {
+                                chosen = Some(manual_override);
+                                ui.close_menu();
+                            }
+                        }
+                    });
+                    if let Some(manual_override) = chosen {
+                        self.override_selections.push((selection, manual_override));
+                        self.selection = None;
+                        self.queue_clean_preview();
+                    }
+                }
+                if ui.input(|i| i.key_pressed(Key::Escape)) {
+                    self.selection = None;
+                }
+
                 let painter = ui.painter();
 
-                painter.image(
-                    self.preview_image_handle.id(),
-                    rect,
-                    Rect::from_x_y_ranges(0.0..=1.0, 0.0..=1.0),
-                    Color32::WHITE,
-                );
+                let full_uv = Rect::from_x_y_ranges(0.0..=1.0, 0.0..=1.0);
+                match self.preview_view {
+                    PreviewView::Cleaned => painter.image(
+                        self.preview_image_handle.id(),
+                        rect,
+                        full_uv,
+                        Color32::WHITE,
+                    ),
+                    PreviewView::Original => painter.image(
+                        self.original_image_handle.id(),
+                        rect,
+                        full_uv,
+                        Color32::WHITE,
+                    ),
+                    PreviewView::Split => {
+                        let divider_x = rect.left() + self.preview_split * rect.width();
+                        let (mut left, mut right) = (rect, rect);
+                        left.set_right(divider_x);
+                        right.set_left(divider_x);
+                        let (mut left_uv, mut right_uv) = (full_uv, full_uv);
+                        left_uv.set_right(self.preview_split);
+                        right_uv.set_left(self.preview_split);
+                        painter.image(
+                            self.original_image_handle.id(),
+                            left,
+                            left_uv,
+                            Color32::WHITE,
+                        );
+                        painter.image(
+                            self.preview_image_handle.id(),
+                            right,
+                            right_uv,
+                            Color32::WHITE,
+                        )
+                    }
+                };
 
                 // Draw margins
+                let margins = self
+                    .preview_cleaner()
+                    .margins_for(self.preview_image_width, self.preview_image_height);
                 for (a, b) in [
                     (
                         Vec2::ZERO,
-                        Vec2::new(image_dimensions.x, self.cleaner.page_margins.1 as f32),
+                        Vec2::new(image_dimensions.x, margins.top as f32),
                     ),
                     (
-                        Vec2::new(0.0, image_dimensions.y - self.cleaner.page_margins.1 as f32),
+                        Vec2::new(0.0, image_dimensions.y - margins.bottom as f32),
                         Vec2::new(image_dimensions.x, image_dimensions.y),
                     ),
                     (
                         Vec2::ZERO,
-                        Vec2::new(self.cleaner.page_margins.0 as f32, image_dimensions.y),
+                        Vec2::new(margins.left as f32, image_dimensions.y),
                     ),
                     (
-                        Vec2::new(image_dimensions.x - self.cleaner.page_margins.0 as f32, 0.0),
+                        Vec2::new(image_dimensions.x - margins.right as f32, 0.0),
                         Vec2::new(image_dimensions.x, image_dimensions.y),
                     ),
                 ] {
@@ -614,6 +2562,30 @@ impl eframe::App for ImageCleanup {
                     );
                 }
 
+                if let Some((start, end)) = self.selection {
+                    painter.rect(
+                        Rect::from_two_pos(image_to_ui_pixels!(start), image_to_ui_pixels!(end)),
+                        0.0,
+                        Color32::from_rgba_unmultiplied(255, 200, 0, 48),
+                        Stroke::new(1.0, Color32::from_rgb(255, 200, 0)),
+                    );
+                }
+
+                if self.preview_view == PreviewView::Split {
+                    let divider_x = rect.left() + self.preview_split * rect.width();
+                    painter.vline(
+                        divider_x,
+                        ui.max_rect().y_range(),
+                        Stroke::new(2.0, Color32::WHITE),
+                    );
+                    painter.circle(
+                        pos2(divider_x, ui.max_rect().center().y),
+                        8.0,
+                        Color32::from_rgb(27, 26, 31),
+                        Stroke::new(2.0, Color32::WHITE),
+                    );
+                }
+
                 if processing {
                     let spinner_radius = 50.0;
                     let spinner_inner_margin = 10.0;
@@ -640,5 +2612,9 @@ impl eframe::App for ImageCleanup {
                     );
                 }
             });
+
+        if let Some(wait) = self.history.update(self.parameters()) {
+            ctx.request_repaint_after(wait);
+        }
     }
 }
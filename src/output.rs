@@ -0,0 +1,185 @@
+// Saving cleaned pages in a chosen format and quality, instead of whatever format their file extension says with the
+// encoder's default settings.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::{CompressionType, FilterType, PngEncoder};
+use image::codecs::webp::WebPEncoder;
+#[cfg(feature = "webp-lossy")]
+use image::codecs::webp::WebPQuality;
+use image::error::{EncodingError, ImageFormatHint};
+use image::*;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum OutputFormat {
+    // The original image's format, from its extension.
+    #[default]
+    Original,
+    // Pages with 256 colors or fewer, like binarized ones, are saved with a palette of just those colors, which takes
+    // a lot less space than full color. Two colors take 1 bit per pixel.
+    Png,
+    // Quality from 1 to 100.
+    Jpeg {
+        quality: u8,
+    },
+    WebPLossless,
+    // Quality from 0 to 100. Needs libwebp, so it's only there with the webp-lossy feature.
+    #[cfg(feature = "webp-lossy")]
+    WebPLossy {
+        quality: u8,
+    },
+}
+
+impl OutputFormat {
+    // Where a page from path gets saved, which is path with its extension swapped for this format's.
+    pub fn output_path(self, path: &Path) -> PathBuf {
+        let extension = match self {
+            OutputFormat::Original => return path.to_path_buf(),
+            OutputFormat::Png => "png",
+            OutputFormat::Jpeg { .. } => "jpg",
+            OutputFormat::WebPLossless => "webp",
+            #[cfg(feature = "webp-lossy")]
+            OutputFormat::WebPLossy { .. } => "webp",
+        };
+        path.with_extension(extension)
+    }
+
+    pub fn save(self, image: &DynamicImage, path: impl AsRef<Path>) -> ImageResult<()> {
+        let path = path.as_ref();
+        if self == OutputFormat::Original {
+            return image.save(path);
+        }
+
+        let mut writer = BufWriter::new(File::create(path)?);
+        match self {
+            OutputFormat::Original => unreachable!("saved above"),
+            OutputFormat::Png => save_png(image, &mut writer)?,
+            // JPEG has no alpha or 16 bit samples.
+            OutputFormat::Jpeg { quality } => {
+                let encoder = JpegEncoder::new_with_quality(&mut writer, quality.clamp(1, 100));
+                if image.color().has_color() {
+                    DynamicImage::ImageRgb8(image.to_rgb8()).write_with_encoder(encoder)?
+                } else {
+                    DynamicImage::ImageLuma8(image.to_luma8()).write_with_encoder(encoder)?
+                }
+            }
+            // WebP only has 8 bit samples.
+            OutputFormat::WebPLossless => {
+                let encoder = WebPEncoder::new_lossless(&mut writer);
+                to_8_bit(image).write_with_encoder(encoder)?
+            }
+            #[cfg(feature = "webp-lossy")]
+            OutputFormat::WebPLossy { quality } => {
+                let encoder = WebPEncoder::new_with_quality(
+                    &mut writer,
+                    WebPQuality::lossy(quality.min(100)),
+                );
+                // libwebp only takes RGB and RGBA.
+                if image.color().has_alpha() {
+                    DynamicImage::ImageRgba8(image.to_rgba8()).write_with_encoder(encoder)?
+                } else {
+                    DynamicImage::ImageRgb8(image.to_rgb8()).write_with_encoder(encoder)?
+                }
+            }
+        }
+        writer.flush()?;
+
+        Ok(())
+    }
+}
+
+fn to_8_bit(image: &DynamicImage) -> DynamicImage {
+    match image {
+        DynamicImage::ImageLuma16(_) => DynamicImage::ImageLuma8(image.to_luma8()),
+        DynamicImage::ImageLumaA16(_) => DynamicImage::ImageLumaA8(image.to_luma_alpha8()),
+        DynamicImage::ImageRgb16(_) | DynamicImage::ImageRgb32F(_) => {
+            DynamicImage::ImageRgb8(image.to_rgb8())
+        }
+        DynamicImage::ImageRgba16(_) | DynamicImage::ImageRgba32F(_) => {
+            DynamicImage::ImageRgba8(image.to_rgba8())
+        }
+        _ => image.clone(),
+    }
+}
+
+fn save_png(image: &DynamicImage, writer: impl Write) -> ImageResult<()> {
+    let Some((palette, indices)) = palette(image) else {
+        let encoder =
+            PngEncoder::new_with_quality(writer, CompressionType::Best, FilterType::Adaptive);
+        return image.write_with_encoder(encoder);
+    };
+
+    // As few bits per pixel as the palette fits in, with the pixels packed into bytes from the high bits down and each
+    // row starting on a new byte.
+    let bit_depth = match palette.len() {
+        0..=2 => png::BitDepth::One,
+        3..=4 => png::BitDepth::Two,
+        5..=16 => png::BitDepth::Four,
+        _ => png::BitDepth::Eight,
+    };
+    let bits = bit_depth as usize;
+    let width = image.width() as usize;
+    let row_bytes = (width * bits).div_ceil(8);
+    let mut data = vec![0; row_bytes * image.height() as usize];
+    for (row, row_indices) in data.chunks_mut(row_bytes).zip(indices.chunks(width)) {
+        for (x, &index) in row_indices.iter().enumerate() {
+            let bit = x * bits;
+            row[bit / 8] |= index << (8 - bits - bit % 8);
+        }
+    }
+
+    let mut encoder = png::Encoder::new(writer, image.width(), image.height());
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(bit_depth);
+    encoder.set_palette(palette.concat());
+    encoder.set_compression(png::Compression::Best);
+    let mut writer = encoder.write_header().map_err(png_error)?;
+    writer.write_image_data(&data).map_err(png_error)?;
+    writer.finish().map_err(png_error)
+}
+
+// The image's colors and each pixel's index into them, if it's opaque with no more than 256 colors. 16 bit images only
+// have a palette if every sample fits in 8 bits exactly, like binarized pages.
+fn palette(image: &DynamicImage) -> Option<(Vec<[u8; 3]>, Vec<u8>)> {
+    let image = match image {
+        DynamicImage::ImageLuma8(_) | DynamicImage::ImageRgb8(_) => image.to_rgb8(),
+        DynamicImage::ImageLuma16(buffer) if buffer.iter().all(|&sample| sample % 257 == 0) => {
+            image.to_rgb8()
+        }
+        DynamicImage::ImageRgb16(buffer) if buffer.iter().all(|&sample| sample % 257 == 0) => {
+            image.to_rgb8()
+        }
+        _ => return None,
+    };
+
+    let mut palette = Vec::new();
+    let mut index_of = HashMap::new();
+    let mut indices = Vec::with_capacity(image.width() as usize * image.height() as usize);
+    for pixel in image.pixels() {
+        let index = match index_of.get(&pixel.0) {
+            Some(&index) => index,
+            None => {
+                if palette.len() == 256 {
+                    return None;
+                }
+                palette.push(pixel.0);
+                index_of.insert(pixel.0, (palette.len() - 1) as u8);
+                (palette.len() - 1) as u8
+            }
+        };
+        indices.push(index);
+    }
+
+    Some((palette, indices))
+}
+
+fn png_error(error: png::EncodingError) -> ImageError {
+    ImageError::Encoding(EncodingError::new(
+        ImageFormatHint::Exact(ImageFormat::Png),
+        error,
+    ))
+}
@@ -0,0 +1,365 @@
+// Carrying manual overrides over from one analysis of a page to the next, so changing an import parameter doesn't undo
+// the graphemes the user already kept or removed by hand, and saving them to a file so they outlast the analysis.
+//
+// The file is text, a header line with the page's size and then a line for each overridden grapheme with its stable id
+// in hex, its bounding box, and keep or remove:
+//
+//     DCL 1 2331 3183
+//     9c2f1e0a55d3b871 102 88 131 120 keep
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::hash::Hasher;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use image::math;
+
+use crate::cache::Fnv1a;
+use crate::{index, AnalyzedImage, DynamicAnalyzedImage, Grapheme};
+
+// How much of the smaller of two graphemes has to be covered by the other for them to count as the same mark. Low
+// enough that a grapheme that got thicker or thinner with a different threshold still matches.
+const MIN_OVERLAP: f32 = 0.5;
+
+impl<P> Grapheme<P> {
+    // An id made from the bounding box and the exact pixels the grapheme covers, the same every time the same grapheme is
+    // found (whatever order it was found in), and different if any of its pixels change.
+    pub fn stable_id(&self) -> u64 {
+        let mut runs = self.runs.clone();
+        runs.sort_unstable();
+
+        let mut hasher = Fnv1a::default();
+        for value in [self.top, self.bottom, self.left, self.right] {
+            hasher.write_u32(value);
+        }
+        for (y, start, end) in runs {
+            hasher.write_u32(y - self.top);
+            hasher.write_u32(start - self.left);
+            hasher.write_u32(end - self.left);
+        }
+
+        hasher.finish()
+    }
+}
+
+impl<P> AnalyzedImage<P> {
+    // Gives each grapheme the manual override of the graphemes in previous (an earlier analysis of the same page) that
+    // it mostly overlaps, or that mostly overlap it. A grapheme that split in two passes its override on to both
+    // halves, and graphemes that merged pass theirs on to the merged one, with keeping winning over removing if they
+    // disagree. Graphemes without a match keep whatever override they have.
+    pub fn transfer_overrides(&mut self, previous: &AnalyzedImage<P>) {
+        if (self.width, self.height) != (previous.width, previous.height) {
+            return;
+        }
+
+        let mut shared: Vec<(u32, usize)> = Vec::new();
+        for grapheme in &mut self.graphemes {
+            // How many of the grapheme's pixels each overridden previous grapheme covers.
+            shared.clear();
+            for (x, y) in grapheme.positions() {
+                let i = previous.map[index(previous.width, x, y)];
+                if i == u32::MAX || previous.graphemes[i as usize].manual_override.is_none() {
+                    continue;
+                }
+                match shared.iter_mut().find(|(j, _)| *j == i) {
+                    Some((_, count)) => *count += 1,
+                    None => shared.push((i, 1)),
+                }
+            }
+
+            let manual_override = shared
+                .iter()
+                .filter(|&&(i, count)| {
+                    let smaller = grapheme
+                        .pixel_count()
+                        .min(previous.graphemes[i as usize].pixel_count());
+                    count as f32 >= smaller as f32 * MIN_OVERLAP
+                })
+                .filter_map(|&(i, _)| previous.graphemes[i as usize].manual_override)
+                .max();
+            if manual_override.is_some() {
+                grapheme.manual_override = manual_override;
+            }
+        }
+    }
+
+    pub fn save_overrides(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writeln!(writer, "DCL 1 {} {}", self.width, self.height)?;
+        for grapheme in &self.graphemes {
+            let Some(keep) = grapheme.manual_override else {
+                continue;
+            };
+            writeln!(
+                writer,
+                "{:016x} {} {} {} {} {}",
+                grapheme.stable_id(),
+                grapheme.left,
+                grapheme.top,
+                grapheme.right,
+                grapheme.bottom,
+                if keep { "keep" } else { "remove" },
+            )?;
+        }
+
+        writer.flush()
+    }
+
+    // Sets the overrides saved by save_overrides, from this analysis or one of the same page with different parameters.
+    // Each one goes to the grapheme with the same stable id, or failing that the one whose bounding box best matches
+    // the saved one, as long as the boxes overlap like in transfer_overrides. Returns how many couldn't be matched to
+    // any grapheme, they're all skipped if the page is a different size. Graphemes the file doesn't mention keep
+    // whatever override they have. A file with a bounding box that isn't inside of its page is invalid.
+    pub fn load_overrides(&mut self, path: impl AsRef<Path>) -> io::Result<usize> {
+        let mut lines = BufReader::new(File::open(path)?).lines();
+        let header = lines.next().transpose()?.unwrap_or_default();
+        let size = match header.split(' ').collect::<Vec<_>>()[..] {
+            ["DCL", "1", width, height] => (parse(width)?, parse(height)?),
+            _ => return Err(invalid_data("not an overrides file")),
+        };
+
+        let mut entries = Vec::new();
+        for line in lines {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            let [id, left, top, right, bottom, keep] = line.split(' ').collect::<Vec<_>>()[..]
+            else {
+                return Err(invalid_data("bad override line"));
+            };
+            let id = u64::from_str_radix(id, 16).map_err(|_| invalid_data("bad id"))?;
+            let bounds = (parse(left)?, parse(top)?, parse(right)?, parse(bottom)?);
+            if bounds.0 > bounds.2
+                || bounds.1 > bounds.3
+                || bounds.2 >= size.0
+                || bounds.3 >= size.1
+            {
+                return Err(invalid_data("bounding box outside of the page"));
+            }
+            let keep = match keep {
+                "keep" => true,
+                "remove" => false,
+                _ => return Err(invalid_data("bad override")),
+            };
+            entries.push((id, bounds, keep));
+        }
+        if size != (self.width, self.height) {
+            return Ok(entries.len());
+        }
+
+        // Only graphemes with the same bounding box as a saved one can have the same id, the rest aren't worth hashing.
+        let boxes: HashSet<_> = entries.iter().map(|&(_, bounds, _)| bounds).collect();
+        let ids: HashMap<u64, usize> = self
+            .graphemes
+            .iter()
+            .enumerate()
+            .filter(|(_, grapheme)| {
+                boxes.contains(&(grapheme.left, grapheme.top, grapheme.right, grapheme.bottom))
+            })
+            .map(|(i, grapheme)| (grapheme.stable_id(), i))
+            .collect();
+        // Keeping wins over removing when several saved overrides land on the same grapheme, like a merged one.
+        let mut matched: HashMap<usize, bool> = HashMap::new();
+        let mut unmatched = 0;
+        for (id, bounds, keep) in entries {
+            match ids
+                .get(&id)
+                .copied()
+                .or_else(|| self.best_box_match(bounds))
+            {
+                Some(i) => *matched.entry(i).or_default() |= keep,
+                None => unmatched += 1,
+            }
+        }
+        for (i, keep) in matched {
+            self.graphemes[i].manual_override = Some(keep);
+        }
+
+        Ok(unmatched)
+    }
+
+    // The grapheme whose bounding box has the most in common with (left, top, right, bottom) relative to both their
+    // sizes, out of the ones that cover enough of it or enough of which it covers.
+    fn best_box_match(&self, (left, top, right, bottom): (u32, u32, u32, u32)) -> Option<usize> {
+        let area = |left: u32, top: u32, right: u32, bottom: u32| {
+            (right - left + 1) as u64 * (bottom - top + 1) as u64
+        };
+        let saved_area = area(left, top, right, bottom);
+        let rect = math::Rect {
+            x: left,
+            y: top,
+            width: right - left + 1,
+            height: bottom - top + 1,
+        };
+
+        self.graphemes_in_rect(rect)
+            .filter_map(|(id, grapheme)| {
+                let shared = area(
+                    grapheme.left.max(left),
+                    grapheme.top.max(top),
+                    grapheme.right.min(right),
+                    grapheme.bottom.min(bottom),
+                );
+                let grapheme_area =
+                    area(grapheme.left, grapheme.top, grapheme.right, grapheme.bottom);
+                let smaller = grapheme_area.min(saved_area);
+                (shared as f32 >= smaller as f32 * MIN_OVERLAP).then(|| {
+                    let union = grapheme_area + saved_area - shared;
+                    (shared as f32 / union as f32, id.index())
+                })
+            })
+            .max_by(|a, b| a.0.total_cmp(&b.0))
+            .map(|(_, i)| i)
+    }
+}
+
+fn parse(value: &str) -> io::Result<u32> {
+    value.parse().map_err(|_| invalid_data("bad number"))
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl DynamicAnalyzedImage {
+    // Does nothing if the images were analyzed as different pixel types.
+    pub fn transfer_overrides(&mut self, previous: &DynamicAnalyzedImage) {
+        match (self, previous) {
+            (DynamicAnalyzedImage::Luma8(image), DynamicAnalyzedImage::Luma8(previous)) => {
+                image.transfer_overrides(previous)
+            }
+            (DynamicAnalyzedImage::Rgb8(image), DynamicAnalyzedImage::Rgb8(previous)) => {
+                image.transfer_overrides(previous)
+            }
+            (DynamicAnalyzedImage::Rgba8(image), DynamicAnalyzedImage::Rgba8(previous)) => {
+                image.transfer_overrides(previous)
+            }
+            (DynamicAnalyzedImage::Luma16(image), DynamicAnalyzedImage::Luma16(previous)) => {
+                image.transfer_overrides(previous)
+            }
+            (DynamicAnalyzedImage::Rgb16(image), DynamicAnalyzedImage::Rgb16(previous)) => {
+                image.transfer_overrides(previous)
+            }
+            (DynamicAnalyzedImage::Rgba16(image), DynamicAnalyzedImage::Rgba16(previous)) => {
+                image.transfer_overrides(previous)
+            }
+            _ => {}
+        }
+    }
+
+    pub fn save_overrides(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        match self {
+            DynamicAnalyzedImage::Luma8(image) => image.save_overrides(path),
+            DynamicAnalyzedImage::Rgb8(image) => image.save_overrides(path),
+            DynamicAnalyzedImage::Rgba8(image) => image.save_overrides(path),
+            DynamicAnalyzedImage::Luma16(image) => image.save_overrides(path),
+            DynamicAnalyzedImage::Rgb16(image) => image.save_overrides(path),
+            DynamicAnalyzedImage::Rgba16(image) => image.save_overrides(path),
+        }
+    }
+
+    pub fn load_overrides(&mut self, path: impl AsRef<Path>) -> io::Result<usize> {
+        match self {
+            DynamicAnalyzedImage::Luma8(image) => image.load_overrides(path),
+            DynamicAnalyzedImage::Rgb8(image) => image.load_overrides(path),
+            DynamicAnalyzedImage::Rgba8(image) => image.load_overrides(path),
+            DynamicAnalyzedImage::Luma16(image) => image.load_overrides(path),
+            DynamicAnalyzedImage::Rgb16(image) => image.load_overrides(path),
+            DynamicAnalyzedImage::Rgba16(image) => image.load_overrides(path),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use image::{imageops, Rgb};
+
+    use super::*;
+    use crate::tests::demo_page;
+    use crate::ImageAnalyzer;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "document_cleaner_{}_{name}.dcl",
+            std::process::id()
+        ))
+    }
+
+    // Analyzes part of the demo page with some graphemes kept and some removed by hand.
+    fn overridden_page() -> (image::RgbImage, AnalyzedImage<Rgb<u8>>) {
+        let image = imageops::crop_imm(&demo_page(), 150, 150, 900, 700).to_image();
+        let mut analyzed_image = ImageAnalyzer::default().analyze(&image);
+        for (i, grapheme) in analyzed_image.graphemes.iter_mut().enumerate().step_by(7) {
+            grapheme.manual_override = Some(i % 2 == 0);
+        }
+        (image, analyzed_image)
+    }
+
+    fn overrides<P>(analyzed_image: &AnalyzedImage<P>) -> Vec<Option<bool>> {
+        analyzed_image
+            .graphemes
+            .iter()
+            .map(|grapheme| grapheme.manual_override)
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let (image, analyzed_image) = overridden_page();
+        let path = temp_path("round_trip");
+        analyzed_image.save_overrides(&path).unwrap();
+
+        let mut loaded = ImageAnalyzer::default().analyze(&image);
+        let unmatched = loaded.load_overrides(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(unmatched.unwrap(), 0);
+        assert_eq!(overrides(&loaded), overrides(&analyzed_image));
+    }
+
+    #[test]
+    fn load_after_changing_parameters() {
+        let (image, analyzed_image) = overridden_page();
+        let path = temp_path("changed_parameters");
+        analyzed_image.save_overrides(&path).unwrap();
+
+        let analyzer = ImageAnalyzer {
+            lightness_threshold: 150,
+            ..ImageAnalyzer::default()
+        };
+        let mut loaded = analyzer.analyze(&image);
+        let unmatched = loaded.load_overrides(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(unmatched.unwrap(), 0);
+
+        // The overrides land on the same graphemes as when they're carried over from the earlier analysis.
+        let mut transferred = analyzer.analyze(&image);
+        transferred.transfer_overrides(&analyzed_image);
+        assert_eq!(overrides(&loaded), overrides(&transferred));
+        assert!(overrides(&loaded).iter().any(Option::is_some));
+    }
+
+    #[test]
+    fn rejects_bounding_boxes_outside_of_the_page() {
+        let path = temp_path("bad_bounds");
+        let mut analyzed_image =
+            ImageAnalyzer::default().analyze(&image::RgbImage::from_pixel(10, 10, Rgb([255; 3])));
+        for line in [
+            "0 5 0 4 0 keep",
+            "0 0 5 0 4 keep",
+            "0 0 0 10 0 keep",
+            "0 0 0 0 10 remove",
+        ] {
+            fs::write(&path, format!("DCL 1 10 10\n{line}\n")).unwrap();
+            let error = analyzed_image.load_overrides(&path).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{line}");
+        }
+        fs::write(&path, "DCL 1 10 10\n0 0 0 9 9 keep\n").unwrap();
+        assert_eq!(analyzed_image.load_overrides(&path).unwrap(), 1);
+        fs::remove_file(&path).unwrap();
+    }
+}
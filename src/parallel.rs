@@ -0,0 +1,452 @@
+// Multithreaded versions of the analysis and cleaning passes. They produce exactly the same graphemes in the same order
+// as the single threaded ones, only the order of the pixels within each grapheme may differ, and exactly the same
+// cleaned images.
+
+use std::ops::Range;
+
+use rayon::prelude::*;
+
+use crate::pixel::Canvas;
+use crate::{
+    detect_rows, index, merge_sets, pack_row, union, AnalyzedImage, BackgroundTest, CancelToken,
+    Cancelled, Connectivity, Decision, DocumentImage, DocumentPixel, Grapheme, ImageAnalyzer,
+    ImageCleaner, Neighbors, VisitedMap,
+};
+
+impl ImageAnalyzer {
+    // Marks every background pixel as visited, so only the graphemes are left.
+    pub(crate) fn whiten<P: DocumentPixel>(
+        &self,
+        image: &DocumentImage<P>,
+        background_test: &BackgroundTest<P::Subpixel>,
+        visited_map: &mut VisitedMap,
+        progress: &mut impl FnMut(f32),
+        is_cancelled: &(impl Fn() -> bool + Sync),
+    ) -> Result<(), Cancelled> {
+        let words_per_row = visited_map.words_per_row;
+        if words_per_row == 0 {
+            return Ok(());
+        }
+
+        // Work through the image a band of rows at a time so progress and cancellation stay responsive.
+        let band_height = rayon::current_num_threads() * 16;
+        for (band, rows) in visited_map
+            .words
+            .chunks_mut(words_per_row * band_height)
+            .enumerate()
+        {
+            if is_cancelled() {
+                return Err(Cancelled);
+            }
+
+            rows.par_chunks_mut(words_per_row)
+                .enumerate()
+                .for_each(|(i, row)| {
+                    let y = (band * band_height + i) as u32;
+                    pack_row(
+                        row,
+                        (0..image.width())
+                            .map(|x| self.is_background(x, y, image, background_test)),
+                    );
+                });
+            progress(
+                ((band + 1) * band_height).min(image.height() as usize) as f32
+                    / image.height() as f32
+                    / 2.0,
+            );
+        }
+
+        Ok(())
+    }
+
+    // Flood fills horizontal strips of the image separately, then joins up the graphemes that cross from one strip into
+    // the next.
+    pub(crate) fn detect_graphemes<P: DocumentPixel>(
+        &self,
+        image: &DocumentImage<P>,
+        visited_map: &VisitedMap,
+        progress: &mut impl FnMut(f32),
+        is_cancelled: &(impl Fn() -> bool + Sync),
+    ) -> Result<Vec<Grapheme<P>>, Cancelled> {
+        let height = image.height();
+        let strip_height = height
+            .div_ceil(rayon::current_num_threads() as u32 * 4)
+            .max(1);
+        let strips: Vec<Range<u32>> = (0..height)
+            .step_by(strip_height as usize)
+            .map(|top| top..(top + strip_height).min(height))
+            .collect();
+
+        let strips = strips
+            .into_par_iter()
+            .map(|rows| self.detect_strip(image, visited_map, rows, is_cancelled))
+            .collect::<Result<Vec<_>, _>>()?;
+        progress(0.9);
+        if is_cancelled() {
+            return Err(Cancelled);
+        }
+
+        // Union-find over every strip's graphemes, numbered strip by strip.
+        let mut offsets = Vec::with_capacity(strips.len());
+        let mut total = 0;
+        for strip in &strips {
+            offsets.push(total);
+            total += strip.graphemes.len();
+        }
+        let mut parents: Vec<usize> = (0..total).collect();
+
+        let neighbors: &[i64] = match self.connectivity {
+            Connectivity::Four => &[0],
+            Connectivity::Eight => &[-1, 0, 1],
+        };
+        for (i, (above, below)) in strips.iter().zip(strips.iter().skip(1)).enumerate() {
+            for (x, &a) in above.bottom_row.iter().enumerate() {
+                if a == u32::MAX {
+                    continue;
+                }
+
+                for &dx in neighbors {
+                    let Some(&b) = below.top_row.get((x as i64 + dx) as usize) else {
+                        continue;
+                    };
+                    if b != u32::MAX {
+                        union(
+                            &mut parents,
+                            offsets[i] + a as usize,
+                            offsets[i + 1] + b as usize,
+                        );
+                    }
+                }
+            }
+        }
+
+        // Strips are in order and so are the graphemes within them, so merging keeps the graphemes in the same order as
+        // a single flood fill over the image.
+        let graphemes = merge_sets(
+            strips.into_iter().flat_map(|strip| strip.graphemes),
+            &mut parents,
+        );
+        progress(1.0);
+
+        Ok(graphemes)
+    }
+
+    fn detect_strip<P: DocumentPixel>(
+        &self,
+        image: &DocumentImage<P>,
+        visited_map: &VisitedMap,
+        rows: Range<u32>,
+        is_cancelled: &(impl Fn() -> bool + Sync),
+    ) -> Result<Strip<P>, Cancelled> {
+        let width = visited_map.width;
+        let graphemes = detect_rows(
+            image,
+            visited_map,
+            rows.clone(),
+            self.connectivity,
+            |p| self.value(p),
+            |_| match is_cancelled() {
+                true => Err(Cancelled),
+                false => Ok(()),
+            },
+        )?;
+
+        // Which grapheme each pixel along the edges of the strip belongs to, for joining them to the next strip.
+        let mut top_row = vec![u32::MAX; width as usize];
+        let mut bottom_row = vec![u32::MAX; width as usize];
+        for (i, grapheme) in graphemes.iter().enumerate() {
+            for &(y, start, end) in &grapheme.runs {
+                let range = start as usize..end as usize;
+                if y == rows.start {
+                    top_row[range.clone()].fill(i as u32);
+                }
+                if y == rows.end - 1 {
+                    bottom_row[range].fill(i as u32);
+                }
+            }
+        }
+
+        Ok(Strip {
+            graphemes,
+            top_row,
+            bottom_row,
+        })
+    }
+}
+
+struct Strip<P> {
+    graphemes: Vec<Grapheme<P>>,
+    top_row: Vec<u32>,
+    bottom_row: Vec<u32>,
+}
+
+impl ImageCleaner {
+    pub(crate) fn decide_all<P: DocumentPixel>(
+        &self,
+        analyzed_image: &AnalyzedImage<P>,
+        neighbors: &Neighbors,
+    ) -> Vec<Decision> {
+        (0..analyzed_image.graphemes.len())
+            .into_par_iter()
+            .map(|i| self.decide(i, analyzed_image, neighbors))
+            .collect()
+    }
+
+    // Draws bands of rows at the same time, each with every grapheme that reaches into it in order, so where holes are
+    // painted over other graphemes the result is the same as drawing them one after the other. Graphemes that reach into
+    // more than one band (like the shadow along the edge of a scan) are drawn into a list of pixels first, so their
+    // holes and fill colors are only worked out once.
+    pub(crate) fn render<P: DocumentPixel>(
+        &self,
+        analyzed_image: &AnalyzedImage<P>,
+        source: Option<&DocumentImage<P>>,
+        image: &mut DocumentImage<P>,
+        decisions: &[Decision],
+        progress: &mut impl FnMut(f32),
+        cancel_token: Option<&CancelToken>,
+    ) -> Result<(), Cancelled> {
+        let (width, height) = image.dimensions();
+        let graphemes = &analyzed_image.graphemes;
+        let band_height = height
+            .div_ceil(rayon::current_num_threads() as u32 * 4)
+            .max(1);
+
+        let is_cancelled = || cancel_token.is_some_and(CancelToken::is_cancelled);
+        let recordings = graphemes
+            .par_iter()
+            .enumerate()
+            .map(|(i, grapheme)| {
+                if grapheme.top / band_height == grapheme.bottom / band_height {
+                    return Ok(None);
+                }
+                if is_cancelled() {
+                    return Err(Cancelled);
+                }
+
+                let mut recording = Recording(Vec::new());
+                self.render_grapheme(i, decisions[i], analyzed_image, source, &mut recording);
+                // A grapheme never paints the same pixel twice, so the order within a row doesn't matter.
+                recording.0.sort_by_key(|&(_, y, _)| y);
+                Ok(Some(recording.0))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut bands: Vec<Vec<usize>> = vec![Vec::new(); height.div_ceil(band_height) as usize];
+        for (i, grapheme) in graphemes.iter().enumerate() {
+            for band in grapheme.top / band_height..=grapheme.bottom / band_height {
+                bands[band as usize].push(i);
+            }
+        }
+
+        let row_length = width as usize * P::CHANNEL_COUNT as usize;
+        let mut bands: Vec<_> = image
+            .chunks_mut(band_height as usize * row_length)
+            .zip(bands)
+            .enumerate()
+            .map(|(band, (samples, indices))| {
+                let top = band as u32 * band_height;
+                (
+                    Band {
+                        samples,
+                        top,
+                        width,
+                    },
+                    indices,
+                )
+            })
+            .collect();
+
+        // A batch of bands at a time so progress and cancellation stay responsive.
+        let band_count = bands.len();
+        for (batch_index, batch) in bands.chunks_mut(rayon::current_num_threads()).enumerate() {
+            batch.par_iter_mut().try_for_each(|(band, indices)| {
+                let rows = band.top..band.top + band_height;
+                for &i in indices.iter() {
+                    if is_cancelled() {
+                        return Err(Cancelled);
+                    }
+                    let Some(recording) = &recordings[i] else {
+                        self.render_grapheme(i, decisions[i], analyzed_image, source, band);
+                        continue;
+                    };
+                    let start = recording.partition_point(|&(_, y, _)| y < rows.start);
+                    let end = recording.partition_point(|&(_, y, _)| y < rows.end);
+                    for &(x, y, color) in &recording[start..end] {
+                        band.paint(x, y, color);
+                    }
+                }
+                Ok(())
+            })?;
+            let done = (batch_index * rayon::current_num_threads() + batch.len()).min(band_count);
+            progress(done as f32 / band_count as f32);
+        }
+
+        Ok(())
+    }
+}
+
+// Rows top.. of an image width pixels wide.
+struct Band<'a, S> {
+    samples: &'a mut [S],
+    top: u32,
+    width: u32,
+}
+
+impl<P: DocumentPixel> Canvas<P> for Band<'_, P::Subpixel> {
+    fn paint(&mut self, x: u32, y: u32, color: P) {
+        let channels = P::CHANNEL_COUNT as usize;
+        let start = index(self.width, x, y - self.top) * channels;
+        self.samples[start..start + channels].copy_from_slice(color.channels());
+    }
+}
+
+// Every pixel painted as (x, y, color), in the order they were painted.
+struct Recording<P>(Vec<(u32, u32, P)>);
+
+impl<P> Canvas<P> for Recording<P> {
+    fn paint(&mut self, x: u32, y: u32, color: P) {
+        self.0.push((x, y, color));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{math, Rgb, RgbImage};
+
+    use super::*;
+    use crate::tests::demo_page;
+    use crate::{DarkestMap, IntegralImage, ThresholdMode};
+
+    // analyze without the parallel feature, put together from the same single threaded steps.
+    fn analyze_serially(analyzer: &ImageAnalyzer, image: &RgbImage) -> AnalyzedImage<Rgb<u8>> {
+        let (width, height) = image.dimensions();
+        let background_test = BackgroundTest {
+            thresholds: analyzer.thresholds(),
+            region: math::Rect {
+                x: 0,
+                y: 0,
+                width,
+                height,
+            },
+            integral_image: matches!(analyzer.threshold_mode, ThresholdMode::Sauvola { .. })
+                .then(|| IntegralImage::new(image, |p| analyzer.value(p))),
+            darkest_map: DarkestMap::new(image, analyzer.lightness_distance, |p| analyzer.value(p)),
+        };
+        let mut visited_map = VisitedMap::default();
+        visited_map.resize(width, height);
+        for (y, row) in visited_map
+            .words
+            .chunks_mut(visited_map.words_per_row)
+            .enumerate()
+        {
+            let y = y as u32;
+            pack_row(
+                row,
+                (0..width).map(|x| analyzer.is_background(x, y, image, &background_test)),
+            );
+        }
+        if analyzer.morph_close > 0 {
+            visited_map.close(analyzer.morph_close);
+        }
+
+        let graphemes = detect_rows(
+            image,
+            &visited_map,
+            0..height,
+            analyzer.connectivity,
+            |p| analyzer.value(p),
+            |_| Ok(()),
+        )
+        .unwrap();
+        let mut analyzed_image = AnalyzedImage::default();
+        analyzed_image
+            .reset(width, height, analyzer.max_pixels)
+            .unwrap();
+        analyzer.store_graphemes(graphemes, &mut analyzed_image);
+        analyzed_image
+    }
+
+    fn assert_same_analysis(serial: &AnalyzedImage<Rgb<u8>>, parallel: &AnalyzedImage<Rgb<u8>>) {
+        assert_eq!(serial.map, parallel.map);
+        assert_eq!(serial.graphemes.len(), parallel.graphemes.len());
+        for (a, b) in serial.graphemes.iter().zip(&parallel.graphemes) {
+            assert_eq!(a.bounds(), b.bounds());
+            assert_eq!(a.value_sum, b.value_sum);
+            assert_eq!(a.perimeter, b.perimeter);
+            // Only the order of the pixels within a grapheme can differ.
+            let mut a_pixels: Vec<_> = a.pixels().map(|(x, y, p)| (y, x, p.0)).collect();
+            let mut b_pixels: Vec<_> = b.pixels().map(|(x, y, p)| (y, x, p.0)).collect();
+            a_pixels.sort_unstable();
+            b_pixels.sort_unstable();
+            assert_eq!(a_pixels, b_pixels);
+        }
+    }
+
+    fn clean_serially(cleaner: &ImageCleaner, analyzed_image: &AnalyzedImage<Rgb<u8>>) -> RgbImage {
+        let mut image = RgbImage::from_pixel(
+            analyzed_image.width,
+            analyzed_image.height,
+            Rgb(cleaner.background_fill_color),
+        );
+        for (i, decision) in classify_serially(cleaner, analyzed_image)
+            .into_iter()
+            .enumerate()
+        {
+            cleaner.render_grapheme(i, decision, analyzed_image, None, &mut image);
+        }
+        image
+    }
+
+    fn classify_serially(
+        cleaner: &ImageCleaner,
+        analyzed_image: &AnalyzedImage<Rgb<u8>>,
+    ) -> Vec<Decision> {
+        let neighbors = cleaner.build_neighbors(&analyzed_image.graphemes);
+        (0..analyzed_image.graphemes.len())
+            .map(|i| cleaner.decide(i, analyzed_image, &neighbors))
+            .collect()
+    }
+
+    #[test]
+    fn parallel_matches_serial_on_demo_page() {
+        let image = demo_page();
+        let analyzers = [
+            ImageAnalyzer::default(),
+            ImageAnalyzer {
+                connectivity: Connectivity::Eight,
+                lightness_distance: 3,
+                merge_distance: 2,
+                morph_close: 1,
+                ..ImageAnalyzer::default()
+            },
+        ];
+        let cleaners = [
+            ImageCleaner::default(),
+            ImageCleaner {
+                speck_size_threshold: 40,
+                protect_punctuation: true,
+                hole_fill_threshold: 30,
+                remove_border_touching: true,
+                speck_fill_color: [255, 0, 255],
+                ..ImageCleaner::default()
+            },
+        ];
+
+        for analyzer in &analyzers {
+            let serial = analyze_serially(analyzer, &image);
+            let parallel = analyzer.analyze(&image);
+            assert_same_analysis(&serial, &parallel);
+
+            for cleaner in &cleaners {
+                assert_eq!(
+                    classify_serially(cleaner, &parallel),
+                    cleaner.classify(&parallel)
+                );
+                assert_eq!(
+                    clean_serially(cleaner, &parallel).as_raw(),
+                    cleaner.clean(&parallel).as_raw()
+                );
+            }
+        }
+    }
+}
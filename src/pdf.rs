@@ -0,0 +1,242 @@
+// Writing pages into a single PDF, with one image filling each page. Every page is compressed and written out as soon
+// as it's added, so only the objects' offsets stay in memory however many pages there are.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use image::DynamicImage;
+
+// The catalog and the page tree are written last, once all the pages are known, but they get the first object numbers.
+const CATALOG: usize = 1;
+const PAGES: usize = 2;
+
+pub struct PdfWriter<W: Write> {
+    writer: W,
+    // How many bytes have been written, for the cross-reference table.
+    position: u64,
+    // By object number, with the first two filled in by finish.
+    offsets: Vec<u64>,
+    pages: Vec<usize>,
+}
+
+impl PdfWriter<BufWriter<File>> {
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?))
+    }
+}
+
+impl<W: Write> PdfWriter<W> {
+    pub fn new(writer: W) -> io::Result<Self> {
+        let mut pdf = Self {
+            writer,
+            position: 0,
+            offsets: vec![0; PAGES + 1],
+            pages: Vec::new(),
+        };
+        // The comment with bytes over 127 tells tools the file is binary.
+        pdf.write(b"%PDF-1.4\n%\xe2\xe3\xcf\xd3\n")?;
+
+        Ok(pdf)
+    }
+
+    // Adds a page the size of image at dpi, with the image filling it. Grayscale images stay grayscale, everything else
+    // is saved as 8 bit RGB. Fails without writing anything if dpi isn't a positive number.
+    pub fn add_page(&mut self, image: &DynamicImage, dpi: f32) -> io::Result<()> {
+        if !dpi.is_finite() || dpi <= 0.0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("can't make a page at {dpi} dpi"),
+            ));
+        }
+
+        let (color_space, samples) = match image {
+            DynamicImage::ImageLuma8(_) | DynamicImage::ImageLuma16(_) => {
+                ("DeviceGray", image.to_luma8().into_raw())
+            }
+            _ => ("DeviceRGB", image.to_rgb8().into_raw()),
+        };
+        let mut compressor = fdeflate::Compressor::new(Vec::new())?;
+        compressor.write_data(&samples)?;
+        let data = compressor.finish()?;
+
+        let image_id = self.start_object()?;
+        self.write(
+            format!(
+                "<< /Type /XObject /Subtype /Image /Width {} /Height {} /ColorSpace /{color_space} \
+                 /BitsPerComponent 8 /Filter /FlateDecode /Length {} >>\nstream\n",
+                image.width(),
+                image.height(),
+                data.len()
+            )
+            .as_bytes(),
+        )?;
+        self.write(&data)?;
+        self.write(b"\nendstream\nendobj\n")?;
+
+        // In points, which are 1/72 of an inch.
+        let width = image.width() as f32 * 72.0 / dpi;
+        let height = image.height() as f32 * 72.0 / dpi;
+        let content = format!("q {width} 0 0 {height} 0 0 cm /Page Do Q");
+        let content_id = self.start_object()?;
+        self.write(
+            format!(
+                "<< /Length {} >>\nstream\n{content}\nendstream\nendobj\n",
+                content.len()
+            )
+            .as_bytes(),
+        )?;
+
+        let page_id = self.start_object()?;
+        self.write(
+            format!(
+                "<< /Type /Page /Parent {PAGES} 0 R /MediaBox [0 0 {width} {height}] \
+                 /Resources << /XObject << /Page {image_id} 0 R >> >> /Contents {content_id} 0 R >>\nendobj\n"
+            )
+            .as_bytes(),
+        )?;
+        self.pages.push(page_id);
+
+        Ok(())
+    }
+
+    // Writes the page tree and the cross-reference table, without which the file can't be opened.
+    pub fn finish(mut self) -> io::Result<W> {
+        let kids: Vec<String> = self.pages.iter().map(|id| format!("{id} 0 R")).collect();
+        self.offsets[PAGES] = self.position;
+        self.write(
+            format!(
+                "{PAGES} 0 obj\n<< /Type /Pages /Kids [{}] /Count {} >>\nendobj\n",
+                kids.join(" "),
+                self.pages.len()
+            )
+            .as_bytes(),
+        )?;
+        self.offsets[CATALOG] = self.position;
+        self.write(
+            format!("{CATALOG} 0 obj\n<< /Type /Catalog /Pages {PAGES} 0 R >>\nendobj\n")
+                .as_bytes(),
+        )?;
+
+        let xref_position = self.position;
+        let mut xref = format!("xref\n0 {}\n0000000000 65535 f \n", self.offsets.len());
+        for offset in &self.offsets[1..] {
+            xref += &format!("{offset:010} 00000 n \n");
+        }
+        xref += &format!(
+            "trailer\n<< /Size {} /Root {CATALOG} 0 R >>\nstartxref\n{xref_position}\n%%EOF\n",
+            self.offsets.len()
+        );
+        self.write(xref.as_bytes())?;
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+
+    fn start_object(&mut self) -> io::Result<usize> {
+        let id = self.offsets.len();
+        self.offsets.push(self.position);
+        self.write(format!("{id} 0 obj\n").as_bytes())?;
+
+        Ok(id)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.writer.write_all(bytes)?;
+        self.position += bytes.len() as u64;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GrayImage, RgbImage};
+
+    use super::*;
+
+    fn find(bytes: &[u8], text: &str, from: usize) -> Option<usize> {
+        bytes[from..]
+            .windows(text.len())
+            .position(|window| window == text.as_bytes())
+            .map(|i| i + from)
+    }
+
+    // The text from start up to the next newline.
+    fn line(bytes: &[u8], start: usize) -> &str {
+        let end = find(bytes, "\n", start).unwrap();
+        std::str::from_utf8(&bytes[start..end]).unwrap()
+    }
+
+    #[test]
+    fn pages_of_different_sizes() {
+        let pages = [
+            DynamicImage::ImageRgb8(RgbImage::new(300, 150)),
+            DynamicImage::ImageLuma8(GrayImage::new(200, 400)),
+        ];
+        let mut pdf = PdfWriter::new(Vec::new()).unwrap();
+        for page in &pages {
+            pdf.add_page(page, 150.0).unwrap();
+        }
+        let bytes = pdf.finish().unwrap();
+        assert!(bytes.starts_with(b"%PDF-1.4\n"));
+        assert!(bytes.ends_with(b"%%EOF\n"));
+
+        // Every object is where the cross-reference table says it is.
+        let startxref = find(&bytes, "startxref\n", 0).unwrap() + "startxref\n".len();
+        let xref: usize = line(&bytes, startxref).parse().unwrap();
+        assert_eq!(line(&bytes, xref), "xref");
+        let mut position = xref + "xref\n".len();
+        let count: usize = line(&bytes, position)
+            .strip_prefix("0 ")
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert_eq!(count, 3 + 3 * pages.len());
+        position += line(&bytes, position).len() + 1;
+        assert_eq!(line(&bytes, position), "0000000000 65535 f ");
+        for id in 1..count {
+            position += 20;
+            let entry = line(&bytes, position);
+            assert_eq!(entry.len(), 19);
+            let offset: usize = entry[..10].parse().unwrap();
+            assert_eq!(line(&bytes, offset), format!("{id} 0 obj"));
+        }
+        assert!(find(&bytes, &format!("/Size {count} /Root 1 0 R"), xref).is_some());
+        assert!(find(&bytes, "/Count 2 >>", 0).is_some());
+
+        // In points at 150 dpi, in the order the pages were added.
+        let mut position = 0;
+        for (page, color_space) in pages.iter().zip(["DeviceRGB", "DeviceGray"]) {
+            let image = find(&bytes, "/Subtype /Image", position).unwrap();
+            let header = line(&bytes, image);
+            assert!(header.contains(&format!(
+                "/Width {} /Height {} /ColorSpace /{color_space}",
+                page.width(),
+                page.height()
+            )));
+            position = find(&bytes, "/MediaBox [", image).unwrap();
+            let media_box = line(&bytes, position);
+            let (width, height) = (
+                page.width() as f32 * 72.0 / 150.0,
+                page.height() as f32 * 72.0 / 150.0,
+            );
+            assert!(media_box.starts_with(&format!("/MediaBox [0 0 {width} {height}]")));
+        }
+        assert!(find(&bytes, "/MediaBox", position + 1).is_none());
+    }
+
+    #[test]
+    fn bad_dpi_is_rejected() {
+        let page = DynamicImage::ImageLuma8(GrayImage::new(10, 10));
+        let mut pdf = PdfWriter::new(Vec::new()).unwrap();
+        for dpi in [0.0, -72.0, f32::NAN, f32::INFINITY] {
+            let error = pdf.add_page(&page, dpi).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput, "{dpi}");
+        }
+        pdf.add_page(&page, 72.0).unwrap();
+        let bytes = pdf.finish().unwrap();
+        assert!(find(&bytes, "/Count 1 >>", 0).is_some());
+        assert!(find(&bytes, "/MediaBox [0 0 10 10]", 0).is_some());
+    }
+}
@@ -0,0 +1,202 @@
+use image::*;
+
+use crate::LuminanceMode;
+
+// The channel types pixels can have. Pixel values and thresholds are compared in the channels' own range, so nothing
+// is lost analyzing 16 bit images.
+pub trait Sample: Primitive + Ord + Into<u64> + TryFrom<u64> + Send + Sync {
+    // Maps an 8 bit value onto the full range, so 0 stays black and 255 stays white.
+    fn from_u8(value: u8) -> Self;
+}
+
+impl Sample for u8 {
+    fn from_u8(value: u8) -> Self {
+        value
+    }
+}
+
+impl Sample for u16 {
+    fn from_u8(value: u8) -> Self {
+        value as u16 * 257
+    }
+}
+
+// The pixel types images can be analyzed and cleaned in. Grayscale scans can be worked on as they are, which takes a
+// third of the memory of converting them to RGB first.
+pub trait DocumentPixel: Pixel<Subpixel: Sample> + PixelWithColorType + Send + Sync {
+    // How light the pixel is, from 0 (black) to the maximum of Subpixel (white).
+    fn value(&self, luminance_mode: LuminanceMode) -> Self::Subpixel;
+
+    // The red, green, and blue channels, all the same for grayscale pixels.
+    fn rgb(&self) -> [Self::Subpixel; 3];
+
+    fn from_rgb(color: [u8; 3]) -> Self;
+
+    // Fully transparent pixels can't be seen, so they're always background.
+    fn is_transparent(&self) -> bool {
+        false
+    }
+
+    // How the pixel looks drawn over background, for pixel types that can be partially transparent.
+    fn composite(&self, _background: [u8; 3]) -> Self {
+        *self
+    }
+}
+
+impl<S: Sample> DocumentPixel for Rgb<S>
+where
+    Self: Pixel<Subpixel = S> + PixelWithColorType,
+{
+    fn value(&self, luminance_mode: LuminanceMode) -> S {
+        pixel_value(self.0, luminance_mode)
+    }
+
+    fn rgb(&self) -> [S; 3] {
+        self.0
+    }
+
+    fn from_rgb(color: [u8; 3]) -> Self {
+        Rgb(color.map(S::from_u8))
+    }
+}
+
+impl<S: Sample> DocumentPixel for Luma<S>
+where
+    Self: Pixel<Subpixel = S> + PixelWithColorType,
+{
+    // Already a single value, so the luminance mode doesn't matter.
+    fn value(&self, _luminance_mode: LuminanceMode) -> S {
+        self.0[0]
+    }
+
+    fn rgb(&self) -> [S; 3] {
+        [self.0[0]; 3]
+    }
+
+    fn from_rgb(color: [u8; 3]) -> Self {
+        Luma([S::from_u8(Rgb(color).to_luma().0[0])])
+    }
+}
+
+impl<S: Sample> DocumentPixel for Rgba<S>
+where
+    Self: Pixel<Subpixel = S> + PixelWithColorType,
+{
+    // The color channels alone, composite the pixel first to take its alpha into account.
+    fn value(&self, luminance_mode: LuminanceMode) -> S {
+        pixel_value(self.rgb(), luminance_mode)
+    }
+
+    fn rgb(&self) -> [S; 3] {
+        [self.0[0], self.0[1], self.0[2]]
+    }
+
+    fn from_rgb([r, g, b]: [u8; 3]) -> Self {
+        Rgba([r, g, b, 255].map(S::from_u8))
+    }
+
+    fn is_transparent(&self) -> bool {
+        self.0[3] == S::DEFAULT_MIN_VALUE
+    }
+
+    fn composite(&self, background: [u8; 3]) -> Self {
+        let max: u64 = S::DEFAULT_MAX_VALUE.into();
+        let alpha: u64 = self.0[3].into();
+        let [r, g, b] = [0, 1, 2].map(|i| {
+            let background: u64 = S::from_u8(background[i]).into();
+            from_u64((self.0[i].into() * alpha + background * (max - alpha) + max / 2) / max)
+        });
+        Rgba([r, g, b, S::DEFAULT_MAX_VALUE])
+    }
+}
+
+pub type DocumentImage<P> = ImageBuffer<P, Vec<<P as Pixel>::Subpixel>>;
+
+// Somewhere graphemes can be drawn, so the same drawing code works for images of their own pixel type and for RGBA.
+pub(crate) trait Canvas<P> {
+    fn paint(&mut self, x: u32, y: u32, color: P);
+}
+
+impl<P: DocumentPixel> Canvas<P> for DocumentImage<P> {
+    fn paint(&mut self, x: u32, y: u32, color: P) {
+        self.put_pixel(x, y, color);
+    }
+}
+
+// Paints 8 bit RGBA, with everything painted fully opaque.
+pub(crate) struct RgbaCanvas<'a>(pub &'a mut RgbaImage);
+
+impl<P: DocumentPixel> Canvas<P> for RgbaCanvas<'_> {
+    fn paint(&mut self, x: u32, y: u32, color: P) {
+        let [r, g, b] = color.rgb().map(to_u8);
+        self.0.put_pixel(x, y, Rgba([r, g, b, 255]));
+    }
+}
+
+fn pixel_value<S: Sample>(rgb: [S; 3], luminance_mode: LuminanceMode) -> S {
+    let [r, g, b] = rgb.map(Into::<u64>::into);
+    from_u64(match luminance_mode {
+        LuminanceMode::Mean => (r + g + b) / 3,
+        // Fixed point to keep this integer only, rounded to nearest.
+        LuminanceMode::Rec709 => (2126 * r + 7152 * g + 722 * b + 5000) / 10000,
+        LuminanceMode::Rec601 => (299 * r + 587 * g + 114 * b + 500) / 1000,
+    })
+}
+
+// Rounded to the nearest 8 bit value, the opposite of Sample::from_u8.
+fn to_u8<S: Sample>(value: S) -> u8 {
+    let max: u64 = S::DEFAULT_MAX_VALUE.into();
+    ((value.into() * 255 + max / 2) / max) as u8
+}
+
+// For averages of samples, which are always in range.
+pub(crate) fn from_u64<S: Sample>(value: u64) -> S {
+    S::try_from(value).unwrap_or(S::DEFAULT_MAX_VALUE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODES: [(LuminanceMode, [f64; 3]); 3] = [
+        (LuminanceMode::Mean, [1.0 / 3.0; 3]),
+        (LuminanceMode::Rec709, [0.2126, 0.7152, 0.0722]),
+        (LuminanceMode::Rec601, [0.299, 0.587, 0.114]),
+    ];
+
+    fn assert_close<S: Sample>(rgb: [S; 3]) {
+        for (luminance_mode, weights) in MODES {
+            let value: u64 = pixel_value(rgb, luminance_mode).into();
+            let float: f64 = rgb
+                .iter()
+                .zip(weights)
+                .map(|(&channel, weight)| Into::<u64>::into(channel) as f64 * weight)
+                .sum();
+            assert!(
+                (value as f64 - float).abs() <= 1.0,
+                "{luminance_mode:?} of {:?} is {value}, not {float}",
+                rgb.map(Into::<u64>::into)
+            );
+        }
+    }
+
+    #[test]
+    fn pixel_value_matches_float_formulas() {
+        // Every red and green with every fifth blue up to 255, which keeps this quick in debug builds.
+        for r in 0..=255u8 {
+            for g in 0..=255 {
+                for b in (0..=255).step_by(5) {
+                    assert_close([r, g, b]);
+                }
+            }
+        }
+        for r in (0..=u16::MAX).step_by(257 * 3) {
+            for g in (0..=u16::MAX).step_by(251) {
+                for b in (0..=u16::MAX).step_by(4093) {
+                    assert_close([r, g, b]);
+                }
+            }
+        }
+        assert_eq!(pixel_value([u16::MAX; 3], LuminanceMode::Rec709), u16::MAX);
+    }
+}
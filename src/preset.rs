@@ -0,0 +1,146 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{ImageAnalyzer, ImageCleaner, Margins, PageSettings};
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+struct Preset {
+    analyzer: ImageAnalyzer,
+    cleaner: ImageCleaner,
+    // By page number, in order so the file's easier to read.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pages: BTreeMap<usize, PagePreset>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+struct PagePreset {
+    analyzer: ImageAnalyzer,
+    cleaner: ImageCleaner,
+}
+
+// Writes the settings to a JSON file.
+pub fn save_preset(
+    path: impl AsRef<Path>,
+    analyzer: &ImageAnalyzer,
+    cleaner: &ImageCleaner,
+) -> io::Result<()> {
+    save_preset_with_pages(path, analyzer, cleaner, &PageSettings::new())
+}
+
+// Like save_preset, along with the settings of the pages (by page number, counting from 1) that have their own.
+pub fn save_preset_with_pages(
+    path: impl AsRef<Path>,
+    analyzer: &ImageAnalyzer,
+    cleaner: &ImageCleaner,
+    pages: &PageSettings,
+) -> io::Result<()> {
+    let preset = Preset {
+        analyzer: *analyzer,
+        cleaner: cleaner.clone(),
+        pages: pages
+            .iter()
+            .map(|(&page, (analyzer, cleaner))| {
+                let settings = PagePreset {
+                    analyzer: *analyzer,
+                    cleaner: cleaner.clone(),
+                };
+                (page, settings)
+            })
+            .collect(),
+    };
+    serde_json::to_writer_pretty(BufWriter::new(File::create(path)?), &preset)?;
+
+    Ok(())
+}
+
+// Reads settings written by save_preset. Anything missing from the file is left at its default, and the settings of
+// single pages are left out.
+pub fn load_preset(path: impl AsRef<Path>) -> io::Result<(ImageAnalyzer, ImageCleaner)> {
+    let (analyzer, cleaner, _) = load_preset_with_pages(path)?;
+
+    Ok((analyzer, cleaner))
+}
+
+// Reads settings written by save_preset_with_pages, or save_preset with no pages of their own.
+pub fn load_preset_with_pages(
+    path: impl AsRef<Path>,
+) -> io::Result<(ImageAnalyzer, ImageCleaner, PageSettings)> {
+    let preset: Preset = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+    let pages = preset
+        .pages
+        .into_iter()
+        .map(|(page, settings)| (page, (settings.analyzer, settings.cleaner)))
+        .collect();
+
+    Ok((preset.analyzer, preset.cleaner, pages))
+}
+
+// Margins are saved with all four sides, but older presets have them as an [x, y] pair.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub(crate) enum MarginsFormat<T> {
+    Sides {
+        left: T,
+        right: T,
+        top: T,
+        bottom: T,
+    },
+    Pair(T, T),
+}
+
+impl<T: Copy> From<MarginsFormat<T>> for Margins<T> {
+    fn from(format: MarginsFormat<T>) -> Self {
+        match format {
+            MarginsFormat::Sides {
+                left,
+                right,
+                top,
+                bottom,
+            } => Margins {
+                left,
+                right,
+                top,
+                bottom,
+            },
+            MarginsFormat::Pair(x, y) => Margins::from((x, y)),
+        }
+    }
+}
+
+// Rects saved as [x, y, width, height].
+pub(crate) mod rects {
+    use image::math;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        rects: &[math::Rect],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(
+            rects
+                .iter()
+                .map(|rect| [rect.x, rect.y, rect.width, rect.height]),
+        )
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<math::Rect>, D::Error> {
+        let rects = Vec::<[u32; 4]>::deserialize(deserializer)?;
+        Ok(rects
+            .into_iter()
+            .map(|[x, y, width, height]| math::Rect {
+                x,
+                y,
+                width,
+                height,
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,406 @@
+// The measurements of every grapheme on a page and what the cleaner does with it, for picking thresholds from the
+// actual numbers instead of by eye.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use image::*;
+
+use crate::{AnalyzedImage, DocumentPixel, DynamicAnalyzedImage, GraphemeId, ImageCleaner};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ComponentStat {
+    pub id: GraphemeId,
+    pub left: u32,
+    pub top: u32,
+    pub right: u32,
+    pub bottom: u32,
+    pub pixel_count: usize,
+    pub centroid_x: f32,
+    pub centroid_y: f32,
+    // From 0 (black) to 255 (white) whatever the image's bit depth, like the thresholds.
+    pub average_value: f32,
+    // The fraction of the bounding box the pixels cover, what max_fill_ratio and min_fill_ratio are compared against.
+    pub density: f32,
+    // Whether clean fills the grapheme in instead of drawing it.
+    pub filled: bool,
+}
+
+// What clean did with a grapheme and why. When several rules would fill a grapheme, it's the first one that's checked,
+// in the order they're listed here.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum Decision {
+    Kept,
+    ManualKeep,
+    ManualRemove,
+    // Kept because it's in one of the protected regions.
+    Protected,
+    // In the margins but over the margin keep size, like a page number, and not filled by any of the rules after the
+    // margins.
+    KeptMargin,
+    // Filled because it's in one of the removal regions.
+    RemovedRegion,
+    RemovedSpeck,
+    RemovedMargin,
+    // Touches the edge of the page.
+    RemovedBorder,
+    // Over the max grapheme area fraction.
+    RemovedLarge,
+    RemovedIsolated,
+    // Lighter on average than the max average value.
+    RemovedFaint,
+    // Over the max fill ratio.
+    RemovedDense,
+    // Under the min fill ratio.
+    RemovedSparse,
+    // Over the max aspect ratio, and within the other streak limits.
+    RemovedLong,
+}
+
+impl Decision {
+    // Whether the grapheme was filled in instead of drawn.
+    pub fn is_removed(self) -> bool {
+        !matches!(
+            self,
+            Decision::Kept | Decision::ManualKeep | Decision::Protected | Decision::KeptMargin
+        )
+    }
+}
+
+// What clean did with every grapheme on a page, for checking a batch's results or tuning the thresholds.
+#[derive(Clone, PartialEq, Eq, Default, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct CleanReport {
+    // By grapheme index, like GraphemeId.
+    pub decisions: Vec<Decision>,
+    // How many graphemes each decision was made for, decisions that weren't made for any are left out.
+    pub counts: BTreeMap<Decision, usize>,
+    pub removed_count: usize,
+    // The area of all the filled graphemes.
+    pub removed_pixels: usize,
+}
+
+impl CleanReport {
+    pub fn count(&self, decision: Decision) -> usize {
+        self.counts.get(&decision).copied().unwrap_or(0)
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.decisions.clear();
+        self.counts.clear();
+        self.removed_count = 0;
+        self.removed_pixels = 0;
+    }
+
+    pub(crate) fn record(&mut self, decision: Decision, pixel_count: usize) {
+        self.decisions.push(decision);
+        *self.counts.entry(decision).or_default() += 1;
+        if decision.is_removed() {
+            self.removed_count += 1;
+            self.removed_pixels += pixel_count;
+        }
+    }
+}
+
+impl<P: DocumentPixel> AnalyzedImage<P> {
+    pub fn component_report(&self, cleaner: &ImageCleaner) -> Vec<ComponentStat> {
+        let max: u64 = P::Subpixel::DEFAULT_MAX_VALUE.into();
+        let decisions = cleaner.classify(self);
+        self.graphemes
+            .iter()
+            .zip(decisions)
+            .enumerate()
+            .map(|(i, (grapheme, decision))| {
+                let (centroid_x, centroid_y) = grapheme.centroid();
+                let average_value: u64 = grapheme.average_value().into();
+                ComponentStat {
+                    id: GraphemeId(i as u32),
+                    left: grapheme.left,
+                    top: grapheme.top,
+                    right: grapheme.right,
+                    bottom: grapheme.bottom,
+                    pixel_count: grapheme.pixel_count(),
+                    centroid_x,
+                    centroid_y,
+                    average_value: average_value as f32 * 255.0 / max as f32,
+                    density: grapheme.density(),
+                    filled: decision.is_removed(),
+                }
+            })
+            .collect()
+    }
+
+    // The fraction of the page's pixels that are in graphemes the cleaner keeps, for spotting pages in a big batch
+    // that came out nearly blank (from thresholds that are too aggressive) or nearly full (from a smudge that wasn't
+    // removed).
+    pub fn foreground_fraction(&self, cleaner: &ImageCleaner) -> f32 {
+        let kept: usize = self
+            .graphemes
+            .iter()
+            .zip(cleaner.classify(self))
+            .filter(|(_, decision)| !decision.is_removed())
+            .map(|(grapheme, _)| grapheme.pixel_count())
+            .sum();
+        let total = self.width as u64 * self.height as u64;
+        (kept as f64 / total.max(1) as f64) as f32
+    }
+}
+
+impl DynamicAnalyzedImage {
+    pub fn foreground_fraction(&self, cleaner: &ImageCleaner) -> f32 {
+        match self {
+            DynamicAnalyzedImage::Luma8(analyzed_image) => {
+                analyzed_image.foreground_fraction(cleaner)
+            }
+            DynamicAnalyzedImage::Rgb8(analyzed_image) => {
+                analyzed_image.foreground_fraction(cleaner)
+            }
+            DynamicAnalyzedImage::Rgba8(analyzed_image) => {
+                analyzed_image.foreground_fraction(cleaner)
+            }
+            DynamicAnalyzedImage::Luma16(analyzed_image) => {
+                analyzed_image.foreground_fraction(cleaner)
+            }
+            DynamicAnalyzedImage::Rgb16(analyzed_image) => {
+                analyzed_image.foreground_fraction(cleaner)
+            }
+            DynamicAnalyzedImage::Rgba16(analyzed_image) => {
+                analyzed_image.foreground_fraction(cleaner)
+            }
+        }
+    }
+
+    pub fn component_report(&self, cleaner: &ImageCleaner) -> Vec<ComponentStat> {
+        match self {
+            DynamicAnalyzedImage::Luma8(analyzed_image) => analyzed_image.component_report(cleaner),
+            DynamicAnalyzedImage::Rgb8(analyzed_image) => analyzed_image.component_report(cleaner),
+            DynamicAnalyzedImage::Rgba8(analyzed_image) => analyzed_image.component_report(cleaner),
+            DynamicAnalyzedImage::Luma16(analyzed_image) => {
+                analyzed_image.component_report(cleaner)
+            }
+            DynamicAnalyzedImage::Rgb16(analyzed_image) => analyzed_image.component_report(cleaner),
+            DynamicAnalyzedImage::Rgba16(analyzed_image) => {
+                analyzed_image.component_report(cleaner)
+            }
+        }
+    }
+}
+
+// Writes the report as CSV with a header row, one grapheme per line.
+pub fn save_component_report_csv(
+    path: impl AsRef<Path>,
+    report: &[ComponentStat],
+) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(
+        writer,
+        "id,left,top,right,bottom,pixel_count,centroid_x,centroid_y,average_value,density,filled"
+    )?;
+    for stat in report {
+        writeln!(
+            writer,
+            "{},{},{},{},{},{},{},{},{},{},{}",
+            stat.id.index(),
+            stat.left,
+            stat.top,
+            stat.right,
+            stat.bottom,
+            stat.pixel_count,
+            stat.centroid_x,
+            stat.centroid_y,
+            stat.average_value,
+            stat.density,
+            stat.filled,
+        )?;
+    }
+
+    writer.flush()
+}
+
+// Writes the report as a JSON array of objects.
+#[cfg(feature = "serde")]
+pub fn save_component_report_json(
+    path: impl AsRef<Path>,
+    report: &[ComponentStat],
+) -> io::Result<()> {
+    serde_json::to_writer_pretty(BufWriter::new(File::create(path)?), report)?;
+
+    Ok(())
+}
+
+// Writes the report as a JSON object, for keeping a record of what was removed from each page of a batch.
+#[cfg(feature = "serde")]
+pub fn save_clean_report_json(path: impl AsRef<Path>, report: &CleanReport) -> io::Result<()> {
+    serde_json::to_writer_pretty(BufWriter::new(File::create(path)?), report)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{math, GrayImage, Luma};
+
+    use super::*;
+    use crate::tests::{blank_page, draw, fill_rect};
+    use crate::{ImageAnalyzer, Margins};
+
+    // A 100x100 page with a 10x10 grapheme drawn by mark at (x, y).
+    fn page(x: u32, y: u32, mark: impl FnOnce(&mut GrayImage, u32, u32)) -> GrayImage {
+        let mut image = blank_page(100, 100);
+        mark(&mut image, x, y);
+        image
+    }
+
+    fn block(image: &mut GrayImage, x: u32, y: u32) {
+        fill_rect(image, x, y, 10, 10);
+    }
+
+    // None of the rules that are on by default, so each test only has to turn on the one it's about.
+    fn plain() -> ImageCleaner {
+        ImageCleaner {
+            speck_size_threshold: 0,
+            page_margins: Margins::from((0, 0)),
+            isolation_size_threshold: 0,
+            ..ImageCleaner::default()
+        }
+    }
+
+    // Cleans the page's only grapheme, checking that the report and the pixels agree with classify.
+    fn decide(image: &GrayImage, manual_override: Option<bool>, cleaner: ImageCleaner) -> Decision {
+        let mut analyzed_image = ImageAnalyzer::default().analyze(image);
+        assert_eq!(analyzed_image.graphemes.len(), 1);
+        analyzed_image.graphemes[0].manual_override = manual_override;
+
+        let decisions = cleaner.classify(&analyzed_image);
+        let (cleaned, report) = cleaner.clean_with_report(&analyzed_image);
+        assert_eq!(report.decisions, decisions);
+        let drawn = analyzed_image.graphemes[0]
+            .positions()
+            .all(|(x, y)| cleaned.get_pixel(x, y) == image.get_pixel(x, y));
+        assert_eq!(drawn, !decisions[0].is_removed(), "{:?}", decisions[0]);
+        decisions[0]
+    }
+
+    #[test]
+    fn every_decision() {
+        let middle = page(45, 45, block);
+        let rect = math::Rect {
+            x: 40,
+            y: 40,
+            width: 20,
+            height: 20,
+        };
+        let specks = ImageCleaner {
+            speck_size_threshold: 1000,
+            ..plain()
+        };
+        let margins = ImageCleaner {
+            page_margins: Margins::from((50, 50)),
+            ..plain()
+        };
+        let gray = page(45, 45, |image, x, y| {
+            for (dx, dy) in (0..10).flat_map(|dx| (0..10).map(move |dy| (dx, dy))) {
+                image.put_pixel(x + dx, y + dy, Luma([90]));
+            }
+        });
+        let outline = page(45, 45, |image, x, y| {
+            let side = "#        #";
+            draw(
+                image,
+                x,
+                y,
+                &[
+                    "##########",
+                    side,
+                    side,
+                    side,
+                    side,
+                    side,
+                    side,
+                    side,
+                    side,
+                    "##########",
+                ],
+            );
+        });
+        let rule = page(30, 50, |image, x, y| fill_rect(image, x, y, 40, 2));
+
+        assert_eq!(decide(&middle, None, plain()), Decision::Kept);
+        assert_eq!(
+            decide(&middle, Some(true), specks.clone()),
+            Decision::ManualKeep
+        );
+        assert_eq!(
+            decide(&middle, Some(false), plain()),
+            Decision::ManualRemove
+        );
+        let cleaner = ImageCleaner {
+            protected_regions: vec![rect],
+            ..specks.clone()
+        };
+        assert_eq!(decide(&middle, None, cleaner), Decision::Protected);
+        let cleaner = ImageCleaner {
+            removal_regions: vec![rect],
+            ..plain()
+        };
+        assert_eq!(decide(&middle, None, cleaner), Decision::RemovedRegion);
+        assert_eq!(decide(&middle, None, specks), Decision::RemovedSpeck);
+        let in_margin = page(10, 45, block);
+        assert_eq!(
+            decide(&in_margin, None, margins.clone()),
+            Decision::RemovedMargin
+        );
+        let cleaner = ImageCleaner {
+            margin_keep_size: Some(50),
+            ..margins
+        };
+        assert_eq!(decide(&in_margin, None, cleaner), Decision::KeptMargin);
+        let cleaner = ImageCleaner {
+            remove_border_touching: true,
+            ..plain()
+        };
+        assert_eq!(
+            decide(&page(0, 45, block), None, cleaner),
+            Decision::RemovedBorder
+        );
+        let cleaner = ImageCleaner {
+            max_grapheme_area_fraction: Some(0.005),
+            ..plain()
+        };
+        assert_eq!(decide(&middle, None, cleaner), Decision::RemovedLarge);
+        let cleaner = ImageCleaner {
+            isolation_size_threshold: 200,
+            ..plain()
+        };
+        assert_eq!(decide(&middle, None, cleaner), Decision::RemovedIsolated);
+        let cleaner = ImageCleaner {
+            max_average_value: Some(89),
+            ..plain()
+        };
+        assert_eq!(decide(&gray, None, cleaner), Decision::RemovedFaint);
+        let cleaner = ImageCleaner {
+            max_average_value: Some(90),
+            ..plain()
+        };
+        assert_eq!(decide(&gray, None, cleaner), Decision::Kept);
+        let cleaner = ImageCleaner {
+            max_fill_ratio: Some(0.9),
+            ..plain()
+        };
+        assert_eq!(decide(&middle, None, cleaner), Decision::RemovedDense);
+        let cleaner = ImageCleaner {
+            min_fill_ratio: Some(0.5),
+            ..plain()
+        };
+        assert_eq!(decide(&outline, None, cleaner), Decision::RemovedSparse);
+        let cleaner = ImageCleaner {
+            max_aspect_ratio: Some(5.0),
+            ..plain()
+        };
+        assert_eq!(decide(&rule, None, cleaner), Decision::RemovedLong);
+    }
+}
@@ -0,0 +1,443 @@
+// Analyzing images too big to comfortably decode whole, like large format maps scanned at a high dpi, a tile at a time.
+//
+// Each tile is analyzed along with a band of overlap around it, so the pixels near its edges are judged with the same
+// surroundings as they would be in the whole image, but only graphemes' pixels inside the tile itself are kept. Graphemes
+// that cross from one tile into the next are then joined up where their pixels touch across the seam. As long as the
+// overlap reaches as far as the analyzer looks around a pixel (see reach), the result is the same as analyzing the
+// whole image, except with auto_flatten, which estimates the paper color from each tile on its own.
+//
+// Memory: besides the tile being analyzed (with its overlap), only the analyzed image itself is kept, which is 4 bytes
+// per pixel for the map, a bit per pixel for the visited map, and the position and color of every grapheme pixel. PNGs
+// are read a row at a time so only the rows of the current band of tiles are decoded at once, other formats have to be
+// decoded whole first.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use image::error::{DecodingError, ImageFormatHint};
+use image::*;
+
+use crate::{
+    index, merge_sets, normalize_background, union, AnalyzedImage, Cancelled, Connectivity,
+    DocumentImage, DocumentPixel, Grapheme, ImageAnalyzer, ThresholdMode, VisitedMap,
+};
+
+impl ImageAnalyzer {
+    // Analyzes the image at path in tiles of tile (width, height) pixels, each read with overlap pixels around it. Any
+    // alpha channel is composited over transparent_background_color, like analyze does.
+    pub fn analyze_tiled(
+        &self,
+        path: impl AsRef<Path>,
+        tile: (u32, u32),
+        overlap: u32,
+    ) -> ImageResult<AnalyzedImage<Rgb<u8>>> {
+        let mut rows = RowReader::open(path.as_ref(), self.transparent_background_color)?;
+        let (width, height) = (rows.width, rows.height);
+        self.analyze_tiles(width, height, tile, overlap, |rect| rows.read(rect))
+    }
+
+    // Analyzes a width by height image in tiles, with read giving the pixels of each rect asked for. Tiles are asked for
+    // a row of tiles at a time from the top down, and left to right within each row.
+    pub fn analyze_tiles<P: DocumentPixel>(
+        &self,
+        width: u32,
+        height: u32,
+        tile: (u32, u32),
+        overlap: u32,
+        mut read: impl FnMut(math::Rect) -> ImageResult<DocumentImage<P>>,
+    ) -> ImageResult<AnalyzedImage<P>> {
+        let (tile_width, tile_height) = (tile.0.max(1), tile.1.max(1));
+        let overlap = overlap.max(self.reach());
+
+        let mut graphemes = Vec::new();
+        for top in (0..height).step_by(tile_height as usize) {
+            for left in (0..width).step_by(tile_width as usize) {
+                let (x, y) = (left.saturating_sub(overlap), top.saturating_sub(overlap));
+                let (right, bottom) = (
+                    (left + tile_width).min(width),
+                    (top + tile_height).min(height),
+                );
+                let rect = math::Rect {
+                    x,
+                    y,
+                    width: right.saturating_add(overlap).min(width) - x,
+                    height: bottom.saturating_add(overlap).min(height) - y,
+                };
+                let image = read(rect)?;
+                if image.dimensions() != (rect.width, rect.height) {
+                    return Err(ImageError::Parameter(error::ParameterError::from_kind(
+                        error::ParameterErrorKind::DimensionMismatch,
+                    )));
+                }
+
+                let core = math::Rect {
+                    x: left - x,
+                    y: top - y,
+                    width: right - left,
+                    height: bottom - top,
+                };
+                graphemes.extend(
+                    self.analyze_tile(&image, core)
+                        .into_iter()
+                        .map(|grapheme| grapheme.translated(left, top)),
+                );
+            }
+        }
+
+        let mut analyzed_image = AnalyzedImage::default();
+        analyzed_image.reset(width, height, self.max_pixels)?;
+        for (i, grapheme) in graphemes.iter().enumerate() {
+            for &(y, start, end) in &grapheme.runs {
+                analyzed_image.map[index(width, start, y)..index(width, end, y)].fill(i as u32);
+            }
+        }
+
+        // Join up the graphemes whose pixels touch across the seams between tiles.
+        let mut parents: Vec<usize> = (0..graphemes.len()).collect();
+        let neighbors: &[i64] = match self.connectivity {
+            Connectivity::Four => &[0],
+            Connectivity::Eight => &[-1, 0, 1],
+        };
+        let map = &analyzed_image.map;
+        let grapheme_at = |x: i64, y: i64| {
+            let inside = (0..width as i64).contains(&x) && (0..height as i64).contains(&y);
+            inside
+                .then(|| map[index(width, x as u32, y as u32)])
+                .filter(|&i| i != u32::MAX)
+        };
+        for seam in (tile_width..width).step_by(tile_width as usize) {
+            for y in 0..height {
+                let Some(a) = grapheme_at(seam as i64 - 1, y as i64) else {
+                    continue;
+                };
+                for &dy in neighbors {
+                    if let Some(b) = grapheme_at(seam as i64, y as i64 + dy) {
+                        union(&mut parents, a as usize, b as usize);
+                    }
+                }
+            }
+        }
+        for seam in (tile_height..height).step_by(tile_height as usize) {
+            for x in 0..width {
+                let Some(a) = grapheme_at(x as i64, seam as i64 - 1) else {
+                    continue;
+                };
+                for &dx in neighbors {
+                    if let Some(b) = grapheme_at(x as i64 + dx, seam as i64) {
+                        union(&mut parents, a as usize, b as usize);
+                    }
+                }
+            }
+        }
+
+        // Back into the order a single flood fill over the whole image would find them in.
+        let mut graphemes = merge_sets(graphemes, &mut parents);
+        graphemes.sort_by_key(Grapheme::first_pixel);
+        analyzed_image.map.fill(u32::MAX);
+        self.store_graphemes(graphemes, &mut analyzed_image);
+
+        Ok(analyzed_image)
+    }
+
+    // How far from a pixel the analyzer looks to decide whether it's background, so tiles need at least this much
+    // overlap to come out the same as the whole image.
+    pub fn reach(&self) -> u32 {
+        let window = match self.threshold_mode {
+            ThresholdMode::Global => 0,
+            ThresholdMode::Sauvola { window, .. } => window / 2,
+        };
+        // Closing dilates and then erodes, each by morph_close.
+        self.lightness_distance.max(window) + 2 * self.morph_close
+    }
+
+    // The graphemes inside core (relative to core's corner), judged with all of image around them.
+    fn analyze_tile<P: DocumentPixel>(
+        &self,
+        image: &DocumentImage<P>,
+        core: math::Rect,
+    ) -> Vec<Grapheme<P>> {
+        let flattened;
+        let image = match self.auto_flatten {
+            Some(block_size) => {
+                flattened = normalize_background(image, block_size);
+                &flattened
+            }
+            None => image,
+        };
+
+        let mut visited_map = VisitedMap::default();
+        visited_map.resize(image.width(), image.height());
+        let whole = math::Rect {
+            x: 0,
+            y: 0,
+            width: image.width(),
+            height: image.height(),
+        };
+        let not_cancelled = || false;
+        let graphemes = self
+            .mark_background(
+                image,
+                whole,
+                self.thresholds(),
+                &mut visited_map,
+                &mut |_| {},
+                &not_cancelled,
+            )
+            .and_then(|()| {
+                let visited_map = visited_map.crop(core);
+                let image = ImageBuffer::from_fn(core.width, core.height, |x, y| {
+                    *image.get_pixel(core.x + x, core.y + y)
+                });
+                self.detect_graphemes(&image, &visited_map, &mut |_| {}, &not_cancelled)
+            });
+        match graphemes {
+            Ok(graphemes) => graphemes,
+            Err(Cancelled) => unreachable!("there's no token to cancel it"),
+        }
+    }
+}
+
+impl<P> Grapheme<P> {
+    fn translated(mut self, dx: u32, dy: u32) -> Self {
+        for (y, start, end) in &mut self.runs {
+            *y += dy;
+            *start += dx;
+            *end += dx;
+        }
+        self.top += dy;
+        self.bottom += dy;
+        self.left += dx;
+        self.right += dx;
+        self
+    }
+
+    // The position of the grapheme's first pixel in row-major order, as (y, x).
+    fn first_pixel(&self) -> (u32, u32) {
+        let x = self
+            .runs
+            .iter()
+            .filter(|&&(y, _, _)| y == self.top)
+            .map(|&(_, start, _)| start)
+            .min();
+        (self.top, x.unwrap_or(self.left))
+    }
+}
+
+// An image's rows read from the top down as RGB, with the rows that are still needed kept around.
+struct RowReader {
+    width: u32,
+    height: u32,
+    source: RowSource,
+    // What transparent pixels are composited over.
+    background: [u8; 3],
+    // The rows from first_row on, each width * 3 bytes.
+    rows: VecDeque<Vec<u8>>,
+    first_row: u32,
+}
+
+enum RowSource {
+    // Decoded a row at a time.
+    Png(Box<png::Reader<BufReader<File>>>),
+    // Formats that can't be decoded a piece at a time are decoded whole up front.
+    Decoded(RgbImage),
+}
+
+impl RowReader {
+    fn open(path: &Path, background: [u8; 3]) -> ImageResult<Self> {
+        let reader = io::Reader::open(path)?.with_guessed_format()?;
+        if reader.format() == Some(ImageFormat::Png) {
+            let mut decoder = png::Decoder::new(BufReader::new(File::open(path)?));
+            decoder.set_transformations(png::Transformations::normalize_to_color8());
+            let png_reader = decoder.read_info().map_err(png_error)?;
+            // Interlaced images come in passes over the whole image rather than rows.
+            if !png_reader.info().interlaced {
+                let (width, height) = png_reader.info().size();
+                return Ok(Self {
+                    width,
+                    height,
+                    source: RowSource::Png(Box::new(png_reader)),
+                    background,
+                    rows: VecDeque::new(),
+                    first_row: 0,
+                });
+            }
+        }
+
+        let image = reader.decode()?;
+        let image = if image.color().has_alpha() {
+            let image = image.into_rgba8();
+            RgbImage::from_fn(image.width(), image.height(), |x, y| {
+                Rgb(composite(image.get_pixel(x, y).0, background))
+            })
+        } else {
+            image.into_rgb8()
+        };
+        Ok(Self {
+            width: image.width(),
+            height: image.height(),
+            source: RowSource::Decoded(image),
+            background,
+            rows: VecDeque::new(),
+            first_row: 0,
+        })
+    }
+
+    // Rects have to come in order of their tops, rows above the last one's top are let go.
+    fn read(&mut self, rect: math::Rect) -> ImageResult<RgbImage> {
+        while self.first_row < rect.y {
+            if self.rows.pop_front().is_none() {
+                self.next_row()?;
+            }
+            self.first_row += 1;
+        }
+        while self.first_row + (self.rows.len() as u32) < rect.y + rect.height {
+            let row = self.next_row()?;
+            self.rows.push_back(row);
+        }
+
+        let mut pixels = Vec::with_capacity(rect.width as usize * rect.height as usize * 3);
+        for row in self.rows.iter().take(rect.height as usize) {
+            pixels.extend_from_slice(&row[rect.x as usize * 3..(rect.x + rect.width) as usize * 3]);
+        }
+        Ok(RgbImage::from_raw(rect.width, rect.height, pixels).unwrap())
+    }
+
+    // The row after the ones already read.
+    fn next_row(&mut self) -> ImageResult<Vec<u8>> {
+        let y = self.first_row + self.rows.len() as u32;
+        match &mut self.source {
+            RowSource::Png(reader) => {
+                let color_type = reader.output_color_type().0;
+                let row = reader
+                    .next_row()
+                    .map_err(png_error)?
+                    .ok_or_else(|| ImageError::IoError(std::io::ErrorKind::UnexpectedEof.into()))?;
+                let channels = row.data().chunks(color_type.samples());
+                let background = self.background;
+                Ok(match color_type {
+                    png::ColorType::Grayscale => channels.flat_map(|pixel| [pixel[0]; 3]).collect(),
+                    png::ColorType::GrayscaleAlpha => channels
+                        .flat_map(|pixel| {
+                            composite([pixel[0], pixel[0], pixel[0], pixel[1]], background)
+                        })
+                        .collect(),
+                    png::ColorType::Rgba => channels
+                        .flat_map(|pixel| {
+                            composite([pixel[0], pixel[1], pixel[2], pixel[3]], background)
+                        })
+                        .collect(),
+                    _ => channels
+                        .flat_map(|pixel| [pixel[0], pixel[1], pixel[2]])
+                        .collect(),
+                })
+            }
+            RowSource::Decoded(image) => {
+                let row_bytes = image.width() as usize * 3;
+                let start = y as usize * row_bytes;
+                Ok(image.as_raw()[start..start + row_bytes].to_vec())
+            }
+        }
+    }
+}
+
+fn composite(pixel: [u8; 4], background: [u8; 3]) -> [u8; 3] {
+    let [r, g, b, _] = Rgba(pixel).composite(background).0;
+    [r, g, b]
+}
+
+fn png_error(error: png::DecodingError) -> ImageError {
+    ImageError::Decoding(DecodingError::new(
+        ImageFormatHint::Exact(ImageFormat::Png),
+        error,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{blank_page, demo_page, draw, fill_rect};
+
+    fn assert_same<P: DocumentPixel>(tiled: &AnalyzedImage<P>, whole: &AnalyzedImage<P>) {
+        assert_eq!(tiled.map, whole.map);
+        assert_eq!(tiled.graphemes.len(), whole.graphemes.len());
+        for (a, b) in tiled.graphemes.iter().zip(&whole.graphemes) {
+            assert_eq!(a.bounds(), b.bounds());
+            let mut positions = a.positions().collect::<Vec<_>>();
+            let mut whole_positions = b.positions().collect::<Vec<_>>();
+            positions.sort_unstable();
+            whole_positions.sort_unstable();
+            assert_eq!(positions, whole_positions);
+            assert_eq!((a.perimeter, a.value_sum), (b.perimeter, b.value_sum));
+        }
+    }
+
+    fn analyze_in_tiles<P: DocumentPixel>(
+        analyzer: &ImageAnalyzer,
+        image: &DocumentImage<P>,
+        tile: (u32, u32),
+    ) -> AnalyzedImage<P> {
+        analyzer
+            .analyze_tiles(image.width(), image.height(), tile, 0, |rect| {
+                Ok(ImageBuffer::from_fn(rect.width, rect.height, |x, y| {
+                    *image.get_pixel(rect.x + x, rect.y + y)
+                }))
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn graphemes_across_seams() {
+        let mut image = blank_page(60, 60);
+        // Across the seam between the first two tiles, across the corner of four tiles, and touching the next tile's
+        // corner diagonally.
+        fill_rect(&mut image, 15, 5, 10, 3);
+        fill_rect(&mut image, 37, 17, 6, 6);
+        draw(&mut image, 17, 38, &["  #", " # ", "#  "]);
+        draw(&mut image, 38, 38, &["#  ", " # ", "  #"]);
+
+        for analyzer in [
+            ImageAnalyzer::default(),
+            ImageAnalyzer {
+                connectivity: Connectivity::Eight,
+                morph_close: 1,
+                ..ImageAnalyzer::default()
+            },
+        ] {
+            let whole = analyzer.analyze(&image);
+            assert_same(&analyze_in_tiles(&analyzer, &image, (20, 20)), &whole);
+        }
+    }
+
+    #[test]
+    fn demo_page_in_tiles() {
+        let image = demo_page();
+        let analyzer = ImageAnalyzer::default();
+        assert_same(
+            &analyze_in_tiles(&analyzer, &image, (700, 900)),
+            &analyzer.analyze(&image),
+        );
+    }
+
+    #[test]
+    fn composites_alpha() {
+        // Black but fully transparent, except for a mark that's opaque or half transparent.
+        let mut image = RgbaImage::from_pixel(40, 30, Rgba([0, 0, 0, 0]));
+        for (x, y) in [(5, 5), (6, 5), (20, 10)] {
+            image.put_pixel(x, y, Rgba([0, 0, 0, 255]));
+        }
+        image.put_pixel(30, 20, Rgba([0, 0, 0, 200]));
+        let path =
+            std::env::temp_dir().join(format!("document_cleaner_{}_alpha.png", std::process::id()));
+        image.save(&path).unwrap();
+
+        let analyzer = ImageAnalyzer::default();
+        let tiled = analyzer.analyze_tiled(&path, (16, 16), 0);
+        std::fs::remove_file(&path).unwrap();
+        let tiled = tiled.unwrap();
+        let whole = analyzer.analyze(&image);
+        assert_eq!(tiled.map, whole.map);
+        assert_eq!(tiled.graphemes.len(), 3);
+    }
+}